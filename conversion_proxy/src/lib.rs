@@ -1,22 +1,299 @@
+// Payment methods and their callbacks take the full payment details, including in generated bindings
+#![allow(clippy::too_many_arguments)]
+
+use std::collections::HashMap;
 use std::convert::TryInto;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{ValidAccountId, U128, U64};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json::json;
 use near_sdk::{
-    bs58, env, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseResult,
-    PublicKey, Timestamp,
+    bs58, env, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult, PublicKey, Timestamp,
 };
 
-near_sdk::setup_alloc!();
+use payee_preferences::{PayeePreferences, PayeesPreferences};
+use payment_intents::{PayeeKeys, PaymentIntent, SignedIntent};
+use proxy_errors::{require, ProxyError};
+use proxy_math::{fee_from_bps, mul_pow10_div, MAX_BPS};
 
-const NO_DEPOSIT: Balance = 0;
 const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
+const NEAR_DECIMALS: i64 = 24;
 // Fiat values with two decimals
-const ONE_FIAT: Balance = 100;
-const MIN_GAS: Gas = 50_000_000_000_000;
-const BASIC_GAS: Gas = 10_000_000_000_000;
+const FIAT_DECIMALS: i64 = 2;
+const MIN_GAS: Gas = Gas(50_000_000_000_000);
+const BASIC_GAS: Gas = Gas(10_000_000_000_000);
+const MAX_FEE_RECIPIENTS: usize = 4;
+
+/// Additional fee recipient, paid on top of `fee_address`
+///
+/// - `address`: `amount` in `currency` of NEAR will be paid to this address
+/// - `amount`: in `currency` with 2 decimals (eg. 1000 is 10.00)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FeeRecipient {
+    pub address: AccountId,
+    pub amount: U128,
+}
+
+/// Computes the fee for `fee_bps` basis points of `amount`, rounded down to the smallest `currency` unit
+fn fee_amount_from_bps(amount: Balance, fee_bps: u16) -> Balance {
+    fee_from_bps(amount, fee_bps).unwrap_or_else(|| {
+        ProxyError::FeeBpsTooHigh {
+            supplied: fee_bps,
+            max: MAX_BPS,
+        }
+        .panic()
+    })
+}
+
+/// Minimum and maximum protocol fee for a currency, in that currency with 2 decimals
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct ProtocolFeeCaps {
+    pub min_amount: U128,
+    pub max_amount: U128,
+}
+
+/// Protocol fee schedule, as configured by the owner
+///
+/// - `bps`: protocol fee in basis points of the payment `amount`
+/// - `treasury_id`: receives the protocol fee (no protocol fee is collected if none)
+/// - `caps`: optional minimum and maximum protocol fee, per currency
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFeeSchedule {
+    pub bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub caps: HashMap<String, ProtocolFeeCaps>,
+}
+
+/// Protocol fee collected on a payment, `amount` being in the payment `currency`
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFee {
+    pub treasury_id: AccountId,
+    pub amount: U128,
+}
+
+/// NEAR transfer of `amount` yoctoNEAR to `receiver_id`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+/// Payment locked in the contract under its payment reference
+///
+/// - `payer`: can release the payment to the payee, or get it refunded after `refund_after`
+/// - `refund_to`: if set, receives refunds instead of the payer
+/// - `to`: the payee, who can refund the payer at any time
+/// - `transfers`: NEAR transfers made on release, to the payee then to fee recipients
+/// - `refund_after`: timestamp in nanoseconds after which the payer can get the payment refunded
+/// - `claim_after`: for scheduled payments, timestamp in nanoseconds after which the payee can claim (release) the payment,
+///   the payer being able to cancel (refund) it only before then
+/// - `payment_log`: standard payment log, emitted on release
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Escrow {
+    pub payer: AccountId,
+    pub to: AccountId,
+    pub transfers: Vec<Transfer>,
+    pub refund_after: U64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim_after: Option<U64>,
+    pub payment_log: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_to: Option<AccountId>,
+}
+
+impl Escrow {
+    /// Total amount locked, in yoctoNEAR
+    pub fn total_amount(&self) -> Balance {
+        self.transfers
+            .iter()
+            .map(|transfer| transfer.amount.0)
+            .sum()
+    }
+
+    /// Account receiving refunds: `refund_to` if set, else the payer
+    pub fn refund_account(&self) -> &AccountId {
+        self.refund_to.as_ref().unwrap_or(&self.payer)
+    }
+
+    /// The payer can release the payment at any time, the payee after `claim_after` for scheduled payments
+    pub fn can_release(&self, caller: &AccountId, timestamp: Timestamp) -> bool {
+        let claimable = self
+            .claim_after
+            .as_ref()
+            .is_some_and(|claim_after| timestamp >= claim_after.0);
+        caller == &self.payer || (caller == &self.to && claimable)
+    }
+
+    /// The payee can refund the payment at any time, the payer after `refund_after`,
+    /// or before `claim_after` for scheduled payments
+    pub fn can_refund(&self, caller: &AccountId, timestamp: Timestamp) -> bool {
+        let refundable = match &self.claim_after {
+            Some(claim_after) => timestamp < claim_after.0,
+            None => timestamp >= self.refund_after.0,
+        };
+        caller == &self.to || (caller == &self.payer && refundable)
+    }
+}
+
+/// Standard payment log, used for indexing and payment detection
+fn payment_log(
+    payment_reference: &str,
+    payment_address: &AccountId,
+    amount: U128,
+    currency: &str,
+    fee_payment_address: &AccountId,
+    fee_amount: U128,
+    max_rate_timespan: U64,
+    fees: &[FeeRecipient],
+    protocol_fee: Option<&ProtocolFee>,
+) -> String {
+    let mut payment_log = json!({
+        "to": payment_address,
+        "amount": amount,
+        "currency": currency,
+        "payment_reference": payment_reference,
+        "fee_amount": fee_amount,
+        "fee_address": fee_payment_address,
+        "max_rate_timespan": max_rate_timespan,
+    });
+    if !fees.is_empty() {
+        payment_log["fees"] = json!(fees);
+    }
+    if let Some(protocol_fee) = protocol_fee {
+        payment_log["protocol_fee"] = json!(protocol_fee);
+    }
+    payment_log.to_string()
+}
+
+/// Makes each of the NEAR `transfers` in its own promise, joined with `and` so that the result of each transfer
+/// can be checked (see `Settlement`), skipping empty ones
+fn transfers_promise(transfers: &[Transfer]) -> Promise {
+    transfers
+        .iter()
+        .filter(|transfer| transfer.amount.0 > 0)
+        .map(|transfer| Promise::new(transfer.receiver_id.clone()).transfer(transfer.amount.0))
+        .reduce(|promise, transfer| promise.and(transfer))
+        .unwrap_or_else(|| Promise::new(env::current_account_id()))
+}
+
+/// Outcome of `transfers` made with `transfers_promise`: which of them executed, and which failed, their amount
+/// being refunded to the proxy
+struct Settlement {
+    transfers: Vec<Transfer>,
+    results: Vec<bool>,
+}
+
+impl Settlement {
+    /// Reads the result of each of the `transfers` from the promise results of the current callback,
+    /// empty transfers being considered executed
+    fn from_promise_results(transfers: Vec<Transfer>) -> Self {
+        let mut results = (0..env::promise_results_count())
+            .map(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)));
+        let results = transfers
+            .iter()
+            .map(|transfer| transfer.amount.0 == 0 || results.next().unwrap_or(false))
+            .collect();
+        Self { transfers, results }
+    }
+
+    /// Whether all the transfers executed
+    fn is_complete(&self) -> bool {
+        self.results.iter().all(|success| *success)
+    }
+
+    /// Whether the `i`-th transfer executed
+    fn executed(&self, i: usize) -> bool {
+        self.results[i]
+    }
+
+    /// The transfers that failed, in their original order
+    fn failed_transfers(&self) -> Vec<Transfer> {
+        self.transfers
+            .iter()
+            .zip(&self.results)
+            .filter(|(_, success)| !**success)
+            .map(|(transfer, _)| transfer.clone())
+            .collect()
+    }
+
+    /// Total amount of the transfers that failed, in yoctoNEAR
+    fn failed_amount(&self) -> Balance {
+        self.failed_transfers()
+            .iter()
+            .map(|transfer| transfer.amount.0)
+            .sum()
+    }
+
+    /// Logs a `transfer_succeeded` or `transfer_failed` event for each transfer
+    fn log(&self, payment_reference: &str) {
+        for (transfer, success) in self.transfers.iter().zip(&self.results) {
+            let event = json!({
+                "event": if *success { "transfer_succeeded" } else { "transfer_failed" },
+                "payment_reference": payment_reference,
+                "receiver_id": transfer.receiver_id,
+                "amount": transfer.amount,
+            });
+            env::log_str(&event.to_string());
+        }
+    }
+
+    /// Comma-separated receivers of the failed transfers
+    fn failed_receivers(&self) -> String {
+        self.failed_transfers()
+            .iter()
+            .map(|transfer| transfer.receiver_id.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+}
+
+/// `payment_log` without the fees whose transfer failed, given the settlement of the payment transfers: to the payee,
+/// the fee address, the additional fee recipients then the treasury
+fn paid_payment_log(payment_log: &str, settlement: &Settlement) -> String {
+    let mut payment_log: serde_json::Value = serde_json::from_str(payment_log).unwrap();
+    if !settlement.executed(1) {
+        payment_log["fee_amount"] = json!(U128::from(0));
+    }
+    let mut fees_results = settlement.results[2..].iter();
+    if let Some(fees) = payment_log["fees"].as_array_mut() {
+        fees.retain(|_| *fees_results.next().unwrap());
+        if fees.is_empty() {
+            payment_log.as_object_mut().unwrap().remove("fees");
+        }
+    }
+    if !payment_log["protocol_fee"].is_null() && !fees_results.next().unwrap() {
+        payment_log.as_object_mut().unwrap().remove("protocol_fee");
+    }
+    payment_log.to_string()
+}
+
+/// Refunds `amount` of a failed payment to `refund_to` (the payer by default), then fails with the `error`,
+/// so that wallets and explorers show the payment as failed while the refund goes through
+fn refund_then_fail(
+    payment_reference: &str,
+    payer: &AccountId,
+    refund_to: &AccountId,
+    amount: Balance,
+    error: ProxyError,
+) -> Promise {
+    // The failure event, with the refund details
+    let mut event = error.event();
+    event["payment_reference"] = json!(payment_reference);
+    event["payer"] = json!(payer);
+    if refund_to != payer {
+        event["refund_to"] = json!(refund_to);
+    }
+    event["refund"] = json!(U128::from(amount));
+    env::log_str(&event.to_string());
+    Promise::new(refund_to.clone()).transfer(amount).then(
+        ext_self::ext(env::current_account_id())
+            .with_static_gas(BASIC_GAS / 2)
+            .on_payment_failed(error.code().to_string(), error.message()),
+    )
+}
 
 /**
  * Switchboard oracle-related declarations
@@ -46,8 +323,8 @@ pub struct SwitchboardIx {
 
 // Interface of the Switchboard feed parser
 #[near_sdk::ext_contract(sb_contract)]
-trait Switchboard {
-    fn aggregator_read(ix: SwitchboardIx) -> Promise<PriceEntry>;
+pub trait Switchboard {
+    fn aggregator_read(ix: SwitchboardIx) -> PriceEntry;
 }
 
 ///
@@ -56,13 +333,50 @@ trait Switchboard {
 /// - feed_address: should be a valid NEAR/USD price feed
 /// - feed_payer: pays for feeds not sponsored by Switchboard
 /// - owner_id: only the owner can edit the contract state values above (default = deployer)
+/// - protocol_fee_bps, treasury_id, protocol_fee_caps: protocol fee schedule, set by the owner
+/// - escrows: payments locked until released or refunded, by payer and payment reference
+/// - payee_keys: keys registered by payees to sign payment intents, by payee
+/// - payee_preferences: tokens and currencies accepted by payees, by payee
 #[near_bindgen]
-#[derive(Default, BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize)]
 pub struct ConversionProxy {
     pub feed_parser: AccountId,
     pub feed_address: Uuid,
     pub feed_payer: Uuid,
     pub owner_id: AccountId,
+    pub protocol_fee_bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub protocol_fee_caps: HashMap<String, ProtocolFeeCaps>,
+    pub escrows: LookupMap<(AccountId, String), Escrow>,
+    pub payee_keys: PayeeKeys,
+    pub payee_preferences: PayeesPreferences,
+}
+
+impl Default for ConversionProxy {
+    fn default() -> Self {
+        // Accounts of an uninitialized contract: "system" never calls contract methods
+        Self {
+            feed_parser: "system".parse().unwrap(),
+            feed_address: Uuid::default(),
+            feed_payer: Uuid::default(),
+            owner_id: "system".parse().unwrap(),
+            protocol_fee_bps: 0,
+            treasury_id: None,
+            protocol_fee_caps: HashMap::new(),
+            escrows: LookupMap::new(b"e".to_vec()),
+            payee_keys: PayeeKeys::new(b"k".to_vec()),
+            payee_preferences: PayeesPreferences::new(b"p".to_vec()),
+        }
+    }
+}
+
+/// State of contracts deployed before the protocol fee, escrows, payment intents and payee preferences (see `migrate`)
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldConversionProxy {
+    feed_parser: AccountId,
+    feed_address: Uuid,
+    feed_payer: Uuid,
+    owner_id: AccountId,
 }
 
 // Callback methods
@@ -71,28 +385,40 @@ pub trait ExtSelfRequestProxy {
     fn on_transfer_with_reference(
         &self,
         payment_reference: String,
-        payment_address: ValidAccountId,
+        payment_address: AccountId,
         amount: U128,
         currency: String,
-        fee_payment_address: ValidAccountId,
+        fee_payment_address: AccountId,
         fee_amount: U128,
         max_rate_timespan: U64,
+        fees: Vec<FeeRecipient>,
+        protocol_fee: Option<ProtocolFee>,
+        transfers: Vec<Transfer>,
         deposit: U128,
         change: U128,
-        predecessor_account_id: AccountId,
-    ) -> bool;
+        payer: AccountId,
+        refund_to: AccountId,
+    ) -> PromiseOrValue<u128>;
 
     fn rate_callback(
         &self,
-        payment_address: ValidAccountId,
+        payment_address: AccountId,
         amount: U128,
         currency: String,
-        fee_payment_address: ValidAccountId,
+        fee_payment_address: AccountId,
         fee_amount: U128,
         payment_reference: String,
         max_rate_timespan: U64,
+        fees: Vec<FeeRecipient>,
         payer: AccountId,
-    ) -> u128;
+        escrow_timeout: Option<U64>,
+        claim_after: Option<U64>,
+        refund_to: Option<AccountId>,
+    ) -> PromiseOrValue<u128>;
+
+    fn on_escrow_released(&self, payment_reference: String, escrow: Escrow) -> bool;
+
+    fn on_payment_failed(&self, code: String, message: String);
 }
 
 #[near_bindgen]
@@ -109,68 +435,159 @@ impl ConversionProxy {
     /// - `fee_payment_address`: `fee_amount` in `currency` of NEAR will be paid to this address
     /// - `fee_amount`: in `currency`
     /// - `max_rate_timespan`: in nanoseconds, the maximum validity for the oracle rate response (or 0 if none)
+    /// - `fees`: optional additional fee recipients, each paid its `amount` in `currency` of NEAR
+    /// - `fee_bps`: optional fee in basis points of `amount`, replacing `fee_amount` (which must then be 0)
+    /// - `escrow_timeout`: if set, the payment is locked in escrow instead (see `release_escrow` and `refund_escrow`),
+    ///   the payer being able to get it refunded after this duration in nanoseconds
+    /// - `claim_after`: if set, the payment is scheduled instead: the payee can claim it with `release_escrow` after
+    ///   this timestamp in nanoseconds, the payer can cancel it with `refund_escrow` before then
+    /// - `refund_to`: if set, receives the change and any refund (failed payment or escrow refund) instead of the payer
+    /// - `intent`: if set, the payment must match the intent signed by the payee with its registered key
+    ///   (see `set_payee_key`), for this `payment_reference`, `to`, `amount` and `currency` in NEAR
     #[payable]
     pub fn transfer_with_reference(
         &mut self,
         payment_reference: String,
-        to: ValidAccountId,
+        to: AccountId,
         amount: U128,
         currency: String,
-        fee_address: ValidAccountId,
+        fee_address: AccountId,
         fee_amount: U128,
         max_rate_timespan: U64,
+        fees: Option<Vec<FeeRecipient>>,
+        fee_bps: Option<u16>,
+        escrow_timeout: Option<U64>,
+        claim_after: Option<U64>,
+        refund_to: Option<AccountId>,
+        intent: Option<SignedIntent>,
     ) -> Promise {
-        assert!(
+        require(
             MIN_GAS <= env::prepaid_gas(),
-            "Not enough attached Gas to call this method (Supplied: {}. Demand: {})",
-            env::prepaid_gas(),
-            MIN_GAS
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: MIN_GAS,
+            },
         );
-        assert_eq!(
-            currency, "USD",
-            "Only payments denominated in USD are implemented for now"
+        require(
+            currency == "USD",
+            ProxyError::UnsupportedCurrency {
+                currency: currency.clone(),
+            },
         );
+        self.payee_preferences
+            .require_accepted(&to, "NEAR", Some(&currency));
 
         let reference_vec: Vec<u8> = hex::decode(payment_reference.replace("0x", ""))
-            .expect("Payment reference value error");
-        assert_eq!(reference_vec.len(), 8, "Incorrect payment reference length");
-
-        let get_rate = sb_contract::aggregator_read(
-            SwitchboardIx {
-                address: self.feed_address.clone(),
-                payer: self.feed_payer.clone(),
+            .unwrap_or_else(|_| ProxyError::InvalidReference.panic());
+        require(reference_vec.len() == 8, ProxyError::InvalidReferenceLength);
+        if let Some(intent) = &intent {
+            let payment_intent = PaymentIntent {
+                payment_reference: reference_vec,
+                to: to.clone(),
+                amount: amount.0,
+                currency: currency.clone(),
+                token: "NEAR".into(),
+                expiry: intent.expiry.0,
+            };
+            self.payee_keys.verify(&payment_intent, intent);
+        } else {
+            self.payee_keys.require_no_key(&to);
+        }
+        require(
+            escrow_timeout.is_none() || claim_after.is_none(),
+            ProxyError::EscrowTimeoutAndClaimAfter,
+        );
+        if let Some(escrow_timeout) = escrow_timeout {
+            require(
+                env::block_timestamp()
+                    .checked_add(escrow_timeout.0)
+                    .is_some(),
+                ProxyError::InvalidEscrowTimeout,
+            );
+        }
+        if escrow_timeout.is_some() || claim_after.is_some() {
+            require(
+                !self
+                    .escrows
+                    .contains_key(&(env::predecessor_account_id(), payment_reference.clone())),
+                ProxyError::EscrowExists,
+            );
+        }
+        let fees = fees.unwrap_or_default();
+        require(
+            fees.len() <= MAX_FEE_RECIPIENTS,
+            ProxyError::TooManyFeeRecipients {
+                supplied: fees.len(),
+                max: MAX_FEE_RECIPIENTS,
             },
-            &self.feed_parser,
-            NO_DEPOSIT,
-            BASIC_GAS,
         );
+        let fee_amount = match fee_bps {
+            Some(fee_bps) => {
+                require(fee_amount.0 == 0, ProxyError::FeeAmountAndFeeBps);
+                U128::from(fee_amount_from_bps(amount.0, fee_bps))
+            }
+            None => fee_amount,
+        };
+
+        let get_rate = sb_contract::ext(self.feed_parser.clone())
+            .with_static_gas(BASIC_GAS)
+            .aggregator_read(SwitchboardIx {
+                address: self.feed_address,
+                payer: self.feed_payer,
+            });
         let callback_gas = BASIC_GAS * 3;
-        let process_request_payment = ext_self::rate_callback(
-            to,
-            amount,
-            currency,
-            fee_address,
-            fee_amount,
-            payment_reference,
-            max_rate_timespan,
-            env::predecessor_account_id(),
-            &env::current_account_id(),
-            env::attached_deposit(),
-            callback_gas,
-        );
+        let process_request_payment = ext_self::ext(env::current_account_id())
+            .with_attached_deposit(env::attached_deposit())
+            .with_static_gas(callback_gas)
+            .rate_callback(
+                to,
+                amount,
+                currency,
+                fee_address,
+                fee_amount,
+                payment_reference,
+                max_rate_timespan,
+                fees,
+                env::predecessor_account_id(),
+                escrow_timeout,
+                claim_after,
+                refund_to,
+            );
         get_rate.then(process_request_payment)
     }
 
+    /// Initializes the contract, owned by the caller, the feed payer being the caller's key (see `set_feed_payer`)
     #[init]
     pub fn new(feed_parser: AccountId, feed_address_pk: &String) -> Self {
-        let owner_id = env::signer_account_id();
-        let feed_payer = Self::get_uuid(env::signer_account_pk()).expect("ERR_OWNER_PK_LENGTH");
+        require(
+            env::signer_account_id() == env::predecessor_account_id(),
+            ProxyError::RelayedCall,
+        );
+        let owner_id = env::predecessor_account_id();
+        let feed_payer = Self::get_uuid(env::signer_account_pk())
+            .unwrap_or_else(|| ProxyError::OwnerPkLength.panic());
         let feed_address = Self::get_uuid_from_string(feed_address_pk);
         Self {
             feed_parser,
             feed_address,
             feed_payer,
             owner_id,
+            ..Default::default()
+        }
+    }
+
+    /// Migrates the state of a contract deployed with the previous layout, keeping its feed and owner
+    /// (see `deploy.sh --patch`)
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldConversionProxy = env::state_read().expect("No state to migrate");
+        Self {
+            feed_parser: old.feed_parser,
+            feed_address: old.feed_address,
+            feed_payer: old.feed_payer,
+            owner_id: old.owner_id,
+            ..Default::default()
         }
     }
 
@@ -179,12 +596,12 @@ impl ConversionProxy {
         if self.owner_id == signer_id {
             self.feed_parser = feed_parser;
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
     pub fn get_feed_parser(&self) -> AccountId {
-        return self.feed_parser.clone();
+        self.feed_parser.clone()
     }
 
     pub fn set_feed_address(&mut self, feed_address: &String) {
@@ -192,49 +609,230 @@ impl ConversionProxy {
         if self.owner_id == signer_id {
             self.feed_address = Self::get_uuid_from_string(feed_address);
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
     pub fn get_feed_address(&self) -> Uuid {
-        return self.feed_address.clone();
+        self.feed_address
     }
 
     pub fn get_encoded_feed_address(&self) -> String {
-        return bs58::encode(self.feed_address.clone()).into_string();
+        bs58::encode(self.feed_address).into_string()
     }
 
-    pub fn set_owner(&mut self, owner: ValidAccountId) {
+    pub fn set_owner(&mut self, owner: AccountId) {
         let signer_id = env::predecessor_account_id();
         if self.owner_id == signer_id {
-            self.owner_id = owner.to_string();
+            self.owner_id = owner;
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
+    /// Sets the feed payer to the key signing the transaction, which cannot be relayed (eg. as a NEP-366
+    /// delegate action) as the signer would then be the relayer
     pub fn set_feed_payer(&mut self) {
         let signer_id = env::predecessor_account_id();
         if self.owner_id == signer_id {
-            self.feed_payer =
-                Self::get_uuid(env::signer_account_pk()).expect("ERR_OWNER_PK_LENGTH");
+            require(
+                env::signer_account_id() == signer_id,
+                ProxyError::RelayedCall,
+            );
+            self.feed_payer = Self::get_uuid(env::signer_account_pk())
+                .unwrap_or_else(|| ProxyError::OwnerPkLength.panic());
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
     pub fn get_feed_payer(&self) -> Uuid {
-        return self.feed_payer.clone();
+        self.feed_payer
     }
 
     pub fn get_encoded_feed_payer(&self) -> String {
-        return bs58::encode(self.feed_payer.clone()).into_string();
+        bs58::encode(self.feed_payer).into_string()
+    }
+
+    /// Sets the protocol fee, in basis points of each payment `amount`, paid by the payer to `treasury_id`
+    pub fn set_protocol_fee(&mut self, bps: u16, treasury_id: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                bps <= MAX_BPS,
+                ProxyError::ProtocolFeeBpsTooHigh {
+                    supplied: bps,
+                    max: MAX_BPS,
+                },
+            );
+            self.protocol_fee_bps = bps;
+            self.treasury_id = Some(treasury_id);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    /// Bounds the protocol fee for payments in `currency`, amounts in `currency` with 2 decimals
+    pub fn set_protocol_fee_caps(&mut self, currency: String, min_amount: U128, max_amount: U128) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                min_amount.0 <= max_amount.0,
+                ProxyError::InvalidProtocolFeeCaps {
+                    min_amount: min_amount.0,
+                    max_amount: max_amount.0,
+                },
+            );
+            self.protocol_fee_caps.insert(
+                currency,
+                ProtocolFeeCaps {
+                    min_amount,
+                    max_amount,
+                },
+            );
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_protocol_fee_caps(&mut self, currency: String) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.protocol_fee_caps.remove(&currency);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_protocol_fee(&self) -> ProtocolFeeSchedule {
+        ProtocolFeeSchedule {
+            bps: self.protocol_fee_bps,
+            treasury_id: self.treasury_id.clone(),
+            caps: self.protocol_fee_caps.clone(),
+        }
+    }
+
+    /// Releases the escrow of `payer` to the payee and fee recipients, callable by the payer, or by the payee after
+    /// `claim_after`
+    pub fn release_escrow(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let escrow = self
+            .escrows
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::EscrowNotFound.panic());
+        require(
+            escrow.can_release(&env::predecessor_account_id(), env::block_timestamp()),
+            ProxyError::EscrowReleaseNotAllowed,
+        );
+        self.escrows.remove(&key);
+        transfers_promise(&escrow.transfers).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(BASIC_GAS * 2)
+                .on_escrow_released(key.1, escrow),
+        )
+    }
+
+    /// Refunds the escrow of `payer`, callable by the payee at any time or by the payer after `refund_after`
+    /// (before `claim_after` for scheduled payments)
+    pub fn refund_escrow(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let escrow = self
+            .escrows
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::EscrowNotFound.panic());
+        if escrow.claim_after.is_some() {
+            require(
+                escrow.can_refund(&env::predecessor_account_id(), env::block_timestamp()),
+                ProxyError::PaymentCancelNotAllowed,
+            );
+        } else {
+            require(
+                escrow.can_refund(&env::predecessor_account_id(), env::block_timestamp()),
+                ProxyError::EscrowRefundNotAllowed,
+            );
+        }
+        self.escrows.remove(&key);
+        let mut event = json!({
+            "event": "escrow_refunded",
+            "payment_reference": key.1,
+            "payer": escrow.payer,
+            "amount": U128::from(escrow.total_amount()),
+        });
+        if let Some(refund_to) = &escrow.refund_to {
+            event["refund_to"] = json!(refund_to);
+        }
+        env::log_str(&event.to_string());
+        Promise::new(escrow.refund_account().clone()).transfer(escrow.total_amount())
+    }
+
+    pub fn get_escrow(&self, payment_reference: String, payer: AccountId) -> Option<Escrow> {
+        self.escrows.get(&(payer, payment_reference))
+    }
+
+    /// Registers `public_key` (ed25519) as the key signing the caller's payment intents, or removes it if `None`.
+    /// The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_key(&mut self, public_key: Option<PublicKey>) {
+        self.payee_keys.set(public_key);
+    }
+
+    pub fn get_payee_key(&self, account_id: AccountId) -> Option<PublicKey> {
+        self.payee_keys.get(&account_id)
+    }
+
+    /// Registers the tokens and currencies accepted by the caller as a payee, or removes them if `None` to accept
+    /// any asset. The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_preferences(&mut self, preferences: Option<PayeePreferences>) {
+        self.payee_preferences.set(preferences);
+    }
+
+    pub fn get_payee_preferences(&self, account_id: AccountId) -> Option<PayeePreferences> {
+        self.payee_preferences.get(&account_id)
+    }
+
+    /// Logs the escrow release, or refunds the payer (or `refund_to`) the transfers that failed, logging the payment
+    /// without the failed fees if the payee was paid
+    #[private]
+    pub fn on_escrow_released(&mut self, payment_reference: String, escrow: Escrow) -> bool {
+        near_sdk::assert_self();
+
+        let settlement = Settlement::from_promise_results(escrow.transfers.clone());
+        let refund = settlement.failed_amount();
+        let paid_amount = escrow.total_amount() - refund;
+        if paid_amount > 0 {
+            let event = json!({
+                "event": "escrow_released",
+                "payment_reference": payment_reference,
+                "payer": escrow.payer,
+                "amount": U128::from(paid_amount),
+            });
+            env::log_str(&event.to_string());
+        }
+        if settlement.is_complete() {
+            // Log success for indexing and payment detection
+            env::log_str(&escrow.payment_log);
+            return true;
+        }
+        settlement.log(&payment_reference);
+        if settlement.executed(0) {
+            env::log_str(&paid_payment_log(&escrow.payment_log, &settlement));
+        }
+        log!(
+            "Failed to release escrow to {}. Returning {} to {}",
+            settlement.failed_receivers(),
+            refund,
+            escrow.refund_account()
+        );
+        Promise::new(escrow.refund_account().clone()).transfer(refund);
+        false
     }
 
     /// This method transforms a PublicKey (eg. ed25519:3H8UcosBhKfPcuZj7ffr3QqG5BxiGzJECqPZAZka5fJn) into a Uuid (alias for [u8; 32])
     /// Should be useless onchain.
     #[private]
     pub fn get_uuid(public_key: PublicKey) -> Option<Uuid> {
+        let public_key = public_key.as_bytes();
         let vec_length = public_key.len();
         if vec_length == 32 {
             return Some(public_key.try_into().unwrap());
@@ -243,76 +841,97 @@ impl ConversionProxy {
         if vec_length == 33 && public_key[0] == 0_u8 {
             return Some(public_key[1..].try_into().unwrap());
         }
-        return None;
+        None
     }
 
     #[private]
     pub fn get_uuid_from_string(public_key: &String) -> Uuid {
         bs58::decode(public_key)
             .into_vec()
-            .expect("public_key should be decodable into a vector")
+            .unwrap_or_else(|_| ProxyError::InvalidFeedAddress.panic())
             .try_into()
-            .expect("public_key should be decodable into [u8; 32]")
+            .unwrap_or_else(|_| ProxyError::InvalidFeedAddress.panic())
     }
 
+    /// Logs the payment and gives the change back, returning the payment in NEAR with two decimals.
+    /// The failed `transfers` are refunded with the change to the payer (or `refund_to`), the payment being logged
+    /// without the failed fees, or failing if the transfer to the payee failed.
     #[private]
     pub fn on_transfer_with_reference(
         &self,
         payment_reference: String,
-        payment_address: ValidAccountId,
+        payment_address: AccountId,
         amount: U128,
         currency: String,
-        fee_payment_address: ValidAccountId,
+        fee_payment_address: AccountId,
         fee_amount: U128,
         max_rate_timespan: U64,
+        fees: Vec<FeeRecipient>,
+        protocol_fee: Option<ProtocolFee>,
+        transfers: Vec<Transfer>,
         deposit: U128,
         change: U128,
-        predecessor_account_id: AccountId,
-    ) -> bool {
+        payer: AccountId,
+        refund_to: AccountId,
+    ) -> PromiseOrValue<u128> {
         near_sdk::assert_self();
 
-        if near_sdk::is_promise_success() {
-            Promise::new(predecessor_account_id).transfer(change.into());
+        let settlement = Settlement::from_promise_results(transfers);
+        let refund = change.0 + settlement.failed_amount();
+        if !settlement.executed(0) {
+            settlement.log(&payment_reference);
+            return refund_then_fail(
+                &payment_reference,
+                &payer,
+                &refund_to,
+                refund,
+                ProxyError::TransferFailed {
+                    receiver_id: payment_address,
+                },
+            )
+            .into();
+        }
 
+        Promise::new(refund_to.clone()).transfer(refund);
+        let payment_log = payment_log(
+            &payment_reference,
+            &payment_address,
+            amount,
+            &currency,
+            &fee_payment_address,
+            fee_amount,
+            max_rate_timespan,
+            &fees,
+            protocol_fee.as_ref(),
+        );
+        if settlement.is_complete() {
             // Log success for indexing and payment detection
-            env::log(
-                &json!({
-                    "to": payment_address,
-                    "amount": amount,
-                    "currency": currency,
-                    "payment_reference": payment_reference,
-                    "fee_amount": fee_amount,
-                    "fee_address": fee_payment_address,
-                    "max_rate_timespan": max_rate_timespan,
-                })
-                .to_string()
-                .into_bytes(),
-            );
-            true
+            env::log_str(&payment_log);
         } else {
+            settlement.log(&payment_reference);
+            env::log_str(&paid_payment_log(&payment_log, &settlement));
             log!(
-                "Failed to transfer to account {}. Returning attached deposit of {} to {}",
-                payment_address,
-                deposit.0,
-                predecessor_account_id
+                "Failed to pay fees to {}. Returning {} with the change to {}",
+                settlement.failed_receivers(),
+                settlement.failed_amount(),
+                refund_to
             );
-            Promise::new(predecessor_account_id).transfer(deposit.into());
-            false
         }
+        // result in NEAR with two decimals
+        PromiseOrValue::Value((deposit.0 - refund) * 100 / ONE_NEAR)
     }
 
-    /// This method refunds a payer, then logs an error message.
-    /// Used as a bandaid until we find a solution for refund.then(panic)
+    /// Final callback of a failed payment, after the payer was refunded (see `refund_then_fail`),
+    /// failing with the code and message of the `ProxyError`
     #[private]
-    pub fn refund_then_log(&mut self, payer: ValidAccountId, error_message: String) -> u128 {
-        Promise::new(payer.clone().to_string()).transfer(env::attached_deposit());
-        log!(error_message);
-        return 0_u128;
+    pub fn on_payment_failed(&self, code: String, message: String) {
+        panic!("{}: {}", code, message);
     }
 
-    /// Recursive util to convert an `amount` with a `converion_rate` having `decimals`.
-    /// The `precision` is used to work around overflows, a precision of 10^n means that the result has a precision of n yocto digits.
-    /// Said another way, a precision of 1000 will give a result rounded to the closest 1000 yoctos, ending with "...000" in yocto.
+    /// Util to convert an `amount` with a `converion_rate` having `decimals`, rounded down to the `precision`.
+    /// A precision of 10^n means that the result has a precision of n yocto digits.
+    /// Said another way, a precision of 1000 will give a result rounded down to 1000 yoctos, ending with "...000" in yocto.
+    /// The result saturates at `u128::MAX`, more than any deposit.
     #[private]
     pub fn apply_conversion_with_precision(
         amount: U128,
@@ -320,137 +939,256 @@ impl ConversionProxy {
         conversion_rate: u128,
         precision: u128,
     ) -> u128 {
-        let (main_payment, flag) = (Balance::from(amount) * ONE_NEAR / ONE_FIAT / precision)
-            .overflowing_mul(10u128.pow(u32::from(decimals)));
-        if flag {
-            return Self::apply_conversion_with_precision(
-                amount,
-                decimals,
-                conversion_rate,
-                precision * 10,
-            );
-        }
-        let main_payment = (main_payment / conversion_rate) * precision;
-        return main_payment;
+        let precision = precision.max(1);
+        let payment = Self::apply_conversion(amount, decimals, conversion_rate);
+        payment / precision * precision
     }
 
+    /// Util to convert an `amount` with a `converion_rate` having `decimals`, rounded down to the yocto.
+    /// The result saturates at `u128::MAX`, more than any deposit.
     #[private]
     pub fn apply_conversion(amount: U128, decimals: u32, conversion_rate: u128) -> u128 {
-        return Self::apply_conversion_with_precision(amount, decimals, conversion_rate, 1);
+        mul_pow10_div(
+            amount.0,
+            i64::from(decimals) + NEAR_DECIMALS - FIAT_DECIMALS,
+            conversion_rate,
+        )
     }
 
     #[private]
     #[payable]
     pub fn rate_callback(
         &mut self,
-        payment_address: ValidAccountId,
+        payment_address: AccountId,
         amount: U128,
         currency: String,
-        fee_payment_address: ValidAccountId,
+        fee_payment_address: AccountId,
         fee_amount: U128,
         payment_reference: String,
         max_rate_timespan: U64,
-        payer: ValidAccountId,
-    ) -> u128 {
+        fees: Vec<FeeRecipient>,
+        payer: AccountId,
+        escrow_timeout: Option<U64>,
+        claim_after: Option<U64>,
+        refund_to: Option<AccountId>,
+    ) -> PromiseOrValue<u128> {
         near_sdk::assert_self();
+        let refund_to: AccountId = refund_to.unwrap_or_else(|| payer.clone());
+        let fail = |error: ProxyError| {
+            PromiseOrValue::Promise(refund_then_fail(
+                &payment_reference,
+                &payer,
+                &refund_to,
+                env::attached_deposit(),
+                error,
+            ))
+        };
         // Parse rate from oracle promise result
         let rate = match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
             PromiseResult::Successful(value) => {
                 match serde_json::from_slice::<PriceEntry>(&value) {
                     Ok(value) => value,
-                    Err(_e) => {
-                        return self.refund_then_log(payer, "ERR_INVALID_ORACLE_RESPONSE".into())
-                    }
+                    Err(_e) => return fail(ProxyError::InvalidOracleResponse),
                 }
             }
             PromiseResult::Failed => {
-                return self.refund_then_log(payer, "ERR_FAILED_ORACLE_FETCH".into());
+                return fail(ProxyError::FailedOracleFetch);
             }
         };
         // Check rate errors
         if rate.num_error != 0 || rate.num_success < 1 {
-            return self.refund_then_log(
-                payer,
-                "Conversion errors:".to_string()
-                    + &rate.num_error.to_string()
-                    + &", successes: "
-                    + &rate.num_success.to_string(),
-            );
+            return fail(ProxyError::OracleErrors {
+                num_error: rate.num_error,
+                num_success: rate.num_success,
+            });
         }
         // Check rate validity
         if u64::from(max_rate_timespan) != 0
-            && rate.round_open_timestamp < env::block_timestamp() - u64::from(max_rate_timespan)
+            && rate.round_open_timestamp
+                < env::block_timestamp().saturating_sub(u64::from(max_rate_timespan))
         {
-            return self.refund_then_log(
-                payer,
-                "Conversion rate too old (Last updated: ".to_string()
-                    + &rate.round_open_timestamp.to_string()
-                    + &")",
-            );
+            return fail(ProxyError::OutdatedRate {
+                last_update: rate.round_open_timestamp,
+            });
         }
-        let conversion_rate = 0_u128
-            .checked_add_signed(rate.result.mantissa)
-            .expect("The conversion rate should be positive");
+        let conversion_rate = match 0_u128.checked_add_signed(rate.result.mantissa) {
+            Some(conversion_rate) if conversion_rate > 0 => conversion_rate,
+            _ => return fail(ProxyError::InvalidRate),
+        };
         let main_payment = Self::apply_conversion(amount, rate.result.scale, conversion_rate);
         let fee_payment = Self::apply_conversion(fee_amount, rate.result.scale, conversion_rate);
+        let additional_fee_payments: Vec<Balance> = fees
+            .iter()
+            .map(|fee| Self::apply_conversion(fee.amount, rate.result.scale, conversion_rate))
+            .collect();
+        let protocol_fee = self.protocol_fee(&currency, amount.0);
+        let protocol_fee_payment = protocol_fee.as_ref().map_or(0, |protocol_fee| {
+            Self::apply_conversion(protocol_fee.amount, rate.result.scale, conversion_rate)
+        });
 
-        let total_payment = main_payment + fee_payment;
+        // Saturating, so that the deposit check fails on overflows
+        let total_payment = additional_fee_payments
+            .iter()
+            .fold(main_payment.saturating_add(fee_payment), |total, fee| {
+                total.saturating_add(*fee)
+            })
+            .saturating_add(protocol_fee_payment);
         // Check deposit
         if total_payment > env::attached_deposit() {
-            return self.refund_then_log(
-                payer,
-                "Deposit too small for payment. Supplied: ".to_string()
-                    + &env::attached_deposit().to_string()
-                    + &". Demand (incl. fees): "
-                    + &total_payment.to_string(),
-            );
+            return fail(ProxyError::DepositTooSmall {
+                supplied: env::attached_deposit(),
+                demand: total_payment,
+            });
         }
 
         let change = env::attached_deposit() - (total_payment);
 
-        // Make payment, log details and give change back
-        Promise::new(payment_address.clone().to_string())
-            .transfer(main_payment)
+        let mut transfers = vec![
+            Transfer {
+                receiver_id: payment_address.clone(),
+                amount: main_payment.into(),
+            },
+            Transfer {
+                receiver_id: fee_payment_address.clone(),
+                amount: fee_payment.into(),
+            },
+        ];
+        for (fee, fee_payment) in fees.iter().zip(additional_fee_payments) {
+            transfers.push(Transfer {
+                receiver_id: fee.address.clone(),
+                amount: fee_payment.into(),
+            });
+        }
+        if let Some(protocol_fee) = &protocol_fee {
+            transfers.push(Transfer {
+                receiver_id: protocol_fee.treasury_id.clone(),
+                amount: protocol_fee_payment.into(),
+            });
+        }
+
+        let refund_after = match (&claim_after, escrow_timeout) {
+            (Some(claim_after), _) => Some(*claim_after),
+            (None, Some(escrow_timeout)) => {
+                match env::block_timestamp().checked_add(escrow_timeout.0) {
+                    Some(refund_after) => Some(refund_after.into()),
+                    None => return fail(ProxyError::InvalidEscrowTimeout),
+                }
+            }
+            (None, None) => None,
+        };
+        if let Some(refund_after) = refund_after {
+            if self
+                .escrows
+                .contains_key(&(payer.clone(), payment_reference.clone()))
+            {
+                return fail(ProxyError::EscrowExists);
+            }
+            // Lock the payment, log details and give change back
+            let escrow = Escrow {
+                payer: payer.clone(),
+                to: payment_address.clone(),
+                transfers,
+                refund_after,
+                claim_after,
+                payment_log: payment_log(
+                    &payment_reference,
+                    &payment_address,
+                    amount,
+                    &currency,
+                    &fee_payment_address,
+                    fee_amount,
+                    max_rate_timespan,
+                    &fees,
+                    protocol_fee.as_ref(),
+                ),
+                refund_to: Some(refund_to.clone()).filter(|refund_to| refund_to != &payer),
+            };
+            self.lock_escrow(payment_reference, escrow);
+            Promise::new(refund_to).transfer(change);
+            return PromiseOrValue::Value(total_payment * 100 / ONE_NEAR);
+        }
+
+        // Make payment, pay fees, log details and give change back
+        transfers_promise(&transfers)
             .then(
-                // Pay fees and declare payment
-                Promise::new(fee_payment_address.to_string().clone())
-                    .transfer(fee_payment)
-                    .then(ext_self::on_transfer_with_reference(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 2)
+                    .on_transfer_with_reference(
                         payment_reference,
                         payment_address.clone(),
-                        amount.into(),
+                        amount,
                         currency,
                         fee_payment_address.clone(),
-                        fee_amount.into(),
-                        max_rate_timespan.into(),
+                        fee_amount,
+                        max_rate_timespan,
+                        fees,
+                        protocol_fee,
+                        transfers,
                         U128::from(env::attached_deposit()),
                         U128::from(change),
-                        payer.to_string(),
-                        &env::current_account_id(),
-                        NO_DEPOSIT,
-                        BASIC_GAS,
-                    )),
-            );
+                        payer,
+                        refund_to,
+                    ),
+            )
+            .into()
+    }
+}
 
-        // result in NEAR with two decimals
-        total_payment * 100 / ONE_NEAR
+impl ConversionProxy {
+    /// Stores an `escrow` under its payer and `payment_reference`, checked to be free beforehand
+    fn lock_escrow(&mut self, payment_reference: String, escrow: Escrow) {
+        self.escrows
+            .insert(&(escrow.payer.clone(), payment_reference.clone()), &escrow);
+        let mut event = json!({
+            "event": "escrow_locked",
+            "payment_reference": payment_reference,
+            "payer": escrow.payer,
+            "to": escrow.to,
+            "amount": U128::from(escrow.total_amount()),
+            "refund_after": escrow.refund_after,
+        });
+        if let Some(claim_after) = escrow.claim_after {
+            event["claim_after"] = json!(claim_after);
+        }
+        if let Some(refund_to) = &escrow.refund_to {
+            event["refund_to"] = json!(refund_to);
+        }
+        env::log_str(&event.to_string());
+    }
+
+    /// Protocol fee due on a payment of `amount` in `currency`, or `None` if there is nothing to collect
+    fn protocol_fee(&self, currency: &str, amount: Balance) -> Option<ProtocolFee> {
+        let treasury_id = self.treasury_id.clone()?;
+        if self.protocol_fee_bps == 0 || amount == 0 {
+            return None;
+        }
+        let mut fee_amount = fee_amount_from_bps(amount, self.protocol_fee_bps);
+        if let Some(caps) = self.protocol_fee_caps.get(currency) {
+            fee_amount = fee_amount.max(caps.min_amount.0).min(caps.max_amount.0);
+        }
+        Some(ProtocolFee {
+            treasury_id,
+            amount: fee_amount.into(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::{testing_env, VMContext};
-    use near_sdk::{AccountId, Balance, MockedBlockchain};
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, Balance, RuntimeFeesConfig, VMConfig, VMContext};
+    use proptest::prelude::*;
     use std::convert::TryInto;
 
     fn alice_account() -> AccountId {
-        "alice.near".to_string()
+        "alice.near".parse().unwrap()
     }
 
     fn bob_account() -> AccountId {
-        "bob.near".to_string()
+        "bob.near".parse().unwrap()
     }
 
     fn get_context(
@@ -459,43 +1197,108 @@ mod tests {
         prepaid_gas: Gas,
         is_view: bool,
     ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: (1..33).collect(), // Public key: Size 32
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 0,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .signer_account_pk(
+                vec![0]
+                    .into_iter()
+                    .chain(1..33)
+                    .collect::<Vec<u8>>()
+                    .try_into()
+                    .unwrap(),
+            )
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(0)
+            .epoch_height(1)
+            .account_balance(ntoy(1_000))
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
     }
 
     fn ntoy(near_amount: Balance) -> Balance {
         near_amount * 10u128.pow(24)
     }
 
-    fn default_values() -> (ValidAccountId, U128, ValidAccountId, U128, U64) {
+    fn default_values() -> (AccountId, U128, AccountId, U128, U64) {
         (
-            alice_account().try_into().unwrap(),
+            alice_account(),
             U128::from(12),
-            bob_account().try_into().unwrap(),
+            bob_account(),
             U128::from(1),
             U64::from(0),
         )
     }
 
+    /// Arguments of `transfer_with_reference`
+    struct TransferArgs {
+        payment_reference: String,
+        to: AccountId,
+        amount: U128,
+        currency: String,
+        fee_address: AccountId,
+        fee_amount: U128,
+        max_rate_timespan: U64,
+        fees: Option<Vec<FeeRecipient>>,
+        fee_bps: Option<u16>,
+        escrow_timeout: Option<U64>,
+        claim_after: Option<U64>,
+        refund_to: Option<AccountId>,
+        intent: Option<SignedIntent>,
+    }
+
+    /// Payment of `default_values` in USD, with `PAYMENT_REF` and no option
+    fn get_default_transfer_args() -> TransferArgs {
+        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
+        TransferArgs {
+            payment_reference: PAYMENT_REF.into(),
+            to,
+            amount,
+            currency: USD.into(),
+            fee_address,
+            fee_amount,
+            max_rate_timespan,
+            fees: None,
+            fee_bps: None,
+            escrow_timeout: None,
+            claim_after: None,
+            refund_to: None,
+            intent: None,
+        }
+    }
+
+    /// Calls `transfer_with_reference` with the default arguments, changed by `overrides`
+    fn default_transfer(
+        contract: &mut ConversionProxy,
+        overrides: impl FnOnce(&mut TransferArgs),
+    ) -> Promise {
+        let mut args = get_default_transfer_args();
+        overrides(&mut args);
+        contract.transfer_with_reference(
+            args.payment_reference,
+            args.to,
+            args.amount,
+            args.currency,
+            args.fee_address,
+            args.fee_amount,
+            args.max_rate_timespan,
+            args.fees,
+            args.fee_bps,
+            args.escrow_timeout,
+            args.claim_after,
+            args.refund_to,
+            args.intent,
+        )
+    }
+
     pub(crate) const USD: &str = "USD";
     pub(crate) const PAYMENT_REF: &str = "0x1122334455667788";
     pub(crate) const FEED_ADDRESS: &str = "HeS3xrDqHA2CSHTmN9osstz8vbXfgh2mzzzzzzzzzzzz";
+    const PAYEE_SECRET_KEY: [u8; 32] = [7; 32];
 
     #[test]
     #[should_panic(expected = r#"Incorrect payment reference length"#)]
@@ -503,21 +1306,14 @@ mod tests {
         testing_env!(get_context(
             alice_account(),
             ntoy(100),
-            10u64.pow(14),
+            Gas(10u64.pow(14)),
             false
         ));
         let mut contract = ConversionProxy::default();
         let payment_reference = "0x11223344556677".to_string();
-        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
-        contract.transfer_with_reference(
-            payment_reference,
-            to,
-            amount,
-            USD.into(),
-            fee_address,
-            fee_amount,
-            max_rate_timespan,
-        );
+        default_transfer(&mut contract, |args| {
+            args.payment_reference = payment_reference
+        });
     }
 
     #[test]
@@ -526,21 +1322,14 @@ mod tests {
         testing_env!(get_context(
             alice_account(),
             ntoy(100),
-            10u64.pow(14),
+            Gas(10u64.pow(14)),
             false
         ));
         let mut contract = ConversionProxy::default();
         let payment_reference = "0x123".to_string();
-        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
-        contract.transfer_with_reference(
-            payment_reference,
-            to,
-            amount,
-            USD.into(),
-            fee_address,
-            fee_amount,
-            max_rate_timespan,
-        );
+        default_transfer(&mut contract, |args| {
+            args.payment_reference = payment_reference
+        });
     }
 
     #[test]
@@ -549,60 +1338,313 @@ mod tests {
         testing_env!(get_context(
             alice_account(),
             ntoy(100),
-            10u64.pow(14),
+            Gas(10u64.pow(14)),
             false
         ));
         let mut contract = ConversionProxy::default();
         let currency = "HKD".to_string();
-        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
-        contract.transfer_with_reference(
-            PAYMENT_REF.into(),
-            to,
-            amount,
-            currency,
-            fee_address,
-            fee_amount,
-            max_rate_timespan,
-        );
+        default_transfer(&mut contract, |args| args.currency = currency);
     }
 
     #[test]
     #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
     fn transfer_with_not_enough_gas() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(13), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(13)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
-        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
-        contract.transfer_with_reference(
-            PAYMENT_REF.into(),
-            to,
-            amount,
-            USD.into(),
-            fee_address,
-            fee_amount,
-            max_rate_timespan,
-        );
+        default_transfer(&mut contract, |_| {});
     }
 
     #[test]
     fn transfer_with_reference() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
-        let (to, amount, fee_address, fee_amount, max_rate_timespan) = default_values();
-        contract.transfer_with_reference(
-            PAYMENT_REF.into(),
-            to,
-            amount,
+        default_transfer(&mut contract, |_| {});
+    }
+
+    fn signed_intent(amount: U128) -> SignedIntent {
+        PaymentIntent {
+            payment_reference: hex::decode(PAYMENT_REF.replace("0x", "")).unwrap(),
+            to: alice_account(),
+            amount: amount.0,
+            currency: USD.into(),
+            token: "NEAR".into(),
+            expiry: 1_000,
+        }
+        .sign(&PAYEE_SECRET_KEY)
+    }
+
+    #[test]
+    fn transfer_with_intent() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_payee_key(Some(payment_intents::public_key(&PAYEE_SECRET_KEY)));
+        assert_eq!(
+            contract.get_payee_key(alice_account()),
+            Some(payment_intents::public_key(&PAYEE_SECRET_KEY))
+        );
+        default_transfer(&mut contract, |args| {
+            args.intent = Some(signed_intent(args.amount))
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn transfer_with_tampered_intent_amount() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_payee_key(Some(payment_intents::public_key(&PAYEE_SECRET_KEY)));
+        default_transfer(&mut contract, |args| {
+            args.intent = Some(signed_intent(args.amount));
+            args.amount = U128::from(args.amount.0 / 2);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INTENT_REQUIRED"#)]
+    fn transfer_without_intent_to_payee_with_key() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_payee_key(Some(payment_intents::public_key(&PAYEE_SECRET_KEY)));
+        default_transfer(&mut contract, |_| {});
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PAYEE_KEY_NOT_FOUND"#)]
+    fn transfer_with_intent_without_payee_key() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| {
+            args.intent = Some(signed_intent(args.amount))
+        });
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_CURRENCY_NOT_ACCEPTED: alice.near does not accept payments in USD"#
+    )]
+    fn transfer_in_currency_not_accepted() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_payee_preferences(Some(PayeePreferences {
+            tokens: vec!["NEAR".into()],
+            currencies: vec!["EUR".into()],
+        }));
+        assert_eq!(
+            contract
+                .get_payee_preferences(alice_account())
+                .unwrap()
+                .currencies,
+            vec!["EUR".to_string()]
+        );
+        default_transfer(&mut contract, |_| {});
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_TOKEN_NOT_ACCEPTED: alice.near does not accept payments in NEAR"#
+    )]
+    fn transfer_in_token_not_accepted() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_payee_preferences(Some(PayeePreferences {
+            tokens: vec!["usdc.near".parse().unwrap()],
+            currencies: vec![],
+        }));
+        default_transfer(&mut contract, |_| {});
+    }
+
+    #[test]
+    fn transfer_with_multiple_fees() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        let fees = vec![FeeRecipient {
+            address: bob_account(),
+            amount: U128::from(2),
+        }];
+        default_transfer(&mut contract, |args| args.fees = Some(fees));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Too many fee recipients"#)]
+    fn transfer_with_too_many_fees() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        let fees = vec![
+            FeeRecipient {
+                address: bob_account(),
+                amount: U128::from(1),
+            };
+            MAX_FEE_RECIPIENTS + 1
+        ];
+        default_transfer(&mut contract, |args| args.fees = Some(fees));
+    }
+
+    #[test]
+    fn transfer_with_fee_bps() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| {
+            args.fee_amount = U128::from(0);
+            args.fee_bps = Some(250);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_amount and fee_bps are mutually exclusive"#)]
+    fn transfer_with_fee_amount_and_fee_bps() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| args.fee_bps = Some(250));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_bps should not exceed 10000"#)]
+    fn transfer_with_fee_bps_too_high() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| {
+            args.fee_amount = U128::from(0);
+            args.fee_bps = Some(10_001);
+        });
+    }
+
+    #[test]
+    fn fee_amount_from_basis_points() {
+        // 2.5% of 120.00 USD is 3.00 USD
+        assert_eq!(fee_amount_from_bps(12000, 250), 300);
+        // 1% of 0.99 USD is rounded down to 0.00 USD
+        assert_eq!(fee_amount_from_bps(99, 100), 0);
+        assert_eq!(fee_amount_from_bps(12000, MAX_BPS), 12000);
+        assert_eq!(fee_amount_from_bps(12000, 0), 0);
+        // No overflow
+        assert_eq!(fee_amount_from_bps(u128::MAX / 2, 250), u128::MAX / 2 / 40);
+        assert_eq!(fee_amount_from_bps(u128::MAX, MAX_BPS), u128::MAX);
+    }
+
+    #[test]
+    fn rate_callback_protocol_fee_of_high_amount() {
+        let rate = PriceEntry {
+            result: SwitchboardDecimal {
+                mantissa: 1234000,
+                scale: 6,
+            },
+            num_success: 1,
+            num_error: 0,
+            round_open_timestamp: 0,
+        };
+        testing_env!(
+            get_context(alice_account(), ntoy(1), Gas(10u64.pow(14)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let mut contract = ConversionProxy {
+            protocol_fee_bps: 100,
+            treasury_id: Some("treasury.near".parse().unwrap()),
+            ..Default::default()
+        };
+        // The payment fails and the deposit is refunded, instead of panicking on the protocol fee
+        let result = contract.rate_callback(
+            bob_account(),
+            U128::from(u128::MAX / 2),
             USD.into(),
-            fee_address,
-            fee_amount,
-            max_rate_timespan,
+            "builder.near".parse().unwrap(),
+            U128::from(0),
+            PAYMENT_REF.into(),
+            U64::from(0),
+            vec![],
+            alice_account(),
+            None,
+            None,
+            None,
         );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, alice_account());
+        assert!(matches!(
+            receipts[0].actions[..],
+            [VmAction::Transfer { deposit }] if deposit == ntoy(1)
+        ));
     }
 
     #[test]
     #[should_panic(expected = r#"ERR_PERMISSION"#)]
     fn admin_feed_address_no_permission() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
         contract.set_feed_address(&FEED_ADDRESS.into());
     }
@@ -610,7 +1652,7 @@ mod tests {
     #[test]
     fn admin_feed_address() {
         let owner = ConversionProxy::default().owner_id;
-        testing_env!(get_context(owner, ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
         let mut contract = ConversionProxy::default();
         contract.set_feed_address(&FEED_ADDRESS.into());
         assert_eq!(
@@ -622,7 +1664,12 @@ mod tests {
     #[test]
     #[should_panic(expected = r#"ERR_PERMISSION"#)]
     fn admin_feed_payer_no_permission() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
         contract.set_feed_payer();
     }
@@ -630,35 +1677,59 @@ mod tests {
     #[test]
     fn admin_feed_payer() {
         let owner = ConversionProxy::default().owner_id;
-        testing_env!(get_context(owner, ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        contract.set_feed_payer();
+        assert_eq!(
+            contract.get_feed_payer(),
+            env::signer_account_pk().as_bytes()[1..]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_RELAYED_CALL"#)]
+    fn admin_feed_payer_relayed() {
+        let owner = ConversionProxy::default().owner_id;
+        let mut context = get_context(owner, ntoy(1), Gas(10u64.pow(14)), false);
+        context.signer_account_id = "relayer.near".parse().unwrap();
+        testing_env!(context);
         let mut contract = ConversionProxy::default();
         contract.set_feed_payer();
-        assert_eq!(contract.get_feed_payer().to_vec(), env::signer_account_pk());
     }
 
     #[test]
     #[should_panic(expected = r#"ERR_PERMISSION"#)]
     fn admin_feed_parser_no_permission() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
         let (to, _, _, _, _) = default_values();
-        contract.set_feed_parser(to.into());
+        contract.set_feed_parser(to);
     }
 
     #[test]
     fn admin_feed_parser() {
         let owner = ConversionProxy::default().owner_id;
         let mut contract = ConversionProxy::default();
-        testing_env!(get_context(owner, ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
         let (to, _, _, _, _) = default_values();
-        contract.set_feed_parser(to.clone().into());
-        assert_eq!(contract.get_feed_parser(), Into::<AccountId>::into(to));
+        contract.set_feed_parser(to.clone());
+        assert_eq!(contract.get_feed_parser(), to);
     }
 
     #[test]
     #[should_panic(expected = r#"ERR_PERMISSION"#)]
     fn admin_owner_no_permission() {
-        testing_env!(get_context(alice_account(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
         let mut contract = ConversionProxy::default();
         let (to, _, _, _, _) = default_values();
         contract.set_owner(to);
@@ -668,13 +1739,752 @@ mod tests {
     fn admin_owner() {
         let owner = ConversionProxy::default().owner_id;
         let mut contract = ConversionProxy::default();
-        testing_env!(get_context(owner, ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
         let (to, _, _, _, _) = default_values();
         contract.set_owner(to.clone());
-        testing_env!(get_context(to.into(), ntoy(1), 10u64.pow(14), false));
+        testing_env!(get_context(to, ntoy(1), Gas(10u64.pow(14)), false));
         assert!(contract.owner_id == env::signer_account_id());
-        assert!(contract.get_feed_payer().to_vec() != env::signer_account_pk());
+        assert!(contract.get_feed_payer() != env::signer_account_pk().as_bytes()[1..]);
         contract.set_feed_payer();
-        assert_eq!(contract.get_feed_payer().to_vec(), env::signer_account_pk());
+        assert_eq!(
+            contract.get_feed_payer(),
+            env::signer_account_pk().as_bytes()[1..]
+        );
+    }
+
+    #[test]
+    fn migrate() {
+        testing_env!(get_context(alice_account(), 0, Gas(10u64.pow(14)), false));
+        env::state_write(&OldConversionProxy {
+            feed_parser: bob_account(),
+            feed_address: [1; 32],
+            feed_payer: [2; 32],
+            owner_id: alice_account(),
+        });
+        let contract = ConversionProxy::migrate();
+        assert_eq!(contract.feed_parser, bob_account());
+        assert_eq!(contract.feed_address, [1; 32]);
+        assert_eq!(contract.feed_payer, [2; 32]);
+        assert_eq!(contract.owner_id, alice_account());
+        assert_eq!(contract.protocol_fee_bps, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_protocol_fee_no_permission() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        let (to, _, _, _, _) = default_values();
+        contract.set_protocol_fee(100, to);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_protocol_fee_caps_no_permission() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        contract.set_protocol_fee_caps(USD.into(), U128::from(10), U128::from(500));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"bps should not exceed 10000 (Supplied: 10001)"#)]
+    fn admin_protocol_fee_too_high() {
+        let owner = ConversionProxy::default().owner_id;
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        let (to, _, _, _, _) = default_values();
+        contract.set_protocol_fee(10_001, to);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"min_amount should not exceed max_amount"#)]
+    fn admin_protocol_fee_caps_inverted() {
+        let owner = ConversionProxy::default().owner_id;
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        contract.set_protocol_fee_caps(USD.into(), U128::from(500), U128::from(10));
+    }
+
+    #[test]
+    fn admin_protocol_fee() {
+        let owner = ConversionProxy::default().owner_id;
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        let (to, _, _, _, _) = default_values();
+        assert!(contract.get_protocol_fee().treasury_id.is_none());
+        contract.set_protocol_fee(100, to.clone());
+        contract.set_protocol_fee_caps(USD.into(), U128::from(10), U128::from(500));
+        let schedule = contract.get_protocol_fee();
+        assert_eq!(schedule.bps, 100);
+        assert_eq!(schedule.treasury_id, Some(to));
+        assert_eq!(schedule.caps[USD].min_amount.0, 10);
+        assert_eq!(schedule.caps[USD].max_amount.0, 500);
+        contract.remove_protocol_fee_caps(USD.into());
+        assert!(contract.get_protocol_fee().caps.is_empty());
+    }
+
+    #[test]
+    fn protocol_fee_with_caps() {
+        let owner = ConversionProxy::default().owner_id;
+        testing_env!(get_context(owner, ntoy(1), Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        let (to, _, _, _, _) = default_values();
+        // No treasury, no protocol fee
+        assert!(contract.protocol_fee(USD, 12000).is_none());
+        contract.set_protocol_fee(100, to);
+        // 1% of 120.00 USD is 1.20 USD
+        assert_eq!(contract.protocol_fee(USD, 12000).unwrap().amount.0, 120);
+        contract.set_protocol_fee_caps(USD.into(), U128::from(10), U128::from(100));
+        assert_eq!(contract.protocol_fee(USD, 12000).unwrap().amount.0, 100);
+        assert_eq!(contract.protocol_fee(USD, 500).unwrap().amount.0, 10);
+        // Nothing is collected on zero payments
+        assert!(contract.protocol_fee(USD, 0).is_none());
+    }
+
+    /// Helper function: a contract with an escrow of 1 NEAR from alice to bob, refundable to alice after 1000ns
+    fn contract_with_escrow() -> ConversionProxy {
+        let mut contract = ConversionProxy::default();
+        contract.escrows.insert(
+            &(alice_account(), PAYMENT_REF.to_string()),
+            &Escrow {
+                payer: alice_account(),
+                to: bob_account(),
+                transfers: vec![Transfer {
+                    receiver_id: bob_account(),
+                    amount: ntoy(1).into(),
+                }],
+                refund_after: 1000.into(),
+                claim_after: None,
+                payment_log: "{}".into(),
+                refund_to: None,
+            },
+        );
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = r#"An escrow already exists for this payment reference"#)]
+    fn transfer_with_existing_escrow() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = contract_with_escrow();
+        default_transfer(&mut contract, |args| {
+            args.escrow_timeout = Some(U64::from(0))
+        });
+    }
+
+    #[test]
+    fn transfer_with_escrow_of_other_payer() {
+        testing_env!(get_context(
+            bob_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = contract_with_escrow();
+        default_transfer(&mut contract, |args| {
+            args.escrow_timeout = Some(U64::from(0))
+        });
+    }
+
+    #[test]
+    fn rate_callback_with_timespan_longer_than_chain() {
+        let rate = PriceEntry {
+            result: SwitchboardDecimal {
+                mantissa: 1234000,
+                scale: 6,
+            },
+            num_success: 1,
+            num_error: 0,
+            round_open_timestamp: 0,
+        };
+        testing_env!(
+            get_context(alice_account(), ntoy(1), Gas(10u64.pow(14)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let mut contract = ConversionProxy::default();
+        // Any rate is recent enough, instead of underflowing
+        contract.rate_callback(
+            bob_account(),
+            U128::from(100),
+            USD.into(),
+            "builder.near".parse().unwrap(),
+            U128::from(0),
+            PAYMENT_REF.into(),
+            U64::from(u64::MAX),
+            vec![],
+            alice_account(),
+            None,
+            None,
+            None,
+        );
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, bob_account());
+        assert!(matches!(
+            receipts[0].actions[..],
+            [VmAction::Transfer { deposit }] if deposit > 0
+        ));
+    }
+
+    #[test]
+    fn rate_callback_with_existing_escrow() {
+        let rate = PriceEntry {
+            result: SwitchboardDecimal {
+                mantissa: 1234000,
+                scale: 6,
+            },
+            num_success: 1,
+            num_error: 0,
+            round_open_timestamp: 0,
+        };
+        testing_env!(
+            get_context(alice_account(), ntoy(1), Gas(10u64.pow(14)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let mut contract = contract_with_escrow();
+        // Locked since the transfer, the escrow fails the payment instead of panicking
+        let result = contract.rate_callback(
+            bob_account(),
+            U128::from(100),
+            USD.into(),
+            "builder.near".parse().unwrap(),
+            U128::from(0),
+            PAYMENT_REF.into(),
+            U64::from(0),
+            vec![],
+            alice_account(),
+            Some(U64::from(0)),
+            None,
+            None,
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id, alice_account());
+        assert!(matches!(
+            receipts[0].actions[..],
+            [VmAction::Transfer { deposit }] if deposit == ntoy(1)
+        ));
+        let escrow = contract
+            .get_escrow(PAYMENT_REF.into(), alice_account())
+            .unwrap();
+        assert_eq!(escrow.transfers[0].amount.0, ntoy(1));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_OUTDATED_RATE: Conversion rate too old"#)]
+    fn payment_failed() {
+        testing_env!(get_context(alice_account(), 0, Gas(10u64.pow(14)), false));
+        let contract = ConversionProxy::default();
+        contract.on_payment_failed(
+            "ERR_OUTDATED_RATE".into(),
+            "Conversion rate too old (Last updated: 0)".into(),
+        );
+    }
+
+    #[test]
+    fn release_escrow() {
+        testing_env!(get_context(alice_account(), 0, Gas(10u64.pow(14)), false));
+        let mut contract = contract_with_escrow();
+        assert_eq!(
+            contract
+                .get_escrow(PAYMENT_REF.into(), alice_account())
+                .unwrap()
+                .total_amount(),
+            ntoy(1)
+        );
+        contract.release_escrow(PAYMENT_REF.into(), alice_account());
+        assert!(contract
+            .get_escrow(PAYMENT_REF.into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the payer can release the escrow"#)]
+    fn release_escrow_not_payer() {
+        testing_env!(get_context(bob_account(), 0, Gas(10u64.pow(14)), false));
+        let mut contract = contract_with_escrow();
+        contract.release_escrow(PAYMENT_REF.into(), alice_account());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"No escrow for this payment reference"#)]
+    fn release_unknown_escrow() {
+        testing_env!(get_context(alice_account(), 0, Gas(10u64.pow(14)), false));
+        let mut contract = ConversionProxy::default();
+        contract.release_escrow(PAYMENT_REF.into(), alice_account());
+    }
+
+    #[test]
+    fn refund_escrow_by_payee() {
+        testing_env!(get_context(bob_account(), 0, Gas(10u64.pow(14)), false));
+        let mut contract = contract_with_escrow();
+        contract.refund_escrow(PAYMENT_REF.into(), alice_account());
+        assert!(contract
+            .get_escrow(PAYMENT_REF.into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Only the payee, or the payer after refund_after, can refund the escrow"#
+    )]
+    fn refund_escrow_by_payer_too_early() {
+        testing_env!(get_context(alice_account(), 0, Gas(10u64.pow(14)), false));
+        let mut contract = contract_with_escrow();
+        contract.refund_escrow(PAYMENT_REF.into(), alice_account());
+    }
+
+    #[test]
+    fn refund_escrow_by_payer_after_timeout() {
+        let mut context = get_context(alice_account(), 0, Gas(10u64.pow(14)), false);
+        context.block_timestamp = 1000;
+        testing_env!(context);
+        let mut contract = contract_with_escrow();
+        contract.refund_escrow(PAYMENT_REF.into(), alice_account());
+        assert!(contract
+            .get_escrow(PAYMENT_REF.into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    fn escrow_refund_account() {
+        let mut escrow = Escrow {
+            payer: alice_account(),
+            to: bob_account(),
+            transfers: vec![],
+            refund_after: 1000.into(),
+            claim_after: None,
+            payment_log: "{}".into(),
+            refund_to: None,
+        };
+        assert_eq!(escrow.refund_account(), &alice_account());
+        escrow.refund_to = Some("carol.near".parse().unwrap());
+        assert_eq!(escrow.refund_account().as_str(), "carol.near");
+        // Only the payer can still get the escrow refunded
+        assert!(escrow.can_refund(&alice_account(), 1000));
+        assert!(!escrow.can_refund(&"carol.near".parse().unwrap(), 1000));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"escrow_timeout and claim_after are mutually exclusive"#)]
+    fn transfer_with_escrow_timeout_and_claim_after() {
+        testing_env!(get_context(
+            alice_account(),
+            ntoy(1),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| {
+            args.escrow_timeout = Some(U64::from(1000));
+            args.claim_after = Some(U64::from(1000));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = r#"escrow_timeout is too large"#)]
+    fn transfer_with_escrow_timeout_too_large() {
+        let mut context = get_context(alice_account(), ntoy(1), Gas(10u64.pow(14)), false);
+        context.block_timestamp = 1;
+        testing_env!(context);
+        let mut contract = ConversionProxy::default();
+        default_transfer(&mut contract, |args| {
+            args.escrow_timeout = Some(U64::from(u64::MAX))
+        });
+    }
+
+    #[test]
+    fn scheduled_payment_permissions() {
+        let escrow = Escrow {
+            payer: alice_account(),
+            to: bob_account(),
+            transfers: vec![],
+            refund_after: 1000.into(),
+            claim_after: Some(1000.into()),
+            payment_log: "{}".into(),
+            refund_to: None,
+        };
+        // Before claim_after: the payer can cancel, the payee cannot claim
+        assert!(escrow.can_refund(&alice_account(), 999));
+        assert!(!escrow.can_release(&bob_account(), 999));
+        // After claim_after: the payee can claim, the payer cannot cancel
+        assert!(!escrow.can_refund(&alice_account(), 1000));
+        assert!(escrow.can_release(&bob_account(), 1000));
+        // The payer can always release, the payee can always refund
+        assert!(escrow.can_release(&alice_account(), 0));
+        assert!(escrow.can_refund(&bob_account(), 2000));
+    }
+
+    fn escrow_with_fees() -> Escrow {
+        let treasury: AccountId = "treasury.near".parse().unwrap();
+        let fees = vec![FeeRecipient {
+            address: "carol.near".parse().unwrap(),
+            amount: U128::from(5),
+        }];
+        let protocol_fee = ProtocolFee {
+            treasury_id: treasury.clone(),
+            amount: U128::from(1),
+        };
+        Escrow {
+            payer: alice_account(),
+            to: bob_account(),
+            transfers: vec![
+                Transfer {
+                    receiver_id: bob_account(),
+                    amount: U128::from(ntoy(100)),
+                },
+                Transfer {
+                    receiver_id: "builder.near".parse().unwrap(),
+                    amount: U128::from(ntoy(10)),
+                },
+                Transfer {
+                    receiver_id: "carol.near".parse().unwrap(),
+                    amount: U128::from(ntoy(5)),
+                },
+                Transfer {
+                    receiver_id: treasury,
+                    amount: U128::from(ntoy(1)),
+                },
+            ],
+            refund_after: 1000.into(),
+            claim_after: None,
+            payment_log: payment_log(
+                PAYMENT_REF,
+                &bob_account(),
+                U128::from(100),
+                USD,
+                &"builder.near".parse().unwrap(),
+                U128::from(10),
+                U64::from(0),
+                &fees,
+                Some(&protocol_fee),
+            ),
+            refund_to: None,
+        }
+    }
+
+    // Releases `escrow_with_fees` with the transfers `results`, returning the NEAR transferred back to the payer
+    fn escrow_released_refund(results: Vec<PromiseResult>) -> (bool, Balance) {
+        testing_env!(
+            get_context(alice_account(), 0, Gas(10u64.pow(14)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results
+        );
+        let mut contract = ConversionProxy::default();
+        let released = contract.on_escrow_released(PAYMENT_REF.into(), escrow_with_fees());
+        let refund = get_created_receipts()
+            .iter()
+            .filter(|receipt| receipt.receiver_id == alice_account())
+            .flat_map(|receipt| &receipt.actions)
+            .map(|action| match action {
+                VmAction::Transfer { deposit } => *deposit,
+                _ => 0,
+            })
+            .sum();
+        (released, refund)
+    }
+
+    #[test]
+    fn escrow_released() {
+        let results = (0..4).map(|_| PromiseResult::Successful(vec![])).collect();
+        assert_eq!(escrow_released_refund(results), (true, 0));
+    }
+
+    #[test]
+    fn escrow_released_with_failed_fees() {
+        let results = vec![
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Failed,
+        ];
+        // Only the failed fees are refunded
+        assert_eq!(escrow_released_refund(results), (false, ntoy(11)));
+    }
+
+    #[test]
+    fn escrow_released_with_failed_payment() {
+        let results = vec![
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![]),
+        ];
+        assert_eq!(escrow_released_refund(results), (false, ntoy(100)));
+    }
+
+    // Pays with `escrow_with_fees` transfers, a deposit of 120 NEAR and a change of 4 NEAR, given the transfers
+    // `results`, returning the payment in NEAR with two decimals (if it did not fail) and the NEAR refunded
+    fn transferred_with_reference_refund(results: Vec<PromiseResult>) -> (Option<u128>, Balance) {
+        testing_env!(
+            get_context(alice_account(), 0, Gas(10u64.pow(14)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results
+        );
+        let contract = ConversionProxy::default();
+        let result = contract.on_transfer_with_reference(
+            PAYMENT_REF.into(),
+            bob_account(),
+            U128::from(100),
+            USD.into(),
+            "builder.near".parse().unwrap(),
+            U128::from(10),
+            U64::from(0),
+            vec![],
+            None,
+            escrow_with_fees().transfers,
+            U128::from(ntoy(120)),
+            U128::from(ntoy(4)),
+            alice_account(),
+            alice_account(),
+        );
+        // Promises are only scheduled when dropped
+        let paid = match result {
+            PromiseOrValue::Value(paid) => Some(paid),
+            PromiseOrValue::Promise(promise) => {
+                drop(promise);
+                None
+            }
+        };
+        let refund = get_created_receipts()
+            .iter()
+            .filter(|receipt| receipt.receiver_id == alice_account())
+            .flat_map(|receipt| &receipt.actions)
+            .map(|action| match action {
+                VmAction::Transfer { deposit } => *deposit,
+                _ => 0,
+            })
+            .sum();
+        (paid, refund)
+    }
+
+    #[test]
+    fn transferred_with_reference() {
+        let results = (0..4).map(|_| PromiseResult::Successful(vec![])).collect();
+        assert_eq!(
+            transferred_with_reference_refund(results),
+            (Some(11600), ntoy(4))
+        );
+    }
+
+    #[test]
+    fn transferred_with_reference_failed_fees() {
+        let results = vec![
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Failed,
+        ];
+        // The change and the failed fees are refunded
+        assert_eq!(
+            transferred_with_reference_refund(results),
+            (Some(10500), ntoy(15))
+        );
+    }
+
+    #[test]
+    fn transferred_with_reference_failed_payment() {
+        let results = vec![
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![]),
+        ];
+        // The payment fails after refunding the change and the payment, but not the paid fees
+        assert_eq!(
+            transferred_with_reference_refund(results),
+            (None, ntoy(104))
+        );
+    }
+
+    #[test]
+    fn paid_payment_log_without_failed_fees() {
+        let escrow = escrow_with_fees();
+        let settlement = Settlement {
+            transfers: escrow.transfers,
+            results: vec![true, false, false, true],
+        };
+        let log: serde_json::Value =
+            serde_json::from_str(&paid_payment_log(&escrow.payment_log, &settlement)).unwrap();
+        assert_eq!(log["fee_amount"], json!("0"));
+        assert!(log["fees"].is_null());
+        assert_eq!(log["protocol_fee"]["amount"], json!("1"));
+
+        let settlement = Settlement {
+            transfers: settlement.transfers,
+            results: vec![true, true, true, false],
+        };
+        let log: serde_json::Value =
+            serde_json::from_str(&paid_payment_log(&escrow.payment_log, &settlement)).unwrap();
+        assert_eq!(log["fee_amount"], json!("10"));
+        assert_eq!(log["fees"][0]["amount"], json!("5"));
+        assert!(log["protocol_fee"].is_null());
+    }
+
+    // Pays `amount` and `fee_amount` in USD with a `deposit` at the rate `mantissa` with `scale`, and returns the NEAR
+    // transferred to the payee and to the fee address
+    fn rate_callback_transfers(
+        amount: Balance,
+        fee_amount: Balance,
+        mantissa: i128,
+        scale: u32,
+        deposit: Balance,
+    ) -> (Balance, Balance) {
+        let mut context = get_context(alice_account(), deposit, Gas(300 * 10u64.pow(12)), false);
+        // The attached deposit is added to the balance
+        context.account_balance = 0;
+        let rate = PriceEntry {
+            result: SwitchboardDecimal { mantissa, scale },
+            num_success: 1,
+            num_error: 0,
+            round_open_timestamp: 0,
+        };
+        testing_env!(
+            context,
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let fee_address: AccountId = "builder.near".parse().unwrap();
+        let mut contract = ConversionProxy::default();
+        contract.rate_callback(
+            bob_account(),
+            amount.into(),
+            USD.into(),
+            fee_address.clone(),
+            fee_amount.into(),
+            PAYMENT_REF.into(),
+            U64::from(0),
+            vec![],
+            alice_account(),
+            None,
+            None,
+            None,
+        );
+        let receipts = get_created_receipts();
+        let transferred_to = |account_id: &AccountId| -> Balance {
+            receipts
+                .iter()
+                .filter(|receipt| &receipt.receiver_id == account_id)
+                .flat_map(|receipt| &receipt.actions)
+                .map(|action| match action {
+                    VmAction::Transfer { deposit } => *deposit,
+                    _ => 0,
+                })
+                .sum()
+        };
+        (transferred_to(&bob_account()), transferred_to(&fee_address))
+    }
+
+    // Realistic values, and any value
+    fn amounts() -> impl Strategy<Value = u128> {
+        prop_oneof![0..10u128.pow(12), any::<u128>()]
+    }
+
+    fn rates() -> impl Strategy<Value = u128> {
+        prop_oneof![1..10u128.pow(12), 1..=u128::MAX]
+    }
+
+    #[test]
+    fn apply_conversion_high_amounts() {
+        // 1'200'000.00 USD at 1.234 USD per NEAR, overflowing before the division
+        assert_eq!(
+            ConversionProxy::apply_conversion(U128::from(120000000), 9, 1234000000),
+            ntoy(1200000) * 1000 / 1234
+        );
+        assert_eq!(
+            ConversionProxy::apply_conversion_with_precision(
+                U128::from(120000000),
+                9,
+                1234000000,
+                1000
+            ),
+            ntoy(1200000) * 1000 / 1234 / 1000 * 1000
+        );
+        assert_eq!(
+            ConversionProxy::apply_conversion(U128::from(u128::MAX), 9, 1),
+            u128::MAX
+        );
+        assert_eq!(ConversionProxy::apply_conversion(U128::from(0), 100, 1), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn apply_conversion_with_precision_rounds_down(
+            amount in amounts(),
+            decimals in 0u32..40,
+            conversion_rate in rates(),
+            precision_decimals in 0u32..39,
+        ) {
+            let expected = ConversionProxy::apply_conversion(amount.into(), decimals, conversion_rate);
+            let precision = 10u128.pow(precision_decimals);
+            let result = ConversionProxy::apply_conversion_with_precision(
+                amount.into(),
+                decimals,
+                conversion_rate,
+                precision,
+            );
+            prop_assert_eq!(result % precision, 0);
+            prop_assert!(result <= expected);
+            prop_assert!(expected - result < precision);
+        }
+
+        #[test]
+        fn rate_callback_pays_within_deposit(
+            amount in amounts(),
+            fee_amount in amounts(),
+            mantissa in prop_oneof![1..10i128.pow(12), 1..=i128::MAX],
+            scale in 0u32..40,
+            // Up to more than the NEAR supply
+            deposit in 0..ntoy(10u128.pow(10)),
+        ) {
+            let (main_payment, fee_payment) =
+                rate_callback_transfers(amount, fee_amount, mantissa, scale, deposit);
+            prop_assert!(main_payment.checked_add(fee_payment).unwrap() <= deposit);
+            // Either the payment is made in full, or nothing is paid
+            let conversion_rate = mantissa as u128;
+            if main_payment > 0 || fee_payment > 0 {
+                prop_assert_eq!(
+                    main_payment,
+                    ConversionProxy::apply_conversion(amount.into(), scale, conversion_rate)
+                );
+                prop_assert_eq!(
+                    fee_payment,
+                    ConversionProxy::apply_conversion(fee_amount.into(), scale, conversion_rate)
+                );
+            }
+        }
     }
 }
@@ -28,7 +28,7 @@ while getopts "pha:-:" OPT; do
       echo "  -h | --help                 : shows this help"
       echo "  -p | --prod | --mainnet     : for prod deployment"
       echo "  -a [account_id]             : to override \$ACCOUNT_ID"
-      echo "  --patch                     : to patch an existing contract (skip the init function, if any)"
+      echo "  --patch                     : to patch an existing contract, migrating its state (calls migrate instead of new)"
       echo ""
       echo "  Choose the contract to deploy with:"
       echo "    --conversion_proxy [default]"
@@ -58,35 +58,33 @@ printf "Deploying %s on NEAR_ENV=%s with ACCOUNT_ID=%s (patch=%s)\n\n" "$contrac
 
 ./build.sh
 
-if [ "$contract_name" = "fungible_proxy" ]; then
-  set -x
-  NEAR_ENV=$NEAR_ENV near deploy -f --wasmFile ./target/wasm32-unknown-unknown/release/$contract_name.wasm \
-   --accountId $ACCOUNT_ID
-else
-  
-  if [ "$contract_name" = "conversion_proxy" ]; then
-    if [ "$NEAR_ENV" = "mainnet" ]; then
-      feed_parser="switchboard-v2.near";
-      feed_address="C3p8SSWQS8j1nx7HrzBBphX5jZcS1EY28EJ5iwjzSix2";
-    else
-      feed_parser="switchboard-v2.testnet";
-      feed_address="7igqhpGQ8xPpyjQ4gMHhXRvtZcrKSGJkdKDJYBiPQgcb";
-    fi
-    initArgs='{"feed_parser":"'$feed_parser'","feed_address_pk":"'$feed_address'"}';
+if [ "$contract_name" = "conversion_proxy" ]; then
+  if [ "$NEAR_ENV" = "mainnet" ]; then
+    feed_parser="switchboard-v2.near";
+    feed_address="C3p8SSWQS8j1nx7HrzBBphX5jZcS1EY28EJ5iwjzSix2";
   else
-    initArgs='{"oracle_account_id": "'$oracle_account_id'", "provider_account_id": "'$provider_account_id'"}';
-  fi
-  echo $initArgs;
-  initParams="";
-  if ! $patch ; then
-    initParams="
-    --initFunction new  \
-    --initArgs $initArgs";
+    feed_parser="switchboard-v2.testnet";
+    feed_address="7igqhpGQ8xPpyjQ4gMHhXRvtZcrKSGJkdKDJYBiPQgcb";
   fi
-  set -x
-  NEAR_ENV=$NEAR_ENV near deploy -f --wasmFile ./target/wasm32-unknown-unknown/release/$contract_name.wasm \
-    --accountId $ACCOUNT_ID \
-    $initParams
+  initArgs='{"feed_parser":"'$feed_parser'","feed_address_pk":"'$feed_address'"}';
+elif [ "$contract_name" = "fungible_proxy" ]; then
+  initArgs='{}';
+else
+  initArgs='{"oracle_account_id": "'$oracle_account_id'", "provider_account_id": "'$provider_account_id'"}';
+fi
+echo $initArgs;
+if $patch ; then
+  initParams="
+  --initFunction migrate  \
+  --initArgs {}";
+else
+  initParams="
+  --initFunction new  \
+  --initArgs $initArgs";
 fi
+set -x
+NEAR_ENV=$NEAR_ENV near deploy -f --wasmFile ./target/wasm32-unknown-unknown/release/$contract_name.wasm \
+  --accountId $ACCOUNT_ID \
+  $initParams
 
 set +x
@@ -1,17 +1,39 @@
+// Payment methods and their callbacks take the full payment details, including in generated bindings
+#![allow(clippy::too_many_arguments)]
+
+use std::collections::{HashMap, HashSet};
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128, U64};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json::json;
 use near_sdk::{
-    env, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseResult, Timestamp,
+    env, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseOrValue,
+    PromiseResult, PublicKey, Timestamp,
 };
-near_sdk::setup_alloc!();
+use payee_preferences::{PayeePreferences, PayeesPreferences};
+use payment_intents::{PayeeKeys, PaymentIntent, SignedIntent};
+use proxy_errors::{require, ProxyError};
+use proxy_math::{fee_from_bps, mul_pow10_div, MAX_BPS};
+use proxy_storage::{check_registrations, unregistered_accounts, StorageFunds};
 
-const NO_DEPOSIT: Balance = 0;
 const YOCTO_DEPOSIT: Balance = 1; // Fungible token transfers require a deposit of exactly 1 yoctoNEAR
-const ONE_FIAT: Balance = 100; // Fiat values with two decimals
-const MIN_GAS: Gas = 150_000_000_000_000;
-const BASIC_GAS: Gas = 10_000_000_000_000;
+const FIAT_DECIMALS: i64 = 2; // Fiat values with two decimals
+const MIN_GAS: Gas = Gas(150_000_000_000_000);
+const BASIC_GAS: Gas = Gas(10_000_000_000_000);
+const MAX_FEE_RECIPIENTS: usize = 4;
+
+/// Additional fee recipient, paid on top of `fee_address`
+///
+/// - `address`: `amount` in `currency` of payment token will be paid to this address
+/// - `amount`: in `currency` with 2 decimals (eg. 1000 is 10.00)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FeeRecipient {
+    pub address: AccountId,
+    pub amount: U128,
+}
 
 /// Helper struct containing arguments supplied by the caller
 ///
@@ -19,18 +41,182 @@ const BASIC_GAS: Gas = 10_000_000_000_000;
 /// - `currency`: ticker, most likely fiat (eg. 'USD')
 /// - `fee_address`: `fee_amount` in `currency` of payment token will be paid to this address
 /// - `fee_amount`: in `currency`
+/// - `fee_bps`: optional fee in basis points of `amount`, replacing `fee_amount` (which must then be 0)
+/// - `fees`: optional additional fee recipients, each paid its `amount` in `currency` of payment token
+/// - `intent`: if set, the payment must match the intent signed by the payee with its registered key
+///   (see `set_payee_key`), for this `payment_reference`, `to`, `amount` and `currency` in the attached token
 /// - `max_rate_timespan`: in nanoseconds, the maximum validity for the oracle rate response (or 0 if none)
 /// - `payment_reference`: used for indexing and matching the payment with a request
+/// - `register_accounts`: if true, `to` and fee recipients not registered with the payment token are registered first,
+///   the storage deposit being paid from the payer's storage fund (see `deposit_storage_fund`)
+/// - `refund_to`: if set, receives the change and the amount of failed transfers instead of the payer
 /// - `to`: `amount` in `currency` of payment token will be paid to this address
 #[derive(Serialize, Deserialize)]
 pub struct PaymentArgs {
     amount: U128,
     currency: String,
-    fee_address: ValidAccountId,
+    fee_address: AccountId,
     fee_amount: U128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fee_bps: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fees: Vec<FeeRecipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intent: Option<SignedIntent>,
     max_rate_timespan: U64,
     payment_reference: String,
-    to: ValidAccountId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    register_accounts: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refund_to: Option<AccountId>,
+    to: AccountId,
+}
+
+/// Gas needed to check the registration of `accounts_count` accounts with the payment token, and register them
+fn registration_gas(accounts_count: usize) -> Gas {
+    BASIC_GAS * 2 * accounts_count as u64 + BASIC_GAS * 3
+}
+
+impl PaymentArgs {
+    /// Additional gas needed to transfer to each of the `fees` recipients
+    fn fees_gas(&self) -> Gas {
+        BASIC_GAS * 2 * self.fees.len() as u64
+    }
+
+    /// Additional gas needed to transfer the unused amount to `refund_to` (see `return_unused`)
+    fn refund_gas(&self) -> Gas {
+        if self.refund_to.is_some() {
+            BASIC_GAS * 3
+        } else {
+            Gas(0)
+        }
+    }
+
+    /// Replaces `fee_amount` with `fee_bps` basis points of `amount`, rounded down to the smallest `currency` unit
+    fn apply_fee_bps(&mut self) {
+        if let Some(fee_bps) = self.fee_bps {
+            require(self.fee_amount.0 == 0, ProxyError::FeeAmountAndFeeBps);
+            self.fee_amount = U128::from(fee_amount_from_bps(self.amount.0, fee_bps));
+        }
+    }
+}
+
+/// Computes the fee for `fee_bps` basis points of `amount`, rounded down to the smallest `currency` unit
+fn fee_amount_from_bps(amount: Balance, fee_bps: u16) -> Balance {
+    fee_from_bps(amount, fee_bps).unwrap_or_else(|| {
+        ProxyError::FeeBpsTooHigh {
+            supplied: fee_bps,
+            max: MAX_BPS,
+        }
+        .panic()
+    })
+}
+
+/// Recurring payment authorization supplied by the payer, the attached amount being deposited for later executions
+///
+/// - `amount`: maximum paid to `to` per period, in `currency` with 2 decimals (eg. 1000 is 10.00)
+/// - `currency`: ticker, most likely fiat (eg. 'USD')
+/// - `executor`: the only account allowed to execute the payment, once per period
+/// - `expiry`: timestamp in nanoseconds after which the payment cannot be executed anymore
+/// - `fee_address`: `fee_amount` in `currency` of payment token will be paid to this address on each execution
+/// - `fee_amount`: in `currency`
+/// - `max_rate_timespan`: in nanoseconds, the maximum validity for the oracle rate response (or 0 if none)
+/// - `payment_reference`: used for indexing and matching each execution with a request
+/// - `period`: in nanoseconds, starting when the payment is authorized
+/// - `to`: the payee
+#[derive(Serialize, Deserialize)]
+pub struct RecurringArgs {
+    pub amount: U128,
+    pub currency: String,
+    pub executor: AccountId,
+    pub expiry: U64,
+    pub fee_address: AccountId,
+    pub fee_amount: U128,
+    pub max_rate_timespan: U64,
+    pub payment_reference: String,
+    pub period: U64,
+    pub to: AccountId,
+}
+
+/// `msg` of `ft_transfer_call` for recurring payments, instead of `PaymentArgs`
+///
+/// - `authorize`: authorizes a new recurring payment, eg. msg = {"authorize":{...}}
+/// - `top_up`: adds the attached amount to the deposit of an existing recurring payment, eg. msg = {"top_up":"1"}
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecurringMsg {
+    Authorize(RecurringArgs),
+    TopUp(U64),
+}
+
+/// Recurring payment authorized by `payer`, see `RecurringArgs`
+///
+/// - `start`: timestamp in nanoseconds at which the first period starts
+/// - `next_execution`: timestamp in nanoseconds at which the next period starts
+/// - `balance`: remaining deposit, in payment token
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct RecurringPayment {
+    pub payer: AccountId,
+    pub token_address: AccountId,
+    pub executor: AccountId,
+    pub to: AccountId,
+    pub amount: U128,
+    pub currency: String,
+    pub fee_address: AccountId,
+    pub fee_amount: U128,
+    pub max_rate_timespan: U64,
+    pub payment_reference: String,
+    pub start: U64,
+    pub period: U64,
+    pub expiry: U64,
+    pub next_execution: U64,
+    pub balance: U128,
+}
+
+/// Recurring payment being executed, whose period is restored if its payee is not paid
+///
+/// - `next_execution`: timestamp at which the period started before the execution
+#[derive(Serialize, Deserialize)]
+pub struct RecurringExecution {
+    pub id: U64,
+    pub next_execution: U64,
+}
+
+/// Minimum and maximum protocol fee for a currency, in that currency with 2 decimals
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct ProtocolFeeCaps {
+    pub min_amount: U128,
+    pub max_amount: U128,
+}
+
+/// Protocol fee schedule, as configured by the owner
+///
+/// - `bps`: protocol fee in basis points of the payment `amount`
+/// - `treasury_id`: receives the protocol fee (no protocol fee is collected if none)
+/// - `caps`: optional minimum and maximum protocol fee, per currency
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFeeSchedule {
+    pub bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub caps: HashMap<String, ProtocolFeeCaps>,
+}
+
+/// Protocol fee collected on a payment, `amount` being in the payment `currency`
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFee {
+    pub treasury_id: AccountId,
+    pub amount: U128,
+}
+
+/// Logs the `recurring_revoked` event, with the balance refunded to the payer
+fn log_recurring_revoked(id: U64, recurring: &RecurringPayment) {
+    let event = json!({
+        "event": "recurring_revoked",
+        "id": id,
+        "payer": recurring.payer,
+        "refund": recurring.balance,
+    });
+    env::log_str(&event.to_string());
 }
 
 /**
@@ -51,9 +237,160 @@ pub struct FungibleTokenMetadata {
 
 // Interface of fungible tokens
 #[near_sdk::ext_contract(ft_contract)]
-trait FungibleTokenContract {
-    fn ft_transfer(receiver_id: String, amount: String, memo: Option<String>);
-    fn ft_metadata() -> Promise<FungibleTokenMetadata>;
+pub trait FungibleTokenContract {
+    fn ft_transfer(receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_metadata() -> FungibleTokenMetadata;
+}
+
+/// Transfer of `amount` of payment token to `receiver_id`, one of the legs of a payment
+struct Transfer {
+    receiver_id: AccountId,
+    amount: Balance,
+}
+
+/// Converts `currency_amount`, with 2 decimals, to the payment token having `token_decimals`, with a
+/// `conversion_rate` having `rate_decimals`, rounded down. The result saturates at `u128::MAX`, more than any deposit.
+fn to_token_amount(
+    currency_amount: Balance,
+    token_decimals: u8,
+    conversion_rate: u128,
+    rate_decimals: u32,
+) -> Balance {
+    mul_pow10_div(
+        currency_amount,
+        i64::from(token_decimals) + i64::from(rate_decimals) - FIAT_DECIMALS,
+        conversion_rate,
+    )
+}
+
+/// Makes each of the `transfers` of `token_address` in its own promise, joined with `and` so that the result of each
+/// transfer can be checked, skipping empty ones as some tokens revert when calling `ft_transfer` with 0
+fn ft_transfer_legs_promise(token_address: &AccountId, transfers: &[Transfer]) -> Promise {
+    transfers
+        .iter()
+        .filter(|transfer| transfer.amount > 0)
+        .map(|transfer| {
+            let transfer_args = json!({ "receiver_id": transfer.receiver_id, "amount": U128(transfer.amount), "memo": None::<String> })
+                .to_string()
+                .into_bytes();
+            Promise::new(token_address.clone()).function_call(
+                "ft_transfer".into(),
+                transfer_args,
+                YOCTO_DEPOSIT,
+                BASIC_GAS * 2,
+            )
+        })
+        .reduce(|promise, transfer| promise.and(transfer))
+        .unwrap_or_else(|| Promise::new(token_address.clone()))
+}
+
+/// Returns `amount` of `token_address` unused by a payment: transferred to `refund_to` if set (see
+/// `on_unused_returned`), else returned for `ft_resolve_transfer` on the token contract to refund the sender
+fn return_unused(
+    token_address: &AccountId,
+    refund_to: Option<AccountId>,
+    amount: Balance,
+) -> PromiseOrValue<U128> {
+    match refund_to {
+        Some(refund_to) if amount > 0 => ft_contract::ext(token_address.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS * 2)
+            .ft_transfer(refund_to.clone(), amount.into(), None)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS)
+                    .on_unused_returned(token_address.clone(), refund_to, amount.into()),
+            )
+            .into(),
+        _ => PromiseOrValue::Value(amount.into()),
+    }
+}
+
+/// Outcome of `transfers` made with `ft_transfer_legs_promise`: which of them executed, and which failed and are
+/// still held by the proxy
+struct Settlement {
+    transfers: Vec<Transfer>,
+    results: Vec<bool>,
+}
+
+impl Settlement {
+    /// Reads the result of each of the `transfers` from the promise results of the current callback,
+    /// empty transfers being considered executed
+    fn from_promise_results(transfers: Vec<Transfer>) -> Self {
+        let mut results = (0..env::promise_results_count())
+            .map(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)));
+        let results = transfers
+            .iter()
+            .map(|transfer| transfer.amount == 0 || results.next().unwrap_or(false))
+            .collect();
+        Self { transfers, results }
+    }
+
+    /// Whether the `i`-th transfer executed
+    fn executed(&self, i: usize) -> bool {
+        self.results[i]
+    }
+
+    /// Total amount of the transfers that failed, which the proxy still holds
+    fn failed_amount(&self) -> Balance {
+        self.transfers
+            .iter()
+            .zip(&self.results)
+            .filter(|(_, success)| !**success)
+            .map(|(transfer, _)| transfer.amount)
+            .sum()
+    }
+
+    /// Logs a `transfer_succeeded` or `transfer_failed` event for each transfer
+    fn log(&self, payment_reference: &str, token_address: &AccountId) {
+        for (transfer, success) in self.transfers.iter().zip(&self.results) {
+            let event = json!({
+                "event": if *success { "transfer_succeeded" } else { "transfer_failed" },
+                "payment_reference": payment_reference,
+                "token_address": token_address,
+                "receiver_id": transfer.receiver_id,
+                "amount": U128::from(transfer.amount),
+            });
+            env::log_str(&event.to_string());
+        }
+    }
+}
+
+/// Transfers paying `amount` to the payee, then the fee recipients and treasury, in that order,
+/// all amounts being in payment token
+fn payment_legs(
+    args: &PaymentArgs,
+    amount: Balance,
+    fee_amount: Balance,
+    fees_amounts: &[Balance],
+    protocol_fee: Option<(&ProtocolFee, Balance)>,
+) -> Vec<Transfer> {
+    let mut transfers = vec![
+        Transfer {
+            receiver_id: args.to.clone(),
+            amount,
+        },
+        Transfer {
+            receiver_id: args.fee_address.clone(),
+            amount: fee_amount,
+        },
+    ];
+    transfers.extend(
+        args.fees
+            .iter()
+            .zip(fees_amounts)
+            .map(|(fee, fee_amount)| Transfer {
+                receiver_id: fee.address.clone(),
+                amount: *fee_amount,
+            }),
+    );
+    if let Some((protocol_fee, protocol_fee_amount)) = protocol_fee {
+        transfers.push(Transfer {
+            receiver_id: protocol_fee.treasury_id.clone(),
+            amount: protocol_fee_amount,
+        });
+    }
+    transfers
 }
 
 /**
@@ -70,8 +407,8 @@ pub struct PriceEntry {
 
 // Interface of the Flux price oracle
 #[near_sdk::ext_contract(fpo_contract)]
-trait FPOContract {
-    fn get_entry(pair: String, provider: AccountId) -> Promise<PriceEntry>;
+pub trait FPOContract {
+    fn get_entry(pair: String, provider: AccountId) -> PriceEntry;
 }
 
 ///
@@ -79,12 +416,57 @@ trait FPOContract {
 /// - oracle_account_id: should be a valid FPO oracle account ID
 /// - provider_account_id: should be a valid FPO provider account ID
 /// - owner_id: only the owner can edit the contract state values above (default = deployer)
+/// - protocol_fee_bps, treasury_id, protocol_fee_caps: protocol fee schedule, set by the owner
+/// - recurring_payments: recurring payment authorizations, by id
+/// - next_recurring_id: id of the next recurring payment authorization
+/// - storage_funds: NEAR deposited by payers to register payment recipients with tokens, by payer
+/// - registration_tokens: tokens with which payment recipients can be registered from storage funds, set by the owner
+/// - payee_keys: keys registered by payees to sign payment intents, by payee
+/// - payee_preferences: tokens and currencies accepted by payees, by payee
 #[near_bindgen]
-#[derive(Default, BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize)]
 pub struct FungibleConversionProxy {
     pub oracle_account_id: AccountId,
     pub provider_account_id: AccountId,
     pub owner_id: AccountId,
+    pub protocol_fee_bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub protocol_fee_caps: HashMap<String, ProtocolFeeCaps>,
+    pub recurring_payments: LookupMap<u64, RecurringPayment>,
+    pub next_recurring_id: u64,
+    pub storage_funds: StorageFunds,
+    pub registration_tokens: HashSet<AccountId>,
+    pub payee_keys: PayeeKeys,
+    pub payee_preferences: PayeesPreferences,
+}
+
+impl Default for FungibleConversionProxy {
+    fn default() -> Self {
+        // Accounts of an uninitialized contract: "system" never calls contract methods
+        Self {
+            oracle_account_id: "system".parse().unwrap(),
+            provider_account_id: "system".parse().unwrap(),
+            owner_id: "system".parse().unwrap(),
+            protocol_fee_bps: 0,
+            treasury_id: None,
+            protocol_fee_caps: HashMap::new(),
+            recurring_payments: LookupMap::new(b"r".to_vec()),
+            next_recurring_id: 0,
+            storage_funds: StorageFunds::new(b"f".to_vec()),
+            registration_tokens: HashSet::new(),
+            payee_keys: PayeeKeys::new(b"k".to_vec()),
+            payee_preferences: PayeesPreferences::new(b"p".to_vec()),
+        }
+    }
+}
+
+/// State of contracts deployed before the protocol fee, recurring payments, storage funds, payment intents and payee
+/// preferences (see `migrate`)
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldFungibleConversionProxy {
+    oracle_account_id: AccountId,
+    provider_account_id: AccountId,
+    owner_id: AccountId,
 }
 
 // Callback methods
@@ -98,8 +480,19 @@ pub trait ExtSelfRequestProxy {
         deposit: U128,
         crypto_amount: U128,
         crypto_fee_amount: U128,
+        crypto_fees_amounts: Vec<U128>,
+        protocol_fee: Option<ProtocolFee>,
+        crypto_protocol_fee_amount: U128,
         change: U128,
-    ) -> String;
+        recurring: Option<RecurringExecution>,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_unused_returned(
+        &self,
+        token_address: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128;
 
     fn ft_metadata_callback(
         &self,
@@ -107,6 +500,7 @@ pub trait ExtSelfRequestProxy {
         token_address: AccountId,
         payer: AccountId,
         deposit: U128,
+        recurring: Option<RecurringExecution>,
     ) -> Promise;
 
     fn rate_callback(
@@ -116,11 +510,39 @@ pub trait ExtSelfRequestProxy {
         payer: AccountId,
         deposit: U128,
         payment_token_decimals: u8,
+        recurring: Option<RecurringExecution>,
+    ) -> Promise;
+
+    fn on_recurring_executed(
+        &mut self,
+        id: U64,
+        recurring: RecurringPayment,
+        deposit: U128,
+        next_execution: U64,
+    ) -> bool;
+
+    fn on_recurring_revoked(&mut self, id: U64, recurring: RecurringPayment) -> bool;
+
+    fn on_recurring_change_refunded(&mut self, id: U64, recurring: RecurringPayment) -> bool;
+
+    fn on_storage_balances(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+        accounts: Vec<AccountId>,
     ) -> Promise;
-}
 
-trait FungibleTokenReceiver {
-    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: String, msg: String) -> Promise;
+    fn on_accounts_registered(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+        accounts: Vec<AccountId>,
+        storage_cost: U128,
+    ) -> Promise;
 }
 
 #[near_bindgen]
@@ -130,17 +552,35 @@ impl FungibleTokenReceiver for FungibleConversionProxy {
     /// `msg` should be a string in JSON format containing all the fields in `PaymentArgs`.
     /// Eg. msg = {"payment_reference":"abc7c8bb1234fd12","to":"dummy.payee.near","amount":"1000000","currency":"USD","fee_address":"fee.requestfinance.near","fee_amount":"200","max_rate_timespan":"0"}
     ///
+    /// `msg` can also be a `RecurringMsg`, to deposit the attached amount for a recurring payment.
+    ///
+    /// Returns the amount unused by the payment, that the token contract refunds to the sender.
+    ///
     /// For more information on the fungible token standard, see https://nomicon.io/Standards/Tokens/FungibleToken/Core
     ///
-    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: String, msg: String) -> Promise {
-        let args: PaymentArgs = serde_json::from_str(&msg).expect("Incorrect msg format");
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
         let token_address = env::predecessor_account_id();
-        self.transfer_with_reference(
-            args,
-            token_address,
-            sender_id,
-            U128::from(amount.parse::<u128>().unwrap()),
-        )
+        if let Ok(recurring_msg) = serde_json::from_str::<RecurringMsg>(&msg) {
+            match recurring_msg {
+                RecurringMsg::Authorize(args) => {
+                    self.authorize_recurring(args, token_address, sender_id, amount)
+                }
+                RecurringMsg::TopUp(id) => {
+                    self.top_up_recurring(id, token_address, sender_id, amount)
+                }
+            }
+            // The full amount is deposited, nothing to return to `ft_resolve_transfer`
+            return PromiseOrValue::Value(0.into());
+        }
+        let args: PaymentArgs =
+            serde_json::from_str(&msg).unwrap_or_else(|_| ProxyError::InvalidMsg.panic());
+        self.transfer_with_reference(args, token_address, sender_id, amount)
+            .into()
     }
 }
 
@@ -160,34 +600,77 @@ impl FungibleConversionProxy {
     #[private]
     fn transfer_with_reference(
         &mut self,
-        args: PaymentArgs,
+        mut args: PaymentArgs,
         token_address: AccountId,
         payer: AccountId,
         deposit: U128,
     ) -> Promise {
-        assert!(
-            MIN_GAS <= env::prepaid_gas(),
-            "Not enough attached Gas to call this method (Supplied: {}. Demand: {})",
-            env::prepaid_gas(),
-            MIN_GAS
+        require(
+            args.fees.len() <= MAX_FEE_RECIPIENTS,
+            ProxyError::TooManyFeeRecipients {
+                supplied: args.fees.len(),
+                max: MAX_FEE_RECIPIENTS,
+            },
+        );
+        let mut min_gas = MIN_GAS + args.fees_gas() + args.refund_gas() + self.protocol_fee_gas();
+        if args.register_accounts == Some(true) {
+            min_gas += registration_gas(self.payment_recipients(&args).len());
+        }
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
         );
 
         let reference_vec: Vec<u8> = hex::decode(args.payment_reference.replace("0x", ""))
-            .expect("Payment reference value error");
-        assert_eq!(reference_vec.len(), 8, "Incorrect payment reference length");
+            .unwrap_or_else(|_| ProxyError::InvalidReference.panic());
+        require(reference_vec.len() == 8, ProxyError::InvalidReferenceLength);
+        // Rejected payments are refunded by `ft_resolve_transfer`
+        self.payee_preferences.require_accepted(
+            &args.to,
+            token_address.as_str(),
+            Some(&args.currency),
+        );
+        if let Some(intent) = &args.intent {
+            let payment_intent = PaymentIntent {
+                payment_reference: reference_vec,
+                to: args.to.clone(),
+                amount: args.amount.0,
+                currency: args.currency.clone(),
+                token: token_address.to_string(),
+                expiry: intent.expiry.0,
+            };
+            self.payee_keys.verify(&payment_intent, intent);
+        } else {
+            self.payee_keys.require_no_key(&args.to);
+        }
+        if args.register_accounts == Some(true) {
+            // Storage funds are only spent for tokens that can be trusted with the payer and storage cost
+            require(
+                self.registration_tokens.contains(&token_address),
+                ProxyError::RegistrationNotAllowed {
+                    token: token_address.clone(),
+                },
+            );
+            // The payment is made by `on_storage_balances` or `on_accounts_registered`
+            return self.register_accounts_then_transfer(args, token_address, payer, deposit);
+        }
+        args.apply_fee_bps();
 
         // We need to get the token symbol and decimals for the oracle and currency conversion respectively
-        ft_contract::ft_metadata(&token_address, NO_DEPOSIT, BASIC_GAS).then(
-            ext_self::ft_metadata_callback(
-                args,
-                token_address,
-                payer,
-                deposit,
-                &env::current_account_id(),
-                env::attached_deposit(),
-                BASIC_GAS * 12,
-            ),
-        )
+        let callback_gas =
+            BASIC_GAS * 12 + args.fees_gas() + args.refund_gas() + self.protocol_fee_gas();
+        ft_contract::ext(token_address.clone())
+            .with_static_gas(BASIC_GAS)
+            .ft_metadata()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_attached_deposit(env::attached_deposit())
+                    .with_static_gas(callback_gas)
+                    .ft_metadata_callback(args, token_address, payer, deposit, None),
+            )
     }
 
     /// Convenience function for constructing the `msg` argument for `ft_transfer_call` in the fungible token contract.
@@ -197,19 +680,29 @@ impl FungibleConversionProxy {
         &self,
         amount: U128,
         currency: String,
-        fee_address: ValidAccountId,
+        fee_address: AccountId,
         fee_amount: U128,
         max_rate_timespan: U64,
         payment_reference: String,
-        to: ValidAccountId,
+        to: AccountId,
+        fees: Option<Vec<FeeRecipient>>,
+        fee_bps: Option<u16>,
+        register_accounts: Option<bool>,
+        refund_to: Option<AccountId>,
+        intent: Option<SignedIntent>,
     ) -> String {
         let args = PaymentArgs {
             amount,
             currency,
             fee_address,
             fee_amount,
+            fee_bps,
+            fees: fees.unwrap_or_default(),
+            intent,
             max_rate_timespan,
             payment_reference,
+            register_accounts,
+            refund_to,
             to,
         };
         serde_json::to_string(&args).unwrap()
@@ -217,85 +710,371 @@ impl FungibleConversionProxy {
 
     #[init]
     pub fn new(oracle_account_id: AccountId, provider_account_id: AccountId) -> Self {
-        let owner_id = env::signer_account_id();
+        let owner_id = env::predecessor_account_id();
         Self {
             oracle_account_id,
             provider_account_id,
             owner_id,
+            ..Default::default()
+        }
+    }
+
+    /// Migrates the state of a contract deployed with the previous layout, keeping its oracle and owner
+    /// (see `deploy.sh --patch`)
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldFungibleConversionProxy = env::state_read().expect("No state to migrate");
+        Self {
+            oracle_account_id: old.oracle_account_id,
+            provider_account_id: old.provider_account_id,
+            owner_id: old.owner_id,
+            ..Default::default()
         }
     }
 
-    pub fn set_oracle_account(&mut self, oracle: ValidAccountId) {
+    pub fn set_oracle_account(&mut self, oracle: AccountId) {
         let signer_id = env::predecessor_account_id();
         if self.owner_id == signer_id {
-            self.oracle_account_id = oracle.to_string();
+            self.oracle_account_id = oracle;
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
     pub fn get_oracle_account(&self) -> AccountId {
-        self.oracle_account_id.to_string()
+        self.oracle_account_id.clone()
     }
 
-    pub fn set_provider_account(&mut self, oracle: ValidAccountId) {
+    pub fn set_provider_account(&mut self, oracle: AccountId) {
         let signer_id = env::predecessor_account_id();
         if self.owner_id == signer_id {
-            self.provider_account_id = oracle.to_string();
+            self.provider_account_id = oracle;
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
         }
     }
 
     pub fn get_provider_account(&self) -> AccountId {
-        self.provider_account_id.to_string()
+        self.provider_account_id.clone()
+    }
+
+    pub fn set_owner(&mut self, owner: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.owner_id = owner;
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    /// Sets the protocol fee, in basis points of each payment `amount`, paid by the payer to `treasury_id`
+    pub fn set_protocol_fee(&mut self, bps: u16, treasury_id: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                bps <= MAX_BPS,
+                ProxyError::ProtocolFeeBpsTooHigh {
+                    supplied: bps,
+                    max: MAX_BPS,
+                },
+            );
+            self.protocol_fee_bps = bps;
+            self.treasury_id = Some(treasury_id);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    /// Bounds the protocol fee for payments in `currency`, amounts in `currency` with 2 decimals
+    pub fn set_protocol_fee_caps(&mut self, currency: String, min_amount: U128, max_amount: U128) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                min_amount.0 <= max_amount.0,
+                ProxyError::InvalidProtocolFeeCaps {
+                    min_amount: min_amount.0,
+                    max_amount: max_amount.0,
+                },
+            );
+            self.protocol_fee_caps.insert(
+                currency,
+                ProtocolFeeCaps {
+                    min_amount,
+                    max_amount,
+                },
+            );
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_protocol_fee_caps(&mut self, currency: String) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.protocol_fee_caps.remove(&currency);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_protocol_fee(&self) -> ProtocolFeeSchedule {
+        ProtocolFeeSchedule {
+            bps: self.protocol_fee_bps,
+            treasury_id: self.treasury_id.clone(),
+            caps: self.protocol_fee_caps.clone(),
+        }
+    }
+
+    /// Adds the attached deposit to the storage fund of `account_id` (default: the caller), used to register
+    /// payment recipients with tokens for payments made with `register_accounts`. Returns the storage fund.
+    #[payable]
+    pub fn deposit_storage_fund(&mut self, account_id: Option<AccountId>) -> U128 {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        self.storage_funds
+            .deposit(&account_id, env::attached_deposit())
+            .into()
+    }
+
+    /// Withdraws `amount` (default: all) from the caller's storage fund
+    pub fn withdraw_storage_fund(&mut self, amount: Option<U128>) -> Promise {
+        self.storage_funds.withdraw(amount.map(|amount| amount.0))
     }
 
-    pub fn set_owner(&mut self, owner: ValidAccountId) {
+    pub fn get_storage_fund(&self, account_id: AccountId) -> U128 {
+        self.storage_funds.get(&account_id).into()
+    }
+
+    /// Allows registering payment recipients with `token_address` from storage funds, the token being trusted to
+    /// report the payer (as `sender_id` of `ft_on_transfer`) and the storage cost
+    pub fn add_registration_token(&mut self, token_address: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.registration_tokens.insert(token_address);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_registration_token(&mut self, token_address: AccountId) {
         let signer_id = env::predecessor_account_id();
         if self.owner_id == signer_id {
-            self.owner_id = owner.to_string();
+            self.registration_tokens.remove(&token_address);
         } else {
-            panic!("ERR_PERMISSION");
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_registration_tokens(&self) -> Vec<AccountId> {
+        self.registration_tokens.iter().cloned().collect()
+    }
+
+    /// Registers `public_key` (ed25519) as the key signing the caller's payment intents, or removes it if `None`.
+    /// The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_key(&mut self, public_key: Option<PublicKey>) {
+        self.payee_keys.set(public_key);
+    }
+
+    pub fn get_payee_key(&self, account_id: AccountId) -> Option<PublicKey> {
+        self.payee_keys.get(&account_id)
+    }
+
+    /// Registers the tokens and currencies accepted by the caller as a payee, or removes them if `None` to accept
+    /// any asset. The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_preferences(&mut self, preferences: Option<PayeePreferences>) {
+        self.payee_preferences.set(preferences);
+    }
+
+    pub fn get_payee_preferences(&self, account_id: AccountId) -> Option<PayeePreferences> {
+        self.payee_preferences.get(&account_id)
+    }
+
+    /// Registers the payment recipients found unregistered with the payment token, paying the storage deposit
+    /// from the payer's storage fund, then pays
+    #[private]
+    pub fn on_storage_balances(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+        accounts: Vec<AccountId>,
+    ) -> Promise {
+        let mut args = args;
+        args.register_accounts = None;
+        let (storage_cost, unregistered_accounts) = unregistered_accounts(accounts);
+        if unregistered_accounts.is_empty() {
+            return self.transfer_with_reference(args, token_address, payer, deposit);
+        }
+
+        let registrations = self.storage_funds.register(
+            &payer,
+            &token_address,
+            &unregistered_accounts,
+            storage_cost,
+            BASIC_GAS,
+        );
+        // The payment gets all the gas left after the registrations
+        registrations.then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(MIN_GAS)
+                .on_accounts_registered(
+                    args,
+                    token_address,
+                    payer,
+                    deposit,
+                    unregistered_accounts,
+                    storage_cost.into(),
+                ),
+        )
+    }
+
+    /// Credits the storage fund back for failed registrations, logs the successful ones, then pays
+    #[private]
+    pub fn on_accounts_registered(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+        accounts: Vec<AccountId>,
+        storage_cost: U128,
+    ) -> Promise {
+        let registered_accounts =
+            self.storage_funds
+                .on_registrations(&payer, accounts, storage_cost.0);
+        if !registered_accounts.is_empty() {
+            let event = json!({
+                "event": "accounts_registered",
+                "payment_reference": args.payment_reference,
+                "payer": payer,
+                "token_address": token_address,
+                "accounts": registered_accounts,
+                "storage_cost": storage_cost,
+            });
+            env::log_str(&event.to_string());
         }
+        self.transfer_with_reference(args, token_address, payer, deposit)
     }
 
     #[private]
     pub fn on_transfer_with_reference(
-        &self,
+        &mut self,
         args: PaymentArgs,
         token_address: AccountId,
         payer: AccountId,
         deposit: U128,
         crypto_amount: U128,
         crypto_fee_amount: U128,
+        crypto_fees_amounts: Vec<U128>,
+        protocol_fee: Option<ProtocolFee>,
+        crypto_protocol_fee_amount: U128,
         change: U128,
-    ) -> String {
-        if near_sdk::is_promise_success() {
-            // Log success for indexing and payment detection
-            env::log(
-                &json!({
-                    "amount": args.amount,
-                    "currency": args.currency,
-                    "token_address": token_address,
-                    "fee_address": args.fee_address,
-                    "fee_amount": args.fee_amount,
-                    "max_rate_timespan": args.max_rate_timespan,
-                    "payment_reference": args.payment_reference,
-                    "to": args.to,
+        recurring: Option<RecurringExecution>,
+    ) -> PromiseOrValue<U128> {
+        let settlement = Settlement::from_promise_results(payment_legs(
+            &args,
+            crypto_amount.0,
+            crypto_fee_amount.0,
+            &crypto_fees_amounts
+                .iter()
+                .map(|amount| amount.0)
+                .collect::<Vec<Balance>>(),
+            protocol_fee
+                .as_ref()
+                .map(|protocol_fee| (protocol_fee, crypto_protocol_fee_amount.0)),
+        ));
+        // Only the change and the failed transfers are returned to `ft_resolve_transfer` on the token contract
+        // (or to `refund_to`), the proxy holding nothing more from this payment
+        let failed_amount = settlement.failed_amount();
+        let change = change.0 + failed_amount;
+        let refund_to = args.refund_to.clone();
+        if failed_amount > 0 {
+            settlement.log(&args.payment_reference, &token_address);
+        }
+        if !settlement.executed(0) {
+            log!(
+                "Failed to transfer to account {}. Returning {} of the attached deposit of {} of token {} to {}",
+                args.to, change, deposit.0, token_address, refund_to.as_ref().unwrap_or(&payer)
+            );
+            // The fees may have been paid, but the period of a recurring payment is only consumed by paying `to`
+            if let Some(execution) = recurring {
+                self.restore_recurring_period(execution);
+            }
+            return return_unused(&token_address, refund_to, change);
+        }
+
+        // Log success for indexing and payment detection, without the fees whose transfer failed
+        let mut payment_log = json!({
+            "amount": args.amount,
+            "currency": args.currency,
+            "token_address": token_address,
+            "fee_address": args.fee_address,
+            "fee_amount": if settlement.executed(1) { args.fee_amount } else { U128::from(0) },
+            "max_rate_timespan": args.max_rate_timespan,
+            "payment_reference": args.payment_reference,
+            "to": args.to,
+            "crypto_amount": crypto_amount,
+            "crypto_fee_amount": if settlement.executed(1) { crypto_fee_amount } else { U128::from(0) },
+        });
+        let paid_fees: Vec<serde_json::Value> = args
+            .fees
+            .iter()
+            .zip(crypto_fees_amounts)
+            .zip(&settlement.results[2..])
+            .filter(|(_, success)| **success)
+            .map(|((fee, crypto_amount), _)| {
+                json!({
+                    "address": fee.address,
+                    "amount": fee.amount,
                     "crypto_amount": crypto_amount,
-                    "crypto_fee_amount": crypto_fee_amount
                 })
-                .to_string()
-                .into_bytes(),
-            );
-            change.0.to_string() // return change for `ft_resolve_transfer` on the token contract
+            })
+            .collect();
+        if !paid_fees.is_empty() {
+            payment_log["fees"] = paid_fees.into();
+        }
+        if let Some(protocol_fee) = protocol_fee {
+            if *settlement.results.last().unwrap() {
+                payment_log["protocol_fee"] = json!({
+                    "treasury_id": protocol_fee.treasury_id,
+                    "amount": protocol_fee.amount,
+                    "crypto_amount": crypto_protocol_fee_amount,
+                });
+            }
+        }
+        env::log_str(&payment_log.to_string());
+        return_unused(&token_address, refund_to, change)
+    }
+
+    /// Logs the `unused_returned` event if the unused amount was transferred to `refund_to`, or returns it
+    /// to `ft_resolve_transfer` for the token contract to refund the sender
+    #[private]
+    pub fn on_unused_returned(
+        &self,
+        token_address: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if near_sdk::is_promise_success() {
+            let event = json!({
+                "event": "unused_returned",
+                "refund_to": refund_to,
+                "token_address": token_address,
+                "amount": amount,
+            });
+            env::log_str(&event.to_string());
+            0.into()
         } else {
             log!(
-                    "Failed to transfer to account {}. Returning attached deposit of {} of token {} to {}",
-                    args.to, deposit.0, token_address, payer
+                "Transfer failed to {}. Returning {} of token {} to the sender",
+                refund_to,
+                amount.0,
+                token_address
             );
-            deposit.0.to_string() // return full amount for `ft_resolve_transfer` on the token contract
+            amount
         }
     }
 
@@ -306,6 +1085,7 @@ impl FungibleConversionProxy {
         token_address: AccountId,
         payer: AccountId,
         deposit: U128,
+        recurring: Option<RecurringExecution>,
     ) -> Promise {
         // Parse fungible token metadata from promise result
         let ft_metadata = match env::promise_result(0) {
@@ -313,29 +1093,31 @@ impl FungibleConversionProxy {
             PromiseResult::Successful(value) => {
                 match serde_json::from_slice::<FungibleTokenMetadata>(&value) {
                     Ok(value) => value,
-                    Err(_e) => panic!("ERR_INVALID_FT_METADATA_RESPONSE"),
+                    Err(_e) => ProxyError::InvalidFtMetadataResponse.panic(),
                 }
             }
-            PromiseResult::Failed => panic!("ERR_FAILED_FT_METADATA_FETCH"),
+            PromiseResult::Failed => ProxyError::FailedFtMetadataFetch.panic(),
         };
 
-        let get_rate = fpo_contract::get_entry(
-            ft_metadata.symbol + "/" + &args.currency,
-            self.provider_account_id.clone(),
-            &self.oracle_account_id,
-            NO_DEPOSIT,
-            BASIC_GAS,
-        );
-        let process_request_payment = ext_self::rate_callback(
-            args,
-            token_address,
-            payer,
-            deposit,
-            ft_metadata.decimals,
-            &env::current_account_id(),
-            env::attached_deposit(),
-            BASIC_GAS * 8,
-        );
+        let get_rate = fpo_contract::ext(self.oracle_account_id.clone())
+            .with_static_gas(BASIC_GAS)
+            .get_entry(
+                ft_metadata.symbol + "/" + &args.currency,
+                self.provider_account_id.clone(),
+            );
+        let callback_gas =
+            BASIC_GAS * 8 + args.fees_gas() + args.refund_gas() + self.protocol_fee_gas();
+        let process_request_payment = ext_self::ext(env::current_account_id())
+            .with_attached_deposit(env::attached_deposit())
+            .with_static_gas(callback_gas)
+            .rate_callback(
+                args,
+                token_address,
+                payer,
+                deposit,
+                ft_metadata.decimals,
+                recurring,
+            );
         get_rate.then(process_request_payment)
     }
 
@@ -347,6 +1129,7 @@ impl FungibleConversionProxy {
         payer: AccountId,
         deposit: U128,
         payment_token_decimals: u8,
+        recurring: Option<RecurringExecution>,
     ) -> Promise {
         // Parse rate from oracle promise result
         let rate = match env::promise_result(0) {
@@ -354,86 +1137,468 @@ impl FungibleConversionProxy {
             PromiseResult::Successful(value) => {
                 match serde_json::from_slice::<PriceEntry>(&value) {
                     Ok(value) => value,
-                    Err(_e) => panic!("ERR_INVALID_ORACLE_RESPONSE"),
+                    Err(_e) => ProxyError::InvalidOracleResponse.panic(),
                 }
             }
-            PromiseResult::Failed => panic!("ERR_FAILED_ORACLE_FETCH"),
+            PromiseResult::Failed => ProxyError::FailedOracleFetch.panic(),
         };
         // Check rate validity
-        assert!(
+        require(
             u64::from(args.max_rate_timespan) == 0
-                || rate.last_update >= env::block_timestamp() - u64::from(args.max_rate_timespan),
-            "Conversion rate too old (Last updated: {})",
-            rate.last_update,
+                || rate.last_update
+                    >= env::block_timestamp().saturating_sub(u64::from(args.max_rate_timespan)),
+            ProxyError::OutdatedRate {
+                last_update: rate.last_update,
+            },
         );
         let conversion_rate = u128::from(rate.price);
+        require(conversion_rate > 0, ProxyError::InvalidRate);
         let decimals = u32::from(rate.decimals); // this is the conversion rate decimals, not the token decimals
-        let amount = Balance::from(args.amount)
-            * 10u128.pow(payment_token_decimals.into())
-            * 10u128.pow(decimals)
-            / conversion_rate
-            / ONE_FIAT;
-        let fee_amount = Balance::from(args.fee_amount)
-            * 10u128.pow(payment_token_decimals.into())
-            * 10u128.pow(decimals)
-            / conversion_rate
-            / ONE_FIAT;
-
-        let total_amount = amount + fee_amount;
+        let to_token_amount = |currency_amount: U128| -> Balance {
+            to_token_amount(
+                currency_amount.0,
+                payment_token_decimals,
+                conversion_rate,
+                decimals,
+            )
+        };
+        let amount = to_token_amount(args.amount);
+        let fee_amount = to_token_amount(args.fee_amount);
+        let fees_amounts: Vec<Balance> = args
+            .fees
+            .iter()
+            .map(|fee| to_token_amount(fee.amount))
+            .collect();
+        let protocol_fee = self.protocol_fee(&args.currency, args.amount.0);
+        let protocol_fee_amount = protocol_fee
+            .as_ref()
+            .map_or(0, |protocol_fee| to_token_amount(protocol_fee.amount));
+
+        // Saturating, so that the deposit check fails on overflows
+        let total_amount = fees_amounts
+            .iter()
+            .fold(amount.saturating_add(fee_amount), |total, fee| {
+                total.saturating_add(*fee)
+            })
+            .saturating_add(protocol_fee_amount);
 
         // Check deposit
-        assert!(total_amount <= deposit.0, "Deposit too small");
+        require(
+            total_amount <= deposit.0,
+            ProxyError::DepositTooSmall {
+                supplied: deposit.0,
+                demand: total_amount,
+            },
+        );
 
         let change = deposit.0 - total_amount;
 
-        let main_transfer_args =
-            json!({ "receiver_id": args.to.to_string(), "amount":amount.to_string(), "memo": None::<String> })
-                .to_string()
-                .into_bytes();
+        let transfers = payment_legs(
+            &args,
+            amount,
+            fee_amount,
+            &fees_amounts,
+            protocol_fee
+                .as_ref()
+                .map(|protocol_fee| (protocol_fee, protocol_fee_amount)),
+        );
 
-        let fee_transfer_args =
-            json!({ "receiver_id": args.fee_address.to_string(), "amount":fee_amount.to_string(), "memo": None::<String> })
-            .to_string()
-            .into_bytes();
+        // Each transfer is a separate promise, so that a failed fee transfer does not prevent paying `to`
+        let callback_gas = BASIC_GAS + args.refund_gas();
+        ft_transfer_legs_promise(&token_address, &transfers).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(callback_gas)
+                .on_transfer_with_reference(
+                    args,
+                    token_address,
+                    payer,
+                    deposit,
+                    U128::from(amount),
+                    U128::from(fee_amount),
+                    fees_amounts.into_iter().map(U128::from).collect(),
+                    protocol_fee,
+                    U128::from(protocol_fee_amount),
+                    U128::from(change),
+                    recurring,
+                ),
+        )
+    }
 
-        // Batch cross-contract calls (either both succeed or both fail)
-        Promise::new(token_address.to_string())
-            .function_call(
-                "ft_transfer".into(),
-                main_transfer_args,
-                YOCTO_DEPOSIT,
-                BASIC_GAS * 2,
+    /// Executes a recurring payment for the current period, callable by its executor.
+    /// `amount` defaults to the authorized amount per period, which it cannot exceed.
+    pub fn execute_recurring(&mut self, id: U64, amount: Option<U128>) -> Promise {
+        let mut recurring = self
+            .recurring_payments
+            .get(&id.0)
+            .unwrap_or_else(|| ProxyError::RecurringNotFound.panic());
+        require(
+            env::predecessor_account_id() == recurring.executor,
+            ProxyError::RecurringExecuteNotAllowed,
+        );
+        let timestamp = env::block_timestamp();
+        require(timestamp < recurring.expiry.0, ProxyError::RecurringExpired);
+        require(
+            timestamp >= recurring.next_execution.0,
+            ProxyError::RecurringAlreadyExecuted,
+        );
+        // The payee may have changed its preferences or registered a key since the authorization
+        self.payee_preferences.require_accepted(
+            &recurring.to,
+            recurring.token_address.as_str(),
+            Some(&recurring.currency),
+        );
+        require(
+            self.payee_keys.get(&recurring.to).is_none(),
+            ProxyError::RecurringPayeeKey,
+        );
+        let amount = amount.unwrap_or(recurring.amount);
+        require(
+            amount.0 <= recurring.amount.0,
+            ProxyError::RecurringAmountTooHigh {
+                supplied: amount.0,
+                max: recurring.amount.0,
+            },
+        );
+        let min_gas = MIN_GAS + self.protocol_fee_gas() + BASIC_GAS * 4;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+
+        // The whole balance is reserved until the payment settles, the change being credited back
+        let deposit = recurring.balance;
+        let next_execution = recurring.next_execution;
+        let elapsed_periods = (timestamp - recurring.start.0) / recurring.period.0;
+        recurring.next_execution =
+            (recurring.start.0 + (elapsed_periods + 1) * recurring.period.0).into();
+        recurring.balance = 0.into();
+        self.recurring_payments.insert(&id.0, &recurring);
+
+        let args = PaymentArgs {
+            amount,
+            currency: recurring.currency.clone(),
+            fee_address: recurring.fee_address.clone(),
+            fee_amount: recurring.fee_amount,
+            fee_bps: None,
+            fees: vec![],
+            intent: None,
+            max_rate_timespan: recurring.max_rate_timespan,
+            payment_reference: recurring.payment_reference.clone(),
+            register_accounts: None,
+            refund_to: None,
+            to: recurring.to.clone(),
+        };
+        let callback_gas = BASIC_GAS * 12 + self.protocol_fee_gas();
+        ft_contract::ext(recurring.token_address.clone())
+            .with_static_gas(BASIC_GAS)
+            .ft_metadata()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas)
+                    .ft_metadata_callback(
+                        args,
+                        recurring.token_address.clone(),
+                        recurring.payer.clone(),
+                        deposit,
+                        Some(RecurringExecution { id, next_execution }),
+                    ),
             )
-            .function_call(
-                "ft_transfer".into(),
-                fee_transfer_args,
-                YOCTO_DEPOSIT,
-                BASIC_GAS * 2,
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 4)
+                    .on_recurring_executed(id, recurring, deposit, next_execution),
             )
-            .then(ext_self::on_transfer_with_reference(
-                args,
-                token_address,
-                payer,
-                deposit,
-                U128::from(amount),
-                U128::from(fee_amount),
-                U128::from(change),
-                &env::current_account_id(),
-                NO_DEPOSIT,
-                BASIC_GAS,
-            ))
+    }
+
+    /// Revokes a recurring payment, callable by the payer, who gets the remaining balance refunded
+    pub fn revoke_recurring(&mut self, id: U64) -> PromiseOrValue<bool> {
+        let recurring = self
+            .recurring_payments
+            .get(&id.0)
+            .unwrap_or_else(|| ProxyError::RecurringNotFound.panic());
+        require(
+            env::predecessor_account_id() == recurring.payer,
+            ProxyError::RecurringRevokeNotAllowed,
+        );
+        self.recurring_payments.remove(&id.0);
+        if recurring.balance.0 == 0 {
+            // Some tokens revert when calling `ft_transfer` with 0
+            log_recurring_revoked(id, &recurring);
+            return PromiseOrValue::Value(true);
+        }
+        let refund = ft_contract::ext(recurring.token_address.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS * 2)
+            .ft_transfer(recurring.payer.clone(), recurring.balance, None);
+        refund
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS)
+                    .on_recurring_revoked(id, recurring),
+            )
+            .into()
+    }
+
+    pub fn get_recurring_payment(&self, id: U64) -> Option<RecurringPayment> {
+        self.recurring_payments.get(&id.0)
+    }
+
+    /// Credits the change of an execution back to the recurring payment balance, restoring the period if
+    /// the payee was not paid, or refunds the change to the payer if the recurring payment was revoked meanwhile
+    #[private]
+    pub fn on_recurring_executed(
+        &mut self,
+        id: U64,
+        recurring: RecurringPayment,
+        deposit: U128,
+        next_execution: U64,
+    ) -> bool {
+        // `on_transfer_with_reference` returns the change, or the full deposit if the transfers failed
+        let change = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .ok()
+                .map(|change| change.0),
+            _ => None,
+        };
+        let mut executed = change.is_some_and(|change| change < deposit.0);
+        let change = change.unwrap_or(deposit.0);
+        match self.recurring_payments.get(&id.0) {
+            Some(mut recurring) => {
+                recurring.balance = (recurring.balance.0 + change).into();
+                self.recurring_payments.insert(&id.0, &recurring);
+                if recurring.next_execution == next_execution {
+                    // Already restored by `on_transfer_with_reference`, only fees having been paid
+                    executed = false;
+                } else if !executed {
+                    self.restore_recurring_period(RecurringExecution { id, next_execution });
+                }
+            }
+            None => {
+                if change > 0 {
+                    // Revoked, the recurring payment is only restored if the refund fails
+                    let mut recurring = recurring;
+                    recurring.balance = change.into();
+                    recurring.expiry = env::block_timestamp().into();
+                    ft_contract::ext(recurring.token_address.clone())
+                        .with_attached_deposit(YOCTO_DEPOSIT)
+                        .with_static_gas(BASIC_GAS * 2)
+                        .ft_transfer(recurring.payer.clone(), change.into(), None)
+                        .then(
+                            ext_self::ext(env::current_account_id())
+                                .with_static_gas(BASIC_GAS)
+                                .on_recurring_change_refunded(id, recurring),
+                        );
+                }
+            }
+        }
+        executed
+    }
+
+    /// Logs the revocation, or restores the recurring payment if the refund failed
+    #[private]
+    pub fn on_recurring_revoked(&mut self, id: U64, recurring: RecurringPayment) -> bool {
+        if near_sdk::is_promise_success() {
+            log_recurring_revoked(id, &recurring);
+            true
+        } else {
+            log!(
+                "Refund failed for recurring payment {}. The recurring payment is kept",
+                id.0
+            );
+            self.recurring_payments.insert(&id.0, &recurring);
+            false
+        }
+    }
+
+    /// Logs a failed refund of the change of an execution after a revocation, restoring the recurring payment,
+    /// expired, so that the payer can claim the change by revoking it again
+    #[private]
+    pub fn on_recurring_change_refunded(&mut self, id: U64, recurring: RecurringPayment) -> bool {
+        if near_sdk::is_promise_success() {
+            return true;
+        }
+        log!(
+            "Refund of the change failed for revoked recurring payment {}. It is kept, expired, until revoked again",
+            id.0
+        );
+        self.recurring_payments.insert(&id.0, &recurring);
+        false
+    }
+}
+
+impl FungibleConversionProxy {
+    /// Stores a new recurring payment authorization, funded with `deposit` of `token_address`
+    fn authorize_recurring(
+        &mut self,
+        args: RecurringArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+    ) {
+        let reference_vec: Vec<u8> = hex::decode(args.payment_reference.replace("0x", ""))
+            .unwrap_or_else(|_| ProxyError::InvalidReference.panic());
+        require(reference_vec.len() == 8, ProxyError::InvalidReferenceLength);
+        require(args.period.0 > 0, ProxyError::InvalidRecurringPeriod);
+        self.payee_preferences.require_accepted(
+            &args.to,
+            token_address.as_str(),
+            Some(&args.currency),
+        );
+        // Intents sign a single payment, so payees requiring them cannot be paid recurring payments
+        require(
+            self.payee_keys.get(&args.to).is_none(),
+            ProxyError::RecurringPayeeKey,
+        );
+        let start = env::block_timestamp();
+        require(args.expiry.0 > start, ProxyError::InvalidRecurringExpiry);
+
+        let id = self.next_recurring_id;
+        self.next_recurring_id += 1;
+        let recurring = RecurringPayment {
+            payer,
+            token_address,
+            executor: args.executor,
+            to: args.to.clone(),
+            amount: args.amount,
+            currency: args.currency,
+            fee_address: args.fee_address.clone(),
+            fee_amount: args.fee_amount,
+            max_rate_timespan: args.max_rate_timespan,
+            payment_reference: args.payment_reference,
+            start: start.into(),
+            period: args.period,
+            expiry: args.expiry,
+            next_execution: start.into(),
+            balance: deposit,
+        };
+        let event = json!({
+            "event": "recurring_authorized",
+            "id": U64::from(id),
+            "payer": recurring.payer,
+            "to": recurring.to,
+            "executor": recurring.executor,
+            "token_address": recurring.token_address,
+            "amount": recurring.amount,
+            "currency": recurring.currency,
+            "period": recurring.period,
+            "expiry": recurring.expiry,
+            "balance": recurring.balance,
+        });
+        env::log_str(&event.to_string());
+        self.recurring_payments.insert(&id, &recurring);
+    }
+
+    /// Adds `deposit` of `token_address` to the balance of a recurring payment
+    fn top_up_recurring(
+        &mut self,
+        id: U64,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+    ) {
+        let mut recurring = self
+            .recurring_payments
+            .get(&id.0)
+            .unwrap_or_else(|| ProxyError::RecurringNotFound.panic());
+        require(
+            payer == recurring.payer,
+            ProxyError::RecurringTopUpNotAllowed,
+        );
+        require(
+            token_address == recurring.token_address,
+            ProxyError::RecurringTokenMismatch,
+        );
+        recurring.balance = (recurring.balance.0 + deposit.0).into();
+        self.recurring_payments.insert(&id.0, &recurring);
+    }
+
+    /// Restores the period of a recurring payment whose execution did not pay its payee, unless it was revoked
+    fn restore_recurring_period(&mut self, execution: RecurringExecution) {
+        if let Some(mut recurring) = self.recurring_payments.get(&execution.id.0) {
+            log!(
+                "Recurring payment {} failed. It can be executed again for this period",
+                execution.id.0
+            );
+            recurring.next_execution = execution.next_execution;
+            self.recurring_payments.insert(&execution.id.0, &recurring);
+        }
+    }
+
+    /// Protocol fee due on a payment of `amount` in `currency`, or `None` if there is nothing to collect
+    fn protocol_fee(&self, currency: &str, amount: Balance) -> Option<ProtocolFee> {
+        let treasury_id = self.treasury_id.clone()?;
+        if self.protocol_fee_bps == 0 || amount == 0 {
+            return None;
+        }
+        let mut fee_amount = fee_amount_from_bps(amount, self.protocol_fee_bps);
+        if let Some(caps) = self.protocol_fee_caps.get(currency) {
+            fee_amount = fee_amount.max(caps.min_amount.0).min(caps.max_amount.0);
+        }
+        Some(ProtocolFee {
+            treasury_id,
+            amount: fee_amount.into(),
+        })
+    }
+
+    /// Accounts paid by `args`: `to`, fee recipients and the treasury, without duplicates
+    fn payment_recipients(&self, args: &PaymentArgs) -> Vec<AccountId> {
+        let mut accounts = vec![args.to.clone(), args.fee_address.clone()];
+        accounts.extend(args.fees.iter().map(|fee| fee.address.clone()));
+        if self.protocol_fee_bps > 0 {
+            accounts.extend(self.treasury_id.clone());
+        }
+        let mut recipients: Vec<AccountId> = vec![];
+        for account in accounts {
+            if !recipients.contains(&account) {
+                recipients.push(account);
+            }
+        }
+        recipients
+    }
+
+    /// Checks the registration of the payment recipients with the payment token, to register them before paying
+    fn register_accounts_then_transfer(
+        &self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        deposit: U128,
+    ) -> Promise {
+        let accounts = self.payment_recipients(&args);
+        let storage_balances = check_registrations(&token_address, &accounts, BASIC_GAS);
+        // The registrations and payment get all the gas left after the checks
+        storage_balances.then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(MIN_GAS)
+                .on_storage_balances(args, token_address, payer, deposit, accounts),
+        )
+    }
+
+    /// Additional gas needed to transfer the protocol fee to the treasury
+    fn protocol_fee_gas(&self) -> Gas {
+        if self.treasury_id.is_some() && self.protocol_fee_bps > 0 {
+            BASIC_GAS * 2
+        } else {
+            Gas(0)
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::{testing_env, VMContext};
-    use near_sdk::{AccountId, Balance, MockedBlockchain};
-    use std::convert::TryInto;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, Balance, RuntimeFeesConfig, VMConfig, VMContext};
+    use proptest::prelude::*;
+    use proxy_storage::{StorageBalanceBounds, MAX_STORAGE_COST};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
 
     fn alice_account() -> AccountId {
-        "alice.near".to_string()
+        "alice.near".parse().unwrap()
     }
 
     fn get_context(
@@ -442,24 +1607,19 @@ mod tests {
         prepaid_gas: Gas,
         is_view: bool,
     ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 0,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(0)
+            .epoch_height(1)
+            .account_balance(ntoy(1_000))
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
     }
 
     fn ntoy(near_amount: Balance) -> Balance {
@@ -471,11 +1631,16 @@ mod tests {
         PaymentArgs {
             amount: 1000000.into(),
             currency: "USD".into(),
-            fee_address: "fee.requestfinance.near".to_string().try_into().unwrap(),
+            fee_address: "fee.requestfinance.near".parse().unwrap(),
             fee_amount: 200.into(),
+            fee_bps: None,
+            fees: vec![],
+            intent: None,
             max_rate_timespan: 0.into(),
             payment_reference: "abc7c8bb1234fd12".into(),
-            to: "dummy.payee.near".to_string().try_into().unwrap(),
+            register_accounts: None,
+            refund_to: None,
+            to: "dummy.payee.near".parse().unwrap(),
         }
     }
 
@@ -495,7 +1660,7 @@ mod tests {
         args.payment_reference = "0x11223344556677".to_string();
         let msg = get_msg_from_args(args);
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
     }
 
     #[test]
@@ -509,20 +1674,20 @@ mod tests {
         args.payment_reference = "0x123".to_string();
         let msg = get_msg_from_args(args);
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
     }
 
     #[test]
     #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
     fn transfer_with_not_enough_gas() {
-        let context = get_context(alice_account(), ntoy(100), 10u64.pow(14), false);
+        let context = get_context(alice_account(), ntoy(100), Gas(10u64.pow(14)), false);
         testing_env!(context);
         let mut contract = FungibleConversionProxy::default();
 
         let args = get_default_payment_args();
         let msg = get_msg_from_args(args);
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
     }
 
     #[test]
@@ -535,7 +1700,7 @@ mod tests {
         let args = get_default_payment_args();
         let msg = get_msg_from_args(args) + ".";
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
     }
 
     #[test]
@@ -548,7 +1713,7 @@ mod tests {
         let args = get_default_payment_args();
         let msg = get_msg_from_args(args).replace("\"amount\":\"1000000\",", "");
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
     }
     #[test]
     fn transfer_with_reference() {
@@ -559,7 +1724,206 @@ mod tests {
         let args = get_default_payment_args();
         let msg = get_msg_from_args(args);
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    const PAYEE_SECRET_KEY: [u8; 32] = [7; 32];
+
+    /// Registers the key of the default payee, and returns its intent to be paid `amount` USD in alice's token
+    fn register_payee_intent(contract: &mut FungibleConversionProxy, amount: u128) -> SignedIntent {
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_payee_key(Some(payment_intents::public_key(&PAYEE_SECRET_KEY)));
+        PaymentIntent {
+            payment_reference: hex::decode("abc7c8bb1234fd12").unwrap(),
+            to: "dummy.payee.near".parse().unwrap(),
+            amount,
+            currency: "USD".into(),
+            token: alice_account().to_string(),
+            expiry: 1_000,
+        }
+        .sign(&PAYEE_SECRET_KEY)
+    }
+
+    #[test]
+    fn transfer_with_intent() {
+        let mut contract = FungibleConversionProxy::default();
+        let mut args = get_default_payment_args();
+        args.intent = Some(register_payee_intent(&mut contract, args.amount.0));
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn transfer_with_tampered_intent_amount() {
+        let mut contract = FungibleConversionProxy::default();
+        let mut args = get_default_payment_args();
+        args.intent = Some(register_payee_intent(&mut contract, args.amount.0));
+        args.amount = U128::from(args.amount.0 * 2);
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INTENT_REQUIRED"#)]
+    fn transfer_without_intent_to_payee_with_key() {
+        let mut contract = FungibleConversionProxy::default();
+        let args = get_default_payment_args();
+        register_payee_intent(&mut contract, args.amount.0);
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    /// Registers the currencies accepted by the default payee
+    fn set_payee_currencies(contract: &mut FungibleConversionProxy, currencies: Vec<String>) {
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_payee_preferences(Some(PayeePreferences {
+            tokens: vec![],
+            currencies,
+        }));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_CURRENCY_NOT_ACCEPTED: dummy.payee.near does not accept payments in USD"#
+    )]
+    fn transfer_in_currency_not_accepted() {
+        let mut contract = FungibleConversionProxy::default();
+        set_payee_currencies(&mut contract, vec!["EUR".into()]);
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS, false));
+        let msg = get_msg_from_args(get_default_payment_args());
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Too many fee recipients"#)]
+    fn transfer_with_too_many_fees() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS * 2, false);
+        testing_env!(context);
+        let mut contract = FungibleConversionProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![
+            FeeRecipient {
+                address: "referrer.near".parse().unwrap(),
+                amount: 100.into(),
+            };
+            MAX_FEE_RECIPIENTS + 1
+        ];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_fees_not_enough_gas() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleConversionProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![FeeRecipient {
+            address: "referrer.near".parse().unwrap(),
+            amount: 100.into(),
+        }];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_amount and fee_bps are mutually exclusive"#)]
+    fn transfer_with_fee_amount_and_fee_bps() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleConversionProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_bps = Some(100);
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_bps should not exceed 10000"#)]
+    fn transfer_with_fee_bps_too_high() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleConversionProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(10_001);
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    fn apply_fee_bps() {
+        let mut args = get_default_payment_args();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(250);
+        args.apply_fee_bps();
+        // 2.5% of 10000.00 USD
+        assert_eq!(args.fee_amount.0, 25000);
+
+        let mut args = get_default_payment_args();
+        args.amount = 99.into();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(100);
+        args.apply_fee_bps();
+        // 1% of 0.99 USD is rounded down
+        assert_eq!(args.fee_amount.0, 0);
+
+        // No overflow
+        let mut args = get_default_payment_args();
+        args.amount = (u128::MAX / 2).into();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(250);
+        args.apply_fee_bps();
+        assert_eq!(args.fee_amount.0, u128::MAX / 2 / 40);
+    }
+
+    #[test]
+    fn test_get_transfer_with_reference_args_with_fees() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, true);
+        testing_env!(context);
+        let contract = FungibleConversionProxy::default();
+
+        let expected_msg = r#"{"amount":"1000000","currency":"USD","fee_address":"fee.requestfinance.near","fee_amount":"200","fees":[{"address":"referrer.near","amount":"100"}],"max_rate_timespan":"0","payment_reference":"abc7c8bb1234fd12","to":"dummy.payee.near"}"#;
+        let args = get_default_payment_args();
+
+        let msg = contract.get_transfer_with_reference_args(
+            args.amount,
+            args.currency,
+            args.fee_address,
+            args.fee_amount,
+            args.max_rate_timespan,
+            args.payment_reference,
+            args.to,
+            Some(vec![FeeRecipient {
+                address: "referrer.near".parse().unwrap(),
+                amount: 100.into(),
+            }]),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(msg, expected_msg);
     }
 
     #[test]
@@ -579,6 +1943,37 @@ mod tests {
             args.max_rate_timespan,
             args.payment_reference,
             args.to,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(msg, expected_msg);
+    }
+
+    #[test]
+    fn test_get_transfer_with_reference_args_with_refund_to() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, true);
+        testing_env!(context);
+        let contract = FungibleConversionProxy::default();
+
+        let expected_msg = r#"{"amount":"1000000","currency":"USD","fee_address":"fee.requestfinance.near","fee_amount":"200","max_rate_timespan":"0","payment_reference":"abc7c8bb1234fd12","refund_to":"refund.near","to":"dummy.payee.near"}"#;
+        let args = get_default_payment_args();
+
+        let msg = contract.get_transfer_with_reference_args(
+            args.amount,
+            args.currency,
+            args.fee_address,
+            args.fee_amount,
+            args.max_rate_timespan,
+            args.payment_reference,
+            args.to,
+            None,
+            None,
+            None,
+            Some("refund.near".parse().unwrap()),
+            None,
         );
         assert_eq!(msg, expected_msg);
     }
@@ -590,7 +1985,7 @@ mod tests {
         testing_env!(context);
         let mut contract = FungibleConversionProxy::default();
 
-        let new_orcale: ValidAccountId = alice_account().try_into().unwrap();
+        let new_orcale: AccountId = alice_account();
         contract.set_oracle_account(new_orcale);
     }
 
@@ -601,9 +1996,9 @@ mod tests {
         let context = get_context(owner, ntoy(1), MIN_GAS, false);
         testing_env!(context);
 
-        let new_orcale: ValidAccountId = alice_account().try_into().unwrap();
+        let new_orcale: AccountId = alice_account();
         contract.set_oracle_account(new_orcale.clone());
-        assert_eq!(contract.oracle_account_id, new_orcale.to_string());
+        assert_eq!(contract.oracle_account_id, new_orcale);
     }
 
     #[test]
@@ -613,7 +2008,7 @@ mod tests {
         testing_env!(context);
         let mut contract = FungibleConversionProxy::default();
 
-        let new_provider: ValidAccountId = alice_account().try_into().unwrap();
+        let new_provider: AccountId = alice_account();
         contract.set_provider_account(new_provider);
     }
 
@@ -621,12 +2016,12 @@ mod tests {
     fn admin_provider() {
         let owner = FungibleConversionProxy::default().owner_id;
         let mut contract = FungibleConversionProxy::default();
-        let context = get_context(owner, ntoy(1), 10u64.pow(14), false);
+        let context = get_context(owner, ntoy(1), Gas(10u64.pow(14)), false);
         testing_env!(context);
 
-        let new_provider: ValidAccountId = alice_account().try_into().unwrap();
+        let new_provider: AccountId = alice_account();
         contract.set_provider_account(new_provider.clone());
-        assert_eq!(contract.provider_account_id, new_provider.to_string());
+        assert_eq!(contract.provider_account_id, new_provider);
     }
 
     #[test]
@@ -636,7 +2031,7 @@ mod tests {
         testing_env!(context);
         let mut contract = FungibleConversionProxy::default();
 
-        let new_owner: ValidAccountId = alice_account().try_into().unwrap();
+        let new_owner: AccountId = alice_account();
         contract.set_owner(new_owner);
     }
 
@@ -647,8 +2042,667 @@ mod tests {
         let context = get_context(owner, ntoy(1), MIN_GAS, false);
         testing_env!(context);
 
-        let new_owner: ValidAccountId = alice_account().try_into().unwrap();
+        let new_owner: AccountId = alice_account();
         contract.set_owner(new_owner.clone());
-        assert_eq!(contract.owner_id, new_owner.to_string());
+        assert_eq!(contract.owner_id, new_owner);
+    }
+
+    #[test]
+    fn migrate() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        env::state_write(&OldFungibleConversionProxy {
+            oracle_account_id: "fpo.near".parse().unwrap(),
+            provider_account_id: "provider.near".parse().unwrap(),
+            owner_id: alice_account(),
+        });
+        let contract = FungibleConversionProxy::migrate();
+        assert_eq!(contract.oracle_account_id.as_str(), "fpo.near");
+        assert_eq!(contract.provider_account_id.as_str(), "provider.near");
+        assert_eq!(contract.owner_id, alice_account());
+        assert_eq!(contract.next_recurring_id, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_protocol_fee_no_permission() {
+        let context = get_context(alice_account(), ntoy(1), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleConversionProxy::default();
+
+        contract.set_protocol_fee(100, alice_account());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"min_amount should not exceed max_amount"#)]
+    fn admin_protocol_fee_caps_inverted() {
+        let owner = FungibleConversionProxy::default().owner_id;
+        let mut contract = FungibleConversionProxy::default();
+        let context = get_context(owner, ntoy(1), MIN_GAS, false);
+        testing_env!(context);
+
+        contract.set_protocol_fee_caps("USD".into(), 500.into(), 10.into());
+    }
+
+    #[test]
+    fn admin_protocol_fee() {
+        let owner = FungibleConversionProxy::default().owner_id;
+        let mut contract = FungibleConversionProxy::default();
+        let context = get_context(owner, ntoy(1), MIN_GAS, false);
+        testing_env!(context);
+
+        let treasury: AccountId = alice_account();
+        contract.set_protocol_fee(100, treasury.clone());
+        contract.set_protocol_fee_caps("USD".into(), 10.into(), 500.into());
+        let schedule = contract.get_protocol_fee();
+        assert_eq!(schedule.bps, 100);
+        assert_eq!(schedule.treasury_id, Some(treasury));
+        assert_eq!(schedule.caps["USD"].max_amount.0, 500);
+        contract.remove_protocol_fee_caps("USD".into());
+        assert!(contract.get_protocol_fee().caps.is_empty());
+    }
+
+    #[test]
+    fn protocol_fee_with_caps() {
+        let owner = FungibleConversionProxy::default().owner_id;
+        let mut contract = FungibleConversionProxy::default();
+        let context = get_context(owner, ntoy(1), MIN_GAS, false);
+        testing_env!(context);
+
+        assert!(contract.protocol_fee("USD", 12000).is_none());
+        assert_eq!(contract.protocol_fee_gas(), Gas(0));
+        contract.set_protocol_fee(100, alice_account());
+        assert_eq!(contract.protocol_fee_gas(), BASIC_GAS * 2);
+        // 1% of 120.00 USD is 1.20 USD
+        assert_eq!(contract.protocol_fee("USD", 12000).unwrap().amount.0, 120);
+        contract.set_protocol_fee_caps("USD".into(), 10.into(), 100.into());
+        assert_eq!(contract.protocol_fee("USD", 12000).unwrap().amount.0, 100);
+        assert_eq!(contract.protocol_fee("USD", 500).unwrap().amount.0, 10);
+        assert!(contract.protocol_fee("USD", 0).is_none());
+    }
+
+    #[test]
+    fn deposit_and_withdraw_storage_fund() {
+        testing_env!(get_context(alice_account(), ntoy(3), MIN_GAS, false));
+        let mut contract = FungibleConversionProxy::default();
+        assert_eq!(contract.deposit_storage_fund(None).0, ntoy(3));
+        contract.deposit_storage_fund(Some("bob.near".parse().unwrap()));
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.withdraw_storage_fund(Some(ntoy(1).into()));
+        assert_eq!(contract.get_storage_fund(alice_account()).0, ntoy(2));
+        assert_eq!(
+            contract.get_storage_fund("bob.near".parse().unwrap()).0,
+            ntoy(3)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough storage fund"#)]
+    fn withdraw_storage_fund_too_much() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleConversionProxy::default();
+        contract.deposit_storage_fund(None);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.withdraw_storage_fund(Some(ntoy(2).into()));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_register_accounts_not_enough_gas() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleConversionProxy::default();
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    fn transfer_with_register_accounts() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleConversionProxy::default();
+        // The predecessor of `ft_on_transfer` is the token
+        contract.registration_tokens.insert(alice_account());
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Registering accounts with alice.near is not allowed"#)]
+    fn transfer_with_register_accounts_token_not_allowed() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleConversionProxy::default();
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Storage cost too high to register accounts"#)]
+    fn register_accounts_storage_cost_too_high() {
+        let bounds = StorageBalanceBounds {
+            min: (MAX_STORAGE_COST + 1).into(),
+            max: None,
+        };
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS * 2, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&bounds).unwrap()),
+                PromiseResult::Successful(b"null".to_vec()),
+            ]
+        );
+        let mut contract = FungibleConversionProxy::default();
+        contract.storage_funds.deposit(&alice_account(), ntoy(1));
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.on_storage_balances(
+            args,
+            "token.near".parse().unwrap(),
+            alice_account(),
+            1.into(),
+            vec!["dummy.payee.near".parse().unwrap()],
+        );
+    }
+
+    /// Helper function: a contract with a recurring payment of up to 10.00 USD from alice to dummy.payee.near
+    /// every 1000ns, until 10000ns, with 5000 tokens deposited
+    fn contract_with_recurring_payment() -> FungibleConversionProxy {
+        let mut contract = FungibleConversionProxy::default();
+        let args = RecurringArgs {
+            amount: 1000.into(),
+            currency: "USD".into(),
+            executor: "executor.near".parse().unwrap(),
+            expiry: 10000.into(),
+            fee_address: "fee.requestfinance.near".parse().unwrap(),
+            fee_amount: 0.into(),
+            max_rate_timespan: 0.into(),
+            payment_reference: "abc7c8bb1234fd12".into(),
+            period: 1000.into(),
+            to: "dummy.payee.near".parse().unwrap(),
+        };
+        let msg = serde_json::to_string(&RecurringMsg::Authorize(args)).unwrap();
+        contract.ft_on_transfer(alice_account(), 5000.into(), msg);
+        contract
+    }
+
+    #[test]
+    fn authorize_recurring_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let contract = contract_with_recurring_payment();
+
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.payer, alice_account());
+        assert_eq!(recurring.token_address, alice_account());
+        assert_eq!(recurring.executor.as_str(), "executor.near");
+        assert_eq!(recurring.balance.0, 5000);
+        assert_eq!(recurring.next_execution.0, 0);
+        assert_eq!(contract.next_recurring_id, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_RECURRING_PAYEE_KEY"#)]
+    fn authorize_recurring_payment_to_payee_with_key() {
+        let mut contract = FungibleConversionProxy::default();
+        register_payee_intent(&mut contract, 1000);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let msg = r#"{"authorize":{"amount":"1000","currency":"USD","executor":"executor.near","expiry":"10000","fee_address":"fee.requestfinance.near","fee_amount":"0","max_rate_timespan":"0","payment_reference":"abc7c8bb1234fd12","period":"1000","to":"dummy.payee.near"}}"#;
+        contract.ft_on_transfer(alice_account(), 5000.into(), msg.into());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_RECURRING_PAYEE_KEY"#)]
+    fn execute_recurring_payment_to_payee_with_key() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        // The payee registered a key since the authorization
+        register_payee_intent(&mut contract, 1000);
+        testing_env!(get_context(
+            "executor.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.execute_recurring(0.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"period should not be 0"#)]
+    fn authorize_recurring_payment_without_period() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleConversionProxy::default();
+        let msg = r#"{"authorize":{"amount":"1000","currency":"USD","executor":"executor.near","expiry":"10000","fee_address":"fee.requestfinance.near","fee_amount":"0","max_rate_timespan":"0","payment_reference":"abc7c8bb1234fd12","period":"0","to":"dummy.payee.near"}}"#;
+        contract.ft_on_transfer(alice_account(), 5000.into(), msg.into());
+    }
+
+    #[test]
+    fn top_up_recurring_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        contract.ft_on_transfer(alice_account(), 1000.into(), r#"{"top_up":"0"}"#.into());
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.balance.0, 6000);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the payer can top up the recurring payment"#)]
+    fn top_up_recurring_payment_not_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        contract.ft_on_transfer(
+            "bob.near".parse().unwrap(),
+            1000.into(),
+            r#"{"top_up":"0"}"#.into(),
+        );
+    }
+
+    #[test]
+    fn execute_recurring_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let mut context = get_context("executor.near".parse().unwrap(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 2500;
+        testing_env!(context);
+        contract.execute_recurring(0.into(), Some(500.into()));
+
+        // The balance is reserved until the payment settles
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.balance.0, 0);
+        assert_eq!(recurring.next_execution.0, 3000);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_CURRENCY_NOT_ACCEPTED"#)]
+    fn execute_recurring_payment_in_currency_not_accepted() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        // The payee no longer accepts the currency of the authorization
+        set_payee_currencies(&mut contract, vec!["EUR".into()]);
+        testing_env!(get_context(
+            "executor.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.execute_recurring(0.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"The recurring payment was already executed for this period"#)]
+    fn execute_recurring_payment_twice() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        testing_env!(get_context(
+            "executor.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.execute_recurring(0.into(), None);
+        contract.execute_recurring(0.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the executor can execute the recurring payment"#)]
+    fn execute_recurring_payment_not_executor() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_recurring_payment();
+        contract.execute_recurring(0.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"amount exceeds the authorized amount per period"#)]
+    fn execute_recurring_payment_above_amount() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        testing_env!(get_context(
+            "executor.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.execute_recurring(0.into(), Some(1001.into()));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"The recurring payment has expired"#)]
+    fn execute_recurring_payment_expired() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let mut context = get_context("executor.near".parse().unwrap(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 10000;
+        testing_env!(context);
+        contract.execute_recurring(0.into(), None);
+    }
+
+    #[test]
+    fn revoke_recurring_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        contract.revoke_recurring(0.into());
+        assert!(contract.get_recurring_payment(0.into()).is_none());
+    }
+
+    #[test]
+    fn recurring_executed_after_revocation() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        contract.revoke_recurring(0.into());
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&U128::from(400)).unwrap()
+            )]
+        );
+        assert!(contract.on_recurring_executed(0.into(), recurring, 5000.into(), 0.into()));
+        // The change is refunded, then checked
+        let calls: Vec<String> = get_created_receipts()
+            .iter()
+            .flat_map(|receipt| &receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, vec!["ft_transfer", "on_recurring_change_refunded"]);
+        assert!(contract.get_recurring_payment(0.into()).is_none());
+    }
+
+    #[test]
+    fn recurring_change_refund_failed() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let mut recurring = contract.get_recurring_payment(0.into()).unwrap();
+        contract.revoke_recurring(0.into());
+        recurring.balance = 400.into();
+        recurring.expiry = 0.into();
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.on_recurring_change_refunded(0.into(), recurring));
+        // The payer can claim the change by revoking again, but it cannot be executed
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.balance.0, 400);
+        assert_eq!(recurring.expiry.0, 0);
+    }
+
+    // Executes the recurring payment with id 0, then settles it with a payee transfer of 400 succeeding or not,
+    // the fees of 100 being paid, and returns the change
+    fn settle_recurring_execution(
+        contract: &mut FungibleConversionProxy,
+        payee_paid: bool,
+    ) -> U128 {
+        testing_env!(get_context(
+            "executor.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.execute_recurring(0.into(), None);
+        let payee_result = if payee_paid {
+            PromiseResult::Successful(vec![])
+        } else {
+            PromiseResult::Failed
+        };
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![payee_result, PromiseResult::Successful(vec![])]
+        );
+        let args = PaymentArgs {
+            to: "dummy.payee.near".parse().unwrap(),
+            ..get_default_payment_args()
+        };
+        match contract.on_transfer_with_reference(
+            args,
+            "token.near".parse().unwrap(),
+            alice_account(),
+            5000.into(),
+            400.into(),
+            100.into(),
+            vec![],
+            None,
+            0.into(),
+            4500.into(),
+            Some(RecurringExecution {
+                id: 0.into(),
+                next_execution: 0.into(),
+            }),
+        ) {
+            PromiseOrValue::Value(change) => change,
+            PromiseOrValue::Promise(_) => panic!("Expected the change to be returned"),
+        }
+    }
+
+    #[test]
+    fn recurring_executed_with_payee_paid() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        let change = settle_recurring_execution(&mut contract, true);
+        assert_eq!(change.0, 4500);
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&change).unwrap()
+            )]
+        );
+        assert!(contract.on_recurring_executed(0.into(), recurring, 5000.into(), 0.into()));
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.balance.0, 4500);
+        assert_eq!(recurring.next_execution.0, 1000);
+    }
+
+    #[test]
+    fn recurring_executed_with_only_fees_paid() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        let change = settle_recurring_execution(&mut contract, false);
+        // The fees are paid, the payee amount is credited back and the period is restored
+        assert_eq!(change.0, 4900);
+        assert_eq!(
+            contract
+                .get_recurring_payment(0.into())
+                .unwrap()
+                .next_execution
+                .0,
+            0
+        );
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&change).unwrap()
+            )]
+        );
+        assert!(!contract.on_recurring_executed(0.into(), recurring, 5000.into(), 0.into()));
+        let recurring = contract.get_recurring_payment(0.into()).unwrap();
+        assert_eq!(recurring.balance.0, 4900);
+        assert_eq!(recurring.next_execution.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the payer can revoke the recurring payment"#)]
+    fn revoke_recurring_payment_not_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_recurring_payment();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS,
+            false
+        ));
+        contract.revoke_recurring(0.into());
+    }
+
+    #[test]
+    fn rate_callback_with_timespan_longer_than_chain() {
+        let rate = PriceEntry {
+            price: 100.into(),
+            decimals: 2,
+            last_update: 0,
+        };
+        testing_env!(
+            get_context(alice_account(), 0, Gas(300 * 10u64.pow(12)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let args = PaymentArgs {
+            max_rate_timespan: u64::MAX.into(),
+            ..get_default_payment_args()
+        };
+        let mut contract = FungibleConversionProxy::default();
+        // Any rate is recent enough, instead of underflowing
+        contract.rate_callback(
+            args,
+            "token.near".parse().unwrap(),
+            alice_account(),
+            2000000.into(),
+            2,
+            None,
+        );
+        assert!(!get_created_receipts().is_empty());
+    }
+
+    // Pays `amount` and `fee_amount` in USD with a `deposit` of a token having `token_decimals`, at the rate `price`
+    // with `decimals`, and returns the tokens transferred to the payee and to the fee address, or `None` on failure
+    fn rate_callback_transfers(
+        amount: Balance,
+        fee_amount: Balance,
+        token_decimals: u8,
+        price: u128,
+        decimals: u16,
+        deposit: Balance,
+    ) -> Option<(Balance, Balance)> {
+        let rate = PriceEntry {
+            price: price.into(),
+            decimals,
+            last_update: 0,
+        };
+        testing_env!(
+            get_context(alice_account(), 0, Gas(300 * 10u64.pow(12)), false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&rate).unwrap()
+            )]
+        );
+        let args = PaymentArgs {
+            amount: amount.into(),
+            fee_amount: fee_amount.into(),
+            ..get_default_payment_args()
+        };
+        let (to, fee_address) = (args.to.clone(), args.fee_address.clone());
+        let mut contract = FungibleConversionProxy::default();
+        catch_unwind(AssertUnwindSafe(|| {
+            contract.rate_callback(
+                args,
+                "token.near".parse().unwrap(),
+                alice_account(),
+                deposit.into(),
+                token_decimals,
+                None,
+            )
+        }))
+        .ok()?;
+        let transfers: Vec<(AccountId, Balance)> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "ft_transfer" => {
+                    let args: serde_json::Value = serde_json::from_slice(&args).unwrap();
+                    Some((
+                        args["receiver_id"].as_str().unwrap().parse().unwrap(),
+                        args["amount"].as_str().unwrap().parse().unwrap(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        let transferred_to = |account_id: &AccountId| -> Balance {
+            transfers
+                .iter()
+                .filter(|(receiver_id, _)| receiver_id == account_id)
+                .map(|(_, amount)| amount)
+                .sum()
+        };
+        Some((transferred_to(&to), transferred_to(&fee_address)))
+    }
+
+    // Realistic values, and any value
+    fn amounts() -> impl Strategy<Value = u128> {
+        prop_oneof![0..10u128.pow(12), any::<u128>()]
+    }
+
+    fn rates() -> impl Strategy<Value = u128> {
+        prop_oneof![1..10u128.pow(12), 1..=u128::MAX]
+    }
+
+    #[test]
+    fn token_amount_with_decimals() {
+        // 100.00 USD at 0.9999 USD per USDC.e
+        assert_eq!(to_token_amount(10000, 6, 999900, 6), 100010001);
+        assert_eq!(to_token_amount(10000, 0, 999900, 6), 100);
+        assert_eq!(
+            to_token_amount(10000, 24, 999900, 6),
+            100 * 10u128.pow(30) / 999900
+        );
+        // Fractions of cents
+        assert_eq!(to_token_amount(150, 0, 1, 0), 1);
+        assert_eq!(to_token_amount(u128::MAX, 24, 1, 6), u128::MAX);
+        assert_eq!(to_token_amount(10000, 6, 0, 6), u128::MAX);
+    }
+
+    proptest! {
+        #[test]
+        fn rate_callback_pays_within_deposit(
+            amount in amounts(),
+            fee_amount in amounts(),
+            token_decimals in 0u8..=24,
+            price in rates(),
+            decimals in 0u16..20,
+            deposit in amounts(),
+        ) {
+            let transfers =
+                rate_callback_transfers(amount, fee_amount, token_decimals, price, decimals, deposit);
+            // Either the payment fails, refunded by `ft_resolve_transfer`, or it is made in full within the deposit
+            if let Some((main_payment, fee_payment)) = transfers {
+                prop_assert!(main_payment.checked_add(fee_payment).unwrap() <= deposit);
+                prop_assert_eq!(
+                    main_payment,
+                    to_token_amount(amount, token_decimals, price, decimals.into())
+                );
+                prop_assert_eq!(
+                    fee_payment,
+                    to_token_amount(fee_amount, token_decimals, price, decimals.into())
+                );
+            }
+        }
     }
 }
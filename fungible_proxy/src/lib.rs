@@ -1,70 +1,804 @@
+// Payment methods and their callbacks take the full payment details, including in generated bindings
+#![allow(clippy::too_many_arguments)]
+
+use std::collections::{HashMap, HashSet};
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::log;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json::json;
-use near_sdk::{env, near_bindgen, serde_json, AccountId, Balance, Gas, Promise};
-near_sdk::setup_alloc!();
+use near_sdk::{
+    env, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseOrValue, PromiseResult,
+    PublicKey,
+};
+use payee_preferences::{PayeePreferences, PayeesPreferences};
+use payment_intents::{PayeeKeys, PaymentIntent, SignedIntent};
+use proxy_errors::{require, ProxyError};
+use proxy_math::{fee_from_bps_included, MAX_BPS};
+use proxy_storage::{
+    check_registrations, storage_contract, unregistered_accounts, StorageBalance, StorageFunds,
+};
 
-const NO_DEPOSIT: Balance = 0;
 const YOCTO_DEPOSIT: Balance = 1; // Fungible token transfers require a deposit of exactly 1 yoctoNEAR
-const MIN_GAS: Gas = 150_000_000_000_000;
-const BASIC_GAS: Gas = 10_000_000_000_000;
+const MIN_GAS: Gas = Gas(150_000_000_000_000);
+const BASIC_GAS: Gas = Gas(10_000_000_000_000);
+const MAX_FEE_RECIPIENTS: usize = 4;
+const ONE_SECOND: u128 = 1_000_000_000; // In nanoseconds
+
+/// Additional fee recipient, paid on top of `fee_address`
+///
+/// - `address`: `amount` of payment token will be paid to this address
+/// - `amount`: in payment token, deducted from the attached amount like `fee_amount`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FeeRecipient {
+    pub address: AccountId,
+    pub amount: U128,
+}
 
 /// Helper struct containing arguments supplied by the caller
 ///
 /// - `amount`: in `currency` with 2 decimals (eg. 1000 is 10.00)
+/// - `claim_after`: if set, the payment is scheduled instead: the payee can claim it with `release_escrow` after
+///   this timestamp in nanoseconds, the payer can cancel it with `refund_escrow` before then
+/// - `escrow_timeout`: if set, the payment is locked in escrow instead (see `release_escrow` and `refund_escrow`),
+///   the payer being able to get it refunded after this duration in nanoseconds
 /// - `fee_address`: `fee_amount` in `currency` of payment token will be paid to this address
 /// - `fee_amount`: in `currency`
+/// - `fee_bps`: optional fee in basis points of the amount paid to `to`, replacing `fee_amount` (which must then be 0)
+/// - `fees`: optional additional fee recipients, each paid its `amount` of payment token
+/// - `intent`: if set, the payment must match the intent signed by the payee with its registered key
+///   (see `set_payee_key`), for this `payment_reference`, `to`, and amount paid to `to` in the attached token
+///   (the token being the intent `currency` too). Exclusive with `swap`.
 /// - `payment_reference`: used for indexing and matching the payment with a request
+/// - `register_accounts`: if true, `to` and fee recipients not registered with the paid token are registered first,
+///   the storage deposit being paid from the payer's storage fund (see `deposit_storage_fund`)
+/// - `refund_to`: if set, receives the change and any refund (failed transfers, escrow refund, stream cancellation)
+///   instead of the payer
+/// - `stream`: if set, the amount paid to `to` is streamed instead (see `withdraw_stream` and `cancel_stream`),
+///   fees being paid upfront
+/// - `swap`: if set, the attached amount is swapped first, and the payment made in the swapped token
 /// - `to`: `amount` in `currency` of payment token will be paid to this address
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PaymentArgs {
-    pub fee_address: ValidAccountId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim_after: Option<U64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escrow_timeout: Option<U64>,
+    pub fee_address: AccountId,
     pub fee_amount: U128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee_bps: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fees: Vec<FeeRecipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intent: Option<SignedIntent>,
     pub payment_reference: String,
-    pub to: ValidAccountId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub register_accounts: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_to: Option<AccountId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<SwapArgs>,
+    pub to: AccountId,
+}
+
+/// Stream requested in the `PaymentArgs`, funded upfront with the attached amount
+///
+/// - `rate`: amount of payment token vested per second
+/// - `start`: timestamp in nanoseconds at which the stream starts vesting
+/// - `end`: timestamp in nanoseconds at which the stream is fully vested
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StreamArgs {
+    pub rate: U128,
+    pub start: U64,
+    pub end: U64,
+}
+
+/// Swap requested in the `PaymentArgs`, through a Ref-style exchange
+///
+/// - `exchange_id`: the exchange contract, allowed by the owner (see `add_exchange`) and on which the proxy should be registered
+/// - `pool_id`: the exchange pool swapping the payment token to `token_out`
+/// - `token_out`: the token paid to `to` and fee recipients, in which `fee_amount` and `fees` are denominated
+/// - `min_amount_out`: minimum amount of `token_out` received from the swap, or the payment is refunded
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SwapArgs {
+    pub exchange_id: AccountId,
+    pub pool_id: u64,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+impl PaymentArgs {
+    /// Sum of `fee_amount` and all additional `fees`, failing if it overflows, as it exceeds any amount
+    pub fn total_fee_amount(&self) -> u128 {
+        self.fees
+            .iter()
+            .try_fold(self.fee_amount.0, |total, fee| {
+                total.checked_add(fee.amount.0)
+            })
+            .unwrap_or_else(|| ProxyError::AmountSmallerThanFees.panic())
+    }
+
+    /// Account receiving the change and refunds: `refund_to` if set, else the `payer`
+    fn refund_account(&self, payer: &AccountId) -> AccountId {
+        self.refund_to
+            .as_ref()
+            .map_or(payer.clone(), |refund_to| refund_to.clone())
+    }
+
+    /// Token paid to `to` and fee recipients, when `token_address` is attached
+    fn paid_token(&self, token_address: &AccountId) -> AccountId {
+        self.swap
+            .as_ref()
+            .map_or(token_address.clone(), |swap| swap.token_out.clone())
+    }
+}
+
+impl From<String> for PaymentArgs {
+    fn from(msg: String) -> Self {
+        serde_json::from_str(&msg).unwrap_or_else(|_| ProxyError::InvalidMsg.panic())
+    }
+}
+
+impl From<PaymentArgs> for String {
+    fn from(args: PaymentArgs) -> Self {
+        serde_json::to_string(&args).unwrap()
+    }
+}
+
+/// Computes the fee for `fee_bps` basis points of the amount paid to `to`, when `amount` includes both.
+/// The result is rounded down, so that `fee <= (amount - fee) * fee_bps / 10000`.
+fn fee_amount_from_bps(amount: u128, fee_bps: u16) -> u128 {
+    fee_from_bps_included(amount, fee_bps).unwrap_or_else(|| {
+        ProxyError::FeeBpsTooHigh {
+            supplied: fee_bps,
+            max: MAX_BPS,
+        }
+        .panic()
+    })
+}
+
+/// Minimum and maximum protocol fee for a token, in that token's smallest unit
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct ProtocolFeeCaps {
+    pub min_amount: U128,
+    pub max_amount: U128,
+}
+
+/// Protocol fee schedule, as configured by the owner
+///
+/// - `bps`: protocol fee in basis points of the amount paid to `to`
+/// - `treasury_id`: receives the protocol fee (no protocol fee is collected if none)
+/// - `caps`: optional minimum and maximum protocol fee, per token address
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFeeSchedule {
+    pub bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub caps: HashMap<AccountId, ProtocolFeeCaps>,
+}
+
+/// Protocol fee collected on a payment, `amount` being in payment token
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolFee {
+    pub treasury_id: AccountId,
+    pub amount: U128,
+}
+
+/// Transfer of `amount` of payment token to `receiver_id`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+/// Payment locked in the contract under its payment reference
+///
+/// - `payer`: can release the payment to the payee, or get it refunded after `refund_after`
+/// - `refund_to`: if set, receives refunds instead of the payer
+/// - `token_address`: the payment token
+/// - `to`: the payee, who can refund the payer at any time
+/// - `transfers`: transfers made on release, to the payee then to fee recipients
+/// - `refund_after`: timestamp in nanoseconds after which the payer can get the payment refunded
+/// - `claim_after`: for scheduled payments, timestamp in nanoseconds after which the payee can claim (release) the payment,
+///   the payer being able to cancel (refund) it only before then
+/// - `payment_log`: standard payment log, emitted on release
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Escrow {
+    pub payer: AccountId,
+    pub token_address: AccountId,
+    pub to: AccountId,
+    pub transfers: Vec<Transfer>,
+    pub refund_after: U64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim_after: Option<U64>,
+    pub payment_log: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_to: Option<AccountId>,
+}
+
+impl Escrow {
+    /// Total amount locked, in payment token
+    pub fn total_amount(&self) -> u128 {
+        self.transfers
+            .iter()
+            .map(|transfer| transfer.amount.0)
+            .sum()
+    }
+
+    /// Account receiving refunds: `refund_to` if set, else the payer
+    pub fn refund_account(&self) -> &AccountId {
+        self.refund_to.as_ref().unwrap_or(&self.payer)
+    }
+
+    /// The payer can release the payment at any time, the payee after `claim_after` for scheduled payments
+    pub fn can_release(&self, caller: &AccountId, timestamp: u64) -> bool {
+        let claimable = self
+            .claim_after
+            .as_ref()
+            .is_some_and(|claim_after| timestamp >= claim_after.0);
+        caller == &self.payer || (caller == &self.to && claimable)
+    }
+
+    /// The payee can refund the payment at any time, the payer after `refund_after`,
+    /// or before `claim_after` for scheduled payments
+    pub fn can_refund(&self, caller: &AccountId, timestamp: u64) -> bool {
+        let refundable = match &self.claim_after {
+            Some(claim_after) => timestamp < claim_after.0,
+            None => timestamp >= self.refund_after.0,
+        };
+        caller == &self.to || (caller == &self.payer && refundable)
+    }
+}
+
+/// Payment streamed to the payee under its payment reference, vesting linearly between `start` and `end`
+///
+/// - `payer`: can cancel the stream, getting the unvested amount back
+/// - `refund_to`: if set, receives the unvested amount on cancellation instead of the payer
+/// - `token_address`: the payment token
+/// - `to`: the payee, who can withdraw the vested amount at any time
+/// - `fee_address`: logged with each withdrawal, fees being paid when the stream is created
+/// - `rate`: amount of payment token vested per second
+/// - `start`, `end`: timestamps in nanoseconds
+/// - `withdrawn`: amount already withdrawn by the payee
+/// - `pending_refund`: unvested amount whose refund failed on cancellation, refunded by the next `cancel_stream`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Stream {
+    pub payer: AccountId,
+    pub token_address: AccountId,
+    pub to: AccountId,
+    pub fee_address: AccountId,
+    pub rate: U128,
+    pub start: U64,
+    pub end: U64,
+    pub withdrawn: U128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund_to: Option<AccountId>,
+    pub pending_refund: U128,
+}
+
+impl Stream {
+    /// Total amount streamed, in payment token
+    pub fn total_amount(&self) -> u128 {
+        self.vested_amount(self.end.0)
+    }
+
+    /// Account receiving the unvested amount on cancellation: `refund_to` if set, else the payer
+    pub fn refund_account(&self) -> &AccountId {
+        self.refund_to.as_ref().unwrap_or(&self.payer)
+    }
+
+    /// Amount vested at `timestamp`, rounded down
+    pub fn vested_amount(&self, timestamp: u64) -> u128 {
+        let elapsed = timestamp.min(self.end.0).saturating_sub(self.start.0) as u128;
+        // Split to avoid overflows with high rates
+        self.rate.0 / ONE_SECOND * elapsed + self.rate.0 % ONE_SECOND * elapsed / ONE_SECOND
+    }
+
+    /// Amount vested at `timestamp` and not withdrawn yet
+    pub fn withdrawable_amount(&self, timestamp: u64) -> u128 {
+        self.vested_amount(timestamp) - self.withdrawn.0
+    }
+}
+
+/// JSON arguments of a `ft_transfer` call to the payment token
+fn ft_transfer_args(receiver_id: &AccountId, amount: u128) -> Vec<u8> {
+    json!({ "receiver_id": receiver_id, "amount": U128(amount), "memo": None::<String> })
+        .to_string()
+        .into_bytes()
+}
+
+/// Makes each of the `transfers` of `token_address` in its own promise, joined with `and` so that the result of each
+/// transfer can be checked (see `Settlement`), skipping empty ones as some tokens revert when calling `ft_transfer` with 0
+fn ft_transfers_promise(token_address: &AccountId, transfers: &[Transfer]) -> Promise {
+    transfers
+        .iter()
+        .filter(|transfer| transfer.amount.0 > 0)
+        .map(|transfer| {
+            Promise::new(token_address.clone()).function_call(
+                "ft_transfer".into(),
+                ft_transfer_args(&transfer.receiver_id, transfer.amount.0),
+                YOCTO_DEPOSIT,
+                BASIC_GAS * 2,
+            )
+        })
+        .reduce(|promise, transfer| promise.and(transfer))
+        .unwrap_or_else(|| Promise::new(token_address.clone()))
+}
+
+/// Returns `amount` of `token_address` unused by a payment: transferred to `refund_to` if set (see
+/// `on_unused_returned`), else returned for `ft_resolve_transfer` on the token contract to refund the sender
+fn return_unused(
+    token_address: &AccountId,
+    refund_to: Option<AccountId>,
+    amount: u128,
+) -> PromiseOrValue<U128> {
+    match refund_to {
+        Some(refund_to) if amount > 0 => ft_contract::ext(token_address.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS * 2)
+            .ft_transfer(refund_to.clone(), amount.into(), None)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS)
+                    .on_unused_returned(token_address.clone(), refund_to, amount.into()),
+            )
+            .into(),
+        _ => PromiseOrValue::Value(amount.into()),
+    }
+}
+
+/// Gas needed by `return_unused` on top of the calling callback
+fn return_unused_gas(args: &PaymentArgs) -> Gas {
+    if args.refund_to.is_some() {
+        BASIC_GAS * 3
+    } else {
+        Gas(0)
+    }
+}
+
+/// Outcome of `transfers` made with `ft_transfers_promise`: which of them executed, and which failed and are
+/// still held by the proxy
+struct Settlement {
+    transfers: Vec<Transfer>,
+    results: Vec<bool>,
+}
+
+impl Settlement {
+    /// Reads the result of each of the `transfers` from the promise results of the current callback,
+    /// empty transfers being considered executed
+    fn from_promise_results(transfers: Vec<Transfer>) -> Self {
+        let mut results = (0..env::promise_results_count())
+            .map(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)));
+        let results = transfers
+            .iter()
+            .map(|transfer| transfer.amount.0 == 0 || results.next().unwrap_or(false))
+            .collect();
+        Self { transfers, results }
+    }
+
+    /// Whether all the transfers executed
+    fn is_complete(&self) -> bool {
+        self.results.iter().all(|success| *success)
+    }
+
+    /// Whether the `i`-th transfer executed
+    fn executed(&self, i: usize) -> bool {
+        self.results[i]
+    }
+
+    /// The transfers that failed, in their original order
+    fn failed_transfers(&self) -> Vec<Transfer> {
+        self.transfers
+            .iter()
+            .zip(&self.results)
+            .filter(|(_, success)| !**success)
+            .map(|(transfer, _)| transfer.clone())
+            .collect()
+    }
+
+    /// Total amount of the transfers that failed, which the proxy still holds
+    fn failed_amount(&self) -> u128 {
+        self.failed_transfers()
+            .iter()
+            .map(|transfer| transfer.amount.0)
+            .sum()
+    }
+
+    /// Logs a `transfer_succeeded` or `transfer_failed` event for each transfer
+    fn log(&self, payment_reference: &str, token_address: &AccountId) {
+        for (transfer, success) in self.transfers.iter().zip(&self.results) {
+            let event = json!({
+                "event": if *success { "transfer_succeeded" } else { "transfer_failed" },
+                "payment_reference": payment_reference,
+                "token_address": token_address,
+                "receiver_id": transfer.receiver_id,
+                "amount": transfer.amount,
+            });
+            env::log_str(&event.to_string());
+        }
+    }
+
+    /// Comma-separated receivers of the failed transfers
+    fn failed_receivers(&self) -> String {
+        self.failed_transfers()
+            .iter()
+            .map(|transfer| transfer.receiver_id.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    }
+}
+
+/// Transfers paying `main_amount` to the payee, then the fee recipients and treasury, in that order
+fn payment_legs(
+    args: &PaymentArgs,
+    main_amount: u128,
+    protocol_fee: Option<&ProtocolFee>,
+) -> Vec<Transfer> {
+    let mut transfers = vec![
+        Transfer {
+            receiver_id: args.to.clone(),
+            amount: main_amount.into(),
+        },
+        Transfer {
+            receiver_id: args.fee_address.clone(),
+            amount: args.fee_amount,
+        },
+    ];
+    transfers.extend(args.fees.iter().map(|fee| Transfer {
+        receiver_id: fee.address.clone(),
+        amount: fee.amount,
+    }));
+    if let Some(protocol_fee) = protocol_fee {
+        transfers.push(Transfer {
+            receiver_id: protocol_fee.treasury_id.clone(),
+            amount: protocol_fee.amount,
+        });
+    }
+    transfers
+}
+
+/// `args` and `protocol_fee` without the fees whose transfer failed, given the settlement of the `payment_legs`
+fn paid_fees(
+    mut args: PaymentArgs,
+    protocol_fee: Option<ProtocolFee>,
+    settlement: &Settlement,
+) -> (PaymentArgs, Option<ProtocolFee>) {
+    if !settlement.executed(1) {
+        args.fee_amount = 0.into();
+    }
+    let mut fees_results = settlement.results[2..].iter();
+    args.fees.retain(|_| *fees_results.next().unwrap());
+    let protocol_fee = protocol_fee.filter(|_| *fees_results.next().unwrap());
+    (args, protocol_fee)
 }
 
-impl Into<PaymentArgs> for String {
-    fn into(self) -> PaymentArgs {
-        serde_json::from_str(&self).expect("Incorrect msg format")
+/// Standard payment log, used for indexing and payment detection
+fn payment_log(
+    args: &PaymentArgs,
+    token_address: &AccountId,
+    amount: U128,
+    protocol_fee: Option<&ProtocolFee>,
+) -> String {
+    let mut payment_log = json!({
+        "amount": amount,
+        "token_address": token_address,
+        "fee_address": args.fee_address,
+        "fee_amount": args.fee_amount,
+        "payment_reference": args.payment_reference,
+        "to": args.to,
+    });
+    if !args.fees.is_empty() {
+        payment_log["fees"] = json!(args.fees);
     }
+    if let Some(protocol_fee) = protocol_fee {
+        payment_log["protocol_fee"] = json!(protocol_fee);
+    }
+    payment_log.to_string()
 }
 
-impl Into<String> for PaymentArgs {
-    fn into(self) -> String {
-        serde_json::to_string(&self).unwrap()
+/// Logs the `stream_created` event
+fn log_stream_created(payment_reference: &str, stream: &Stream) {
+    let mut event = json!({
+        "event": "stream_created",
+        "payment_reference": payment_reference,
+        "payer": stream.payer,
+        "to": stream.to,
+        "token_address": stream.token_address,
+        "amount": U128::from(stream.total_amount()),
+        "rate": stream.rate,
+        "start": stream.start,
+        "end": stream.end,
+    });
+    if let Some(refund_to) = &stream.refund_to {
+        event["refund_to"] = json!(refund_to);
     }
+    env::log_str(&event.to_string());
+}
+
+/// Standard payment log for a stream withdrawal, fees having been paid when the stream was created
+fn stream_payment_log(payment_reference: &str, stream: &Stream, amount: U128) -> String {
+    json!({
+        "amount": amount,
+        "token_address": stream.token_address,
+        "fee_address": stream.fee_address,
+        "fee_amount": U128::from(0),
+        "payment_reference": payment_reference,
+        "to": stream.to,
+    })
+    .to_string()
+}
+
+/// Gas needed by `on_swap_withdrawn` to make `transfers_count` transfers, then refund the payer if they fail
+fn swap_payment_gas(transfers_count: usize) -> Gas {
+    BASIC_GAS * 2 * transfers_count as u64 + BASIC_GAS * 4
+}
+
+/// Gas needed by `on_swapped` to withdraw the swapped tokens from the exchange, check the proxy balance, then pay
+fn swap_withdraw_gas(transfers_count: usize) -> Gas {
+    BASIC_GAS * 5 + swap_payment_gas(transfers_count)
+}
+
+/// Gas needed by `on_swap_deposited` to swap and check the proxy balance, then withdraw and pay
+fn swap_gas(transfers_count: usize) -> Gas {
+    BASIC_GAS * 4 + swap_withdraw_gas(transfers_count)
+}
+
+/// Gas needed to check the registration of `accounts_count` accounts with the paid token, and register them
+fn registration_gas(accounts_count: usize) -> Gas {
+    BASIC_GAS * 2 * accounts_count as u64 + BASIC_GAS * 3
 }
 
 // Interface of fungible tokens
 #[near_sdk::ext_contract(ft_contract)]
-trait FungibleTokenContract {
-    fn ft_transfer(receiver_id: String, amount: String, memo: Option<String>);
+pub trait FungibleTokenContract {
+    fn ft_transfer(receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String);
+    fn ft_balance_of(account_id: AccountId) -> U128;
+}
+
+/// Swap action of Ref-style exchanges
+#[derive(Serialize, Deserialize)]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+// Interface of the wrapped NEAR contract
+#[near_sdk::ext_contract(wrap_contract)]
+pub trait WrapNearContract {
+    fn near_deposit();
+    fn near_withdraw(amount: U128);
+}
+
+// Interface of Ref-style exchanges
+#[near_sdk::ext_contract(exchange_contract)]
+pub trait ExchangeContract {
+    fn swap(actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128;
+    fn withdraw(token_id: AccountId, amount: U128, unregister: Option<bool>);
 }
 
 // Callback methods
 #[near_sdk::ext_contract(ext_self)]
-trait ExtSelfRequestProxy {
+pub trait ExtSelfRequestProxy {
     fn on_transfer_with_reference(
         &self,
         args: PaymentArgs,
         token_address: AccountId,
         payer: AccountId,
         amount: U128,
-    ) -> String;
-}
+        protocol_fee: Option<ProtocolFee>,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_unused_returned(
+        &self,
+        token_address: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128;
+
+    fn on_escrow_transfer(
+        &mut self,
+        payment_reference: String,
+        escrow: Escrow,
+        released: bool,
+    ) -> bool;
+
+    fn on_swap_deposited(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_swapped(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_swap_refunded(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_swap_withdrawn(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        amount_out: U128,
+        balance_before: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_swap_transfer(
+        &self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        amount_out: U128,
+        main_amount: U128,
+        protocol_fee: Option<ProtocolFee>,
+    ) -> U128;
+
+    fn on_exchange_claim_checked(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_exchange_claim_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        balance_before: U128,
+    ) -> U128;
+
+    fn on_near_wrapped(
+        &mut self,
+        args: PaymentArgs,
+        wrap_account_id: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_wrapped_transfer(
+        &mut self,
+        wrap_account_id: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_near_unwrapped(
+        &mut self,
+        wrap_account_id: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128;
+
+    fn on_storage_balances(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        return_change: bool,
+        accounts: Vec<AccountId>,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_accounts_registered(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        return_change: bool,
+        accounts: Vec<AccountId>,
+        storage_cost: U128,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_stream_created(
+        &mut self,
+        payment_reference: String,
+        payer: AccountId,
+        fee_transfers: Vec<Transfer>,
+        change: U128,
+        change_to: Option<AccountId>,
+    ) -> PromiseOrValue<U128>;
+
+    fn on_stream_withdrawal(
+        &mut self,
+        payment_reference: String,
+        stream: Stream,
+        amount: U128,
+    ) -> bool;
 
-trait FungibleTokenReceiver {
-    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: String, msg: String) -> Promise;
+    fn on_stream_cancelled(
+        &mut self,
+        payment_reference: String,
+        stream: Stream,
+        timestamp: U64,
+        amount: U128,
+        refund: U128,
+    ) -> bool;
 }
 
 ///
 /// This contract
+/// - owner_id: only the owner can edit the contract state values below (default = deployer)
+/// - protocol_fee_bps, treasury_id, protocol_fee_caps: protocol fee schedule, set by the owner
+/// - escrows: payments locked until released or refunded, by payer and payment reference
+/// - streams: payments vesting over time, by payer and payment reference
+/// - wrap_account_id: wrapped NEAR contract, for payments in NEAR (see `wrap_and_transfer_with_reference`)
+/// - exchanges: exchanges allowed for swaps, set by the owner
+/// - storage_funds: NEAR deposited by payers to register payment recipients with tokens, by payer
+/// - registration_tokens: tokens with which payment recipients can be registered from storage funds, set by the owner
+/// - payee_keys: keys registered by payees to sign payment intents, by payee
+/// - payee_preferences: tokens and currencies accepted by payees, by payee
+/// - exchange_balances: swap tokens the proxy could not withdraw from an exchange, claimable with
+///   `withdraw_from_exchange`, by payer (or `refund_to`), exchange and token
 #[near_bindgen]
-#[derive(Default, BorshDeserialize, BorshSerialize)]
-pub struct FungibleProxy {}
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FungibleProxy {
+    pub owner_id: AccountId,
+    pub protocol_fee_bps: u16,
+    pub treasury_id: Option<AccountId>,
+    pub protocol_fee_caps: HashMap<AccountId, ProtocolFeeCaps>,
+    pub escrows: LookupMap<(AccountId, String), Escrow>,
+    pub streams: LookupMap<(AccountId, String), Stream>,
+    pub wrap_account_id: Option<AccountId>,
+    pub exchanges: HashSet<AccountId>,
+    pub storage_funds: StorageFunds,
+    pub registration_tokens: HashSet<AccountId>,
+    pub payee_keys: PayeeKeys,
+    pub payee_preferences: PayeesPreferences,
+    pub exchange_balances: LookupMap<(AccountId, AccountId, AccountId), Balance>,
+}
+
+impl Default for FungibleProxy {
+    fn default() -> Self {
+        // Owner of an uninitialized contract: "system" never calls contract methods
+        Self {
+            owner_id: "system".parse().unwrap(),
+            protocol_fee_bps: 0,
+            treasury_id: None,
+            protocol_fee_caps: HashMap::new(),
+            escrows: LookupMap::new(b"e".to_vec()),
+            streams: LookupMap::new(b"s".to_vec()),
+            wrap_account_id: None,
+            exchanges: HashSet::new(),
+            storage_funds: StorageFunds::new(b"f".to_vec()),
+            registration_tokens: HashSet::new(),
+            payee_keys: PayeeKeys::new(b"k".to_vec()),
+            payee_preferences: PayeesPreferences::new(b"p".to_vec()),
+            exchange_balances: LookupMap::new(b"x".to_vec()),
+        }
+    }
+}
+
+/// State of contracts deployed before the owner, escrows, streams, NEAR and swap payments, storage funds, payment
+/// intents and payee preferences: no state at all (see `migrate`)
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldFungibleProxy {}
 
 #[near_bindgen]
 impl FungibleTokenReceiver for FungibleProxy {
@@ -73,16 +807,18 @@ impl FungibleTokenReceiver for FungibleProxy {
     /// `msg` should be a string in JSON format containing all the fields in `PaymentArgs`.
     /// Eg. msg = {"payment_reference":"abc7c8bb1234fd12","to":"dummy.payee.near","fee_address":"fee.requestfinance.near","fee_amount":"200"}
     ///
+    /// Returns the amount unused by the payment, that the token contract refunds to the sender.
+    ///
     /// For more information on the fungible token standard, see https://nomicon.io/Standards/Tokens/FungibleToken/Core
     ///
-    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: String, msg: String) -> Promise {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
         let token_address = env::predecessor_account_id();
-        self.transfer_with_reference(
-            msg.into(),
-            token_address,
-            sender_id,
-            U128::from(amount.parse::<u128>().unwrap()),
-        )
+        self.transfer_with_reference(msg.into(), token_address, sender_id.clone(), amount, false)
     }
 }
 
@@ -99,87 +835,255 @@ impl FungibleProxy {
     /// See https://nomicon.io/Standards/Tokens/FungibleToken/Core for more information on how NEAR handles
     /// sending fungible tokens to be used by a contract function.
     ///
+    /// With `return_change`, the change is returned to the caller even if `refund_to` is set, `refund_to` only
+    /// receiving the later refunds of escrows and streams (see `wrap_and_transfer_with_reference`).
+    ///
     #[private]
     fn transfer_with_reference(
         &mut self,
-        args: PaymentArgs,
+        mut args: PaymentArgs,
         token_address: AccountId,
         payer: AccountId,
         amount: U128,
-    ) -> Promise {
-        assert!(
-            MIN_GAS <= env::prepaid_gas(),
-            "Not enough attached Gas to call this method (Supplied: {}. Demand: {})",
-            env::prepaid_gas(),
-            MIN_GAS
+        return_change: bool,
+    ) -> PromiseOrValue<U128> {
+        require(
+            args.fees.len() <= MAX_FEE_RECIPIENTS,
+            ProxyError::TooManyFeeRecipients {
+                supplied: args.fees.len(),
+                max: MAX_FEE_RECIPIENTS,
+            },
+        );
+        // Each additional fee recipient and the protocol fee need their own `ft_transfer`
+        let protocol_fee_count = (self.treasury_id.is_some() && self.protocol_fee_bps > 0) as usize;
+        let transfers_count = args.fees.len() + protocol_fee_count;
+        let mut min_gas =
+            MIN_GAS + BASIC_GAS * 2 * transfers_count as u64 + return_unused_gas(&args);
+        if args.register_accounts == Some(true) {
+            min_gas += registration_gas(self.payment_recipients(&args).len());
+        }
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
         );
 
         let reference_vec: Vec<u8> = hex::decode(args.payment_reference.replace("0x", ""))
-            .expect("Payment reference value error");
-        assert_eq!(reference_vec.len(), 8, "Incorrect payment reference length");
-        assert!(
-            args.fee_amount.0 <= amount.0,
-            "amount smaller than fee_amount"
-        );
-        let main_amount = amount.0 - args.fee_amount.0;
-        let main_transfer_args =
-            json!({ "receiver_id": args.to.to_string(), "amount":main_amount.to_string(), "memo": None::<String> })
-                .to_string()
-                .into_bytes();
-
-        let fee_transfer_args =
-            json!({ "receiver_id": args.fee_address.to_string(), "amount":args.fee_amount.0.to_string(), "memo": None::<String> })
-            .to_string()
-            .into_bytes();
-
-        // Some tokens revert when calling `ft_transfer` with 0
-        let payment_promise = if main_amount > 0 && args.fee_amount.0 > 0 {
-            // Main case: amount and fee
-            Promise::new(token_address.to_string())
-                .function_call(
-                    "ft_transfer".into(),
-                    main_transfer_args,
-                    YOCTO_DEPOSIT,
-                    BASIC_GAS * 2,
+            .unwrap_or_else(|_| ProxyError::InvalidReference.panic());
+        require(reference_vec.len() == 8, ProxyError::InvalidReferenceLength);
+        // Rejected payments are refunded by `ft_resolve_transfer`
+        self.payee_preferences.require_accepted(
+            &args.to,
+            args.paid_token(&token_address).as_str(),
+            None,
+        );
+        if let Some(intent) = &args.intent {
+            // The swapped amount is unknown until the swap
+            require(args.swap.is_none(), ProxyError::SwapExclusive);
+            let (_, main_amount, _) =
+                self.payment_transfers(&mut args.clone(), &token_address, amount.0);
+            let payment_intent = PaymentIntent {
+                payment_reference: reference_vec,
+                to: args.to.clone(),
+                amount: main_amount,
+                currency: token_address.to_string(),
+                token: token_address.to_string(),
+                expiry: intent.expiry.0,
+            };
+            self.payee_keys.verify(&payment_intent, intent);
+        } else {
+            self.payee_keys.require_no_key(&args.to);
+        }
+        require(
+            args.escrow_timeout.is_none() || args.claim_after.is_none(),
+            ProxyError::EscrowTimeoutAndClaimAfter,
+        );
+        if let Some(escrow_timeout) = args.escrow_timeout {
+            require(
+                env::block_timestamp()
+                    .checked_add(escrow_timeout.0)
+                    .is_some(),
+                ProxyError::InvalidEscrowTimeout,
+            );
+        }
+        if args.escrow_timeout.is_some() || args.claim_after.is_some() {
+            require(
+                !self
+                    .escrows
+                    .contains_key(&(payer.clone(), args.payment_reference.clone())),
+                ProxyError::EscrowExists,
+            );
+        }
+        if let Some(stream) = &args.stream {
+            require(
+                args.escrow_timeout.is_none() && args.claim_after.is_none(),
+                ProxyError::StreamExclusive,
+            );
+            require(
+                stream.start.0 < stream.end.0,
+                ProxyError::InvalidStreamPeriod,
+            );
+            require(
+                !self
+                    .streams
+                    .contains_key(&(payer.clone(), args.payment_reference.clone())),
+                ProxyError::StreamExists,
+            );
+        }
+        if args.register_accounts == Some(true) {
+            // Storage funds are only spent for tokens that can be trusted with the payer and storage cost
+            for token in [&token_address, &args.paid_token(&token_address)] {
+                require(
+                    self.registration_tokens.contains(token),
+                    ProxyError::RegistrationNotAllowed {
+                        token: token.clone(),
+                    },
+                );
+            }
+            // The payment is made by `on_storage_balances` or `on_accounts_registered`
+            return self
+                .register_accounts_then_transfer(args, token_address, payer, amount, return_change)
+                .into();
+        }
+        // Escrows and streams keep `refund_to` for their later refunds
+        let change_to = args.refund_to.clone().filter(|_| !return_change);
+        if args.escrow_timeout.is_none() && args.claim_after.is_none() && args.stream.is_none() {
+            args.refund_to = change_to.clone();
+        }
+        if let Some(swap) = args.swap.clone() {
+            require(
+                args.escrow_timeout.is_none()
+                    && args.claim_after.is_none()
+                    && args.stream.is_none(),
+                ProxyError::SwapExclusive,
+            );
+            require(
+                self.exchanges.contains(&swap.exchange_id),
+                ProxyError::ExchangeNotAllowed {
+                    exchange_id: swap.exchange_id.clone(),
+                },
+            );
+            let transfers_count = self.transfers_count(&args);
+            let min_gas = BASIC_GAS * 6 + swap_gas(transfers_count);
+            require(
+                min_gas <= env::prepaid_gas(),
+                ProxyError::NotEnoughGas {
+                    supplied: env::prepaid_gas(),
+                    demand: min_gas,
+                },
+            );
+            // Fees are paid in `token_out`: they are checked against the minimum swapped amount
+            self.payment_transfers(&mut args.clone(), &swap.token_out, swap.min_amount_out.0);
+            // The attached amount is deposited on the exchange, then swapped
+            return ft_contract::ext(token_address.clone())
+                .with_attached_deposit(YOCTO_DEPOSIT)
+                .with_static_gas(BASIC_GAS * 4)
+                .ft_transfer_call(swap.exchange_id.clone(), amount, None, "".into())
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(swap_gas(transfers_count))
+                        .on_swap_deposited(args, token_address, payer, amount),
                 )
-                .function_call(
-                    "ft_transfer".into(),
-                    fee_transfer_args,
-                    YOCTO_DEPOSIT,
-                    BASIC_GAS * 2,
+                .into();
+        }
+
+        let (transfers, main_amount, protocol_fee) =
+            self.payment_transfers(&mut args, &token_address, amount.0);
+
+        if let Some(stream) = &args.stream {
+            let stream = Stream {
+                payer,
+                token_address: token_address.clone(),
+                to: args.to.clone(),
+                fee_address: args.fee_address.clone(),
+                rate: stream.rate,
+                start: stream.start,
+                end: stream.end,
+                withdrawn: 0.into(),
+                refund_to: args.refund_to.clone(),
+                pending_refund: 0.into(),
+            };
+            let stream_amount = stream.total_amount();
+            require(stream_amount > 0, ProxyError::EmptyStream);
+            require(
+                stream_amount <= main_amount,
+                ProxyError::AmountSmallerThanStream,
+            );
+            // Any excess is returned to the payer by `ft_resolve_transfer`, or to `refund_to`
+            let change = main_amount - stream_amount;
+            let key = (stream.payer.clone(), args.payment_reference.clone());
+            self.streams.insert(&key, &stream);
+            if amount.0 == main_amount {
+                log_stream_created(&args.payment_reference, &stream);
+                return return_unused(&token_address, change_to, change);
+            }
+            // Fees are paid upfront
+            return ft_transfers_promise(&token_address, &transfers[1..])
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(BASIC_GAS + return_unused_gas(&args))
+                        .on_stream_created(
+                            args.payment_reference.clone(),
+                            stream.payer,
+                            transfers[1..].to_vec(),
+                            change.into(),
+                            change_to,
+                        ),
                 )
-        } else if main_amount > 0 {
-            // No fee
-            Promise::new(token_address.to_string()).function_call(
-                "ft_transfer".into(),
-                main_transfer_args,
-                YOCTO_DEPOSIT,
-                BASIC_GAS * 2,
-            )
-        } else if args.fee_amount.0 > 0 {
-            // Only fee payment
-            Promise::new(token_address.to_string()).function_call(
-                "ft_transfer".into(),
-                fee_transfer_args,
-                YOCTO_DEPOSIT,
-                BASIC_GAS * 2,
-            )
-        } else {
-            // No payment
-            Promise::new(token_address.to_string())
+                .into();
+        }
+
+        let refund_after = match (&args.claim_after, &args.escrow_timeout) {
+            (Some(claim_after), _) => Some(*claim_after),
+            (None, Some(escrow_timeout)) => Some(
+                env::block_timestamp()
+                    .checked_add(escrow_timeout.0)
+                    .unwrap_or_else(|| ProxyError::InvalidEscrowTimeout.panic())
+                    .into(),
+            ),
+            (None, None) => None,
         };
+        if let Some(refund_after) = refund_after {
+            let escrow = Escrow {
+                payer,
+                token_address: token_address.clone(),
+                to: args.to.clone(),
+                transfers,
+                refund_after,
+                claim_after: args.claim_after,
+                payment_log: payment_log(
+                    &args,
+                    &token_address,
+                    main_amount.into(),
+                    protocol_fee.as_ref(),
+                ),
+                refund_to: args.refund_to.clone(),
+            };
+            self.lock_escrow(args.payment_reference, escrow);
+            // The full amount is used, nothing to return to `ft_resolve_transfer`
+            return PromiseOrValue::Value(0.into());
+        }
 
-        payment_promise.then(ext_self::on_transfer_with_reference(
-            args,
-            token_address,
-            payer,
-            main_amount.into(),
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            BASIC_GAS,
-        ))
+        let callback_gas = BASIC_GAS + return_unused_gas(&args);
+        ft_transfers_promise(&token_address, &transfers)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas)
+                    .on_transfer_with_reference(
+                        args,
+                        token_address,
+                        payer,
+                        main_amount.into(),
+                        protocol_fee,
+                    ),
+            )
+            .into()
     }
 
+    /// Logs the payment if the payee was paid, and returns the amount of the failed transfers to `ft_resolve_transfer`
+    /// on the token contract, or to `refund_to`. On any failure, the result of each transfer is logged.
     #[private]
     pub fn on_transfer_with_reference(
         &self,
@@ -187,193 +1091,3078 @@ impl FungibleProxy {
         token_address: AccountId,
         payer: AccountId,
         amount: U128,
-    ) -> String {
-        if near_sdk::is_promise_success() {
+        protocol_fee: Option<ProtocolFee>,
+    ) -> PromiseOrValue<U128> {
+        let settlement =
+            Settlement::from_promise_results(payment_legs(&args, amount.0, protocol_fee.as_ref()));
+        if settlement.is_complete() {
             // Log success for indexing and payment detection
-            env::log(
-                &json!({
-                    "amount": amount,
-                    "token_address": token_address,
-                    "fee_address": args.fee_address,
-                    "fee_amount": args.fee_amount,
-                    "payment_reference": args.payment_reference,
-                    "to": args.to,
-                })
-                .to_string()
-                .into_bytes(),
-            );
-            0.to_string()
-        } else {
-            // return full amount for `ft_resolve_transfer` on the token contract
-            let change = (amount.0 + args.fee_amount.0).to_string();
-            log!(
-                "Transfer failed to {} or {}. Returning attached amount of {} of token {} to {}",
-                args.to,
-                args.fee_address,
-                change,
-                token_address,
-                payer
-            );
-            change
+            let payment_log = payment_log(&args, &token_address, amount, protocol_fee.as_ref());
+            env::log_str(&payment_log);
+            return PromiseOrValue::Value(0.into());
         }
-    }
+        settlement.log(&args.payment_reference, &token_address);
+        // Only the failed transfers are returned, the proxy holding nothing more from this payment
+        let change = settlement.failed_amount();
+        log!(
+            "Transfer failed to {}. Returning {} of token {} to {}",
+            settlement.failed_receivers(),
+            change,
+            token_address,
+            args.refund_account(&payer)
+        );
+        let refund_to = args.refund_to.clone();
+        if settlement.executed(0) {
+            // The payee was paid, the payment is logged without the failed fees
+            let (args, protocol_fee) = paid_fees(args, protocol_fee, &settlement);
+            let payment_log = payment_log(&args, &token_address, amount, protocol_fee.as_ref());
+            env::log_str(&payment_log);
+        }
+        return_unused(&token_address, refund_to, change)
+    }
+
+    /// Logs the `unused_returned` event if the unused amount was transferred to `refund_to`, or returns it
+    /// to `ft_resolve_transfer` for the token contract to refund the sender
+    #[private]
+    pub fn on_unused_returned(
+        &self,
+        token_address: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if near_sdk::is_promise_success() {
+            let event = json!({
+                "event": "unused_returned",
+                "refund_to": refund_to,
+                "token_address": token_address,
+                "amount": amount,
+            });
+            env::log_str(&event.to_string());
+            0.into()
+        } else {
+            log!(
+                "Transfer failed to {}. Returning {} of token {} to the sender",
+                refund_to,
+                amount.0,
+                token_address
+            );
+            amount
+        }
+    }
+
+    /// Swaps the amount deposited on the exchange, or returns what could not be deposited to the payer
+    #[private]
+    pub fn on_swap_deposited(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        // `ft_transfer_call` returns the amount used by the exchange
+        let deposited = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).map_or(0, |deposited| deposited.0)
+            }
+            _ => 0,
+        };
+        if deposited < amount.0 {
+            log!(
+                "Deposit to the exchange failed. Returning {} of token {} to {}",
+                amount.0 - deposited,
+                token_address,
+                args.refund_account(&payer)
+            );
+            // return the amount not deposited for `ft_resolve_transfer` on the token contract, or to `refund_to`
+            let refund_to = args.refund_to;
+            return return_unused(&token_address, refund_to, amount.0 - deposited);
+        }
+        let swap = args.swap.clone().unwrap();
+        let transfers_count = self.transfers_count(&args);
+        let action = SwapAction {
+            pool_id: swap.pool_id,
+            token_in: token_address.clone(),
+            amount_in: Some(amount),
+            token_out: swap.token_out.clone(),
+            min_amount_out: swap.min_amount_out,
+        };
+        // The proxy balance of `token_out` is checked along the swap, to bound the amount paid after the withdrawal
+        exchange_contract::ext(swap.exchange_id.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS * 2)
+            .swap(vec![action], None)
+            .and(
+                ft_contract::ext(swap.token_out.clone())
+                    .with_static_gas(BASIC_GAS)
+                    .ft_balance_of(env::current_account_id()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(swap_withdraw_gas(transfers_count))
+                    .on_swapped(args, token_address, payer, amount),
+            )
+            .into()
+    }
+
+    /// Withdraws the swapped tokens from the exchange, then checks the proxy balance of `token_out` again,
+    /// or withdraws the deposited tokens if the swap failed (eg. on slippage)
+    #[private]
+    pub fn on_swapped(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        let swap = args.swap.clone().unwrap();
+        let amount_out = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        };
+        let balance_before = match env::promise_result(1) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        };
+        match (amount_out, balance_before) {
+            (Some(amount_out), None) => {
+                log!("Balance check of token {} failed", swap.token_out);
+                // The attached amount was swapped, nothing to return to `ft_resolve_transfer`
+                self.keep_on_exchange(
+                    args.refund_account(&payer),
+                    swap.exchange_id,
+                    swap.token_out,
+                    amount_out.0,
+                );
+                PromiseOrValue::Value(0.into())
+            }
+            (Some(amount_out), Some(balance_before)) => {
+                let payment_gas = swap_payment_gas(self.transfers_count(&args));
+                exchange_contract::ext(swap.exchange_id.clone())
+                    .with_attached_deposit(YOCTO_DEPOSIT)
+                    .with_static_gas(BASIC_GAS * 3)
+                    .withdraw(swap.token_out.clone(), amount_out, None)
+                    .then(
+                        ft_contract::ext(swap.token_out.clone())
+                            .with_static_gas(BASIC_GAS)
+                            .ft_balance_of(env::current_account_id()),
+                    )
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(payment_gas)
+                            .on_swap_withdrawn(
+                                args,
+                                token_address,
+                                payer,
+                                amount,
+                                amount_out,
+                                balance_before,
+                            ),
+                    )
+                    .into()
+            }
+            (None, _) => {
+                log!(
+                    "Swap failed on exchange {}. Returning attached amount of {} of token {} to {}",
+                    swap.exchange_id,
+                    amount.0,
+                    token_address,
+                    args.refund_account(&payer)
+                );
+                exchange_contract::ext(swap.exchange_id.clone())
+                    .with_attached_deposit(YOCTO_DEPOSIT)
+                    .with_static_gas(BASIC_GAS * 3)
+                    .withdraw(token_address.clone(), amount, None)
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(BASIC_GAS + return_unused_gas(&args))
+                            .on_swap_refunded(args, token_address, payer, amount),
+                    )
+                    .into()
+            }
+        }
+    }
+
+    /// Returns the amount withdrawn from the exchange after a failed swap, for `ft_resolve_transfer` to refund the payer,
+    /// or to `refund_to`. If the withdrawal failed, the amount is kept on the exchange for them.
+    #[private]
+    pub fn on_swap_refunded(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        if near_sdk::is_promise_success() {
+            return_unused(&token_address, args.refund_to, amount.0)
+        } else {
+            log!(
+                "Withdrawal of {} of token {} from the exchange failed",
+                amount.0,
+                token_address
+            );
+            let exchange_id = args.swap.as_ref().unwrap().exchange_id.clone();
+            self.keep_on_exchange(
+                args.refund_account(&payer),
+                exchange_id,
+                token_address,
+                amount.0,
+            );
+            PromiseOrValue::Value(0.into())
+        }
+    }
+
+    /// Pays the swapped tokens to the payee and fee recipients. The exchange reports `amount_out`, but no more than
+    /// the increase of the proxy balance of `token_out` since `balance_before` is paid.
+    #[private]
+    pub fn on_swap_withdrawn(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        amount_out: U128,
+        balance_before: U128,
+    ) -> PromiseOrValue<U128> {
+        let mut args = args;
+        let swap = args.swap.clone().unwrap();
+        // The balance does not increase if the withdrawal failed
+        let balance_after = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).map_or(0, |balance| balance.0)
+            }
+            _ => 0,
+        };
+        let received = balance_after
+            .saturating_sub(balance_before.0)
+            .min(amount_out.0);
+        // The attached amount was swapped, nothing to return to `ft_resolve_transfer`
+        if received == 0 {
+            log!(
+                "Withdrawal of {} of token {} from the exchange failed",
+                amount_out.0,
+                swap.token_out
+            );
+            self.keep_on_exchange(
+                args.refund_account(&payer),
+                swap.exchange_id,
+                swap.token_out,
+                amount_out.0,
+            );
+            return PromiseOrValue::Value(0.into());
+        }
+        if received < swap.min_amount_out.0 {
+            log!(
+                "Only {} of token {} received from the exchange, less than min_amount_out. Returning it to {}",
+                received,
+                swap.token_out,
+                args.refund_account(&payer)
+            );
+            ft_contract::ext(swap.token_out)
+                .with_attached_deposit(YOCTO_DEPOSIT)
+                .with_static_gas(BASIC_GAS * 2)
+                .ft_transfer(args.refund_account(&payer), received.into(), None);
+            return PromiseOrValue::Value(0.into());
+        }
+        let token_out = swap.token_out;
+        let (transfers, main_amount, protocol_fee) =
+            self.payment_transfers(&mut args, &token_out, received);
+        ft_transfers_promise(&token_out, &transfers)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 3)
+                    .on_swap_transfer(
+                        args,
+                        token_address,
+                        payer,
+                        amount,
+                        received.into(),
+                        main_amount.into(),
+                        protocol_fee,
+                    ),
+            )
+            .into()
+    }
+
+    /// Logs the payment if the payee was paid, and refunds the swapped tokens of the failed transfers to the payer,
+    /// or to `refund_to`
+    #[private]
+    pub fn on_swap_transfer(
+        &self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        amount_out: U128,
+        main_amount: U128,
+        protocol_fee: Option<ProtocolFee>,
+    ) -> U128 {
+        let token_out = args.swap.as_ref().unwrap().token_out.clone();
+        let settlement = Settlement::from_promise_results(payment_legs(
+            &args,
+            main_amount.0,
+            protocol_fee.as_ref(),
+        ));
+        let refund = settlement.failed_amount();
+        if refund > 0 {
+            settlement.log(&args.payment_reference, &token_out);
+            log!(
+                "Transfer failed. Returning swapped amount of {} of token {} to {}",
+                refund,
+                token_out,
+                args.refund_account(&payer)
+            );
+            ft_contract::ext(token_out.clone())
+                .with_attached_deposit(YOCTO_DEPOSIT)
+                .with_static_gas(BASIC_GAS * 2)
+                .ft_transfer(args.refund_account(&payer), refund.into(), None);
+        }
+        if settlement.executed(0) {
+            // Log success for indexing and payment detection, without the failed fees and with the swapped amounts
+            let (args, protocol_fee) = paid_fees(args, protocol_fee, &settlement);
+            let payment_log = payment_log(&args, &token_out, main_amount, protocol_fee.as_ref());
+            let mut payment_log: serde_json::Value = serde_json::from_str(&payment_log).unwrap();
+            payment_log["swap"] = json!({
+                "token_in": token_address,
+                "amount_in": amount,
+                "amount_out": amount_out,
+            });
+            env::log_str(&payment_log.to_string());
+        }
+        // The attached amount was swapped, nothing to return to `ft_resolve_transfer`
+        0.into()
+    }
+
+    /// Pays in NEAR: wraps the attached deposit on the wrap contract, then pays like `ft_on_transfer` in wrapped NEAR.
+    /// The amount unused by the payment, or the full amount if it failed, is unwrapped and refunded in NEAR.
+    #[payable]
+    pub fn wrap_and_transfer_with_reference(&mut self, args: PaymentArgs) -> Promise {
+        let wrap_account_id = self
+            .wrap_account_id
+            .clone()
+            .unwrap_or_else(|| ProxyError::NoWrapAccount.panic());
+        let amount = env::attached_deposit();
+        require(amount > 0, ProxyError::ZeroDeposit);
+        self.payee_preferences
+            .require_accepted(&args.to, wrap_account_id.as_str(), None);
+        let min_gas = MIN_GAS + BASIC_GAS * 10;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+        let payer = env::predecessor_account_id();
+        // Unused wrapped NEAR is unwrapped and refunded in NEAR to `refund_to` if set
+        let refund_to = args.refund_account(&payer);
+        // The payment gets all the gas left after wrapping and refunding
+        wrap_contract::ext(wrap_account_id.clone())
+            .with_attached_deposit(amount)
+            .with_static_gas(BASIC_GAS)
+            .with_unused_gas_weight(0)
+            .near_deposit()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(MIN_GAS)
+                    .on_near_wrapped(args, wrap_account_id.clone(), payer.clone(), amount.into()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 6)
+                    .with_unused_gas_weight(0)
+                    .on_wrapped_transfer(wrap_account_id, refund_to, amount.into()),
+            )
+    }
+
+    /// Pays `args` in wrapped NEAR, or refunds the payer (or `refund_to`) if wrapping failed
+    #[private]
+    pub fn on_near_wrapped(
+        &mut self,
+        args: PaymentArgs,
+        wrap_account_id: AccountId,
+        payer: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        if !near_sdk::is_promise_success() {
+            log!(
+                "Wrapping NEAR failed. Returning attached deposit of {} to {}",
+                amount.0,
+                args.refund_account(&payer)
+            );
+            Promise::new(args.refund_account(&payer)).transfer(amount.0);
+            // Nothing was wrapped, nothing to unwrap
+            return PromiseOrValue::Value(0.into());
+        }
+        // The change is returned to `on_wrapped_transfer`, which refunds `refund_to` in NEAR, `refund_to` only
+        // receiving in wrapped NEAR the later refunds of escrows and streams
+        self.transfer_with_reference(args, wrap_account_id, payer, amount, true)
+    }
+
+    /// Unwraps and refunds the amount unused by the payment, like `ft_resolve_transfer` for token payments
+    #[private]
+    pub fn on_wrapped_transfer(
+        &mut self,
+        wrap_account_id: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        // The payment returns the unused amount, the full amount is unused if it panicked
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map_or(amount.0, |unused_amount| unused_amount.0.min(amount.0)),
+            _ => amount.0,
+        };
+        if unused_amount == 0 {
+            return PromiseOrValue::Value(0.into());
+        }
+        wrap_contract::ext(wrap_account_id.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS)
+            .near_withdraw(unused_amount.into())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 3)
+                    .on_near_unwrapped(wrap_account_id, refund_to, unused_amount.into()),
+            )
+            .into()
+    }
+
+    /// Refunds the unwrapped NEAR to the payer (or `refund_to`), or the wrapped NEAR if unwrapping failed,
+    /// returning the refunded amount
+    #[private]
+    pub fn on_near_unwrapped(
+        &mut self,
+        wrap_account_id: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if near_sdk::is_promise_success() {
+            Promise::new(refund_to).transfer(amount.0);
+        } else {
+            log!(
+                "Unwrapping failed. Returning {} of wrapped NEAR to {}",
+                amount.0,
+                refund_to
+            );
+            ft_contract::ext(wrap_account_id)
+                .with_attached_deposit(YOCTO_DEPOSIT)
+                .with_static_gas(BASIC_GAS * 2)
+                .ft_transfer(refund_to, amount, None);
+        }
+        amount
+    }
+
+    /// Registers the payment recipients found unregistered with the paid token, paying the storage deposit
+    /// from the payer's storage fund, then pays
+    #[private]
+    pub fn on_storage_balances(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        return_change: bool,
+        accounts: Vec<AccountId>,
+    ) -> PromiseOrValue<U128> {
+        let mut args = args;
+        args.register_accounts = None;
+        let (storage_cost, unregistered_accounts) = unregistered_accounts(accounts);
+        if unregistered_accounts.is_empty() {
+            return self.transfer_with_reference(args, token_address, payer, amount, return_change);
+        }
+
+        let registrations = self.storage_funds.register(
+            &payer,
+            &args.paid_token(&token_address),
+            &unregistered_accounts,
+            storage_cost,
+            BASIC_GAS,
+        );
+        // The payment gets all the gas left after the registrations
+        registrations
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(MIN_GAS)
+                    .on_accounts_registered(
+                        args,
+                        token_address,
+                        payer,
+                        amount,
+                        return_change,
+                        unregistered_accounts,
+                        storage_cost.into(),
+                    ),
+            )
+            .into()
+    }
+
+    /// Credits the storage fund back for failed registrations, logs the successful ones, then pays
+    #[private]
+    pub fn on_accounts_registered(
+        &mut self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        return_change: bool,
+        accounts: Vec<AccountId>,
+        storage_cost: U128,
+    ) -> PromiseOrValue<U128> {
+        let registered_accounts =
+            self.storage_funds
+                .on_registrations(&payer, accounts, storage_cost.0);
+        if !registered_accounts.is_empty() {
+            let event = json!({
+                "event": "accounts_registered",
+                "payment_reference": args.payment_reference,
+                "payer": payer,
+                "token_address": args.paid_token(&token_address),
+                "accounts": registered_accounts,
+                "storage_cost": storage_cost,
+            });
+            env::log_str(&event.to_string());
+        }
+        self.transfer_with_reference(args, token_address, payer, amount, return_change)
+    }
+
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            owner_id: env::predecessor_account_id(),
+            ..Default::default()
+        }
+    }
+
+    /// Migrates the state of a contract deployed with the previous layout, owned by the contract account
+    /// (see `deploy.sh --patch`), which can then transfer ownership with `set_owner`
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        // Contracts never called have no state
+        let _: Option<OldFungibleProxy> = env::state_read();
+        Self {
+            owner_id: env::current_account_id(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_owner(&mut self, owner: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.owner_id = owner;
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn set_wrap_account(&mut self, wrap: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.wrap_account_id = Some(wrap);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_wrap_account(&self) -> Option<AccountId> {
+        self.wrap_account_id.clone()
+    }
+
+    /// Allows swaps through `exchange_id`, which must be trusted: it reports the swapped amount, the proxy
+    /// only checking that its own balance increased accordingly
+    pub fn add_exchange(&mut self, exchange_id: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.exchanges.insert(exchange_id);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_exchange(&mut self, exchange_id: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.exchanges.remove(&exchange_id);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_exchanges(&self) -> Vec<AccountId> {
+        self.exchanges.iter().cloned().collect()
+    }
+
+    /// Amount of `token_id` kept on `exchange_id` for `account_id`, after a swap payment whose tokens could not be
+    /// withdrawn from the exchange (see `withdraw_from_exchange`)
+    pub fn get_exchange_balance(
+        &self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+    ) -> U128 {
+        let key = (account_id, exchange_id, token_id);
+        self.exchange_balances.get(&key).unwrap_or(0).into()
+    }
+
+    /// Withdraws the caller's balance of `token_id` kept on `exchange_id` (see `get_exchange_balance`) and transfers
+    /// it to the caller, who must be registered with the token. The balance is kept if the withdrawal fails.
+    pub fn withdraw_from_exchange(
+        &mut self,
+        exchange_id: AccountId,
+        token_id: AccountId,
+    ) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let key = (account_id.clone(), exchange_id.clone(), token_id.clone());
+        let amount = self
+            .exchange_balances
+            .remove(&key)
+            .unwrap_or_else(|| ProxyError::NothingToWithdraw.panic());
+        let min_gas = MIN_GAS;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+        ft_contract::ext(token_id.clone())
+            .with_static_gas(BASIC_GAS)
+            .ft_balance_of(env::current_account_id())
+            .and(
+                storage_contract::ext(token_id.clone())
+                    .with_static_gas(BASIC_GAS)
+                    .storage_balance_of(account_id.clone()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(BASIC_GAS * 9)
+                    .on_exchange_claim_checked(account_id, exchange_id, token_id, amount.into()),
+            )
+    }
+
+    /// Withdraws the claimed tokens from the exchange if the claimant is registered with the token, checking the
+    /// proxy balance again afterwards, or keeps them on the exchange for the claimant
+    #[private]
+    pub fn on_exchange_claim_checked(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<U128> {
+        let balance_before = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok(),
+            _ => None,
+        };
+        let registered = match env::promise_result(1) {
+            PromiseResult::Successful(value) => {
+                matches!(
+                    serde_json::from_slice::<Option<StorageBalance>>(&value),
+                    Ok(Some(_))
+                )
+            }
+            _ => false,
+        };
+        match balance_before {
+            Some(balance_before) if registered => exchange_contract::ext(exchange_id.clone())
+                .with_attached_deposit(YOCTO_DEPOSIT)
+                .with_static_gas(BASIC_GAS * 3)
+                .withdraw(token_id.clone(), amount, None)
+                .then(
+                    ft_contract::ext(token_id.clone())
+                        .with_static_gas(BASIC_GAS)
+                        .ft_balance_of(env::current_account_id()),
+                )
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(BASIC_GAS * 3)
+                        .on_exchange_claim_withdrawn(
+                            account_id,
+                            exchange_id,
+                            token_id,
+                            amount,
+                            balance_before,
+                        ),
+                )
+                .into(),
+            _ => {
+                log!(
+                    "{} is not registered with token {}, or its balance check failed",
+                    account_id,
+                    token_id
+                );
+                self.keep_on_exchange(account_id, exchange_id, token_id, amount.0);
+                PromiseOrValue::Value(0.into())
+            }
+        }
+    }
+
+    /// Transfers the claimed tokens withdrawn from the exchange to the claimant, no more than the increase of the
+    /// proxy balance since `balance_before`, or keeps them on the exchange if the withdrawal failed.
+    /// Returns the amount transferred.
+    #[private]
+    pub fn on_exchange_claim_withdrawn(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        balance_before: U128,
+    ) -> U128 {
+        // The balance does not increase if the withdrawal failed
+        let balance_after = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<U128>(&value).map_or(0, |balance| balance.0)
+            }
+            _ => 0,
+        };
+        let received = balance_after.saturating_sub(balance_before.0).min(amount.0);
+        if received == 0 {
+            log!(
+                "Withdrawal of {} of token {} from the exchange failed",
+                amount.0,
+                token_id
+            );
+            self.keep_on_exchange(account_id, exchange_id, token_id, amount.0);
+            return 0.into();
+        }
+        ft_contract::ext(token_id.clone())
+            .with_attached_deposit(YOCTO_DEPOSIT)
+            .with_static_gas(BASIC_GAS * 2)
+            .ft_transfer(account_id.clone(), received.into(), None);
+        let event = json!({
+            "event": "exchange_balance_withdrawn",
+            "account_id": account_id,
+            "exchange_id": exchange_id,
+            "token_address": token_id,
+            "amount": U128::from(received),
+        });
+        env::log_str(&event.to_string());
+        received.into()
+    }
+
+    /// Adds the attached deposit to the storage fund of `account_id` (default: the caller), used to register
+    /// payment recipients with tokens for payments made with `register_accounts`. Returns the storage fund.
+    #[payable]
+    pub fn deposit_storage_fund(&mut self, account_id: Option<AccountId>) -> U128 {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        self.storage_funds
+            .deposit(&account_id, env::attached_deposit())
+            .into()
+    }
+
+    /// Withdraws `amount` (default: all) from the caller's storage fund
+    pub fn withdraw_storage_fund(&mut self, amount: Option<U128>) -> Promise {
+        self.storage_funds.withdraw(amount.map(|amount| amount.0))
+    }
+
+    pub fn get_storage_fund(&self, account_id: AccountId) -> U128 {
+        self.storage_funds.get(&account_id).into()
+    }
+
+    /// Allows registering payment recipients with `token_address` from storage funds, the token being trusted to
+    /// report the payer (as `sender_id` of `ft_on_transfer`) and the storage cost
+    pub fn add_registration_token(&mut self, token_address: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.registration_tokens.insert(token_address);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_registration_token(&mut self, token_address: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.registration_tokens.remove(&token_address);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_registration_tokens(&self) -> Vec<AccountId> {
+        self.registration_tokens.iter().cloned().collect()
+    }
+
+    /// Registers `public_key` (ed25519) as the key signing the caller's payment intents, or removes it if `None`.
+    /// The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_key(&mut self, public_key: Option<PublicKey>) {
+        self.payee_keys.set(public_key);
+    }
+
+    pub fn get_payee_key(&self, account_id: AccountId) -> Option<PublicKey> {
+        self.payee_keys.get(&account_id)
+    }
+
+    /// Registers the tokens and currencies accepted by the caller as a payee, or removes them if `None` to accept
+    /// any asset. The attached deposit pays for the storage, the excess being refunded.
+    #[payable]
+    pub fn set_payee_preferences(&mut self, preferences: Option<PayeePreferences>) {
+        self.payee_preferences.set(preferences);
+    }
+
+    pub fn get_payee_preferences(&self, account_id: AccountId) -> Option<PayeePreferences> {
+        self.payee_preferences.get(&account_id)
+    }
+
+    /// Sets the protocol fee, in basis points of the amount paid to `to`, paid by the payer to `treasury_id`
+    pub fn set_protocol_fee(&mut self, bps: u16, treasury_id: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                bps <= MAX_BPS,
+                ProxyError::ProtocolFeeBpsTooHigh {
+                    supplied: bps,
+                    max: MAX_BPS,
+                },
+            );
+            self.protocol_fee_bps = bps;
+            self.treasury_id = Some(treasury_id);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    /// Bounds the protocol fee for payments in `token_address`, amounts in that token
+    pub fn set_protocol_fee_caps(
+        &mut self,
+        token_address: AccountId,
+        min_amount: U128,
+        max_amount: U128,
+    ) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            require(
+                min_amount.0 <= max_amount.0,
+                ProxyError::InvalidProtocolFeeCaps {
+                    min_amount: min_amount.0,
+                    max_amount: max_amount.0,
+                },
+            );
+            self.protocol_fee_caps.insert(
+                token_address.clone(),
+                ProtocolFeeCaps {
+                    min_amount,
+                    max_amount,
+                },
+            );
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn remove_protocol_fee_caps(&mut self, token_address: AccountId) {
+        let signer_id = env::predecessor_account_id();
+        if self.owner_id == signer_id {
+            self.protocol_fee_caps.remove(&token_address);
+        } else {
+            ProxyError::Permission.panic();
+        }
+    }
+
+    pub fn get_protocol_fee(&self) -> ProtocolFeeSchedule {
+        ProtocolFeeSchedule {
+            bps: self.protocol_fee_bps,
+            treasury_id: self.treasury_id.clone(),
+            caps: self.protocol_fee_caps.clone(),
+        }
+    }
+
+    /// Releases the escrow of `payer` to the payee and fee recipients, callable by the payer, or by the payee after
+    /// `claim_after`
+    pub fn release_escrow(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let escrow = self
+            .escrows
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::EscrowNotFound.panic());
+        require(
+            escrow.can_release(&env::predecessor_account_id(), env::block_timestamp()),
+            ProxyError::EscrowReleaseNotAllowed,
+        );
+        let min_gas = MIN_GAS + BASIC_GAS * 2 * escrow.transfers.len() as u64;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+        self.escrows.remove(&key);
+        ft_transfers_promise(&escrow.token_address, &escrow.transfers).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(BASIC_GAS)
+                .on_escrow_transfer(key.1, escrow, true),
+        )
+    }
+
+    /// Refunds the escrow of `payer`, callable by the payee at any time or by the payer after `refund_after`
+    /// (before `claim_after` for scheduled payments)
+    pub fn refund_escrow(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let escrow = self
+            .escrows
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::EscrowNotFound.panic());
+        if escrow.claim_after.is_some() {
+            require(
+                escrow.can_refund(&env::predecessor_account_id(), env::block_timestamp()),
+                ProxyError::PaymentCancelNotAllowed,
+            );
+        } else {
+            require(
+                escrow.can_refund(&env::predecessor_account_id(), env::block_timestamp()),
+                ProxyError::EscrowRefundNotAllowed,
+            );
+        }
+        self.escrows.remove(&key);
+        let refund = Transfer {
+            receiver_id: escrow.refund_account().clone(),
+            amount: escrow.total_amount().into(),
+        };
+        ft_transfers_promise(&escrow.token_address, &[refund]).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(BASIC_GAS)
+                .on_escrow_transfer(key.1, escrow, false),
+        )
+    }
+
+    pub fn get_escrow(&self, payment_reference: String, payer: AccountId) -> Option<Escrow> {
+        self.escrows.get(&(payer, payment_reference))
+    }
+
+    /// Logs the escrow release or refund, or keeps in escrow the transfers that failed
+    ///
+    /// On a partial release, the payment is logged once the payee is paid, the escrow only holding the failed fees.
+    #[private]
+    pub fn on_escrow_transfer(
+        &mut self,
+        payment_reference: String,
+        escrow: Escrow,
+        released: bool,
+    ) -> bool {
+        let mut escrow = escrow;
+        let settlement = if released {
+            Settlement::from_promise_results(escrow.transfers.clone())
+        } else {
+            let refund = Transfer {
+                receiver_id: escrow.refund_account().clone(),
+                amount: escrow.total_amount().into(),
+            };
+            Settlement::from_promise_results(vec![refund])
+        };
+        let paid_amount = escrow.total_amount() - settlement.failed_amount();
+        if paid_amount > 0 {
+            let mut event = json!({
+                "event": if released { "escrow_released" } else { "escrow_refunded" },
+                "payment_reference": payment_reference,
+                "payer": escrow.payer,
+                "amount": U128::from(paid_amount),
+            });
+            if let (false, Some(refund_to)) = (released, &escrow.refund_to) {
+                event["refund_to"] = json!(refund_to);
+            }
+            env::log_str(&event.to_string());
+        }
+        // While the payment is not logged, the first transfer of the escrow is the one to the payee
+        if released && settlement.executed(0) && !escrow.payment_log.is_empty() {
+            // Log success for indexing and payment detection
+            env::log_str(&escrow.payment_log);
+            escrow.payment_log = String::new();
+        }
+        if settlement.is_complete() {
+            return true;
+        }
+        settlement.log(&payment_reference, &escrow.token_address);
+        log!(
+            "Transfer failed to {} for escrow {}. {} of token {} are kept in escrow for a later release or refund",
+            settlement.failed_receivers(),
+            payment_reference,
+            settlement.failed_amount(),
+            escrow.token_address
+        );
+        if released {
+            escrow.transfers = settlement.failed_transfers();
+        }
+        self.escrows
+            .insert(&(escrow.payer.clone(), payment_reference), &escrow);
+        false
+    }
+
+    /// Withdraws the vested amount of a stream to the payee, callable by the payee
+    pub fn withdraw_stream(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let mut stream = self
+            .streams
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::StreamNotFound.panic());
+        require(
+            env::predecessor_account_id() == stream.to,
+            ProxyError::StreamWithdrawNotAllowed,
+        );
+        let amount = stream.withdrawable_amount(env::block_timestamp());
+        require(amount > 0, ProxyError::NothingToWithdraw);
+        let min_gas = MIN_GAS + BASIC_GAS * 2;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+        stream.withdrawn = (stream.withdrawn.0 + amount).into();
+        self.streams.insert(&key, &stream);
+        let (_, payment_reference) = key;
+        let transfer = Transfer {
+            receiver_id: stream.to.clone(),
+            amount: amount.into(),
+        };
+        ft_transfers_promise(&stream.token_address, &[transfer]).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(BASIC_GAS)
+                .on_stream_withdrawal(payment_reference, stream, amount.into()),
+        )
+    }
+
+    /// Cancels a stream, callable by the payer: the vested amount not withdrawn yet is paid to the payee,
+    /// the unvested amount is refunded to the payer (or `refund_to`), with any refund that failed on a previous
+    /// cancellation
+    pub fn cancel_stream(&mut self, payment_reference: String, payer: AccountId) -> Promise {
+        let key = (payer, payment_reference);
+        let stream = self
+            .streams
+            .get(&key)
+            .unwrap_or_else(|| ProxyError::StreamNotFound.panic());
+        require(
+            env::predecessor_account_id() == stream.payer,
+            ProxyError::StreamCancelNotAllowed,
+        );
+        let min_gas = MIN_GAS + BASIC_GAS * 4;
+        require(
+            min_gas <= env::prepaid_gas(),
+            ProxyError::NotEnoughGas {
+                supplied: env::prepaid_gas(),
+                demand: min_gas,
+            },
+        );
+        let timestamp = env::block_timestamp();
+        let amount = stream.withdrawable_amount(timestamp);
+        let refund =
+            stream.total_amount() - stream.vested_amount(timestamp) + stream.pending_refund.0;
+        self.streams.remove(&key);
+        let (_, payment_reference) = key;
+        let transfers = [
+            Transfer {
+                receiver_id: stream.to.clone(),
+                amount: amount.into(),
+            },
+            Transfer {
+                receiver_id: stream.refund_account().clone(),
+                amount: refund.into(),
+            },
+        ];
+        ft_transfers_promise(&stream.token_address, &transfers).then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(BASIC_GAS)
+                .on_stream_cancelled(
+                    payment_reference,
+                    stream,
+                    timestamp.into(),
+                    amount.into(),
+                    refund.into(),
+                ),
+        )
+    }
+
+    pub fn get_stream(&self, payment_reference: String, payer: AccountId) -> Option<Stream> {
+        self.streams.get(&(payer, payment_reference))
+    }
+
+    /// Amount the payee can currently withdraw from a stream
+    pub fn get_withdrawable_amount(&self, payment_reference: String, payer: AccountId) -> U128 {
+        self.streams
+            .get(&(payer, payment_reference))
+            .map_or(0, |stream| {
+                stream.withdrawable_amount(env::block_timestamp())
+            })
+            .into()
+    }
+
+    /// Logs the stream creation, returning the change and the fees that could not be paid (to `ft_resolve_transfer`,
+    /// or to `change_to`), the stream being funded either way
+    #[private]
+    pub fn on_stream_created(
+        &mut self,
+        payment_reference: String,
+        payer: AccountId,
+        fee_transfers: Vec<Transfer>,
+        change: U128,
+        change_to: Option<AccountId>,
+    ) -> PromiseOrValue<U128> {
+        let stream = self
+            .streams
+            .get(&(payer, payment_reference.clone()))
+            .unwrap_or_else(|| ProxyError::StreamNotFound.panic());
+        let settlement = Settlement::from_promise_results(fee_transfers);
+        log_stream_created(&payment_reference, &stream);
+        if settlement.is_complete() {
+            return return_unused(&stream.token_address, change_to, change.0);
+        }
+        settlement.log(&payment_reference, &stream.token_address);
+        let failed_amount = settlement.failed_amount();
+        log!(
+            "Fee transfer failed to {} for stream {}. Returning {} of token {} to {}",
+            settlement.failed_receivers(),
+            payment_reference,
+            failed_amount,
+            stream.token_address,
+            change_to.as_ref().unwrap_or(&stream.payer)
+        );
+        return_unused(&stream.token_address, change_to, change.0 + failed_amount)
+    }
+
+    /// Logs the withdrawal, or restores the withdrawable amount if the transfer failed: in the stream, or in a stream
+    /// of that amount ending now if it was cancelled in the meantime
+    #[private]
+    pub fn on_stream_withdrawal(
+        &mut self,
+        payment_reference: String,
+        stream: Stream,
+        amount: U128,
+    ) -> bool {
+        let key = (stream.payer.clone(), payment_reference.clone());
+        if near_sdk::is_promise_success() {
+            // Log success for indexing and payment detection
+            let payment_log = stream_payment_log(&payment_reference, &stream, amount);
+            env::log_str(&payment_log);
+            if stream.withdrawn.0 == stream.total_amount() && stream.pending_refund.0 == 0 {
+                self.streams.remove(&key);
+            }
+            true
+        } else {
+            match self.streams.get(&key) {
+                Some(mut stream) => {
+                    log!(
+                        "Transfer failed for stream {}. The amount can be withdrawn later",
+                        payment_reference
+                    );
+                    stream.withdrawn = (stream.withdrawn.0 - amount.0).into();
+                    self.streams.insert(&key, &stream);
+                }
+                None => {
+                    log!(
+                        "Transfer failed for stream {}, cancelled in the meantime. {} of token {} can be withdrawn later",
+                        payment_reference,
+                        amount.0,
+                        stream.token_address
+                    );
+                    // Vesting `amount` per second over the last second, the whole amount is withdrawable
+                    let timestamp = env::block_timestamp();
+                    let stream = Stream {
+                        rate: amount,
+                        start: timestamp.saturating_sub(ONE_SECOND as u64).into(),
+                        end: timestamp.into(),
+                        withdrawn: 0.into(),
+                        pending_refund: 0.into(),
+                        ..stream
+                    };
+                    self.streams.insert(&key, &stream);
+                }
+            }
+            false
+        }
+    }
+
+    /// Logs the stream cancellation, or keeps in the stream the amounts whose transfer failed:
+    /// - if the payee was not paid, the stream ends at the cancellation for a later withdrawal
+    /// - if the payer was not refunded, the stream ends at the cancellation with the payee's part withdrawn, the
+    ///   refund being pending for a later cancellation
+    #[private]
+    pub fn on_stream_cancelled(
+        &mut self,
+        payment_reference: String,
+        stream: Stream,
+        timestamp: U64,
+        amount: U128,
+        refund: U128,
+    ) -> bool {
+        let mut stream = stream;
+        let transfers = vec![
+            Transfer {
+                receiver_id: stream.to.clone(),
+                amount,
+            },
+            Transfer {
+                receiver_id: stream.refund_account().clone(),
+                amount: refund,
+            },
+        ];
+        let settlement = Settlement::from_promise_results(transfers);
+        if settlement.is_complete() {
+            let mut event = json!({
+                "event": "stream_cancelled",
+                "payment_reference": payment_reference,
+                "payer": stream.payer,
+                "to": stream.to,
+                "amount": amount,
+                "refund": refund,
+            });
+            if let Some(refund_to) = &stream.refund_to {
+                event["refund_to"] = json!(refund_to);
+            }
+            env::log_str(&event.to_string());
+            if amount.0 > 0 {
+                // Log success for indexing and payment detection
+                let payment_log = stream_payment_log(&payment_reference, &stream, amount);
+                env::log_str(&payment_log);
+            }
+            true
+        } else {
+            settlement.log(&payment_reference, &stream.token_address);
+            log!(
+                "Transfer failed to {} for stream {}. The stream is kept for a later withdrawal or cancellation",
+                settlement.failed_receivers(),
+                payment_reference
+            );
+            match (settlement.executed(0), settlement.executed(1)) {
+                (false, true) => {
+                    // The unvested amount was refunded, what remains is vested at the cancellation
+                    stream.end = timestamp.0.clamp(stream.start.0, stream.end.0).into();
+                    stream.pending_refund = 0.into();
+                }
+                (true, false) => {
+                    if amount.0 > 0 {
+                        let payment_log = stream_payment_log(&payment_reference, &stream, amount);
+                        env::log_str(&payment_log);
+                    }
+                    // Nothing vests after the cancellation, the unvested amount is owed to the payer
+                    stream.end = timestamp.0.clamp(stream.start.0, stream.end.0).into();
+                    stream.withdrawn = (stream.withdrawn.0 + amount.0).into();
+                    stream.pending_refund = refund;
+                }
+                _ => {}
+            }
+            self.streams
+                .insert(&(stream.payer.clone(), payment_reference), &stream);
+            false
+        }
+    }
+}
+
+impl FungibleProxy {
+    /// Keeps `amount` of `token_id` that could not be withdrawn from `exchange_id` for `account_id`, who can claim
+    /// it with `withdraw_from_exchange`
+    fn keep_on_exchange(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+    ) {
+        let key = (account_id, exchange_id, token_id);
+        let balance = self.exchange_balances.get(&key).unwrap_or(0) + amount;
+        self.exchange_balances.insert(&key, &balance);
+        let (account_id, exchange_id, token_id) = key;
+        log!(
+            "{} of token {} are kept on exchange {} for {}, who can claim them with withdraw_from_exchange",
+            amount,
+            token_id,
+            exchange_id,
+            account_id
+        );
+    }
+
+    /// Stores an `escrow` under its payer and `payment_reference`
+    fn lock_escrow(&mut self, payment_reference: String, escrow: Escrow) {
+        let key = (escrow.payer.clone(), payment_reference.clone());
+        require(
+            self.escrows.insert(&key, &escrow).is_none(),
+            ProxyError::EscrowExists,
+        );
+        let mut event = json!({
+            "event": "escrow_locked",
+            "payment_reference": payment_reference,
+            "payer": escrow.payer,
+            "to": escrow.to,
+            "token_address": escrow.token_address,
+            "amount": U128::from(escrow.total_amount()),
+            "refund_after": escrow.refund_after,
+        });
+        if let Some(claim_after) = escrow.claim_after {
+            event["claim_after"] = json!(claim_after);
+        }
+        if let Some(refund_to) = &escrow.refund_to {
+            event["refund_to"] = json!(refund_to);
+        }
+        env::log_str(&event.to_string());
+    }
+
+    /// Splits `amount` of `token_address` into transfers to the payee, fee recipients and treasury, in that order,
+    /// computing `fee_amount` from `fee_bps` if set. Returns the transfers, the amount paid to the payee and the protocol fee.
+    fn payment_transfers(
+        &self,
+        args: &mut PaymentArgs,
+        token_address: &AccountId,
+        amount: u128,
+    ) -> (Vec<Transfer>, u128, Option<ProtocolFee>) {
+        if let Some(fee_bps) = args.fee_bps {
+            require(args.fee_amount.0 == 0, ProxyError::FeeAmountAndFeeBps);
+            // Only the additional fees are deducted at this stage, as `fee_amount` is 0
+            let fees_amount = args.total_fee_amount();
+            require(fees_amount <= amount, ProxyError::AmountSmallerThanFees);
+            args.fee_amount = U128::from(fee_amount_from_bps(amount - fees_amount, fee_bps));
+        }
+        let total_fee_amount = args.total_fee_amount();
+        require(
+            total_fee_amount <= amount,
+            ProxyError::AmountSmallerThanFees,
+        );
+        let protocol_fee = self.protocol_fee(token_address, amount - total_fee_amount);
+        let protocol_fee_amount = protocol_fee.as_ref().map_or(0, |fee| fee.amount.0);
+        require(
+            protocol_fee_amount <= amount - total_fee_amount,
+            ProxyError::AmountSmallerThanProtocolFee,
+        );
+        let main_amount = amount - total_fee_amount - protocol_fee_amount;
+        let transfers = payment_legs(args, main_amount, protocol_fee.as_ref());
+        (transfers, main_amount, protocol_fee)
+    }
+
+    /// Accounts paid by `args`: `to`, fee recipients and the treasury, without duplicates
+    fn payment_recipients(&self, args: &PaymentArgs) -> Vec<AccountId> {
+        let mut accounts = vec![args.to.clone(), args.fee_address.clone()];
+        accounts.extend(args.fees.iter().map(|fee| fee.address.clone()));
+        if self.protocol_fee_bps > 0 {
+            accounts.extend(self.treasury_id.clone());
+        }
+        let mut recipients: Vec<AccountId> = vec![];
+        for account in accounts {
+            if !recipients.contains(&account) {
+                recipients.push(account);
+            }
+        }
+        recipients
+    }
+
+    /// Checks the registration of the payment recipients with the paid token, to register them before paying
+    fn register_accounts_then_transfer(
+        &self,
+        args: PaymentArgs,
+        token_address: AccountId,
+        payer: AccountId,
+        amount: U128,
+        return_change: bool,
+    ) -> Promise {
+        let accounts = self.payment_recipients(&args);
+        let storage_balances =
+            check_registrations(&args.paid_token(&token_address), &accounts, BASIC_GAS);
+        // The registrations and payment get all the gas left after the checks
+        storage_balances.then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(MIN_GAS)
+                .on_storage_balances(args, token_address, payer, amount, return_change, accounts),
+        )
+    }
+
+    /// Number of `ft_transfer` needed to pay `args`, fee and protocol fee transfers included
+    fn transfers_count(&self, args: &PaymentArgs) -> usize {
+        let protocol_fee_count = (self.treasury_id.is_some() && self.protocol_fee_bps > 0) as usize;
+        2 + args.fees.len() + protocol_fee_count
+    }
+
+    /// Protocol fee due on `amount` of `token_address`, where `amount` includes the protocol fee, or `None` if there is nothing to collect
+    fn protocol_fee(&self, token_address: &AccountId, amount: u128) -> Option<ProtocolFee> {
+        let treasury_id = self.treasury_id.clone()?;
+        if self.protocol_fee_bps == 0 || amount == 0 {
+            return None;
+        }
+        let mut fee_amount = fee_amount_from_bps(amount, self.protocol_fee_bps);
+        if let Some(caps) = self.protocol_fee_caps.get(token_address) {
+            fee_amount = fee_amount.max(caps.min_amount.0).min(caps.max_amount.0);
+        }
+        Some(ProtocolFee {
+            treasury_id,
+            amount: fee_amount.into(),
+        })
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::{testing_env, VMContext};
-    use near_sdk::{AccountId, Balance, MockedBlockchain};
-    use std::convert::TryInto;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, Balance, RuntimeFeesConfig, VMConfig, VMContext};
+    use proxy_storage::{StorageBalanceBounds, MAX_STORAGE_COST};
+
+    fn alice_account() -> AccountId {
+        "alice.near".parse().unwrap()
+    }
+
+    fn get_context(
+        predecessor_account_id: AccountId,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        is_view: bool,
+    ) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(0)
+            .epoch_height(1)
+            .account_balance(ntoy(1_000))
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
+    }
+
+    fn ntoy(near_amount: Balance) -> Balance {
+        near_amount * 10u128.pow(24)
+    }
+
+    /// Helper function: get default values for PaymentArgs
+    fn get_default_payment_args() -> PaymentArgs {
+        PaymentArgs {
+            claim_after: None,
+            escrow_timeout: None,
+            fee_address: "fee.requestfinance.near".parse().unwrap(),
+            fee_amount: 200.into(),
+            fee_bps: None,
+            fees: vec![],
+            intent: None,
+            payment_reference: "abc7c8bb1234fd12".into(),
+            register_accounts: None,
+            refund_to: None,
+            stream: None,
+            swap: None,
+            to: "dummy.payee.near".parse().unwrap(),
+        }
+    }
+
+    /// Helper function: convert a PaymentArgs into the msg string to be passed into `ft_transfer_call`
+    fn get_msg_from_args(args: PaymentArgs) -> String {
+        serde_json::to_string(&args).unwrap()
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Incorrect payment reference length"#)]
+    fn transfer_with_invalid_reference_length() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.payment_reference = "0x11223344556677".to_string();
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Payment reference value error"#)]
+    fn transfer_with_invalid_reference_value() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.payment_reference = "0x123".to_string();
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_not_enough_gas() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS - Gas(1), false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let args = get_default_payment_args();
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Incorrect msg format"#)]
+    fn transfer_with_invalid_msg_format() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let args = get_default_payment_args();
+        let msg = get_msg_from_args(args) + ".";
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Incorrect msg format"#)]
+    fn transfer_with_msg_missing_fields() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let args = get_default_payment_args();
+        let msg = get_msg_from_args(args).replace("\"fee_amount\":\"200\",", "");
+
+        contract.ft_on_transfer(alice_account(), 1000000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"amount smaller than fee_amount"#)]
+    fn transfer_less_than_fee_amount() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let args = get_default_payment_args();
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 100.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"amount smaller than fee_amount"#)]
+    fn transfer_less_than_total_fees() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS * 2, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![FeeRecipient {
+            address: "referrer.near".parse().unwrap(),
+            amount: 100.into(),
+        }];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 250.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"amount smaller than fee_amount"#)]
+    fn transfer_with_overflowing_fees() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_amount = u128::MAX.into();
+        args.fees = vec![FeeRecipient {
+            address: "referrer.near".parse().unwrap(),
+            amount: 1.into(),
+        }];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Too many fee recipients"#)]
+    fn transfer_with_too_many_fees() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS * 2, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![
+            FeeRecipient {
+                address: "referrer.near".parse().unwrap(),
+                amount: 1.into(),
+            };
+            MAX_FEE_RECIPIENTS + 1
+        ];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_fees_not_enough_gas() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![FeeRecipient {
+            address: "referrer.near".parse().unwrap(),
+            amount: 100.into(),
+        }];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    fn transfer_with_multiple_fees() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS * 2, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fees = vec![FeeRecipient {
+            address: "referrer.near".parse().unwrap(),
+            amount: 100.into(),
+        }];
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    const PAYEE_SECRET_KEY: [u8; 32] = [7; 32];
+
+    /// Registers the key of the default payee, and returns its intent to be paid `amount` of alice's token
+    fn register_payee_intent(contract: &mut FungibleProxy, amount: u128) -> SignedIntent {
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_payee_key(Some(payment_intents::public_key(&PAYEE_SECRET_KEY)));
+        PaymentIntent {
+            payment_reference: hex::decode("abc7c8bb1234fd12").unwrap(),
+            to: "dummy.payee.near".parse().unwrap(),
+            amount,
+            currency: alice_account().to_string(),
+            token: alice_account().to_string(),
+            expiry: 1_000,
+        }
+        .sign(&PAYEE_SECRET_KEY)
+    }
+
+    #[test]
+    fn transfer_with_intent() {
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        // 1000 attached, 200 of fee
+        args.intent = Some(register_payee_intent(&mut contract, 800));
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn transfer_with_intent_wrong_amount() {
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.intent = Some(register_payee_intent(&mut contract, 800));
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 900.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn transfer_with_intent_wrong_reference() {
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.intent = Some(register_payee_intent(&mut contract, 800));
+        args.payment_reference = "abc7c8bb1234fd11".into();
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INTENT_REQUIRED"#)]
+    fn transfer_without_intent_to_payee_with_key() {
+        let mut contract = FungibleProxy::default();
+        register_payee_intent(&mut contract, 800);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.ft_on_transfer(
+            alice_account(),
+            1000.into(),
+            get_msg_from_args(get_default_payment_args()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_SWAP_EXCLUSIVE"#)]
+    fn transfer_with_intent_and_swap() {
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.intent = Some(register_payee_intent(&mut contract, 800));
+        args.swap = Some(get_swap_args());
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    /// Registers the tokens accepted by the default payee
+    fn set_payee_tokens(contract: &mut FungibleProxy, tokens: Vec<String>) {
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_payee_preferences(Some(PayeePreferences {
+            tokens,
+            currencies: vec![],
+        }));
+    }
+
+    #[test]
+    fn transfer_in_accepted_token() {
+        let mut contract = FungibleProxy::default();
+        set_payee_tokens(
+            &mut contract,
+            vec!["usdc.near".into(), alice_account().to_string()],
+        );
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let msg = get_msg_from_args(get_default_payment_args());
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_TOKEN_NOT_ACCEPTED: dummy.payee.near does not accept payments in alice.near"#
+    )]
+    fn transfer_in_token_not_accepted() {
+        let mut contract = FungibleProxy::default();
+        set_payee_tokens(&mut contract, vec!["usdc.near".into()]);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let msg = get_msg_from_args(get_default_payment_args());
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_TOKEN_NOT_ACCEPTED: dummy.payee.near does not accept payments in token.near"#
+    )]
+    fn transfer_with_swap_to_token_not_accepted() {
+        let mut contract = FungibleProxy::default();
+        // The payee accepts the attached token, but is paid in the swapped token
+        set_payee_tokens(&mut contract, vec![alice_account().to_string()]);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_amount and fee_bps are mutually exclusive"#)]
+    fn transfer_with_fee_amount_and_fee_bps() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_bps = Some(100);
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"fee_bps should not exceed 10000"#)]
+    fn transfer_with_fee_bps_too_high() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(10_001);
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    fn transfer_with_fee_bps() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let mut args = get_default_payment_args();
+        args.fee_amount = 0.into();
+        args.fee_bps = Some(100);
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1010.into(), msg);
+    }
+
+    #[test]
+    fn fee_amount_from_basis_points() {
+        // 1% fee on top of 1000: 1010 is split into 1000 + 10
+        assert_eq!(fee_amount_from_bps(1010, 100), 10);
+        // Rounded down
+        assert_eq!(fee_amount_from_bps(1009, 100), 9);
+        // 100% fee: half of the amount
+        assert_eq!(fee_amount_from_bps(1000, MAX_BPS), 500);
+        assert_eq!(fee_amount_from_bps(1000, 0), 0);
+        // No overflow on large amounts
+        assert_eq!(fee_amount_from_bps(u128::MAX, MAX_BPS), u128::MAX / 2);
+    }
+
+    #[test]
+    fn transfer_with_reference() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+        testing_env!(context);
+        let mut contract = FungibleProxy::default();
+
+        let args = get_default_payment_args();
+        let msg = get_msg_from_args(args);
+
+        contract.ft_on_transfer(alice_account(), 1000001.into(), msg);
+    }
+
+    #[test]
+    fn test_get_transfer_with_reference_args() {
+        let context = get_context(alice_account(), ntoy(100), MIN_GAS, true);
+        testing_env!(context);
+
+        let expected_msg = r#"{"fee_address":"fee.requestfinance.near","fee_amount":"200","payment_reference":"abc7c8bb1234fd12","to":"dummy.payee.near"}"#;
+        let msg: String = get_default_payment_args().into();
+        assert_eq!(msg, expected_msg);
+    }
+
+    #[test]
+    fn migrate() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        env::state_write(&OldFungibleProxy {});
+        let contract = FungibleProxy::migrate();
+        assert_eq!(contract.owner_id, alice_account());
+        assert!(contract.exchanges.is_empty());
+        assert!(contract.registration_tokens.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_protocol_fee_no_permission() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        contract.set_protocol_fee(100, alice_account());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_owner_no_permission() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        testing_env!(get_context(
+            "bob.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_owner("bob.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PERMISSION"#)]
+    fn admin_wrap_account_no_permission() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        testing_env!(get_context(
+            "bob.near".parse().unwrap(),
+            ntoy(1),
+            MIN_GAS,
+            false
+        ));
+        contract.set_wrap_account("wrap.near".parse().unwrap());
+    }
+
+    #[test]
+    fn admin_wrap_account() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        assert!(contract.get_wrap_account().is_none());
+        contract.set_wrap_account("wrap.near".parse().unwrap());
+        assert_eq!(
+            contract.get_wrap_account(),
+            Some("wrap.near".parse().unwrap())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"No wrap account configured"#)]
+    fn wrap_and_transfer_without_wrap_account() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::new();
+        contract.wrap_and_transfer_with_reference(get_default_payment_args());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn wrap_and_transfer_not_enough_gas() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_wrap_account("wrap.near".parse().unwrap());
+        contract.wrap_and_transfer_with_reference(get_default_payment_args());
+    }
+
+    #[test]
+    fn wrap_and_transfer() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_wrap_account("wrap.near".parse().unwrap());
+        contract.wrap_and_transfer_with_reference(get_default_payment_args());
+    }
+
+    #[test]
+    fn near_wrapped_with_escrow() {
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS * 2, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let mut contract = FungibleProxy::default();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        let mut args = get_default_payment_args();
+        args.escrow_timeout = Some(1000.into());
+        args.refund_to = Some(bob.clone());
+        contract.on_near_wrapped(
+            args,
+            "wrap.near".parse().unwrap(),
+            alice_account(),
+            1000.into(),
+        );
+        // The escrow is refunded to `refund_to` later on
+        let escrow = contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(escrow.refund_to, Some(bob));
+    }
+
+    #[test]
+    fn near_unwrapped_failed() {
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        let mut contract = FungibleProxy::default();
+        let refunded = contract.on_near_unwrapped(
+            "wrap.near".parse().unwrap(),
+            "bob.near".parse().unwrap(),
+            500.into(),
+        );
+        assert_eq!(refunded.0, 500);
+        // The wrapped NEAR is returned instead
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.as_str(), "wrap.near");
+        match &receipts[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "ft_transfer");
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["receiver_id"], "bob.near");
+                assert_eq!(args["amount"], "500");
+            }
+            _ => panic!("Unexpected action"),
+        }
+    }
+
+    #[test]
+    fn deposit_storage_fund() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        assert_eq!(contract.deposit_storage_fund(None).0, ntoy(1));
+        assert_eq!(contract.deposit_storage_fund(None).0, ntoy(2));
+        contract.deposit_storage_fund(Some("bob.near".parse().unwrap()));
+        assert_eq!(contract.get_storage_fund(alice_account()).0, ntoy(2));
+        assert_eq!(
+            contract.get_storage_fund("bob.near".parse().unwrap()).0,
+            ntoy(1)
+        );
+    }
+
+    #[test]
+    fn withdraw_storage_fund() {
+        testing_env!(get_context(alice_account(), ntoy(3), MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        contract.deposit_storage_fund(None);
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        contract.withdraw_storage_fund(Some(ntoy(1).into()));
+        assert_eq!(contract.get_storage_fund(alice_account()).0, ntoy(2));
+        contract.withdraw_storage_fund(None);
+        assert_eq!(contract.get_storage_fund(alice_account()).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough storage fund"#)]
+    fn withdraw_storage_fund_too_much() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        contract.deposit_storage_fund(None);
+        testing_env!(get_context("bob.near".parse().unwrap(), 0, MIN_GAS, false));
+        contract.withdraw_storage_fund(Some(ntoy(1).into()));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_register_accounts_not_enough_gas() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    fn transfer_with_register_accounts() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::default();
+        // The predecessor of `ft_on_transfer` is the token
+        contract.registration_tokens.insert(alice_account());
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Registering accounts with alice.near is not allowed"#)]
+    fn transfer_with_register_accounts_token_not_allowed() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.ft_on_transfer(alice_account(), 1000.into(), get_msg_from_args(args));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Storage cost too high to register accounts"#)]
+    fn register_accounts_storage_cost_too_high() {
+        let bounds = StorageBalanceBounds {
+            min: (MAX_STORAGE_COST + 1).into(),
+            max: None,
+        };
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS * 2, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&bounds).unwrap()),
+                PromiseResult::Successful(b"null".to_vec()),
+            ]
+        );
+        let mut contract = FungibleProxy::default();
+        contract.storage_funds.deposit(&alice_account(), ntoy(1));
+        let mut args = get_default_payment_args();
+        args.register_accounts = Some(true);
+        contract.on_storage_balances(
+            args,
+            "token.near".parse().unwrap(),
+            alice_account(),
+            1000.into(),
+            false,
+            vec!["dummy.payee.near".parse().unwrap()],
+        );
+    }
+
+    #[test]
+    fn payment_legs_and_paid_fees() {
+        let mut args = get_default_payment_args();
+        args.fees = vec![
+            FeeRecipient {
+                address: "referrer.near".parse().unwrap(),
+                amount: 100.into(),
+            },
+            FeeRecipient {
+                address: "partner.near".parse().unwrap(),
+                amount: 50.into(),
+            },
+        ];
+        let protocol_fee = ProtocolFee {
+            treasury_id: "treasury.near".parse().unwrap(),
+            amount: 10.into(),
+        };
+        let transfers = payment_legs(&args, 1000, Some(&protocol_fee));
+        assert_eq!(
+            transfers
+                .iter()
+                .map(|transfer| (transfer.receiver_id.as_str(), transfer.amount.0))
+                .collect::<Vec<_>>(),
+            vec![
+                ("dummy.payee.near", 1000),
+                ("fee.requestfinance.near", 200),
+                ("referrer.near", 100),
+                ("partner.near", 50),
+                ("treasury.near", 10)
+            ]
+        );
+
+        let settlement = Settlement {
+            transfers,
+            results: vec![true, false, true, false, false],
+        };
+        assert!(!settlement.is_complete());
+        assert_eq!(settlement.failed_amount(), 260);
+        assert_eq!(
+            settlement.failed_receivers(),
+            "fee.requestfinance.near, partner.near, treasury.near"
+        );
+        let (args, protocol_fee) = paid_fees(args, Some(protocol_fee), &settlement);
+        assert_eq!(args.fee_amount.0, 0);
+        assert_eq!(args.fees.len(), 1);
+        assert_eq!(args.fees[0].address.to_string(), "referrer.near");
+        assert!(protocol_fee.is_none());
+    }
+
+    #[test]
+    fn payment_recipients() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+        let mut args = get_default_payment_args();
+        args.fees = vec![FeeRecipient {
+            address: "dummy.payee.near".parse().unwrap(),
+            amount: 100.into(),
+        }];
+        assert_eq!(
+            contract.payment_recipients(&args),
+            vec![
+                "dummy.payee.near".parse().unwrap(),
+                "fee.requestfinance.near".parse().unwrap(),
+                "treasury.near".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn admin_protocol_fee() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        assert_eq!(contract.get_owner(), alice_account());
+        assert!(contract.get_protocol_fee().treasury_id.is_none());
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+        let token: AccountId = "token.near".parse().unwrap();
+        contract.set_protocol_fee_caps(token.clone(), 10.into(), 500.into());
+        let schedule = contract.get_protocol_fee();
+        assert_eq!(schedule.bps, 100);
+        assert_eq!(schedule.treasury_id, Some("treasury.near".parse().unwrap()));
+        assert_eq!(schedule.caps[&token].min_amount.0, 10);
+        assert_eq!(schedule.caps[&token].max_amount.0, 500);
+        contract.remove_protocol_fee_caps(token);
+        assert!(contract.get_protocol_fee().caps.is_empty());
+    }
+
+    #[test]
+    fn protocol_fee_with_caps() {
+        testing_env!(get_context(alice_account(), ntoy(1), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        let token: AccountId = "token.near".parse().unwrap();
+        assert!(contract.protocol_fee(&token, 1010).is_none());
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+        // 1010 is split into 1000 for the payee and 10 for the treasury
+        assert_eq!(contract.protocol_fee(&token, 1010).unwrap().amount.0, 10);
+        contract.set_protocol_fee_caps(token.clone(), 5.into(), 8.into());
+        assert_eq!(contract.protocol_fee(&token, 1010).unwrap().amount.0, 8);
+        assert_eq!(contract.protocol_fee(&token, 101).unwrap().amount.0, 5);
+        assert!(contract.protocol_fee(&token, 0).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_protocol_fee_not_enough_gas() {
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+
+        let msg = get_msg_from_args(get_default_payment_args());
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
 
-    fn alice_account() -> AccountId {
-        "alice.near".to_string()
+    #[test]
+    #[should_panic(expected = r#"amount smaller than protocol fee"#)]
+    fn transfer_less_than_protocol_fee() {
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+        contract.set_protocol_fee_caps(alice_account(), 500.into(), 500.into());
+
+        let msg = get_msg_from_args(get_default_payment_args());
+
+        contract.ft_on_transfer(alice_account(), 300.into(), msg);
     }
 
-    fn get_context(
-        predecessor_account_id: AccountId,
-        attached_deposit: Balance,
-        prepaid_gas: Gas,
-        is_view: bool,
-    ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 0,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+    #[test]
+    fn transfer_with_protocol_fee() {
+        testing_env!(get_context(alice_account(), ntoy(100), MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::new();
+        contract.set_protocol_fee(100, "treasury.near".parse().unwrap());
+
+        let msg = get_msg_from_args(get_default_payment_args());
+
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
     }
 
-    fn ntoy(near_amount: Balance) -> Balance {
-        near_amount * 10u128.pow(24)
+    /// Helper function: a contract with 1000 tokens in escrow from alice to dummy.payee.near, refundable after 1000ns
+    fn contract_with_escrow() -> FungibleProxy {
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.escrow_timeout = Some(1000.into());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+        contract
     }
 
-    /// Helper function: get default values for PaymentArgs
-    fn get_default_payment_args() -> PaymentArgs {
-        PaymentArgs {
-            fee_address: "fee.requestfinance.near".to_string().try_into().unwrap(),
-            fee_amount: 200.into(),
-            payment_reference: "abc7c8bb1234fd12".into(),
-            to: "dummy.payee.near".to_string().try_into().unwrap(),
-        }
+    #[test]
+    fn transfer_with_escrow() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let contract = contract_with_escrow();
+
+        let escrow = contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(escrow.payer, alice_account());
+        assert_eq!(escrow.token_address, alice_account());
+        assert_eq!(escrow.to.as_str(), "dummy.payee.near");
+        assert_eq!(escrow.total_amount(), 1000);
+        assert_eq!(escrow.transfers[0].amount.0, 800);
+        assert_eq!(escrow.refund_after.0, 1000);
     }
 
-    /// Helper function: convert a PaymentArgs into the msg string to be passed into `ft_transfer_call`
-    fn get_msg_from_args(args: PaymentArgs) -> String {
-        serde_json::to_string(&args).unwrap()
+    #[test]
+    #[should_panic(expected = r#"An escrow already exists for this payment reference"#)]
+    fn transfer_with_existing_escrow() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_escrow();
+
+        let mut args = get_default_payment_args();
+        args.escrow_timeout = Some(1000.into());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
     }
 
     #[test]
-    #[should_panic(expected = r#"Incorrect payment reference length"#)]
-    fn transfer_with_invalid_reference_length() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
-        testing_env!(context);
-        let mut contract = FungibleProxy::default();
+    fn transfer_with_escrow_of_other_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_escrow();
 
         let mut args = get_default_payment_args();
-        args.payment_reference = "0x11223344556677".to_string();
+        args.escrow_timeout = Some(1000.into());
         let msg = get_msg_from_args(args);
+        let bob: AccountId = "bob.near".parse().unwrap();
+        contract.ft_on_transfer(bob.clone(), 1000.into(), msg);
+        let escrow = contract
+            .get_escrow("abc7c8bb1234fd12".into(), bob.clone())
+            .unwrap();
+        assert_eq!(escrow.payer, bob);
+    }
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+    #[test]
+    fn release_escrow() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_escrow();
+        contract.release_escrow("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
     }
 
     #[test]
-    #[should_panic(expected = r#"Payment reference value error"#)]
-    fn transfer_with_invalid_reference_value() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+    #[should_panic(expected = r#"Only the payer can release the escrow"#)]
+    fn release_escrow_not_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_escrow();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.release_escrow("abc7c8bb1234fd12".into(), alice_account());
+    }
+
+    #[test]
+    fn refund_escrow_by_payee() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_escrow();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS,
+            false
+        ));
+        contract.refund_escrow("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Only the payee, or the payer after refund_after, can refund the escrow"#
+    )]
+    fn refund_escrow_by_payer_too_early() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_escrow();
+        contract.refund_escrow("abc7c8bb1234fd12".into(), alice_account());
+    }
+
+    #[test]
+    fn refund_escrow_by_payer_after_timeout() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_escrow();
+        let mut context = get_context(alice_account(), 0, MIN_GAS, false);
+        context.block_timestamp = 1000;
+        testing_env!(context);
+        contract.refund_escrow("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"escrow_timeout and claim_after are mutually exclusive"#)]
+    fn transfer_with_escrow_timeout_and_claim_after() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.escrow_timeout = Some(1000.into());
+        args.claim_after = Some(1000.into());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"escrow_timeout is too large"#)]
+    fn transfer_with_escrow_timeout_too_large() {
+        let mut context = get_context(alice_account(), 0, MIN_GAS, false);
+        context.block_timestamp = 1;
         testing_env!(context);
         let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.escrow_timeout = Some(u64::MAX.into());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
 
+    /// Helper function: a contract with 1000 tokens scheduled from alice to dummy.payee.near, claimable after 1000ns
+    fn contract_with_scheduled_payment() -> FungibleProxy {
+        let mut contract = FungibleProxy::default();
         let mut args = get_default_payment_args();
-        args.payment_reference = "0x123".to_string();
+        args.claim_after = Some(1000.into());
         let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+        contract
+    }
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+    #[test]
+    #[should_panic(
+        expected = r#"Only the payer can release the escrow, or the payee after claim_after"#
+    )]
+    fn claim_scheduled_payment_too_early() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_scheduled_payment();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.release_escrow("abc7c8bb1234fd12".into(), alice_account());
     }
 
     #[test]
-    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
-    fn transfer_with_not_enough_gas() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS - 1, false);
+    fn claim_scheduled_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_scheduled_payment();
+        let mut context = get_context("dummy.payee.near".parse().unwrap(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 1000;
+        testing_env!(context);
+        contract.release_escrow("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    fn cancel_scheduled_payment() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_scheduled_payment();
+        contract.refund_escrow("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_escrow("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Only the payee, or the payer before claim_after, can cancel the payment"#
+    )]
+    fn cancel_scheduled_payment_too_late() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_scheduled_payment();
+        let mut context = get_context(alice_account(), 0, MIN_GAS, false);
+        context.block_timestamp = 1000;
         testing_env!(context);
+        contract.refund_escrow("abc7c8bb1234fd12".into(), alice_account());
+    }
+
+    /// Helper function: a contract with 800 tokens streamed from alice to dummy.payee.near over 8 seconds
+    fn contract_with_stream() -> FungibleProxy {
         let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.stream = Some(StreamArgs {
+            rate: 100.into(),
+            start: 0.into(),
+            end: 8_000_000_000.into(),
+        });
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+        contract
+    }
 
-        let args = get_default_payment_args();
+    #[test]
+    fn stream_vested_amount() {
+        let stream = Stream {
+            payer: alice_account(),
+            token_address: alice_account(),
+            to: "dummy.payee.near".parse().unwrap(),
+            fee_address: "fee.requestfinance.near".parse().unwrap(),
+            rate: 3.into(),
+            start: 1_000_000_000.into(),
+            end: 4_000_000_000.into(),
+            withdrawn: 2.into(),
+            refund_to: None,
+            pending_refund: 0.into(),
+        };
+        assert_eq!(stream.total_amount(), 9);
+        assert_eq!(stream.vested_amount(0), 0);
+        assert_eq!(stream.vested_amount(2_500_000_000), 4);
+        assert_eq!(stream.withdrawable_amount(2_500_000_000), 2);
+        assert_eq!(stream.vested_amount(5_000_000_000), 9);
+    }
+
+    #[test]
+    fn refund_account() {
+        let mut args = get_default_payment_args();
+        assert_eq!(args.refund_account(&alice_account()), alice_account());
+        let msg = get_msg_from_args(args.clone());
+        assert!(!msg.contains("refund_to"));
+
+        args.refund_to = Some("refund.near".parse().unwrap());
+        assert_eq!(
+            args.refund_account(&alice_account()).as_str(),
+            "refund.near"
+        );
+        let args: PaymentArgs = get_msg_from_args(args).into();
+        assert_eq!(
+            args.refund_account(&alice_account()).as_str(),
+            "refund.near"
+        );
+    }
+
+    #[test]
+    fn transfer_with_stream() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let contract = contract_with_stream();
+
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(stream.payer, alice_account());
+        assert_eq!(stream.to.as_str(), "dummy.payee.near");
+        assert_eq!(stream.total_amount(), 800);
+        assert_eq!(stream.withdrawn.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"amount smaller than stream total"#)]
+    fn transfer_less_than_stream_total() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.stream = Some(StreamArgs {
+            rate: 101.into(),
+            start: 0.into(),
+            end: 8_000_000_000.into(),
+        });
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"stream end should be after its start"#)]
+    fn transfer_with_invalid_stream() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.stream = Some(StreamArgs {
+            rate: 100.into(),
+            start: 8_000_000_000.into(),
+            end: 8_000_000_000.into(),
+        });
         let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
 
-        contract.ft_on_transfer(alice_account(), "1".into(), msg);
+    #[test]
+    fn stream_references_by_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        // Another payer can stream under the same payment reference
+        let mut args = get_default_payment_args();
+        args.stream = Some(StreamArgs {
+            rate: 50.into(),
+            start: 0.into(),
+            end: 8_000_000_000.into(),
+        });
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer("bob.near".parse().unwrap(), 1000.into(), msg);
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), "bob.near".parse().unwrap())
+            .unwrap();
+        assert_eq!(stream.total_amount(), 400);
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(stream.total_amount(), 800);
     }
 
     #[test]
-    #[should_panic(expected = r#"Incorrect msg format"#)]
-    fn transfer_with_invalid_msg_format() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+    #[should_panic(expected = r#"A stream already exists for this payment reference"#)]
+    fn transfer_with_existing_stream() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        let mut args = get_default_payment_args();
+        args.stream = Some(StreamArgs {
+            rate: 100.into(),
+            start: 0.into(),
+            end: 8_000_000_000.into(),
+        });
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 1000.into(), msg);
+    }
+
+    #[test]
+    fn withdraw_stream() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        let mut context = get_context("dummy.payee.near".parse().unwrap(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 2_000_000_000;
         testing_env!(context);
-        let mut contract = FungibleProxy::default();
+        assert_eq!(
+            contract
+                .get_withdrawable_amount("abc7c8bb1234fd12".into(), alice_account())
+                .0,
+            200
+        );
+        contract.withdraw_stream("abc7c8bb1234fd12".into(), alice_account());
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(stream.withdrawn.0, 200);
+    }
 
-        let args = get_default_payment_args();
-        let msg = get_msg_from_args(args) + ".";
+    #[test]
+    #[should_panic(expected = r#"Nothing to withdraw"#)]
+    fn withdraw_stream_before_start() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.withdraw_stream("abc7c8bb1234fd12".into(), alice_account());
+    }
 
-        contract.ft_on_transfer(alice_account(), "1000".into(), msg);
+    #[test]
+    #[should_panic(expected = r#"Only the payee can withdraw from the stream"#)]
+    fn withdraw_stream_not_payee() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_stream();
+        contract.withdraw_stream("abc7c8bb1234fd12".into(), alice_account());
     }
 
     #[test]
-    #[should_panic(expected = r#"Incorrect msg format"#)]
-    fn transfer_with_msg_missing_fields() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+    fn cancel_stream() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        let mut context = get_context(alice_account(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 2_000_000_000;
         testing_env!(context);
-        let mut contract = FungibleProxy::default();
+        contract.cancel_stream("abc7c8bb1234fd12".into(), alice_account());
+        assert!(contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .is_none());
+    }
 
-        let args = get_default_payment_args();
-        let msg = get_msg_from_args(args).replace("\"fee_amount\":\"200\",", "");
+    #[test]
+    fn stream_withdrawal_failed_after_cancel() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        let mut context = get_context("dummy.payee.near".parse().unwrap(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 2_000_000_000;
+        testing_env!(context.clone());
+        contract.withdraw_stream("abc7c8bb1234fd12".into(), alice_account());
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        context.predecessor_account_id = alice_account();
+        testing_env!(context.clone());
+        contract.cancel_stream("abc7c8bb1234fd12".into(), alice_account());
 
-        contract.ft_on_transfer(alice_account(), "1000000".into(), msg);
+        context.block_timestamp = 3_000_000_000;
+        testing_env!(
+            context,
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.on_stream_withdrawal("abc7c8bb1234fd12".into(), stream, 200.into()));
+        // The failed withdrawal can be withdrawn again, and nothing more
+        assert_eq!(
+            contract
+                .get_withdrawable_amount("abc7c8bb1234fd12".into(), alice_account())
+                .0,
+            200
+        );
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(stream.total_amount(), 200);
+        assert_eq!(stream.to.as_str(), "dummy.payee.near");
     }
 
     #[test]
-    #[should_panic(expected = r#"amount smaller than fee_amount"#)]
-    fn transfer_less_than_fee_amount() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
+    fn stream_refund_failed_on_cancel() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        let mut context = get_context(alice_account(), 0, MIN_GAS * 2, false);
+        context.block_timestamp = 2_000_000_000;
+        testing_env!(context.clone());
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        contract.cancel_stream("abc7c8bb1234fd12".into(), alice_account());
+
+        // The payee was paid 200, the refund of 600 failed
+        context.block_timestamp = 3_000_000_000;
+        testing_env!(
+            context.clone(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+        );
+        assert!(!contract.on_stream_cancelled(
+            "abc7c8bb1234fd12".into(),
+            stream,
+            2_000_000_000.into(),
+            200.into(),
+            600.into(),
+        ));
+        // Nothing vests after the cancellation
+        let stream = contract
+            .get_stream("abc7c8bb1234fd12".into(), alice_account())
+            .unwrap();
+        assert_eq!(stream.end.0, 2_000_000_000);
+        assert_eq!(stream.pending_refund.0, 600);
+        assert_eq!(
+            contract
+                .get_withdrawable_amount("abc7c8bb1234fd12".into(), alice_account())
+                .0,
+            0
+        );
+
+        // Cancelling again refunds the pending refund only
         testing_env!(context);
+        contract.cancel_stream("abc7c8bb1234fd12".into(), alice_account());
+        let refunds: Vec<Balance> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "ft_transfer" => {
+                    let args: serde_json::Value = serde_json::from_slice(&args).unwrap();
+                    assert_eq!(args["receiver_id"], json!(alice_account()));
+                    Some(args["amount"].as_str().unwrap().parse().unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(refunds, vec![600]);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the payer can cancel the stream"#)]
+    fn cancel_stream_not_payer() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_stream();
+        testing_env!(get_context(
+            "dummy.payee.near".parse().unwrap(),
+            0,
+            MIN_GAS * 2,
+            false
+        ));
+        contract.cancel_stream("abc7c8bb1234fd12".into(), alice_account());
+    }
+
+    /// Helper function: swap of the payment token to token.near, for at least 1000
+    fn get_swap_args() -> SwapArgs {
+        SwapArgs {
+            exchange_id: "exchange.near".parse().unwrap(),
+            pool_id: 0,
+            token_out: "token.near".parse().unwrap(),
+            min_amount_out: 1000.into(),
+        }
+    }
+
+    /// Helper function: a contract allowing swaps through exchange.near
+    fn contract_with_exchange() -> FungibleProxy {
         let mut contract = FungibleProxy::default();
+        contract.exchanges.insert("exchange.near".parse().unwrap());
+        contract
+    }
 
-        let args = get_default_payment_args();
+    #[test]
+    fn transfer_with_swap() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
         let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 500.into(), msg);
+    }
 
-        contract.ft_on_transfer(alice_account(), "100".into(), msg);
+    #[test]
+    #[should_panic(expected = r#"Not enough attached Gas to call this method"#)]
+    fn transfer_with_swap_not_enough_gas() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 500.into(), msg);
     }
 
     #[test]
-    fn transfer_with_reference() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, false);
-        testing_env!(context);
-        let mut contract = FungibleProxy::default();
+    #[should_panic(expected = r#"amount smaller than fee_amount"#)]
+    fn transfer_with_swap_min_amount_out_less_than_fee_amount() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        let mut swap = get_swap_args();
+        swap.min_amount_out = 100.into();
+        args.swap = Some(swap);
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 500.into(), msg);
+    }
 
-        let args = get_default_payment_args();
+    #[test]
+    #[should_panic(
+        expected = r#"swap is exclusive with escrow_timeout, claim_after, stream and intent"#
+    )]
+    fn transfer_with_swap_and_escrow() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        args.escrow_timeout = Some(1000.into());
         let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 500.into(), msg);
+    }
 
-        contract.ft_on_transfer(alice_account(), "1000001".into(), msg);
+    #[test]
+    #[should_panic(expected = r#"Swaps through exchange.near are not allowed"#)]
+    fn transfer_with_swap_exchange_not_allowed() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS * 2, false));
+        let mut contract = FungibleProxy::default();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        let msg = get_msg_from_args(args);
+        contract.ft_on_transfer(alice_account(), 500.into(), msg);
     }
 
     #[test]
-    fn test_get_transfer_with_reference_args() {
-        let context = get_context(alice_account(), ntoy(100), MIN_GAS, true);
-        testing_env!(context);
+    #[should_panic(expected = r#"Only the owner can call this method"#)]
+    fn admin_exchange_no_permission() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        testing_env!(get_context("bob.near".parse().unwrap(), 0, MIN_GAS, false));
+        contract.add_exchange("exchange.near".parse().unwrap());
+    }
 
-        let expected_msg = r#"{"fee_address":"fee.requestfinance.near","fee_amount":"200","payment_reference":"abc7c8bb1234fd12","to":"dummy.payee.near"}"#;
-        let msg: String = get_default_payment_args().into();
-        assert_eq!(msg, expected_msg);
+    #[test]
+    fn admin_exchanges() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = FungibleProxy::new();
+        contract.add_exchange("exchange.near".parse().unwrap());
+        assert_eq!(
+            contract.get_exchanges(),
+            vec!["exchange.near".parse::<AccountId>().unwrap()]
+        );
+        contract.remove_exchange("exchange.near".parse().unwrap());
+        assert!(contract.get_exchanges().is_empty());
+    }
+
+    /// Helper function: calls `on_swap_withdrawn` for an exchange reporting 10000 of token.near swapped, while the
+    /// proxy balance increased by `received`. Returns the `ft_transfer`s made.
+    fn swap_withdrawn_transfers(received: Balance) -> Vec<(AccountId, Balance)> {
+        let balance_before: Balance = 50_000;
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&U128(balance_before + received)).unwrap()
+            )]
+        );
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        contract.on_swap_withdrawn(
+            args,
+            "payment.near".parse().unwrap(),
+            alice_account(),
+            500.into(),
+            10_000.into(),
+            balance_before.into(),
+        );
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "ft_transfer" => {
+                    let args: serde_json::Value = serde_json::from_slice(&args).unwrap();
+                    Some((
+                        args["receiver_id"].as_str().unwrap().parse().unwrap(),
+                        args["amount"].as_str().unwrap().parse().unwrap(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn swap_withdrawn_pays_received_amount() {
+        let transfers = swap_withdrawn_transfers(1_500);
+        assert_eq!(
+            transfers,
+            vec![
+                ("dummy.payee.near".parse().unwrap(), 1_300),
+                ("fee.requestfinance.near".parse().unwrap(), 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn swap_withdrawn_pays_at_most_amount_out() {
+        let transfers = swap_withdrawn_transfers(20_000);
+        assert_eq!(
+            transfers.iter().map(|(_, amount)| amount).sum::<Balance>(),
+            10_000
+        );
+    }
+
+    #[test]
+    fn swap_withdrawn_below_min_amount_out() {
+        // Less than min_amount_out received is returned to the payer
+        let transfers = swap_withdrawn_transfers(500);
+        assert_eq!(transfers, vec![(alice_account(), 500)]);
+        assert!(swap_withdrawn_transfers(0).is_empty());
+    }
+
+    /// Helper function: alice's balance of `token_id` kept on exchange.near
+    fn exchange_balance(contract: &FungibleProxy, token_id: &str) -> Balance {
+        contract
+            .get_exchange_balance(
+                alice_account(),
+                "exchange.near".parse().unwrap(),
+                token_id.parse().unwrap(),
+            )
+            .0
+    }
+
+    #[test]
+    fn swap_withdrawal_failed_kept_on_exchange() {
+        // The proxy balance did not increase
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&U128(50_000)).unwrap()
+            )]
+        );
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        let unused = contract.on_swap_withdrawn(
+            args,
+            "payment.near".parse().unwrap(),
+            alice_account(),
+            500.into(),
+            10_000.into(),
+            50_000.into(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        assert_eq!(exchange_balance(&contract, "token.near"), 10_000);
+    }
+
+    #[test]
+    fn swap_refund_failed_kept_on_exchange() {
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        contract.on_swap_refunded(
+            args.clone(),
+            "payment.near".parse().unwrap(),
+            alice_account(),
+            500.into(),
+        );
+        contract.on_swap_refunded(
+            args,
+            "payment.near".parse().unwrap(),
+            alice_account(),
+            300.into(),
+        );
+        assert_eq!(exchange_balance(&contract, "payment.near"), 800);
+        assert_eq!(exchange_balance(&contract, "token.near"), 0);
+    }
+
+    #[test]
+    fn swap_balance_check_failed_kept_on_exchange() {
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&U128(10_000)).unwrap()),
+                PromiseResult::Failed,
+            ]
+        );
+        let mut contract = contract_with_exchange();
+        let mut args = get_default_payment_args();
+        args.swap = Some(get_swap_args());
+        args.refund_to = Some("refund.near".parse().unwrap());
+        contract.on_swapped(
+            args,
+            "payment.near".parse().unwrap(),
+            alice_account(),
+            500.into(),
+        );
+        // Kept for `refund_to`
+        assert_eq!(exchange_balance(&contract, "token.near"), 0);
+        let balance = contract.get_exchange_balance(
+            "refund.near".parse().unwrap(),
+            "exchange.near".parse().unwrap(),
+            "token.near".parse().unwrap(),
+        );
+        assert_eq!(balance.0, 10_000);
+    }
+
+    #[test]
+    fn withdraw_from_exchange() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_exchange();
+        contract.keep_on_exchange(
+            alice_account(),
+            "exchange.near".parse().unwrap(),
+            "token.near".parse().unwrap(),
+            10_000,
+        );
+        contract.withdraw_from_exchange(
+            "exchange.near".parse().unwrap(),
+            "token.near".parse().unwrap(),
+        );
+        // Removed until the withdrawal fails
+        assert_eq!(exchange_balance(&contract, "token.near"), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Nothing to withdraw"#)]
+    fn withdraw_from_exchange_nothing_kept() {
+        testing_env!(get_context(alice_account(), 0, MIN_GAS, false));
+        let mut contract = contract_with_exchange();
+        contract.withdraw_from_exchange(
+            "exchange.near".parse().unwrap(),
+            "token.near".parse().unwrap(),
+        );
+    }
+
+    #[test]
+    fn exchange_claim_unregistered_kept_on_exchange() {
+        testing_env!(
+            get_context(alice_account(), 0, MIN_GAS, false),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(serde_json::to_vec(&U128(50_000)).unwrap()),
+                PromiseResult::Successful(b"null".to_vec()),
+            ]
+        );
+        let mut contract = contract_with_exchange();
+        contract.on_exchange_claim_checked(
+            alice_account(),
+            "exchange.near".parse().unwrap(),
+            "token.near".parse().unwrap(),
+            10_000.into(),
+        );
+        assert_eq!(exchange_balance(&contract, "token.near"), 10_000);
+    }
+
+    #[test]
+    fn exchange_claim_withdrawn() {
+        let claim = |balance_after: Balance| {
+            testing_env!(
+                get_context(alice_account(), 0, MIN_GAS, false),
+                VMConfig::test(),
+                RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![PromiseResult::Successful(
+                    serde_json::to_vec(&U128(balance_after)).unwrap()
+                )]
+            );
+            let mut contract = contract_with_exchange();
+            let received = contract.on_exchange_claim_withdrawn(
+                alice_account(),
+                "exchange.near".parse().unwrap(),
+                "token.near".parse().unwrap(),
+                10_000.into(),
+                50_000.into(),
+            );
+            (received.0, exchange_balance(&contract, "token.near"))
+        };
+        assert_eq!(claim(60_000), (10_000, 0));
+        assert_eq!(claim(70_000), (10_000, 0));
+        // The withdrawal failed
+        assert_eq!(claim(50_000), (0, 10_000));
     }
 }
@@ -1,4 +1,4 @@
 #!/bin/bash
 set -e
 
-RUSTFLAGS='-C link-arg=-s' cargo build -p mocks --target wasm32-unknown-unknown
+RUSTFLAGS='-C link-arg=-s' cargo build -p mocks -p reference_ft_mock --target wasm32-unknown-unknown
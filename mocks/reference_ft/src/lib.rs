@@ -0,0 +1,65 @@
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+
+/**
+ * Reference fungible token (NEP-141 and NEP-145), implemented with `near-contract-standards`.
+ * Unlike the `mocks` fungible token, balances can only change through the standard methods:
+ * payments go through `ft_transfer_call`, and unused amounts are refunded by `ft_resolve_transfer`.
+ */
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct ReferenceFungibleToken {
+    token: FungibleToken,
+}
+
+#[near_bindgen]
+impl ReferenceFungibleToken {
+    /// Creates the token with a `total_supply` owned by `owner_id`
+    #[init]
+    pub fn new(owner_id: AccountId, total_supply: U128) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        let mut this = Self {
+            token: FungibleToken::new(b"a".to_vec()),
+        };
+        this.token.internal_register_account(&owner_id);
+        this.token.internal_deposit(&owner_id, total_supply.into());
+        this
+    }
+
+    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        log!("Closed @{} with {}", account_id, balance);
+    }
+
+    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
+        log!("Account @{} burned {}", account_id, amount);
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(ReferenceFungibleToken, token, on_tokens_burned);
+near_contract_standards::impl_fungible_token_storage!(
+    ReferenceFungibleToken,
+    token,
+    on_account_closed
+);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for ReferenceFungibleToken {
+    /// Same metadata as the `mocks` fungible token, known by the oracle mocks
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "USD Coin".into(),
+            symbol: "USDC.e".into(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 6,
+        }
+    }
+}
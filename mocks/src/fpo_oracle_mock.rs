@@ -3,6 +3,7 @@ use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::Timestamp;
 use near_sdk::{env, near_bindgen, AccountId};
+use std::collections::HashMap;
 
 /**
  * Mocking the Flux oracle contract
@@ -16,10 +17,23 @@ pub struct PriceEntry {
     pub last_update: Timestamp, // Time of report
 }
 
-// For mocks: state of Flux price oracle
+// For mocks: a price set by the owner, updated at `last_update`, or 10 nanoseconds before each read if `None`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MockedPrice {
+    pub price: U128,
+    pub decimals: u16,
+    pub last_update: Option<Timestamp>,
+}
+
+// For mocks: state of Flux price oracle, with the prices set by the owner overriding the default ones
 #[near_bindgen]
 #[derive(Default, BorshDeserialize, BorshSerialize)]
-pub struct FPOContract {}
+pub struct FPOContract {
+    // `None` for a pair without entry
+    prices: HashMap<String, Option<MockedPrice>>,
+    // Error `get_entry` panics with
+    error: Option<String>,
+}
 
 /**
  * Mocked FPO contract for tests
@@ -29,6 +43,18 @@ pub struct FPOContract {}
 impl FPOContract {
     #[allow(unused_variables)]
     pub fn get_entry(&self, pair: String, provider: AccountId) -> Option<PriceEntry> {
+        if let Some(error) = &self.error {
+            panic!("{}", error);
+        }
+        if let Some(price) = self.prices.get(&pair) {
+            return price.as_ref().map(|price| PriceEntry {
+                price: price.price,
+                decimals: price.decimals,
+                last_update: price
+                    .last_update
+                    .unwrap_or_else(|| env::block_timestamp() - 10),
+            });
+        }
         match &*pair {
             "NEAR/USD" => Some(PriceEntry {
                 // 1 NEAR = 1.234 USD, 10 nanoseconds ago
@@ -45,6 +71,46 @@ impl FPOContract {
             _ => None,
         }
     }
+
+    /// Helper function for testing: sets the price of `pair`, last updated at `last_update`, or just before each
+    /// read if `None`. Only callable by the owner, i.e. the mock account.
+    pub fn set_price(
+        &mut self,
+        pair: String,
+        price: U128,
+        decimals: u16,
+        last_update: Option<Timestamp>,
+    ) {
+        assert_owner();
+        self.prices.insert(
+            pair,
+            Some(MockedPrice {
+                price,
+                decimals,
+                last_update,
+            }),
+        );
+    }
+
+    /// Helper function for testing: `pair` has no entry, as when delayed or not provided
+    pub fn remove_price(&mut self, pair: String) {
+        assert_owner();
+        self.prices.insert(pair, None);
+    }
+
+    /// Helper function for testing: `get_entry` panics with `error`, or works again if `None`
+    pub fn set_error(&mut self, error: Option<String>) {
+        assert_owner();
+        self.error = error;
+    }
+}
+
+fn assert_owner() {
+    assert_eq!(
+        env::predecessor_account_id(),
+        env::current_account_id(),
+        "Only the owner can set the mock state"
+    );
 }
 
 #[cfg(test)]
@@ -52,8 +118,8 @@ mod tests {
 
     use super::*;
     use crate::fpo_oracle_mock::AccountId;
-    use near_sdk::{testing_env, Balance, Gas, MockedBlockchain, VMContext};
-    use near_sdk_sim::to_yocto;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, Balance, Gas, VMContext};
 
     fn get_context(
         predecessor_account_id: AccountId,
@@ -61,31 +127,31 @@ mod tests {
         prepaid_gas: Gas,
         is_view: bool,
     ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 10,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(10)
+            .epoch_height(1)
+            .account_balance(0)
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
     }
     #[test]
     fn get_entry() {
-        let context = get_context("alice.near".to_string(), to_yocto("1"), 10u64.pow(14), true);
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            true,
+        );
         testing_env!(context);
         let contract = FPOContract::default();
-        if let Some(result) = contract.get_entry("NEAR/USD".to_string(), "any".to_string()) {
+        if let Some(result) = contract.get_entry("NEAR/USD".to_string(), "any".parse().unwrap()) {
             assert_eq!(result.price, U128::from(1234000));
             assert_eq!(result.decimals, 6);
         } else {
@@ -94,14 +160,72 @@ mod tests {
     }
     #[test]
     fn get_missing_pair_entry() {
-        let context = get_context("alice.near".to_string(), to_yocto("1"), 10u64.pow(14), true);
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            true,
+        );
         testing_env!(context);
         let contract = FPOContract::default();
-        assert_eq!(
-            contract
-                .get_entry("NEAR/WRONG".to_string(), "any".to_string())
-                .is_none(),
-            true
+        assert!(contract
+            .get_entry("NEAR/WRONG".to_string(), "any".parse().unwrap())
+            .is_none());
+    }
+    #[test]
+    fn get_set_price_entry() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false,
         );
+        testing_env!(context);
+        let mut contract = FPOContract::default();
+        contract.set_price("NEAR/USD".to_string(), U128::from(2500), 3, Some(5));
+        contract.set_price("NEAR/EUR".to_string(), U128::from(2000), 3, None);
+        let result = contract
+            .get_entry("NEAR/USD".to_string(), "any".parse().unwrap())
+            .unwrap();
+        assert_eq!(result.price, U128::from(2500));
+        assert_eq!(result.decimals, 3);
+        assert_eq!(result.last_update, 5);
+        let result = contract
+            .get_entry("NEAR/EUR".to_string(), "any".parse().unwrap())
+            .unwrap();
+        assert_eq!(result.last_update, 0);
+
+        contract.remove_price("NEAR/USD".to_string());
+        assert!(contract
+            .get_entry("NEAR/USD".to_string(), "any".parse().unwrap())
+            .is_none());
+    }
+    #[test]
+    #[should_panic(expected = r#"Oracle unavailable"#)]
+    fn get_entry_with_error() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false,
+        );
+        testing_env!(context);
+        let mut contract = FPOContract::default();
+        contract.set_error(Some("Oracle unavailable".to_string()));
+        contract.get_entry("NEAR/USD".to_string(), "any".parse().unwrap());
+    }
+    #[test]
+    #[should_panic(expected = r#"Only the owner can set the mock state"#)]
+    fn set_price_not_owner() {
+        let mut context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false,
+        );
+        context.current_account_id = "mockedfpo.near".parse().unwrap();
+        testing_env!(context);
+        let mut contract = FPOContract::default();
+        contract.set_price("NEAR/USD".to_string(), U128::from(2500), 3, None);
     }
 }
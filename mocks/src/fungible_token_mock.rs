@@ -2,15 +2,22 @@ use near_sdk::assert_one_yocto;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{Base64VecU8, U128};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, near_bindgen, AccountId};
+use near_sdk::{
+    env, near_bindgen, serde_json, AccountId, Balance, Gas, Promise, PromiseOrValue, PromiseResult,
+};
 use std::collections::HashMap;
 
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER.0);
+const STORAGE_BALANCE_MIN: Balance = 1_250_000_000_000_000_000_000; // 0.00125 NEAR, as on wrap.near
+const MAX_DECIMALS: u8 = 24;
+
 /**
  * Mocking a fungible token contract (NEP-141)
  */
 
 // Return type of a fungible token metadata
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 pub struct FungibleTokenMetadata {
     pub spec: String,
     pub name: String,
@@ -21,11 +28,54 @@ pub struct FungibleTokenMetadata {
     pub decimals: u8,
 }
 
+// Storage balance of a registered account (NEP-145)
+#[derive(Serialize, Deserialize)]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+// Storage deposit bounds (NEP-145)
+#[derive(Serialize, Deserialize)]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+// Interface of fungible token receivers
+#[near_sdk::ext_contract(ext_receiver)]
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+// Callback of `ft_transfer_call`
+#[near_sdk::ext_contract(ext_self)]
+pub trait FungibleTokenResolver {
+    fn ft_resolve_transfer(sender_id: AccountId, receiver_id: AccountId, amount: String);
+}
+
+// For mocks: opt-in pathological behaviors of the token, set by the owner
+#[derive(Default, BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MockedBehaviors {
+    // Transfers of 0 tokens panic, as with the `near-contract-standards` token
+    pub reject_zero_transfers: bool,
+    // `ft_metadata` panics
+    pub panic_in_metadata: bool,
+    // Gas burnt by `ft_transfer`, `ft_transfer_call` and `ft_metadata`, in Tgas
+    pub burnt_tgas: u64,
+    // Fee in basis points withheld from each transfer, the receiver being credited the rest
+    pub transfer_fee_bps: u16,
+}
+
 // For mocks: state of a fungible token
 #[near_bindgen]
 #[derive(Default, BorshDeserialize, BorshSerialize, Serialize)]
 pub struct FungibleTokenContract {
     balances: HashMap<AccountId, U128>,
+    // `None` for the default USDC.e metadata
+    metadata: Option<FungibleTokenMetadata>,
+    behaviors: MockedBehaviors,
 }
 
 /**
@@ -42,42 +92,230 @@ impl FungibleTokenContract {
     #[payable]
     pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: String, memo: Option<String>) {
         assert_one_yocto();
-        assert!(
-            self.balances.contains_key(&env::predecessor_account_id()),
-            "sender is not registered with fungible token contract"
+        self.burn_gas();
+        self.internal_transfer(
+            env::predecessor_account_id(),
+            receiver_id,
+            amount.parse::<u128>().unwrap(),
         );
-        assert!(
-            self.balances.contains_key(&receiver_id),
-            "receiver is not registered with fungible token contract"
+    }
+
+    /// Simulates a fungible token transfer to a contract, followed by a call to `ft_on_transfer` on that contract.
+    /// The amount that the receiver does not use is refunded by `ft_resolve_transfer`.
+    /// The gas burnt by the token (see `set_behaviors`) is not forwarded to the receiver.
+    #[allow(unused_variables)]
+    #[payable]
+    pub fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: String,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        self.burn_gas();
+        let sender_id = env::predecessor_account_id();
+        let received_amount = self.internal_transfer(
+            sender_id.clone(),
+            receiver_id.clone(),
+            amount.parse::<u128>().unwrap(),
         );
+        ext_receiver::ext(receiver_id.clone())
+            .with_static_gas(
+                env::prepaid_gas()
+                    - GAS_FOR_FT_TRANSFER_CALL
+                    - Gas::ONE_TERA * self.behaviors.burnt_tgas,
+            )
+            .ft_on_transfer(sender_id.clone(), U128::from(received_amount), msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .ft_resolve_transfer(sender_id, receiver_id, received_amount.to_string()),
+            )
+    }
 
-        let amt = amount.parse::<u128>().unwrap();
+    /// Refunds the amount unused by the receiver of `ft_transfer_call`, the full amount if the call failed,
+    /// and returns the amount used
+    #[private]
+    pub fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: String,
+    ) -> U128 {
+        let amount = amount.parse::<u128>().unwrap();
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map_or(amount, |unused_amount| unused_amount.0.min(amount)),
+            _ => amount,
+        };
+        let receiver_balance = self.ft_balance_of(receiver_id.clone()).0;
+        let refund_amount = unused_amount.min(receiver_balance);
+        if refund_amount > 0 {
+            let sender_balance = self.ft_balance_of(sender_id.clone()).0;
+            self.set_balance(receiver_id, U128::from(receiver_balance - refund_amount));
+            self.set_balance(sender_id, U128::from(sender_balance + refund_amount));
+        }
+        U128::from(amount - refund_amount)
+    }
 
+    /// Simulates wrapping NEAR (as on `wrap.near`): the attached deposit is credited to the registered caller
+    #[payable]
+    pub fn near_deposit(&mut self) {
+        let account_id = env::predecessor_account_id();
         assert!(
-            self.balances[&env::predecessor_account_id()].0 >= amt,
-            "sender balance is insufficient"
+            self.balances.contains_key(&account_id),
+            "The account {} is not registered",
+            account_id
         );
+        let balance = self.ft_balance_of(account_id.clone());
+        self.set_balance(account_id, U128::from(balance.0 + env::attached_deposit()));
+    }
 
-        let old_sender_balance = self.ft_balance_of(env::predecessor_account_id());
-        let old_receiver_balance = self.ft_balance_of(receiver_id.to_string());
+    /// Simulates unwrapping NEAR (as on `wrap.near`): `amount` is debited, and sent to the caller in NEAR
+    #[payable]
+    pub fn near_withdraw(&mut self, amount: U128) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self.ft_balance_of(account_id.clone());
+        assert!(balance.0 >= amount.0, "sender balance is insufficient");
+        self.set_balance(account_id.clone(), U128::from(balance.0 - amount.0));
+        Promise::new(account_id).transfer(amount.0)
+    }
 
-        self.set_balance(
-            env::predecessor_account_id(),
-            U128::from(old_sender_balance.0 - amt),
+    /// Simulates a storage deposit (NEP-145), registering `account_id` (default: the caller) with a fixed storage cost.
+    /// The deposit is refunded if the account is already registered, and the excess if `registration_only`.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let deposit = env::attached_deposit();
+        let refund = if self.balances.contains_key(&account_id) {
+            deposit
+        } else {
+            assert!(
+                deposit >= STORAGE_BALANCE_MIN,
+                "The attached deposit is less than the minimum storage balance"
+            );
+            self.register_account(account_id.clone());
+            if registration_only == Some(true) {
+                deposit - STORAGE_BALANCE_MIN
+            } else {
+                0
+            }
+        };
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        if self.balances.contains_key(&account_id) {
+            Some(StorageBalance {
+                total: STORAGE_BALANCE_MIN.into(),
+                available: 0.into(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: STORAGE_BALANCE_MIN.into(),
+            max: Some(STORAGE_BALANCE_MIN.into()),
+        }
+    }
+
+    /// Simulates a storage withdrawal (NEP-145): nothing is available above the fixed storage cost
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let storage_balance = self
+            .storage_balance_of(account_id.clone())
+            .unwrap_or_else(|| panic!("The account {} is not registered", account_id));
+        assert!(
+            amount.map_or(0, |amount| amount.0) <= storage_balance.available.0,
+            "The amount is greater than the available storage balance"
         );
-        self.set_balance(receiver_id, U128::from(old_receiver_balance.0 + amt));
+        storage_balance
+    }
+
+    /// Simulates a storage unregistration (NEP-145), refunding the storage cost. The balance of the caller must be
+    /// empty, unless `force`, in which case it is burnt.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        match self.balances.get(&account_id) {
+            None => false,
+            Some(balance) => {
+                assert!(
+                    balance.0 == 0 || force == Some(true),
+                    "Can't unregister the account with the positive balance without force"
+                );
+                self.unregister_account(account_id.clone());
+                Promise::new(account_id).transfer(STORAGE_BALANCE_MIN);
+                true
+            }
+        }
     }
 
     pub fn ft_metadata(&self) -> Option<FungibleTokenMetadata> {
-        Some(FungibleTokenMetadata {
+        self.burn_gas();
+        assert!(
+            !self.behaviors.panic_in_metadata,
+            "Fungible token metadata unavailable"
+        );
+        Some(
+            self.metadata
+                .clone()
+                .unwrap_or_else(|| FungibleTokenMetadata {
+                    spec: "ft-1.0.0".into(),
+                    name: "USD Coin".into(),
+                    symbol: "USDC.e".into(),
+                    icon: None,
+                    reference: None,
+                    reference_hash: None,
+                    decimals: 6,
+                }),
+        )
+    }
+
+    /// Helper function for testing: sets the symbol and decimals of the token metadata.
+    /// Only callable by the owner, i.e. the mock account.
+    pub fn set_metadata(&mut self, symbol: String, decimals: u8) {
+        assert_owner();
+        assert!(
+            decimals <= MAX_DECIMALS,
+            "Decimals should be at most {}",
+            MAX_DECIMALS
+        );
+        self.metadata = Some(FungibleTokenMetadata {
             spec: "ft-1.0.0".into(),
-            name: "USD Coin".into(),
-            symbol: "USDC.e".into(),
+            name: symbol.clone(),
+            symbol,
             icon: None,
             reference: None,
             reference_hash: None,
-            decimals: 6,
-        })
+            decimals,
+        });
+    }
+
+    /// Helper function for testing: sets the pathological behaviors of the token, all disabled by default.
+    /// Only callable by the owner, i.e. the mock account.
+    pub fn set_behaviors(&mut self, behaviors: MockedBehaviors) {
+        assert_owner();
+        assert!(
+            behaviors.transfer_fee_bps <= 10_000,
+            "The transfer fee should be at most 10000 basis points"
+        );
+        self.behaviors = behaviors;
     }
 
     /// Helper function for testing
@@ -100,14 +338,74 @@ impl FungibleTokenContract {
         self.balances.contains_key(&account)
     }
 
-    /// Helper function for testing
-    pub fn ft_balance_of(&self, account: AccountId) -> U128 {
+    /// Balance of `account_id`, which must be registered
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
         assert!(
-            self.balances.contains_key(&account),
+            self.balances.contains_key(&account_id),
             "account is not registered with fungible token contract"
         );
-        self.balances[&account]
+        self.balances[&account_id]
+    }
+}
+
+impl FungibleTokenContract {
+    /// Transfers `amount` from `sender_id` to `receiver_id`, withholding the transfer fee, and returns the amount
+    /// credited to `receiver_id`
+    fn internal_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: Balance,
+    ) -> Balance {
+        assert!(
+            self.balances.contains_key(&sender_id),
+            "sender is not registered with fungible token contract"
+        );
+        assert!(
+            self.balances.contains_key(&receiver_id),
+            "receiver is not registered with fungible token contract"
+        );
+        assert!(
+            amount > 0 || !self.behaviors.reject_zero_transfers,
+            "The amount should be a positive number"
+        );
+
+        let old_sender_balance = self.ft_balance_of(sender_id.clone());
+        assert!(
+            old_sender_balance.0 >= amount,
+            "sender balance is insufficient"
+        );
+        let received_amount =
+            amount - amount * Balance::from(self.behaviors.transfer_fee_bps) / 10_000;
+
+        self.set_balance(sender_id, U128::from(old_sender_balance.0 - amount));
+        let old_receiver_balance = self.ft_balance_of(receiver_id.clone());
+        self.set_balance(
+            receiver_id,
+            U128::from(old_receiver_balance.0 + received_amount),
+        );
+        received_amount
     }
+
+    /// Burns the gas set with `set_behaviors`, with host function calls charged by the runtime.
+    /// Gas cannot be measured in view calls, which must not burn gas.
+    fn burn_gas(&self) {
+        if self.behaviors.burnt_tgas == 0 {
+            return;
+        }
+        let target_gas = env::used_gas() + Gas::ONE_TERA * self.behaviors.burnt_tgas;
+        while env::used_gas() < target_gas {
+            env::sha256(&[]);
+        }
+    }
+}
+
+fn assert_owner() {
+    assert_eq!(
+        env::predecessor_account_id(),
+        env::current_account_id(),
+        "Only the owner can set the mock state"
+    );
 }
 
 #[cfg(test)]
@@ -115,7 +413,8 @@ mod tests {
 
     use super::*;
     use crate::fungible_token_mock::AccountId;
-    use near_sdk::{testing_env, Balance, Gas, MockedBlockchain, VMContext};
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, Balance, Gas, VMContext};
 
     fn get_context(
         predecessor_account_id: AccountId,
@@ -123,79 +422,146 @@ mod tests {
         prepaid_gas: Gas,
         is_view: bool,
     ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 10,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(10)
+            .epoch_height(1)
+            .account_balance(0)
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
     }
 
     #[test]
     #[should_panic(expected = r#"Requires attached deposit of exactly 1 yoctoNEAR"#)]
     fn test_ft_transfer_no_yocto() {
-        let context = get_context("alice.near".to_string(), 0, 10u64.pow(14), false);
+        let context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), false);
         testing_env!(context);
         let mut contract = FungibleTokenContract::default();
 
-        contract.ft_transfer("bob.near".to_string(), "100".into(), None);
+        contract.ft_transfer("bob.near".parse().unwrap(), "100".into(), None);
     }
 
     #[test]
     #[should_panic(expected = r#"sender balance is insufficient"#)]
     fn test_ft_transfer_sender_balance_too_low() {
-        let context = get_context("alice.near".to_string(), 1, 10u64.pow(14), false);
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
         testing_env!(context);
         let mut contract = FungibleTokenContract::default();
 
-        contract.register_account("alice.near".to_string());
-        contract.register_account("bob.near".to_string());
-        contract.set_balance("alice.near".to_string(), 99.into());
+        contract.register_account("alice.near".parse().unwrap());
+        contract.register_account("bob.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 99.into());
 
-        contract.ft_transfer("bob.near".to_string(), "100".into(), None);
+        contract.ft_transfer("bob.near".parse().unwrap(), "100".into(), None);
     }
 
     #[test]
     #[should_panic(expected = r#"receiver is not registered with fungible token contract"#)]
     fn test_ft_transfer_receiver_not_registered() {
-        let context = get_context("alice.near".to_string(), 1, 10u64.pow(14), false);
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
         testing_env!(context);
         let mut contract = FungibleTokenContract::default();
 
-        contract.register_account("alice.near".to_string());
-        contract.set_balance("alice.near".to_string(), 100.into());
+        contract.register_account("alice.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 100.into());
 
-        contract.ft_transfer("bob.near".to_string(), "100".into(), None);
+        contract.ft_transfer("bob.near".parse().unwrap(), "100".into(), None);
     }
 
     #[test]
     fn test_ft_transfer() {
-        let context = get_context("alice.near".to_string(), 1, 10u64.pow(14), false);
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.register_account("alice.near".parse().unwrap());
+        contract.register_account("bob.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 100.into());
+
+        contract.ft_transfer("bob.near".parse().unwrap(), "100".into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"The account alice.near is not registered"#)]
+    fn test_near_deposit_not_registered() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            100,
+            Gas(10u64.pow(14)),
+            false,
+        );
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.near_deposit();
+    }
+
+    #[test]
+    fn test_near_deposit() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            100,
+            Gas(10u64.pow(14)),
+            false,
+        );
         testing_env!(context);
         let mut contract = FungibleTokenContract::default();
 
-        contract.register_account("alice.near".to_string());
-        contract.register_account("bob.near".to_string());
-        contract.set_balance("alice.near".to_string(), 100.into());
+        contract.register_account("alice.near".parse().unwrap());
+        contract.near_deposit();
+        assert_eq!(contract.ft_balance_of("alice.near".parse().unwrap()).0, 100);
+    }
 
-        contract.ft_transfer("bob.near".to_string(), "100".into(), None);
+    #[test]
+    #[should_panic(expected = r#"The attached deposit is less than the minimum storage balance"#)]
+    fn test_storage_deposit_too_small() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            100,
+            Gas(10u64.pow(14)),
+            false,
+        );
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.storage_deposit(Some("bob.near".parse().unwrap()), Some(true));
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let context = get_context(
+            "alice.near".parse().unwrap(),
+            STORAGE_BALANCE_MIN,
+            Gas(10u64.pow(14)),
+            false,
+        );
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        assert!(contract
+            .storage_balance_of("bob.near".parse().unwrap())
+            .is_none());
+        contract.storage_deposit(Some("bob.near".parse().unwrap()), Some(true));
+        assert!(contract.is_registered("bob.near".parse().unwrap()));
+        assert_eq!(
+            contract
+                .storage_balance_of("bob.near".parse().unwrap())
+                .unwrap()
+                .total
+                .0,
+            contract.storage_balance_bounds().min.0
+        );
     }
 
     #[test]
     fn test_ft_metadata() {
-        let context = get_context("alice.near".to_string(), 0, 10u64.pow(14), true);
+        let context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), true);
         testing_env!(context);
         let contract = FungibleTokenContract::default();
 
@@ -206,4 +572,117 @@ mod tests {
             panic!("Fungible token metadata mock returned None")
         }
     }
+
+    #[test]
+    fn test_set_metadata() {
+        let context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.set_metadata("wNEAR".into(), 24);
+        let result = contract.ft_metadata().unwrap();
+        assert_eq!(result.symbol, "wNEAR");
+        assert_eq!(result.decimals, 24);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Decimals should be at most 24"#)]
+    fn test_set_metadata_too_many_decimals() {
+        let context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.set_metadata("wNEAR".into(), 25);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Only the owner can set the mock state"#)]
+    fn test_set_behaviors_not_owner() {
+        let mut context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), false);
+        context.current_account_id = "mockedft.near".parse().unwrap();
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.set_behaviors(MockedBehaviors::default());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"Fungible token metadata unavailable"#)]
+    fn test_ft_metadata_panics() {
+        let context = get_context("alice.near".parse().unwrap(), 0, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.set_behaviors(MockedBehaviors {
+            panic_in_metadata: true,
+            ..Default::default()
+        });
+        contract.ft_metadata();
+    }
+
+    #[test]
+    #[should_panic(expected = r#"The amount should be a positive number"#)]
+    fn test_ft_transfer_zero_rejected() {
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.register_account("alice.near".parse().unwrap());
+        contract.register_account("bob.near".parse().unwrap());
+        contract.set_behaviors(MockedBehaviors {
+            reject_zero_transfers: true,
+            ..Default::default()
+        });
+        contract.ft_transfer("bob.near".parse().unwrap(), "0".into(), None);
+    }
+
+    #[test]
+    fn test_ft_transfer_with_fee_and_burnt_gas() {
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.register_account("alice.near".parse().unwrap());
+        contract.register_account("bob.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 1000.into());
+        contract.set_behaviors(MockedBehaviors {
+            burnt_tgas: 5,
+            transfer_fee_bps: 150,
+            ..Default::default()
+        });
+        contract.ft_transfer("bob.near".parse().unwrap(), "1000".into(), None);
+        assert_eq!(contract.ft_balance_of("alice.near".parse().unwrap()).0, 0);
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 985);
+        assert!(env::used_gas() >= Gas::ONE_TERA * 5);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"Can't unregister the account with the positive balance without force"#
+    )]
+    fn test_storage_unregister_positive_balance() {
+        let context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        contract.register_account("alice.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 100.into());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_storage_unregister() {
+        let mut context = get_context("alice.near".parse().unwrap(), 1, Gas(10u64.pow(14)), false);
+        // The storage cost to refund
+        context.account_balance = STORAGE_BALANCE_MIN;
+        testing_env!(context);
+        let mut contract = FungibleTokenContract::default();
+
+        assert!(!contract.storage_unregister(None));
+        contract.register_account("alice.near".parse().unwrap());
+        contract.set_balance("alice.near".parse().unwrap(), 100.into());
+        assert_eq!(contract.storage_withdraw(None).available.0, 0);
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(!contract.is_registered("alice.near".parse().unwrap()));
+    }
 }
@@ -1,3 +1,4 @@
 pub mod fpo_oracle_mock;
 pub mod fungible_token_mock;
+pub mod ref_exchange_mock;
 pub mod switchboard_feed_parser_mock;
@@ -0,0 +1,255 @@
+use near_sdk::assert_one_yocto;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise};
+use std::collections::HashMap;
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+
+/**
+ * Mocking a Ref Finance exchange contract
+ */
+
+// Swap action, as in Ref Finance
+#[derive(Serialize, Deserialize)]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+// For mocks: a pool swapping `token_in` to `token_out` at a fixed rate
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+pub struct Pool {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub rate_numerator: U128,
+    pub rate_denominator: U128,
+}
+
+// Interface of fungible tokens
+#[near_sdk::ext_contract(ft_contract)]
+pub trait FungibleTokenContract {
+    fn ft_transfer(receiver_id: AccountId, amount: String, memo: Option<String>);
+}
+
+// For mocks: state of the exchange, with deposits by account then token
+#[near_bindgen]
+#[derive(Default, BorshDeserialize, BorshSerialize)]
+pub struct RefExchangeContract {
+    pools: HashMap<u64, Pool>,
+    deposits: HashMap<AccountId, HashMap<AccountId, U128>>,
+    withdrawals_paused: bool,
+}
+
+/**
+ * Mocked Ref exchange contract for tests
+ */
+
+#[near_bindgen]
+impl RefExchangeContract {
+    /// Deposits the tokens transferred with `ft_transfer_call`, only deposits (with an empty `msg`) are supported
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> U128 {
+        assert!(msg.is_empty(), "ERR_MSG_NOT_SUPPORTED");
+        let token_id = env::predecessor_account_id();
+        let deposit = self.get_deposit(sender_id.clone(), token_id.clone());
+        self.set_deposit(&sender_id, &token_id, deposit.0 + amount.0);
+        U128::from(0)
+    }
+
+    /// Simulates a swap on deposited tokens, at the rate of the pool. Ensures that:
+    /// - the pool swaps `token_in` to `token_out`
+    /// - the deposit of `token_in` is sufficient
+    /// - at least `min_amount_out` is received
+    #[allow(unused_variables)]
+    #[payable]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        assert_eq!(actions.len(), 1, "Only single swaps are mocked");
+        let action = &actions[0];
+        let pool = self.pools.get(&action.pool_id).expect("ERR85_NO_POOL");
+        assert!(
+            pool.token_in == action.token_in && pool.token_out == action.token_out,
+            "ERR_MISSING_TOKEN"
+        );
+        let account_id = env::predecessor_account_id();
+        let deposit = self.get_deposit(account_id.clone(), action.token_in.clone());
+        let amount_in = action.amount_in.unwrap_or(deposit).0;
+        assert!(deposit.0 >= amount_in, "ERR22_NOT_ENOUGH_TOKENS");
+
+        let amount_out = amount_in * pool.rate_numerator.0 / pool.rate_denominator.0;
+        assert!(amount_out >= action.min_amount_out.0, "ERR68_SLIPPAGE");
+
+        let token_out = action.token_out.clone();
+        let deposit_out = self.get_deposit(account_id.clone(), token_out.clone());
+        self.set_deposit(&account_id, &action.token_in, deposit.0 - amount_in);
+        self.set_deposit(&account_id, &token_out, deposit_out.0 + amount_out);
+        U128::from(amount_out)
+    }
+
+    /// Withdraws deposited tokens to the caller
+    #[allow(unused_variables)]
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unregister: Option<bool>,
+    ) -> Promise {
+        assert_one_yocto();
+        assert!(!self.withdrawals_paused, "ERR_PAUSED");
+        let account_id = env::predecessor_account_id();
+        let deposit = self.get_deposit(account_id.clone(), token_id.clone());
+        assert!(deposit.0 >= amount.0, "ERR22_NOT_ENOUGH_TOKENS");
+        self.set_deposit(&account_id, &token_id, deposit.0 - amount.0);
+        ft_contract::ext(token_id)
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(account_id, amount.0.to_string(), None)
+    }
+
+    /// Helper function for testing: the pool `pool_id` swaps `token_in` to `token_out`, at `rate_numerator / rate_denominator`
+    pub fn set_pool(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        token_out: AccountId,
+        rate_numerator: U128,
+        rate_denominator: U128,
+    ) {
+        self.pools.insert(
+            pool_id,
+            Pool {
+                token_in,
+                token_out,
+                rate_numerator,
+                rate_denominator,
+            },
+        );
+    }
+
+    /// Helper function for testing: withdrawals fail while paused
+    pub fn set_withdrawals_paused(&mut self, paused: bool) {
+        self.withdrawals_paused = paused;
+    }
+
+    pub fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.deposits
+            .get(&account_id)
+            .and_then(|deposits| deposits.get(&token_id))
+            .copied()
+            .unwrap_or_else(|| U128::from(0))
+    }
+}
+
+impl RefExchangeContract {
+    fn set_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: u128) {
+        self.deposits
+            .entry(account_id.clone())
+            .or_default()
+            .insert(token_id.clone(), U128::from(amount));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, Balance, VMContext};
+
+    fn get_context(
+        predecessor_account_id: AccountId,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        is_view: bool,
+    ) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(10)
+            .epoch_height(1)
+            .account_balance(0)
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
+    }
+
+    /// Helper function: an exchange swapping 1 token.a for 2 token.b, with 100 token.a deposited by alice
+    fn exchange_with_deposit() -> RefExchangeContract {
+        let mut contract = RefExchangeContract::default();
+        contract.set_pool(
+            0,
+            "token.a".parse().unwrap(),
+            "token.b".parse().unwrap(),
+            2.into(),
+            1.into(),
+        );
+        testing_env!(get_context(
+            "token.a".parse().unwrap(),
+            0,
+            Gas(10u64.pow(14)),
+            false
+        ));
+        contract.ft_on_transfer("alice.near".parse().unwrap(), 100.into(), "".into());
+        testing_env!(get_context(
+            "alice.near".parse().unwrap(),
+            1,
+            Gas(10u64.pow(14)),
+            false
+        ));
+        contract
+    }
+
+    fn swap_action(min_amount_out: u128) -> SwapAction {
+        SwapAction {
+            pool_id: 0,
+            token_in: "token.a".parse().unwrap(),
+            amount_in: Some(100.into()),
+            token_out: "token.b".parse().unwrap(),
+            min_amount_out: min_amount_out.into(),
+        }
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut contract = exchange_with_deposit();
+
+        let amount_out = contract.swap(vec![swap_action(200)], None);
+        assert_eq!(amount_out.0, 200);
+        let deposit =
+            contract.get_deposit("alice.near".parse().unwrap(), "token.a".parse().unwrap());
+        assert_eq!(deposit.0, 0);
+        let deposit =
+            contract.get_deposit("alice.near".parse().unwrap(), "token.b".parse().unwrap());
+        assert_eq!(deposit.0, 200);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR68_SLIPPAGE"#)]
+    fn test_swap_slippage() {
+        let mut contract = exchange_with_deposit();
+        contract.swap(vec![swap_action(201)], None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR22_NOT_ENOUGH_TOKENS"#)]
+    fn test_withdraw_too_much() {
+        let mut contract = exchange_with_deposit();
+        contract.withdraw("token.a".parse().unwrap(), 101.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PAUSED"#)]
+    fn test_withdraw_paused() {
+        let mut contract = exchange_with_deposit();
+        contract.set_withdrawals_paused(true);
+        contract.withdraw("token.a".parse().unwrap(), 100.into(), None);
+    }
+}
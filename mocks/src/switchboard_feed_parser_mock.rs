@@ -1,6 +1,7 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{bs58, env, near_bindgen, Timestamp};
+use std::collections::HashMap;
 use std::str;
 
 /**
@@ -29,10 +30,26 @@ pub struct SwitchboardIx {
     pub payer: Uuid,
 }
 
-// For mocks: state of Switchboard feed parser
+// For mocks: a feed result set by the owner, with a round opened at `round_open_timestamp`, or 10 nanoseconds
+// before each read if `None`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct MockedFeed {
+    pub mantissa: i128,
+    pub scale: u32,
+    pub num_success: u32,
+    pub num_error: u32,
+    pub round_open_timestamp: Option<Timestamp>,
+}
+
+// For mocks: state of Switchboard feed parser, with the feeds set by the owner overriding the default one
 #[near_bindgen]
 #[derive(Default, BorshDeserialize, BorshSerialize)]
-pub struct SwitchboardFeedParser {}
+pub struct SwitchboardFeedParser {
+    // Feeds by bs58 feed key, `None` for a feed without result
+    feeds: HashMap<String, Option<MockedFeed>>,
+    // Error `aggregator_read` panics with
+    error: Option<String>,
+}
 
 const VALID_FEED_ADDRESS: [u8; 32] = [0; 32];
 
@@ -44,11 +61,27 @@ pub fn valid_feed_key() -> String {
 impl SwitchboardFeedParser {
     #[allow(unused_variables)]
     pub fn aggregator_read(&self, ix: SwitchboardIx) -> Option<PriceEntry> {
+        if let Some(error) = &self.error {
+            panic!("{}", error);
+        }
+        if let Some(feed) = self.feeds.get(&bs58::encode(&ix.address).into_string()) {
+            return feed.as_ref().map(|feed| PriceEntry {
+                result: SwitchboardDecimal {
+                    mantissa: feed.mantissa,
+                    scale: feed.scale,
+                },
+                num_success: feed.num_success,
+                num_error: feed.num_error,
+                round_open_timestamp: feed
+                    .round_open_timestamp
+                    .unwrap_or_else(|| env::block_timestamp() - 10),
+            });
+        }
         match ix.address {
             VALID_FEED_ADDRESS => Some(PriceEntry {
                 result: SwitchboardDecimal {
                     mantissa: i128::from(1234000000),
-                    scale: u8::from(9).into(),
+                    scale: 9,
                 },
                 num_success: 1,
                 num_error: 0,
@@ -59,14 +92,69 @@ impl SwitchboardFeedParser {
             }
         }
     }
+
+    /// Helper function for testing: sets the result of the feed `feed_key`, from a round opened at
+    /// `round_open_timestamp`, or just before each read if `None`. The round has 1 success and no error, see
+    /// `set_round_counts`. Only callable by the owner, i.e. the mock account.
+    pub fn set_price(
+        &mut self,
+        feed_key: String,
+        mantissa: i128,
+        scale: u32,
+        round_open_timestamp: Option<Timestamp>,
+    ) {
+        assert_owner();
+        self.feeds.insert(
+            feed_key,
+            Some(MockedFeed {
+                mantissa,
+                scale,
+                num_success: 1,
+                num_error: 0,
+                round_open_timestamp,
+            }),
+        );
+    }
+
+    /// Helper function for testing: sets the success and error counts of the round of a feed set with `set_price`
+    pub fn set_round_counts(&mut self, feed_key: String, num_success: u32, num_error: u32) {
+        assert_owner();
+        match self.feeds.get_mut(&feed_key) {
+            Some(Some(feed)) => {
+                feed.num_success = num_success;
+                feed.num_error = num_error;
+            }
+            _ => panic!("Feed not set"),
+        }
+    }
+
+    /// Helper function for testing: the feed `feed_key` has no result, as when delayed or not provided
+    pub fn remove_price(&mut self, feed_key: String) {
+        assert_owner();
+        self.feeds.insert(feed_key, None);
+    }
+
+    /// Helper function for testing: `aggregator_read` panics with `error`, or works again if `None`
+    pub fn set_error(&mut self, error: Option<String>) {
+        assert_owner();
+        self.error = error;
+    }
+}
+
+fn assert_owner() {
+    assert_eq!(
+        env::predecessor_account_id(),
+        env::current_account_id(),
+        "Only the owner can set the mock state"
+    );
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use near_sdk::{testing_env, AccountId, Balance, Gas, MockedBlockchain, VMContext};
-    use near_sdk_sim::to_yocto;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, AccountId, Balance, Gas, VMContext};
 
     fn get_context(
         predecessor_account_id: AccountId,
@@ -74,31 +162,26 @@ mod tests {
         prepaid_gas: Gas,
         is_view: bool,
     ) -> VMContext {
-        VMContext {
-            current_account_id: predecessor_account_id.clone(),
-            signer_account_id: predecessor_account_id.clone(),
-            signer_account_pk: vec![0, 1, 2],
-            predecessor_account_id,
-            input: vec![],
-            block_index: 1,
-            block_timestamp: 10,
-            epoch_height: 1,
-            account_balance: 0,
-            account_locked_balance: 0,
-            storage_usage: 10u64.pow(6),
-            attached_deposit,
-            prepaid_gas,
-            random_seed: vec![0, 1, 2],
-            is_view,
-            output_data_receivers: vec![],
-        }
+        VMContextBuilder::new()
+            .current_account_id(predecessor_account_id.clone())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .block_index(1)
+            .block_timestamp(10)
+            .epoch_height(1)
+            .account_balance(0)
+            .storage_usage(10u64.pow(6))
+            .attached_deposit(attached_deposit)
+            .prepaid_gas(prepaid_gas)
+            .is_view(is_view)
+            .build()
     }
     #[test]
     fn aggregator_read() {
         testing_env!(get_context(
-            "alice.near".to_string(),
-            to_yocto("1"),
-            10u64.pow(14),
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
             true
         ));
         let contract = SwitchboardFeedParser::default();
@@ -116,9 +199,9 @@ mod tests {
     #[should_panic(expected = r#"InvalidAggregator"#)]
     fn missing_aggregator_read() {
         testing_env!(get_context(
-            "alice.near".to_string(),
-            to_yocto("1"),
-            10u64.pow(14),
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
             true
         ));
         let contract = SwitchboardFeedParser::default();
@@ -127,4 +210,65 @@ mod tests {
             payer: [1; 32],
         });
     }
+    #[test]
+    fn aggregator_read_set_price() {
+        testing_env!(get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = SwitchboardFeedParser::default();
+        contract.set_price(valid_feed_key(), -5, 2, Some(3));
+        contract.set_round_counts(valid_feed_key(), 2, 1);
+        let result = contract
+            .aggregator_read(SwitchboardIx {
+                address: [0; 32],
+                payer: [1; 32],
+            })
+            .unwrap();
+        assert_eq!(result.result.mantissa, -5);
+        assert_eq!(result.result.scale, 2);
+        assert_eq!(result.num_success, 2);
+        assert_eq!(result.num_error, 1);
+        assert_eq!(result.round_open_timestamp, 3);
+
+        contract.remove_price(valid_feed_key());
+        assert!(contract
+            .aggregator_read(SwitchboardIx {
+                address: [0; 32],
+                payer: [1; 32],
+            })
+            .is_none());
+    }
+    #[test]
+    #[should_panic(expected = r#"Feed unavailable"#)]
+    fn aggregator_read_with_error() {
+        testing_env!(get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false
+        ));
+        let mut contract = SwitchboardFeedParser::default();
+        contract.set_error(Some("Feed unavailable".to_string()));
+        contract.aggregator_read(SwitchboardIx {
+            address: [0; 32],
+            payer: [1; 32],
+        });
+    }
+    #[test]
+    #[should_panic(expected = r#"Only the owner can set the mock state"#)]
+    fn set_price_not_owner() {
+        let mut context = get_context(
+            "alice.near".parse().unwrap(),
+            10u128.pow(24),
+            Gas(10u64.pow(14)),
+            false,
+        );
+        context.current_account_id = "mockedswitchboard.near".parse().unwrap();
+        testing_env!(context);
+        let mut contract = SwitchboardFeedParser::default();
+        contract.set_price(valid_feed_key(), 5, 2, None);
+    }
 }
@@ -0,0 +1,199 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{env, AccountId};
+use proxy_errors::{require, ProxyError};
+use proxy_storage::settle_storage;
+
+// Bounds the storage and the checks of each payment
+const MAX_ACCEPTED_ASSETS: usize = 20;
+
+/// Assets accepted by a payee, payments in other assets being rejected
+///
+/// - `tokens`: accepted tokens, "NEAR" for native NEAR payments, or any token if empty
+/// - `currencies`: accepted currencies of conversion payments (eg. "USD"), or any currency if empty
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default, Debug, PartialEq,
+)]
+pub struct PayeePreferences {
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(default)]
+    pub currencies: Vec<String>,
+}
+
+impl PayeePreferences {
+    pub fn accepts_token(&self, token: &str) -> bool {
+        self.tokens.is_empty() || self.tokens.iter().any(|accepted| accepted == token)
+    }
+
+    pub fn accepts_currency(&self, currency: &str) -> bool {
+        self.currencies.is_empty() || self.currencies.iter().any(|accepted| accepted == currency)
+    }
+}
+
+/// Assets accepted by payees, by payee. Payees without preferences accept any asset.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PayeesPreferences {
+    preferences: LookupMap<AccountId, PayeePreferences>,
+}
+
+impl PayeesPreferences {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            preferences: LookupMap::new(prefix),
+        }
+    }
+
+    /// Registers the `preferences` of the caller, or removes them if `None` to accept any asset.
+    /// The attached deposit pays for the storage used, the excess and any released storage being refunded.
+    pub fn set(&mut self, preferences: Option<PayeePreferences>) {
+        let account_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        match &preferences {
+            Some(preferences) => {
+                let assets_count = preferences.tokens.len() + preferences.currencies.len();
+                require(
+                    assets_count <= MAX_ACCEPTED_ASSETS,
+                    ProxyError::TooManyAcceptedAssets {
+                        supplied: assets_count,
+                        max: MAX_ACCEPTED_ASSETS,
+                    },
+                );
+                self.preferences.insert(&account_id, preferences);
+            }
+            None => {
+                self.preferences.remove(&account_id);
+            }
+        }
+        settle_storage(initial_storage, &account_id);
+        env::log_str(
+            &json!({
+                "event": "payee_preferences_set",
+                "account_id": account_id,
+                "preferences": preferences,
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get(&self, account_id: &AccountId) -> Option<PayeePreferences> {
+        self.preferences.get(account_id)
+    }
+
+    /// Panics unless `to` accepts payments in `token`, denominated in `currency` for conversion payments
+    pub fn require_accepted(&self, to: &AccountId, token: &str, currency: Option<&str>) {
+        let preferences = match self.get(to) {
+            Some(preferences) => preferences,
+            None => return,
+        };
+        require(
+            preferences.accepts_token(token),
+            ProxyError::TokenNotAccepted {
+                to: to.clone(),
+                token: token.to_string(),
+            },
+        );
+        if let Some(currency) = currency {
+            require(
+                preferences.accepts_currency(currency),
+                ProxyError::CurrencyNotAccepted {
+                    to: to.clone(),
+                    currency: currency.to_string(),
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    fn set_context(predecessor: &str, attached_deposit: u128) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account(predecessor))
+            .attached_deposit(attached_deposit)
+            .build());
+    }
+
+    fn bob_preferences() -> PayeePreferences {
+        PayeePreferences {
+            tokens: vec!["usdc.near".into(), "NEAR".into()],
+            currencies: vec!["USD".into()],
+        }
+    }
+
+    #[test]
+    fn set_payee_preferences() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut preferences = PayeesPreferences::new(b"p".to_vec());
+        preferences.set(Some(bob_preferences()));
+        assert_eq!(
+            preferences.get(&account("bob.near")),
+            Some(bob_preferences())
+        );
+        assert!(get_logs()[0].contains(r#""event":"payee_preferences_set""#));
+
+        set_context("alice.near", 0);
+        preferences.require_accepted(&account("bob.near"), "usdc.near", None);
+        preferences.require_accepted(&account("bob.near"), "NEAR", Some("USD"));
+        // Payees without preferences accept any asset
+        preferences.require_accepted(&account("carol.near"), "dai.near", Some("EUR"));
+
+        set_context("bob.near", 0);
+        preferences.set(None);
+        assert_eq!(preferences.get(&account("bob.near")), None);
+    }
+
+    #[test]
+    fn empty_preferences_accept_any_asset() {
+        let preferences = PayeePreferences {
+            tokens: vec![],
+            currencies: vec!["USD".into()],
+        };
+        assert!(preferences.accepts_token("dai.near"));
+        assert!(preferences.accepts_currency("USD"));
+        assert!(!preferences.accepts_currency("EUR"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_TOKEN_NOT_ACCEPTED: bob.near does not accept payments in dai.near"#
+    )]
+    fn token_not_accepted() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut preferences = PayeesPreferences::new(b"p".to_vec());
+        preferences.set(Some(bob_preferences()));
+        preferences.require_accepted(&account("bob.near"), "dai.near", None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = r#"ERR_CURRENCY_NOT_ACCEPTED: bob.near does not accept payments in EUR"#
+    )]
+    fn currency_not_accepted() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut preferences = PayeesPreferences::new(b"p".to_vec());
+        preferences.set(Some(bob_preferences()));
+        preferences.require_accepted(&account("bob.near"), "usdc.near", Some("EUR"));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_TOO_MANY_ACCEPTED_ASSETS"#)]
+    fn too_many_accepted_assets() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut preferences = PayeesPreferences::new(b"p".to_vec());
+        preferences.set(Some(PayeePreferences {
+            tokens: (0..21).map(|i| format!("token{}.near", i)).collect(),
+            currencies: vec![],
+        }));
+    }
+}
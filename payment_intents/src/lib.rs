@@ -0,0 +1,315 @@
+use std::convert::TryFrom;
+
+#[cfg(any(test, feature = "test-utils"))]
+use ed25519_dalek::{ExpandedSecretKey, SecretKey};
+use ed25519_dalek::{PublicKey, Signature};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::json;
+use near_sdk::{bs58, env, AccountId, CurveType, PublicKey as NearPublicKey, Timestamp};
+use proxy_errors::{require, ProxyError};
+use proxy_storage::settle_storage;
+
+// Prepended to signed intents, so that their signatures cannot be valid for other payloads
+const INTENT_PREFIX: &[u8] = b"payment_intent:";
+const ED25519_PREFIX: &str = "ed25519:";
+
+/// Payment requested by a payee (or a Request Network node on its behalf), signed with the payee's registered key
+///
+/// - `payment_reference`: the decoded payment reference (8 bytes)
+/// - `to`: the payee
+/// - `amount`: paid to `to`, in `currency` (with 2 decimals for fiat currencies)
+/// - `currency`: "USD" for conversion payments, or the token itself for payments denominated in the token
+/// - `token`: the paid token, "NEAR" for native NEAR payments
+/// - `expiry`: timestamp in nanoseconds after which the intent cannot be paid anymore
+#[derive(BorshSerialize)]
+pub struct PaymentIntent {
+    pub payment_reference: Vec<u8>,
+    pub to: AccountId,
+    pub amount: u128,
+    pub currency: String,
+    pub token: String,
+    pub expiry: Timestamp,
+}
+
+/// Signature of a `PaymentIntent`, supplied by the payer along with the payment arguments
+///
+/// - `expiry`: timestamp in nanoseconds, as signed in the intent
+/// - `signature`: ed25519 signature of the intent message (see `PaymentIntent::message`), base58-encoded,
+///   optionally prefixed with "ed25519:"
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SignedIntent {
+    pub expiry: U64,
+    pub signature: String,
+}
+
+impl PaymentIntent {
+    /// Message signed by the payee: `INTENT_PREFIX` followed by the Borsh-serialized intent
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = INTENT_PREFIX.to_vec();
+        message.extend(
+            self.try_to_vec()
+                .expect("Failed to serialize the payment intent"),
+        );
+        message
+    }
+
+    /// Signs the intent with the payee's ed25519 `secret_key` (32 bytes), as done off-chain by payees, for tests
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn sign(&self, secret_key: &[u8]) -> SignedIntent {
+        let secret_key = SecretKey::from_bytes(secret_key).expect("Invalid ed25519 secret key");
+        let public_key = PublicKey::from(&secret_key);
+        let signature = ExpandedSecretKey::from(&secret_key).sign(&self.message(), &public_key);
+        SignedIntent {
+            expiry: self.expiry.into(),
+            signature: format!(
+                "{}{}",
+                ED25519_PREFIX,
+                bs58::encode(signature.to_bytes()).into_string()
+            ),
+        }
+    }
+
+    /// Panics unless `signature` is a valid signature of the intent by `payee_key` (raw ed25519 key), before expiry
+    pub fn verify(&self, payee_key: &[u8], signature: &str) {
+        require(
+            env::block_timestamp() <= self.expiry,
+            ProxyError::IntentExpired {
+                expiry: self.expiry,
+            },
+        );
+        let public_key = PublicKey::from_bytes(payee_key)
+            .unwrap_or_else(|_| ProxyError::InvalidPayeeKey.panic());
+        let signature = bs58::decode(signature.trim_start_matches(ED25519_PREFIX))
+            .into_vec()
+            .ok()
+            .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+            .unwrap_or_else(|| ProxyError::InvalidIntentSignature.panic());
+        require(
+            public_key
+                .verify_strict(&self.message(), &signature)
+                .is_ok(),
+            ProxyError::InvalidIntentSignature,
+        );
+    }
+}
+
+/// Raw ed25519 key of `public_key`, which must be an ed25519 key
+pub fn ed25519_key(public_key: &NearPublicKey) -> Vec<u8> {
+    require(
+        public_key.curve_type() == CurveType::ED25519,
+        ProxyError::InvalidPayeeKey,
+    );
+    public_key.as_bytes()[1..].to_vec()
+}
+
+/// `PublicKey` of the raw ed25519 `key`
+pub fn near_key(key: &[u8]) -> NearPublicKey {
+    let mut public_key = vec![CurveType::ED25519 as u8];
+    public_key.extend_from_slice(key);
+    NearPublicKey::try_from(public_key).unwrap_or_else(|_| ProxyError::InvalidPayeeKey.panic())
+}
+
+/// Public key of the ed25519 `secret_key` (32 bytes), as registered by payees to sign their intents, for tests
+#[cfg(any(test, feature = "test-utils"))]
+pub fn public_key(secret_key: &[u8]) -> NearPublicKey {
+    let secret_key = SecretKey::from_bytes(secret_key).expect("Invalid ed25519 secret key");
+    near_key(PublicKey::from(&secret_key).as_bytes())
+}
+
+/// Keys registered by payees to sign their payment intents, by payee
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PayeeKeys {
+    keys: LookupMap<AccountId, Vec<u8>>,
+}
+
+impl PayeeKeys {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            keys: LookupMap::new(prefix),
+        }
+    }
+
+    /// Registers `public_key` as the key of the caller, or removes it if `None`.
+    /// The attached deposit pays for the storage used, the excess and any released storage being refunded.
+    pub fn set(&mut self, public_key: Option<NearPublicKey>) {
+        let account_id = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        match &public_key {
+            Some(public_key) => {
+                self.keys.insert(&account_id, &ed25519_key(public_key));
+            }
+            None => {
+                self.keys.remove(&account_id);
+            }
+        }
+        settle_storage(initial_storage, &account_id);
+        env::log_str(
+            &json!({
+                "event": "payee_key_set",
+                "account_id": account_id,
+                "public_key": public_key,
+            })
+            .to_string(),
+        );
+    }
+
+    pub fn get(&self, account_id: &AccountId) -> Option<NearPublicKey> {
+        self.keys.get(account_id).map(|key| near_key(&key))
+    }
+
+    /// Panics if the payee `to` registered a key, its payments requiring a signed intent
+    pub fn require_no_key(&self, to: &AccountId) {
+        require(!self.keys.contains_key(to), ProxyError::IntentRequired);
+    }
+
+    /// Panics unless `signed_intent` is a valid signature of `intent` by the key registered by the payee
+    pub fn verify(&self, intent: &PaymentIntent, signed_intent: &SignedIntent) {
+        let payee_key = self
+            .keys
+            .get(&intent.to)
+            .unwrap_or_else(|| ProxyError::PayeeKeyNotFound.panic());
+        intent.verify(&payee_key, &signed_intent.signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, Balance};
+
+    const SECRET_KEY: [u8; 32] = [7; 32];
+
+    fn intent() -> PaymentIntent {
+        PaymentIntent {
+            payment_reference: vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88],
+            to: "bob.near".parse().unwrap(),
+            amount: 100000,
+            currency: "USD".into(),
+            token: "NEAR".into(),
+            expiry: 1_000,
+        }
+    }
+
+    fn set_context(predecessor: &str, attached_deposit: Balance) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(attached_deposit)
+            .block_timestamp(500)
+            .build());
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        set_context("alice.near", 0);
+        let signed_intent = intent().sign(&SECRET_KEY);
+        assert_eq!(signed_intent.expiry, U64::from(1_000));
+        assert!(signed_intent.signature.starts_with(ED25519_PREFIX));
+        let payee_key = ed25519_key(&public_key(&SECRET_KEY));
+        intent().verify(&payee_key, &signed_intent.signature);
+        // The prefix is optional
+        intent().verify(
+            &payee_key,
+            signed_intent.signature.trim_start_matches(ED25519_PREFIX),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn verify_tampered_amount() {
+        set_context("alice.near", 0);
+        let signed_intent = intent().sign(&SECRET_KEY);
+        let tampered = PaymentIntent {
+            amount: 1000000,
+            ..intent()
+        };
+        tampered.verify(
+            &ed25519_key(&public_key(&SECRET_KEY)),
+            &signed_intent.signature,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn verify_tampered_payee() {
+        set_context("alice.near", 0);
+        let signed_intent = intent().sign(&SECRET_KEY);
+        let tampered = PaymentIntent {
+            to: "mallory.near".parse().unwrap(),
+            ..intent()
+        };
+        tampered.verify(
+            &ed25519_key(&public_key(&SECRET_KEY)),
+            &signed_intent.signature,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_INTENT_SIGNATURE"#)]
+    fn verify_malformed_signature() {
+        set_context("alice.near", 0);
+        intent().verify(&ed25519_key(&public_key(&SECRET_KEY)), "ed25519:not-base58");
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INTENT_EXPIRED"#)]
+    fn verify_expired() {
+        set_context("alice.near", 0);
+        let expired = PaymentIntent {
+            expiry: 100,
+            ..intent()
+        };
+        let signed_intent = expired.sign(&SECRET_KEY);
+        expired.verify(
+            &ed25519_key(&public_key(&SECRET_KEY)),
+            &signed_intent.signature,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_INVALID_PAYEE_KEY"#)]
+    fn set_secp256k1_key() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut payee_keys = PayeeKeys::new(b"k".to_vec());
+        let mut public_key = vec![CurveType::SECP256K1 as u8];
+        public_key.extend_from_slice(&[2; 64]);
+        payee_keys.set(Some(NearPublicKey::try_from(public_key).unwrap()));
+    }
+
+    #[test]
+    fn set_and_verify_payee_key() {
+        set_context("bob.near", env::storage_byte_cost() * 1000);
+        let mut payee_keys = PayeeKeys::new(b"k".to_vec());
+        payee_keys.set(Some(public_key(&SECRET_KEY)));
+        assert_eq!(
+            payee_keys.get(&"bob.near".parse().unwrap()),
+            Some(public_key(&SECRET_KEY))
+        );
+        assert_eq!(payee_keys.get(&"alice.near".parse().unwrap()), None);
+        assert!(get_logs()[0].contains(r#""event":"payee_key_set""#));
+
+        set_context("alice.near", 0);
+        payee_keys.verify(&intent(), &intent().sign(&SECRET_KEY));
+
+        set_context("bob.near", 0);
+        payee_keys.set(None);
+        assert_eq!(payee_keys.get(&"bob.near".parse().unwrap()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_DEPOSIT_TOO_SMALL"#)]
+    fn set_payee_key_without_deposit() {
+        set_context("bob.near", 0);
+        PayeeKeys::new(b"k".to_vec()).set(Some(public_key(&SECRET_KEY)));
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_PAYEE_KEY_NOT_FOUND"#)]
+    fn verify_without_payee_key() {
+        set_context("alice.near", 0);
+        PayeeKeys::new(b"k".to_vec()).verify(&intent(), &intent().sign(&SECRET_KEY));
+    }
+}
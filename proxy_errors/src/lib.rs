@@ -0,0 +1,553 @@
+use std::fmt;
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::{env, AccountId, Balance, Gas, Timestamp};
+
+/// Errors of the payment proxies
+///
+/// Each error has a stable `code`, a human-readable `message` and `params`, logged in a `failure` event
+/// (see `ProxyError::event`) so that frontends can localize errors and monitoring can alert per error type.
+/// Codes must never change once released, messages may be reworded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyError {
+    // Access and call conditions
+    Permission,
+    RelayedCall,
+    NotEnoughGas {
+        supplied: Gas,
+        demand: Gas,
+    },
+    InvalidMsg,
+    ZeroDeposit,
+    DepositTooSmall {
+        supplied: Balance,
+        demand: Balance,
+    },
+    // Payment arguments
+    InvalidReference,
+    InvalidReferenceLength,
+    UnsupportedCurrency {
+        currency: String,
+    },
+    TooManyFeeRecipients {
+        supplied: usize,
+        max: usize,
+    },
+    FeeBpsTooHigh {
+        supplied: u16,
+        max: u16,
+    },
+    FeeAmountAndFeeBps,
+    AmountSmallerThanFees,
+    AmountSmallerThanProtocolFee,
+    // Protocol fee
+    ProtocolFeeBpsTooHigh {
+        supplied: u16,
+        max: u16,
+    },
+    InvalidProtocolFeeCaps {
+        min_amount: Balance,
+        max_amount: Balance,
+    },
+    // Escrows and scheduled payments
+    EscrowTimeoutAndClaimAfter,
+    InvalidEscrowTimeout,
+    EscrowExists,
+    EscrowNotFound,
+    EscrowReleaseNotAllowed,
+    EscrowRefundNotAllowed,
+    PaymentCancelNotAllowed,
+    // Streams
+    StreamExclusive,
+    InvalidStreamPeriod,
+    StreamExists,
+    StreamNotFound,
+    EmptyStream,
+    AmountSmallerThanStream,
+    StreamWithdrawNotAllowed,
+    StreamCancelNotAllowed,
+    NothingToWithdraw,
+    // Payment intents
+    InvalidPayeeKey,
+    PayeeKeyNotFound,
+    IntentExpired {
+        expiry: Timestamp,
+    },
+    InvalidIntentSignature,
+    IntentRequired,
+    // Payee preferences
+    TooManyAcceptedAssets {
+        supplied: usize,
+        max: usize,
+    },
+    TokenNotAccepted {
+        to: AccountId,
+        token: String,
+    },
+    CurrencyNotAccepted {
+        to: AccountId,
+        currency: String,
+    },
+    // Swaps and wrapped NEAR
+    SwapExclusive,
+    ExchangeNotAllowed {
+        exchange_id: AccountId,
+    },
+    NoWrapAccount,
+    // Storage registration
+    NotEnoughStorageFund {
+        supplied: Balance,
+        demand: Balance,
+    },
+    NotEnoughStorageFundToRegister {
+        accounts: Vec<AccountId>,
+        supplied: Balance,
+        demand: Balance,
+    },
+    RegistrationNotAllowed {
+        token: AccountId,
+    },
+    StorageCostTooHigh {
+        supplied: Balance,
+        max: Balance,
+    },
+    // Oracles and conversion
+    InvalidOracleResponse,
+    FailedOracleFetch,
+    OracleErrors {
+        num_error: u32,
+        num_success: u32,
+    },
+    OutdatedRate {
+        last_update: Timestamp,
+    },
+    InvalidRate,
+    InvalidFtMetadataResponse,
+    FailedFtMetadataFetch,
+    OwnerPkLength,
+    InvalidFeedAddress,
+    // Transfers
+    TransferFailed {
+        receiver_id: AccountId,
+    },
+    // Recurring payments
+    RecurringNotFound,
+    RecurringExecuteNotAllowed,
+    RecurringExpired,
+    RecurringAlreadyExecuted,
+    RecurringAmountTooHigh {
+        supplied: Balance,
+        max: Balance,
+    },
+    RecurringRevokeNotAllowed,
+    RecurringTopUpNotAllowed,
+    RecurringTokenMismatch,
+    InvalidRecurringPeriod,
+    InvalidRecurringExpiry,
+    RecurringPayeeKey,
+}
+
+impl ProxyError {
+    /// Stable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::Permission => "ERR_PERMISSION",
+            ProxyError::RelayedCall => "ERR_RELAYED_CALL",
+            ProxyError::NotEnoughGas { .. } => "ERR_NOT_ENOUGH_GAS",
+            ProxyError::InvalidMsg => "ERR_INVALID_MSG",
+            ProxyError::ZeroDeposit => "ERR_ZERO_DEPOSIT",
+            ProxyError::DepositTooSmall { .. } => "ERR_DEPOSIT_TOO_SMALL",
+            ProxyError::InvalidReference => "ERR_INVALID_REFERENCE",
+            ProxyError::InvalidReferenceLength => "ERR_INVALID_REFERENCE_LENGTH",
+            ProxyError::UnsupportedCurrency { .. } => "ERR_UNSUPPORTED_CURRENCY",
+            ProxyError::TooManyFeeRecipients { .. } => "ERR_TOO_MANY_FEE_RECIPIENTS",
+            ProxyError::FeeBpsTooHigh { .. } => "ERR_FEE_BPS_TOO_HIGH",
+            ProxyError::FeeAmountAndFeeBps => "ERR_FEE_AMOUNT_AND_FEE_BPS",
+            ProxyError::AmountSmallerThanFees => "ERR_AMOUNT_SMALLER_THAN_FEES",
+            ProxyError::AmountSmallerThanProtocolFee => "ERR_AMOUNT_SMALLER_THAN_PROTOCOL_FEE",
+            ProxyError::ProtocolFeeBpsTooHigh { .. } => "ERR_PROTOCOL_FEE_BPS_TOO_HIGH",
+            ProxyError::InvalidProtocolFeeCaps { .. } => "ERR_INVALID_PROTOCOL_FEE_CAPS",
+            ProxyError::EscrowTimeoutAndClaimAfter => "ERR_ESCROW_TIMEOUT_AND_CLAIM_AFTER",
+            ProxyError::InvalidEscrowTimeout => "ERR_INVALID_ESCROW_TIMEOUT",
+            ProxyError::EscrowExists => "ERR_ESCROW_EXISTS",
+            ProxyError::EscrowNotFound => "ERR_ESCROW_NOT_FOUND",
+            ProxyError::EscrowReleaseNotAllowed => "ERR_ESCROW_RELEASE_NOT_ALLOWED",
+            ProxyError::EscrowRefundNotAllowed => "ERR_ESCROW_REFUND_NOT_ALLOWED",
+            ProxyError::PaymentCancelNotAllowed => "ERR_PAYMENT_CANCEL_NOT_ALLOWED",
+            ProxyError::StreamExclusive => "ERR_STREAM_EXCLUSIVE",
+            ProxyError::InvalidStreamPeriod => "ERR_INVALID_STREAM_PERIOD",
+            ProxyError::StreamExists => "ERR_STREAM_EXISTS",
+            ProxyError::StreamNotFound => "ERR_STREAM_NOT_FOUND",
+            ProxyError::EmptyStream => "ERR_EMPTY_STREAM",
+            ProxyError::AmountSmallerThanStream => "ERR_AMOUNT_SMALLER_THAN_STREAM",
+            ProxyError::StreamWithdrawNotAllowed => "ERR_STREAM_WITHDRAW_NOT_ALLOWED",
+            ProxyError::StreamCancelNotAllowed => "ERR_STREAM_CANCEL_NOT_ALLOWED",
+            ProxyError::NothingToWithdraw => "ERR_NOTHING_TO_WITHDRAW",
+            ProxyError::InvalidPayeeKey => "ERR_INVALID_PAYEE_KEY",
+            ProxyError::PayeeKeyNotFound => "ERR_PAYEE_KEY_NOT_FOUND",
+            ProxyError::IntentExpired { .. } => "ERR_INTENT_EXPIRED",
+            ProxyError::InvalidIntentSignature => "ERR_INVALID_INTENT_SIGNATURE",
+            ProxyError::IntentRequired => "ERR_INTENT_REQUIRED",
+            ProxyError::TooManyAcceptedAssets { .. } => "ERR_TOO_MANY_ACCEPTED_ASSETS",
+            ProxyError::TokenNotAccepted { .. } => "ERR_TOKEN_NOT_ACCEPTED",
+            ProxyError::CurrencyNotAccepted { .. } => "ERR_CURRENCY_NOT_ACCEPTED",
+            ProxyError::SwapExclusive => "ERR_SWAP_EXCLUSIVE",
+            ProxyError::ExchangeNotAllowed { .. } => "ERR_EXCHANGE_NOT_ALLOWED",
+            ProxyError::NoWrapAccount => "ERR_NO_WRAP_ACCOUNT",
+            ProxyError::NotEnoughStorageFund { .. } => "ERR_NOT_ENOUGH_STORAGE_FUND",
+            ProxyError::NotEnoughStorageFundToRegister { .. } => {
+                "ERR_NOT_ENOUGH_STORAGE_FUND_TO_REGISTER"
+            }
+            ProxyError::RegistrationNotAllowed { .. } => "ERR_REGISTRATION_NOT_ALLOWED",
+            ProxyError::StorageCostTooHigh { .. } => "ERR_STORAGE_COST_TOO_HIGH",
+            ProxyError::InvalidOracleResponse => "ERR_INVALID_ORACLE_RESPONSE",
+            ProxyError::FailedOracleFetch => "ERR_FAILED_ORACLE_FETCH",
+            ProxyError::OracleErrors { .. } => "ERR_ORACLE_ERRORS",
+            ProxyError::OutdatedRate { .. } => "ERR_OUTDATED_RATE",
+            ProxyError::InvalidRate => "ERR_INVALID_RATE",
+            ProxyError::InvalidFtMetadataResponse => "ERR_INVALID_FT_METADATA_RESPONSE",
+            ProxyError::FailedFtMetadataFetch => "ERR_FAILED_FT_METADATA_FETCH",
+            ProxyError::OwnerPkLength => "ERR_OWNER_PK_LENGTH",
+            ProxyError::InvalidFeedAddress => "ERR_INVALID_FEED_ADDRESS",
+            ProxyError::TransferFailed { .. } => "ERR_TRANSFER_FAILED",
+            ProxyError::RecurringNotFound => "ERR_RECURRING_NOT_FOUND",
+            ProxyError::RecurringExecuteNotAllowed => "ERR_RECURRING_EXECUTE_NOT_ALLOWED",
+            ProxyError::RecurringExpired => "ERR_RECURRING_EXPIRED",
+            ProxyError::RecurringAlreadyExecuted => "ERR_RECURRING_ALREADY_EXECUTED",
+            ProxyError::RecurringAmountTooHigh { .. } => "ERR_RECURRING_AMOUNT_TOO_HIGH",
+            ProxyError::RecurringRevokeNotAllowed => "ERR_RECURRING_REVOKE_NOT_ALLOWED",
+            ProxyError::RecurringTopUpNotAllowed => "ERR_RECURRING_TOP_UP_NOT_ALLOWED",
+            ProxyError::RecurringTokenMismatch => "ERR_RECURRING_TOKEN_MISMATCH",
+            ProxyError::InvalidRecurringPeriod => "ERR_INVALID_RECURRING_PERIOD",
+            ProxyError::InvalidRecurringExpiry => "ERR_INVALID_RECURRING_EXPIRY",
+            ProxyError::RecurringPayeeKey => "ERR_RECURRING_PAYEE_KEY",
+        }
+    }
+
+    /// Human-readable message, in English
+    pub fn message(&self) -> String {
+        match self {
+            ProxyError::Permission => "Only the owner can call this method".into(),
+            ProxyError::RelayedCall => {
+                "This method must be signed by the caller, not relayed".into()
+            }
+            ProxyError::NotEnoughGas { supplied, demand } => format!(
+                "Not enough attached Gas to call this method (Supplied: {}. Demand: {})",
+                supplied.0, demand.0
+            ),
+            ProxyError::InvalidMsg => "Incorrect msg format".into(),
+            ProxyError::ZeroDeposit => "Deposit should not be 0".into(),
+            ProxyError::DepositTooSmall { supplied, demand } => format!(
+                "Deposit too small for payment. Supplied: {}. Demand (incl. fees): {}",
+                supplied, demand
+            ),
+            ProxyError::InvalidReference => "Payment reference value error".into(),
+            ProxyError::InvalidReferenceLength => "Incorrect payment reference length".into(),
+            ProxyError::UnsupportedCurrency { .. } => {
+                "Only payments denominated in USD are implemented for now".into()
+            }
+            ProxyError::TooManyFeeRecipients { supplied, max } => format!(
+                "Too many fee recipients (Supplied: {}. Max: {})",
+                supplied, max
+            ),
+            ProxyError::FeeBpsTooHigh { supplied, max } => {
+                format!("fee_bps should not exceed {} (Supplied: {})", max, supplied)
+            }
+            ProxyError::FeeAmountAndFeeBps => {
+                "fee_amount and fee_bps are mutually exclusive".into()
+            }
+            ProxyError::AmountSmallerThanFees => "amount smaller than fee_amount".into(),
+            ProxyError::AmountSmallerThanProtocolFee => "amount smaller than protocol fee".into(),
+            ProxyError::ProtocolFeeBpsTooHigh { supplied, max } => {
+                format!("bps should not exceed {} (Supplied: {})", max, supplied)
+            }
+            ProxyError::InvalidProtocolFeeCaps { .. } => {
+                "min_amount should not exceed max_amount".into()
+            }
+            ProxyError::EscrowTimeoutAndClaimAfter => {
+                "escrow_timeout and claim_after are mutually exclusive".into()
+            }
+            ProxyError::InvalidEscrowTimeout => "escrow_timeout is too large".into(),
+            ProxyError::EscrowExists => {
+                "An escrow already exists for this payment reference".into()
+            }
+            ProxyError::EscrowNotFound => "No escrow for this payment reference".into(),
+            ProxyError::EscrowReleaseNotAllowed => {
+                "Only the payer can release the escrow, or the payee after claim_after".into()
+            }
+            ProxyError::EscrowRefundNotAllowed => {
+                "Only the payee, or the payer after refund_after, can refund the escrow".into()
+            }
+            ProxyError::PaymentCancelNotAllowed => {
+                "Only the payee, or the payer before claim_after, can cancel the payment".into()
+            }
+            ProxyError::StreamExclusive => {
+                "stream is exclusive with escrow_timeout and claim_after".into()
+            }
+            ProxyError::InvalidStreamPeriod => "stream end should be after its start".into(),
+            ProxyError::StreamExists => "A stream already exists for this payment reference".into(),
+            ProxyError::StreamNotFound => "No stream for this payment reference".into(),
+            ProxyError::EmptyStream => "Empty stream".into(),
+            ProxyError::AmountSmallerThanStream => "amount smaller than stream total".into(),
+            ProxyError::StreamWithdrawNotAllowed => {
+                "Only the payee can withdraw from the stream".into()
+            }
+            ProxyError::StreamCancelNotAllowed => "Only the payer can cancel the stream".into(),
+            ProxyError::NothingToWithdraw => "Nothing to withdraw".into(),
+            ProxyError::InvalidPayeeKey => "public_key should be an ed25519 key".into(),
+            ProxyError::PayeeKeyNotFound => {
+                "The payee has no registered key to verify the payment intent".into()
+            }
+            ProxyError::IntentExpired { expiry } => {
+                format!("The payment intent has expired (Expiry: {})", expiry)
+            }
+            ProxyError::InvalidIntentSignature => {
+                "The payment intent signature does not match the payment".into()
+            }
+            ProxyError::IntentRequired => {
+                "The payee has a registered key, payments require its signed intent".into()
+            }
+            ProxyError::TooManyAcceptedAssets { supplied, max } => format!(
+                "Too many accepted tokens and currencies (Supplied: {}. Max: {})",
+                supplied, max
+            ),
+            ProxyError::TokenNotAccepted { to, token } => {
+                format!("{} does not accept payments in {}", to, token)
+            }
+            ProxyError::CurrencyNotAccepted { to, currency } => {
+                format!("{} does not accept payments in {}", to, currency)
+            }
+            ProxyError::SwapExclusive => {
+                "swap is exclusive with escrow_timeout, claim_after, stream and intent".into()
+            }
+            ProxyError::ExchangeNotAllowed { exchange_id } => {
+                format!("Swaps through {} are not allowed", exchange_id)
+            }
+            ProxyError::NoWrapAccount => "No wrap account configured".into(),
+            ProxyError::NotEnoughStorageFund { supplied, demand } => format!(
+                "Not enough storage fund (Supplied: {}. Demand: {})",
+                supplied, demand
+            ),
+            ProxyError::NotEnoughStorageFundToRegister {
+                accounts,
+                supplied,
+                demand,
+            } => format!(
+                "Not enough storage fund to register {} (Supplied: {}. Demand: {})",
+                accounts
+                    .iter()
+                    .map(AccountId::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                supplied,
+                demand
+            ),
+            ProxyError::RegistrationNotAllowed { token } => {
+                format!("Registering accounts with {} is not allowed", token)
+            }
+            ProxyError::StorageCostTooHigh { supplied, max } => format!(
+                "Storage cost too high to register accounts (Supplied: {}. Max: {})",
+                supplied, max
+            ),
+            ProxyError::InvalidOracleResponse => "Invalid oracle response".into(),
+            ProxyError::FailedOracleFetch => "Failed to fetch the conversion rate".into(),
+            ProxyError::OracleErrors {
+                num_error,
+                num_success,
+            } => format!(
+                "Conversion errors: {}, successes: {}",
+                num_error, num_success
+            ),
+            ProxyError::OutdatedRate { last_update } => {
+                format!("Conversion rate too old (Last updated: {})", last_update)
+            }
+            ProxyError::InvalidRate => "The conversion rate should be positive".into(),
+            ProxyError::InvalidFtMetadataResponse => "Invalid fungible token metadata".into(),
+            ProxyError::FailedFtMetadataFetch => {
+                "Failed to fetch the fungible token metadata".into()
+            }
+            ProxyError::OwnerPkLength => "The signer public key should be 32 bytes long".into(),
+            ProxyError::InvalidFeedAddress => "public_key should be decodable into [u8; 32]".into(),
+            ProxyError::TransferFailed { receiver_id } => {
+                format!("Failed to transfer to account {}", receiver_id)
+            }
+            ProxyError::RecurringNotFound => "No recurring payment for this id".into(),
+            ProxyError::RecurringExecuteNotAllowed => {
+                "Only the executor can execute the recurring payment".into()
+            }
+            ProxyError::RecurringExpired => "The recurring payment has expired".into(),
+            ProxyError::RecurringAlreadyExecuted => {
+                "The recurring payment was already executed for this period".into()
+            }
+            ProxyError::RecurringAmountTooHigh { .. } => {
+                "amount exceeds the authorized amount per period".into()
+            }
+            ProxyError::RecurringRevokeNotAllowed => {
+                "Only the payer can revoke the recurring payment".into()
+            }
+            ProxyError::RecurringTopUpNotAllowed => {
+                "Only the payer can top up the recurring payment".into()
+            }
+            ProxyError::RecurringTokenMismatch => {
+                "The recurring payment is in another token".into()
+            }
+            ProxyError::InvalidRecurringPeriod => "period should not be 0".into(),
+            ProxyError::InvalidRecurringExpiry => "expiry should be in the future".into(),
+            ProxyError::RecurringPayeeKey => {
+                "The payee has a registered key, recurring payments to it are not supported".into()
+            }
+        }
+    }
+
+    /// Parameters of the error, for frontends to build localized messages, amounts being strings as in `U128`
+    pub fn params(&self) -> Value {
+        match self {
+            ProxyError::NotEnoughGas { supplied, demand } => {
+                json!({ "supplied": supplied.0, "demand": demand.0 })
+            }
+            ProxyError::DepositTooSmall { supplied, demand }
+            | ProxyError::NotEnoughStorageFund { supplied, demand } => {
+                json!({ "supplied": U128::from(*supplied), "demand": U128::from(*demand) })
+            }
+            ProxyError::UnsupportedCurrency { currency } => json!({ "currency": currency }),
+            ProxyError::TooManyFeeRecipients { supplied, max }
+            | ProxyError::TooManyAcceptedAssets { supplied, max } => {
+                json!({ "supplied": supplied, "max": max })
+            }
+            ProxyError::TokenNotAccepted { to, token } => json!({ "to": to, "token": token }),
+            ProxyError::CurrencyNotAccepted { to, currency } => {
+                json!({ "to": to, "currency": currency })
+            }
+            ProxyError::FeeBpsTooHigh { supplied, max }
+            | ProxyError::ProtocolFeeBpsTooHigh { supplied, max } => {
+                json!({ "supplied": supplied, "max": max })
+            }
+            ProxyError::InvalidProtocolFeeCaps {
+                min_amount,
+                max_amount,
+            } => json!({
+                "min_amount": U128::from(*min_amount),
+                "max_amount": U128::from(*max_amount),
+            }),
+            ProxyError::NotEnoughStorageFundToRegister {
+                accounts,
+                supplied,
+                demand,
+            } => json!({
+                "accounts": accounts,
+                "supplied": U128::from(*supplied),
+                "demand": U128::from(*demand),
+            }),
+            ProxyError::OracleErrors {
+                num_error,
+                num_success,
+            } => json!({ "num_error": num_error, "num_success": num_success }),
+            ProxyError::OutdatedRate { last_update } => {
+                json!({ "last_update": last_update.to_string() })
+            }
+            ProxyError::IntentExpired { expiry } => json!({ "expiry": expiry.to_string() }),
+            ProxyError::TransferFailed { receiver_id } => json!({ "receiver_id": receiver_id }),
+            ProxyError::ExchangeNotAllowed { exchange_id } => json!({ "exchange_id": exchange_id }),
+            ProxyError::RegistrationNotAllowed { token } => json!({ "token": token }),
+            ProxyError::RecurringAmountTooHigh { supplied, max }
+            | ProxyError::StorageCostTooHigh { supplied, max } => {
+                json!({ "supplied": U128::from(*supplied), "max": U128::from(*max) })
+            }
+            _ => json!({}),
+        }
+    }
+
+    /// `failure` event logged for the error
+    pub fn event(&self) -> Value {
+        json!({
+            "event": "failure",
+            "code": self.code(),
+            "message": self.message(),
+            "params": self.params(),
+        })
+    }
+
+    /// Logs the `failure` event of the error
+    pub fn log(&self) {
+        env::log_str(&self.event().to_string());
+    }
+
+    /// Logs the `failure` event of the error, then panics with its code and message
+    pub fn panic(&self) -> ! {
+        self.log();
+        panic!("{}", self)
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+/// Panics with `error` unless `condition` holds
+pub fn require(condition: bool, error: ProxyError) {
+    if !condition {
+        error.panic()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn error_display() {
+        let error = ProxyError::NotEnoughGas {
+            supplied: Gas(10),
+            demand: Gas(20),
+        };
+        assert_eq!(
+            error.to_string(),
+            "ERR_NOT_ENOUGH_GAS: Not enough attached Gas to call this method (Supplied: 10. Demand: 20)"
+        );
+    }
+
+    #[test]
+    fn error_event() {
+        let error = ProxyError::NotEnoughStorageFundToRegister {
+            accounts: vec!["bob.near".parse().unwrap(), "builder.near".parse().unwrap()],
+            supplied: 1,
+            demand: 2,
+        };
+        assert_eq!(
+            error.event(),
+            json!({
+                "event": "failure",
+                "code": "ERR_NOT_ENOUGH_STORAGE_FUND_TO_REGISTER",
+                "message": "Not enough storage fund to register bob.near, builder.near (Supplied: 1. Demand: 2)",
+                "params": { "accounts": ["bob.near", "builder.near"], "supplied": "1", "demand": "2" },
+            })
+        );
+        assert_eq!(ProxyError::EscrowNotFound.params(), json!({}));
+    }
+
+    #[test]
+    fn error_log() {
+        testing_env!(VMContextBuilder::new().build());
+        require(true, ProxyError::Permission);
+        assert!(get_logs().is_empty());
+        ProxyError::Permission.log();
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"{"code":"ERR_PERMISSION","event":"failure","message":"Only the owner can call this method","params":{}}"#
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_ESCROW_NOT_FOUND: No escrow for this payment reference"#)]
+    fn error_panic() {
+        testing_env!(VMContextBuilder::new().build());
+        ProxyError::EscrowNotFound.panic();
+    }
+}
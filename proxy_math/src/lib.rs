@@ -0,0 +1,207 @@
+use std::convert::TryFrom;
+
+/// Basis points in 100%, the maximum fee in basis points
+pub const MAX_BPS: u16 = 10_000;
+
+/// Fee of `fee_bps` basis points of `amount`, the amount paid to the payee, rounded down, or `None` if `fee_bps`
+/// exceeds `MAX_BPS`. Fees in basis points are always a share of the amount paid to the payee, paid on top of it.
+pub fn fee_from_bps(amount: u128, fee_bps: u16) -> Option<u128> {
+    if fee_bps > MAX_BPS {
+        return None;
+    }
+    Some(mul_div(amount, fee_bps, u128::from(MAX_BPS)))
+}
+
+/// Fee of `fee_bps` basis points of the amount paid to the payee, when `total` includes both the fee and that amount
+/// (see `fee_from_bps`), rounded down so that `fee <= (total - fee) * fee_bps / MAX_BPS`, or `None` if `fee_bps`
+/// exceeds `MAX_BPS`
+pub fn fee_from_bps_included(total: u128, fee_bps: u16) -> Option<u128> {
+    if fee_bps > MAX_BPS {
+        return None;
+    }
+    Some(mul_div(
+        total,
+        fee_bps,
+        u128::from(MAX_BPS) + u128::from(fee_bps),
+    ))
+}
+
+/// Computes `amount * numerator / denominator`, rounded down, without overflowing for a `numerator` up to `denominator`
+fn mul_div(amount: u128, numerator: u16, denominator: u128) -> u128 {
+    let numerator = u128::from(numerator);
+    amount / denominator * numerator + amount % denominator * numerator / denominator
+}
+
+/// Computes `amount * 10^exponent / divisor`, rounded down, without overflowing: the quotient is computed one decimal
+/// digit at a time. The result saturates at `u128::MAX`, as for a non-zero `amount` with a zero `divisor`.
+pub fn mul_pow10_div(amount: u128, exponent: i64, divisor: u128) -> u128 {
+    let amount = match u32::try_from(exponent.min(0).unsigned_abs()) {
+        Ok(shift) => 10u128.checked_pow(shift).map_or(0, |scale| amount / scale),
+        Err(_) => 0,
+    };
+    if amount == 0 {
+        return 0;
+    }
+    if divisor == 0 {
+        return u128::MAX;
+    }
+    let mut quotient = amount / divisor;
+    let mut remainder = amount % divisor;
+    // The loop ends within 80 digits, the non-zero quotient overflowing within 39 digits
+    for digits in 0..exponent.max(0) {
+        if remainder == 0 {
+            // The next digits are 0s
+            return u32::try_from(exponent - digits)
+                .ok()
+                .and_then(|shift| 10u128.checked_pow(shift))
+                .and_then(|scale| quotient.checked_mul(scale))
+                .unwrap_or(u128::MAX);
+        }
+        // Next digit of `remainder * 10 / divisor`, adding `remainder` 10 times modulo `divisor`
+        let mut digit = 0;
+        let mut next_remainder: u128 = 0;
+        for _ in 0..10 {
+            if next_remainder >= divisor - remainder {
+                next_remainder -= divisor - remainder;
+                digit += 1;
+            } else {
+                next_remainder += remainder;
+            }
+        }
+        quotient = match quotient.checked_mul(10).and_then(|q| q.checked_add(digit)) {
+            Some(quotient) => quotient,
+            None => return u128::MAX,
+        };
+        remainder = next_remainder;
+    }
+    quotient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
+    // `amount * 10^exponent / divisor` without rounding nor overflow, for a non-zero `divisor`
+    fn reference(amount: u128, exponent: i64, divisor: u128) -> BigUint {
+        let scale = BigUint::from(10u8).pow(u32::try_from(exponent.unsigned_abs()).unwrap());
+        if exponent >= 0 {
+            BigUint::from(amount) * scale / BigUint::from(divisor)
+        } else {
+            BigUint::from(amount) / scale / BigUint::from(divisor)
+        }
+    }
+
+    // Realistic values, and any value
+    fn amounts() -> impl Strategy<Value = u128> {
+        prop_oneof![0..10u128.pow(12), any::<u128>()]
+    }
+
+    fn divisors() -> impl Strategy<Value = u128> {
+        prop_oneof![1..10u128.pow(12), 1..=u128::MAX]
+    }
+
+    #[test]
+    fn fee_from_bps_values() {
+        // 2.5% of 120.00 USD is 3.00 USD
+        assert_eq!(fee_from_bps(12000, 250), Some(300));
+        // 1% of 0.99 USD is rounded down to 0.00 USD
+        assert_eq!(fee_from_bps(99, 100), Some(0));
+        assert_eq!(fee_from_bps(12000, MAX_BPS), Some(12000));
+        assert_eq!(fee_from_bps(12000, 0), Some(0));
+        assert_eq!(fee_from_bps(u128::MAX, MAX_BPS), Some(u128::MAX));
+        assert_eq!(fee_from_bps(12000, MAX_BPS + 1), None);
+    }
+
+    #[test]
+    fn fee_from_bps_included_values() {
+        // 1% of the 1000 paid to the payee, out of 1010
+        assert_eq!(fee_from_bps_included(1010, 100), Some(10));
+        assert_eq!(fee_from_bps_included(1009, 100), Some(9));
+        assert_eq!(fee_from_bps_included(1000, MAX_BPS), Some(500));
+        assert_eq!(fee_from_bps_included(1000, 0), Some(0));
+        assert_eq!(
+            fee_from_bps_included(u128::MAX, MAX_BPS),
+            Some(u128::MAX / 2)
+        );
+        assert_eq!(fee_from_bps_included(1010, MAX_BPS + 1), None);
+    }
+
+    #[test]
+    fn mul_pow10_div_values() {
+        // 1'200'000.00 USD at 1.234 USD per NEAR (9 decimals), overflowing before the division
+        assert_eq!(
+            mul_pow10_div(120000000, 9 + 24 - 2, 1234000000),
+            1200000 * 10u128.pow(24) * 1000 / 1234
+        );
+        assert_eq!(mul_pow10_div(150, -2, 1), 1);
+        assert_eq!(mul_pow10_div(7, 0, 2), 3);
+        // Saturating
+        assert_eq!(mul_pow10_div(u128::MAX, 1, 1), u128::MAX);
+        assert_eq!(mul_pow10_div(1, 39, 1), u128::MAX);
+        assert_eq!(mul_pow10_div(1, 0, 0), u128::MAX);
+        // Extreme exponents
+        assert_eq!(mul_pow10_div(u128::MAX, -39, 1), 0);
+        assert_eq!(mul_pow10_div(u128::MAX, i64::MIN, 1), 0);
+        assert_eq!(mul_pow10_div(1, i64::MAX, u128::MAX), u128::MAX);
+        assert_eq!(mul_pow10_div(0, i64::MAX, 0), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn fee_from_bps_matches_reference(amount in amounts(), fee_bps in 0..=MAX_BPS) {
+            let reference = BigUint::from(amount) * fee_bps / MAX_BPS;
+            prop_assert_eq!(
+                fee_from_bps(amount, fee_bps),
+                Some(u128::try_from(reference).unwrap())
+            );
+        }
+
+        #[test]
+        fn fee_from_bps_included_is_the_fee_of_the_rest(amount in amounts(), fee_bps in 0..=MAX_BPS) {
+            let fee = fee_from_bps_included(amount, fee_bps).unwrap();
+            let reference = BigUint::from(amount) * fee_bps / (u32::from(MAX_BPS) + u32::from(fee_bps));
+            prop_assert_eq!(BigUint::from(fee), reference);
+            // The same bps means the same fee, up to rounding, whether the fee is included or not
+            let rest_fee = fee_from_bps(amount - fee, fee_bps).unwrap();
+            prop_assert!(fee <= rest_fee + 1 && rest_fee <= fee + 1);
+        }
+
+        #[test]
+        fn mul_pow10_div_never_panics(
+            amount in any::<u128>(),
+            exponent in any::<i64>(),
+            divisor in any::<u128>(),
+        ) {
+            mul_pow10_div(amount, exponent, divisor);
+        }
+
+        #[test]
+        fn mul_pow10_div_matches_reference(
+            amount in amounts(),
+            exponent in -45i64..80,
+            divisor in divisors(),
+        ) {
+            prop_assert_eq!(
+                mul_pow10_div(amount, exponent, divisor),
+                u128::try_from(reference(amount, exponent, divisor)).unwrap_or(u128::MAX)
+            );
+        }
+
+        #[test]
+        fn mul_pow10_div_is_monotonic(
+            amounts in (amounts(), amounts()),
+            exponent in -45i64..80,
+            divisors in (divisors(), divisors()),
+        ) {
+            let (low_amount, high_amount) = (amounts.0.min(amounts.1), amounts.0.max(amounts.1));
+            let (low_divisor, high_divisor) = (divisors.0.min(divisors.1), divisors.0.max(divisors.1));
+            // Increasing with the amount and the exponent, decreasing with the divisor
+            let low = mul_pow10_div(low_amount, exponent, low_divisor);
+            prop_assert!(low <= mul_pow10_div(high_amount, exponent, low_divisor));
+            prop_assert!(low <= mul_pow10_div(low_amount, exponent + 1, low_divisor));
+            prop_assert!(mul_pow10_div(low_amount, exponent, high_divisor) <= low);
+        }
+    }
+}
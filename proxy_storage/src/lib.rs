@@ -0,0 +1,316 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, serde_json, AccountId, Balance, Gas, Promise, PromiseResult, StorageUsage};
+use proxy_errors::{require, ProxyError};
+
+// Maximum storage deposit paid from storage funds to register an account with a token, 0.0125 NEAR
+pub const MAX_STORAGE_COST: Balance = 12_500_000_000_000_000_000_000;
+
+// Storage management of fungible tokens (NEP-145)
+#[near_sdk::ext_contract(storage_contract)]
+pub trait StorageManagement {
+    fn storage_balance_of(account_id: AccountId) -> Option<StorageBalance>;
+    fn storage_balance_bounds() -> StorageBalanceBounds;
+    fn storage_deposit(
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+}
+
+/// Storage balance of an account registered with a token (NEP-145)
+#[derive(Serialize, Deserialize)]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// Storage deposit bounds of a token (NEP-145), `min` being the cost of registering an account
+#[derive(Serialize, Deserialize)]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+/// NEAR deposited by payers, by payer, to register the recipients of their payments with the paid tokens
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageFunds {
+    funds: LookupMap<AccountId, Balance>,
+}
+
+impl StorageFunds {
+    pub fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            funds: LookupMap::new(prefix),
+        }
+    }
+
+    pub fn get(&self, account_id: &AccountId) -> Balance {
+        self.funds.get(account_id).unwrap_or(0)
+    }
+
+    /// Adds `amount` to the storage fund of `account_id`, and returns the storage fund
+    pub fn deposit(&mut self, account_id: &AccountId, amount: Balance) -> Balance {
+        let storage_fund = self.get(account_id) + amount;
+        self.funds.insert(account_id, &storage_fund);
+        storage_fund
+    }
+
+    /// Withdraws `amount` (default: all) from the caller's storage fund
+    pub fn withdraw(&mut self, amount: Option<Balance>) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let storage_fund = self.get(&account_id);
+        let amount = amount.unwrap_or(storage_fund);
+        require(amount > 0, ProxyError::NothingToWithdraw);
+        require(
+            amount <= storage_fund,
+            ProxyError::NotEnoughStorageFund {
+                supplied: storage_fund,
+                demand: amount,
+            },
+        );
+        self.funds.insert(&account_id, &(storage_fund - amount));
+        Promise::new(account_id).transfer(amount)
+    }
+
+    /// Registers `accounts` with `token_address`, paying `storage_cost` for each from the storage fund of `payer`.
+    /// The deposit of failed registrations is credited back by `on_registrations`.
+    pub fn register(
+        &mut self,
+        payer: &AccountId,
+        token_address: &AccountId,
+        accounts: &[AccountId],
+        storage_cost: Balance,
+        gas: Gas,
+    ) -> Promise {
+        require(
+            storage_cost <= MAX_STORAGE_COST,
+            ProxyError::StorageCostTooHigh {
+                supplied: storage_cost,
+                max: MAX_STORAGE_COST,
+            },
+        );
+        let total_storage_cost = storage_cost * accounts.len() as u128;
+        let storage_fund = self.get(payer);
+        if total_storage_cost > storage_fund {
+            ProxyError::NotEnoughStorageFundToRegister {
+                accounts: accounts.to_vec(),
+                supplied: storage_fund,
+                demand: total_storage_cost,
+            }
+            .panic();
+        }
+        self.funds
+            .insert(payer, &(storage_fund - total_storage_cost));
+        accounts
+            .iter()
+            .map(|account| {
+                storage_contract::ext(token_address.clone())
+                    .with_attached_deposit(storage_cost)
+                    .with_static_gas(gas)
+                    .with_unused_gas_weight(0)
+                    .storage_deposit(Some(account.clone()), Some(true))
+            })
+            .reduce(|promise, registration| promise.and(registration))
+            .unwrap()
+    }
+
+    /// Reads the results of `register`, crediting the storage fund of `payer` back for the failed registrations,
+    /// and returns the registered accounts
+    pub fn on_registrations(
+        &mut self,
+        payer: &AccountId,
+        accounts: Vec<AccountId>,
+        storage_cost: Balance,
+    ) -> Vec<AccountId> {
+        let (registered_accounts, failed_accounts): (Vec<_>, Vec<_>) =
+            accounts.into_iter().enumerate().partition(|(i, _)| {
+                matches!(env::promise_result(*i as u64), PromiseResult::Successful(_))
+            });
+        // The deposit of failed registrations is refunded to the proxy
+        if !failed_accounts.is_empty() {
+            self.deposit(payer, storage_cost * failed_accounts.len() as u128);
+        }
+        registered_accounts
+            .into_iter()
+            .map(|(_, account)| account)
+            .collect()
+    }
+}
+
+/// Checks the registration of `accounts` with `token_address`, the results being read by `unregistered_accounts`
+pub fn check_registrations(token_address: &AccountId, accounts: &[AccountId], gas: Gas) -> Promise {
+    accounts.iter().fold(
+        storage_contract::ext(token_address.clone())
+            .with_static_gas(gas)
+            .with_unused_gas_weight(0)
+            .storage_balance_bounds(),
+        |promise, account| {
+            promise.and(
+                storage_contract::ext(token_address.clone())
+                    .with_static_gas(gas)
+                    .with_unused_gas_weight(0)
+                    .storage_balance_of(account.clone()),
+            )
+        },
+    )
+}
+
+/// Reads the results of `check_registrations`, returning the cost of registering an account with the token and
+/// the unregistered `accounts`. Tokens without storage management are paid without registering accounts.
+pub fn unregistered_accounts(accounts: Vec<AccountId>) -> (Balance, Vec<AccountId>) {
+    let storage_cost = match env::promise_result(0) {
+        PromiseResult::Successful(value) => serde_json::from_slice::<StorageBalanceBounds>(&value)
+            .ok()
+            .map(|bounds| bounds.min.0),
+        _ => None,
+    };
+    match storage_cost {
+        Some(storage_cost) => (
+            storage_cost,
+            accounts
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| match env::promise_result(*i as u64 + 1) {
+                    PromiseResult::Successful(value) => {
+                        serde_json::from_slice::<Option<StorageBalance>>(&value)
+                            .is_ok_and(|balance| balance.is_none())
+                    }
+                    _ => false,
+                })
+                .map(|(_, account)| account)
+                .collect(),
+        ),
+        None => (0, vec![]),
+    }
+}
+
+/// Charges the attached deposit for the storage used since `initial_storage`, refunding the excess to `account_id`,
+/// or refunds the released storage with the deposit
+pub fn settle_storage(initial_storage: StorageUsage, account_id: &AccountId) {
+    let storage_cost = |bytes: StorageUsage| Balance::from(bytes) * env::storage_byte_cost();
+    let deposit = env::attached_deposit();
+    let storage_usage = env::storage_usage();
+    let refund = if storage_usage >= initial_storage {
+        let cost = storage_cost(storage_usage - initial_storage);
+        require(
+            cost <= deposit,
+            ProxyError::DepositTooSmall {
+                supplied: deposit,
+                demand: cost,
+            },
+        );
+        deposit - cost
+    } else {
+        deposit + storage_cost(initial_storage - storage_usage)
+    };
+    if refund > 0 {
+        Promise::new(account_id.clone()).transfer(refund);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    const STORAGE_COST: Balance = 1_250_000_000_000_000_000_000;
+
+    fn context(attached_deposit: Balance, storage_usage: StorageUsage) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id("bob.near".parse().unwrap())
+            .attached_deposit(attached_deposit)
+            .storage_usage(storage_usage)
+            .build()
+    }
+
+    fn accounts() -> Vec<AccountId> {
+        vec!["carol.near".parse().unwrap(), "dave.near".parse().unwrap()]
+    }
+
+    #[test]
+    fn settle_storage_refunds_excess() {
+        testing_env!(context(env::storage_byte_cost() * 150, 1100));
+        settle_storage(1000, &"bob.near".parse().unwrap());
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.as_str(), "bob.near");
+    }
+
+    #[test]
+    fn settle_storage_exact_deposit() {
+        testing_env!(context(env::storage_byte_cost() * 100, 1100));
+        settle_storage(1000, &"bob.near".parse().unwrap());
+        assert!(get_created_receipts().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_DEPOSIT_TOO_SMALL"#)]
+    fn settle_storage_deposit_too_small() {
+        testing_env!(context(env::storage_byte_cost() * 99, 1100));
+        settle_storage(1000, &"bob.near".parse().unwrap());
+    }
+
+    #[test]
+    fn register_from_storage_fund() {
+        testing_env!(context(0, 1000));
+        let payer: AccountId = "bob.near".parse().unwrap();
+        let mut storage_funds = StorageFunds::new(b"f".to_vec());
+        storage_funds.deposit(&payer, STORAGE_COST * 3);
+        drop(storage_funds.register(
+            &payer,
+            &"token.near".parse().unwrap(),
+            &accounts(),
+            STORAGE_COST,
+            Gas(10_000_000_000_000),
+        ));
+        assert_eq!(storage_funds.get(&payer), STORAGE_COST);
+        assert_eq!(get_created_receipts().len(), 2);
+
+        // The deposit of the failed registration is credited back
+        testing_env!(
+            context(0, 1000),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed]
+        );
+        let registered = storage_funds.on_registrations(&payer, accounts(), STORAGE_COST);
+        assert_eq!(registered, vec!["carol.near".parse::<AccountId>().unwrap()]);
+        assert_eq!(storage_funds.get(&payer), STORAGE_COST * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = r#"ERR_NOT_ENOUGH_STORAGE_FUND_TO_REGISTER"#)]
+    fn register_with_not_enough_storage_fund() {
+        testing_env!(context(0, 1000));
+        let payer: AccountId = "bob.near".parse().unwrap();
+        let mut storage_funds = StorageFunds::new(b"f".to_vec());
+        storage_funds.deposit(&payer, STORAGE_COST);
+        storage_funds.register(
+            &payer,
+            &"token.near".parse().unwrap(),
+            &accounts(),
+            STORAGE_COST,
+            Gas(10_000_000_000_000),
+        );
+    }
+
+    #[test]
+    fn unregistered_accounts_of_token_without_storage_management() {
+        testing_env!(
+            context(0, 1000),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Failed,
+                PromiseResult::Successful(b"null".to_vec()),
+                PromiseResult::Successful(b"null".to_vec()),
+            ]
+        );
+        assert_eq!(unregistered_accounts(accounts()), (0, vec![]));
+    }
+}
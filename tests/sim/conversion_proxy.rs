@@ -1,107 +1,176 @@
 use crate::utils::*;
-use conversion_proxy::ConversionProxyContract;
-use mocks::switchboard_feed_parser_mock::{valid_feed_key, SwitchboardFeedParserContract};
+use mocks::switchboard_feed_parser_mock::valid_feed_key;
 use near_sdk::json_types::{U128, U64};
-use near_sdk_sim::init_simulator;
-use near_sdk_sim::runtime::GenesisConfig;
-use near_sdk_sim::ContractAccount;
-use near_sdk_sim::UserAccount;
-use near_sdk_sim::{call, deploy, lazy_static_include, to_yocto};
-use std::convert::TryInto;
-use std::str;
-
-near_sdk::setup_alloc!();
+use near_sdk::serde_json::{json, Value};
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::Gas;
+use near_workspaces::{Account, Contract, Worker};
 
 const PROXY_ID: &str = "conversion_proxy";
-lazy_static_include::lazy_static_include_bytes! {
-   pub PROXY_BYTES => "target/wasm32-unknown-unknown/release/conversion_proxy.wasm"
-}
-lazy_static_include::lazy_static_include_bytes! {
-   pub MOCKED_BYTES => "target/wasm32-unknown-unknown/debug/mocks.wasm"
-}
+const PROXY_WASM: &str = "target/wasm32-unknown-unknown/release/conversion_proxy.wasm";
 
 const DEFAULT_BALANCE: &str = "400000";
 const USD: &str = "USD";
 const PAYMENT_REF: &str = "0x1122334455667788";
 
 // Initialize test environment with 3 accounts (alice, bob, builder), a conversion mock, and its owner account.
-fn init() -> (
-    UserAccount,
-    UserAccount,
-    UserAccount,
-    ContractAccount<ConversionProxyContract>,
-    UserAccount,
-) {
-    let mut genesis = GenesisConfig::default();
-    genesis.gas_price = 0;
-    let root = init_simulator(Some(genesis));
-
-    deploy!(
-        contract: SwitchboardFeedParserContract,
-        contract_id: "mockedswitchboard".to_string(),
-        bytes: &MOCKED_BYTES,
-        signer_account: root,
-        deposit: to_yocto("7")
-    );
-
-    let account = root.create_user("alice".to_string(), to_yocto(DEFAULT_BALANCE));
-
-    let zero_balance: u128 = 1820000000000000000000;
-    let empty_account_1 = root.create_user("bob".parse().unwrap(), zero_balance);
-    let empty_account_2 = root.create_user("builder".parse().unwrap(), zero_balance);
-
-    let proxy = deploy!(
-        contract: ConversionProxyContract,
-        contract_id: PROXY_ID,
-        bytes: &PROXY_BYTES,
-        signer_account: root,
-        deposit: to_yocto("5"),
-        init_method: new("mockedswitchboard".into(), &valid_feed_key())
-    );
-
-    let set_feed_payer_result = call!(root, proxy.set_feed_payer());
+async fn init() -> anyhow::Result<(
+    Worker<Sandbox>,
+    Account,
+    Account,
+    Account,
+    Contract,
+    Account,
+)> {
+    let (worker, alice, bob, builder, proxy, root, _) = init_with_feed_parser().await?;
+    Ok((worker, alice, bob, builder, proxy, root))
+}
+
+// Same as `init`, also returning the Switchboard feed parser mock, to configure its feeds.
+async fn init_with_feed_parser() -> anyhow::Result<(
+    Worker<Sandbox>,
+    Account,
+    Account,
+    Account,
+    Contract,
+    Account,
+    Contract,
+)> {
+    let worker = near_workspaces::sandbox().await?;
+    let root = worker.root_account()?;
+
+    let switchboard = deploy(&worker, "mockedswitchboard", MOCKED_WASM, to_yocto("7")).await?;
+
+    let account = create_account(&worker, "alice", to_yocto(DEFAULT_BALANCE)).await?;
+
+    // Bob and builder hold 1 NEAR, for their storage and the gas of their calls
+    let empty_account_1 = create_account(&worker, "bob", to_yocto("1")).await?;
+    let empty_account_2 = create_account(&worker, "builder", to_yocto("1")).await?;
+
+    let proxy = deploy(&worker, PROXY_ID, PROXY_WASM, to_yocto("5")).await?;
+    call(
+        &root,
+        proxy.id(),
+        "new",
+        json!({ "feed_parser": "mockedswitchboard", "feed_address_pk": valid_feed_key() }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let set_feed_payer_result = call(&root, proxy.id(), "set_feed_payer", json!({}), 0).await?;
     set_feed_payer_result.assert_success();
-    let get_parser_result = call!(root, proxy.get_feed_parser());
-    get_parser_result.assert_success();
+    let feed_parser = view(&proxy, "get_feed_parser", json!({}))
+        .await?
+        .json::<String>()?;
+    assert_eq!(feed_parser, "mockedswitchboard");
+
+    Ok((
+        worker,
+        account,
+        empty_account_1,
+        empty_account_2,
+        proxy,
+        root,
+        switchboard,
+    ))
+}
+
+// Pays 1'200.00 USD with the feed configured by `set_feed`, expecting the payment to fail with `error`
+async fn assert_transfer_fails_with_feed(
+    set_feed: &[(&str, Value)],
+    max_rate_timespan: u64,
+    error: &str,
+) -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _, switchboard) = init_with_feed_parser().await?;
+    for (method, args) in set_feed {
+        call(
+            switchboard.as_account(),
+            switchboard.id(),
+            method,
+            args.clone(),
+            0,
+        )
+        .await?
+        .assert_success();
+    }
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_proxy_balance = balance(proxy.as_account()).await?;
 
-    debug_assert_eq!(
-        &get_parser_result.unwrap_json_value().to_owned(),
-        &"mockedswitchboard".to_string()
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": bob.id(),
+            "amount": U128::from(120000),
+            "currency": USD,
+            "fee_address": builder.id(),
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(max_rate_timespan),
+        }),
+        to_yocto("100"),
+    )
+    .await?;
+    result.assert_payment_failed(error);
+
+    assert_eq!(
+        initial_alice_balance,
+        balance(&alice).await? + gas_cost(&result),
+        "Alice should not spend NEAR on a payment failing with {error}.",
+    );
+    assert_eq!(
+        balance(&bob).await?,
+        initial_bob_balance,
+        "Bob's balance should be unchanged"
+    );
+    assert!(
+        unchanged_but_gas_rewards(
+            balance(proxy.as_account()).await?,
+            initial_proxy_balance,
+            &result
+        ),
+        "Contract's balance should be unchanged"
     );
 
-    (account, empty_account_1, empty_account_2, proxy, root)
+    Ok(())
 }
 
-#[test]
-fn test_transfer() {
-    let (alice, bob, builder, proxy, _) = init();
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_bob_balance = bob.account().unwrap().amount;
-    let initial_builder_balance = builder.account().unwrap().amount;
+#[tokio::test]
+async fn test_transfer() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_builder_balance = balance(&builder).await?;
     let transfer_amount = to_yocto("200000");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
             // 12000.00 USD (main)
-            U128::from(1200000),
-            USD.into(),
-            fee_address,
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
             // 1.00 USD (fee)
-            U128::from(100),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
     result.assert_success();
 
-    let alice_balance = alice.account().unwrap().amount;
+    let alice_balance = balance(&alice).await?;
     assert!(alice_balance < initial_alice_balance);
-    let spent_amount = initial_alice_balance - alice_balance;
+    let spent_amount = initial_alice_balance - alice_balance - gas_cost(&result);
     // 12'001.00 USD worth of NEAR / 1.234
     let expected_spent = to_yocto("12001") * 1000 / 1234;
     assert!(
@@ -109,8 +178,8 @@ fn test_transfer() {
         "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have spent 12'000 + 1 USD worth of NEAR.",
     );
 
-    assert!(bob.account().unwrap().amount > initial_bob_balance);
-    let received_amount = bob.account().unwrap().amount - initial_bob_balance;
+    assert!(balance(&bob).await? > initial_bob_balance);
+    let received_amount = balance(&bob).await? - initial_bob_balance;
     assert_eq!(
         received_amount,
         // 12'000 USD / rate mocked
@@ -118,148 +187,813 @@ fn test_transfer() {
         "Bob should receive exactly 12'000 USD worth of NEAR."
     );
 
-    assert!(builder.account().unwrap().amount > initial_builder_balance);
-    let received_amount = builder.account().unwrap().amount - initial_builder_balance;
+    assert!(balance(&builder).await? > initial_builder_balance);
+    let received_amount = balance(&builder).await? - initial_builder_balance;
     assert_eq!(
         received_amount,
         // 1 USD / rate mocked
         to_yocto("1") * 1000 / 1234,
         "Builder should receive exactly 1 USD worth of NEAR"
     );
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_with_invalid_reference_length() {
-    let transfer_amount = to_yocto("500");
+#[tokio::test]
+async fn test_transfer_with_multiple_fees() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
+    let referrer = create_account(&worker, "referrer", to_yocto("1")).await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_builder_balance = balance(&builder).await?;
+    let initial_referrer_balance = balance(&referrer).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
 
-    let (alice, bob, builder, proxy, _) = init();
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            // 2.00 USD (referrer fee)
+            "fees": [{ "address": referrer.id(), "amount": U128::from(200) }],
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
 
-    // Token transfer failed
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            "0x11223344556677".to_string(),
-            payment_address,
-            U128::from(12),
-            USD.into(),
-            fee_address,
-            U128::from(1),
-            U64::from(0)
+    let spent_amount = initial_alice_balance - balance(&alice).await? - gas_cost(&result);
+    // 12'000.00 + 1.00 + 2.00 USD worth of NEAR / 1.234, each payment being rounded separately
+    let expected_spent =
+        to_yocto("12000") * 1000 / 1234 + to_yocto("1") * 1000 / 1234 + to_yocto("2") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(spent_amount, expected_spent),
+        "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have spent 12'000 + 1 + 2 USD worth of NEAR.",
+    );
+    assert_eq!(
+        balance(&bob).await? - initial_bob_balance,
+        to_yocto("12000") * 1000 / 1234,
+        "Bob should receive exactly 12'000 USD worth of NEAR."
+    );
+    assert_eq!(
+        balance(&builder).await? - initial_builder_balance,
+        to_yocto("1") * 1000 / 1234,
+        "Builder should receive exactly 1 USD worth of NEAR"
+    );
+    assert_eq!(
+        balance(&referrer).await? - initial_referrer_balance,
+        to_yocto("2") * 1000 / 1234,
+        "Referrer should receive exactly 2 USD worth of NEAR"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_fee_bps() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_builder_balance = balance(&builder).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+            // 1% fee, 120.00 USD
+            "fee_bps": Some(100),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+
+    let spent_amount = initial_alice_balance - balance(&alice).await? - gas_cost(&result);
+    // 12'000.00 + 120.00 USD worth of NEAR / 1.234, each payment being rounded separately
+    let expected_spent = to_yocto("12000") * 1000 / 1234 + to_yocto("120") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(spent_amount, expected_spent),
+        "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have spent 12'000 + 120 USD worth of NEAR.",
+    );
+    assert_eq!(
+        balance(&bob).await? - initial_bob_balance,
+        to_yocto("12000") * 1000 / 1234,
+        "Bob should receive exactly 12'000 USD worth of NEAR."
+    );
+    assert_eq!(
+        balance(&builder).await? - initial_builder_balance,
+        to_yocto("120") * 1000 / 1234,
+        "Builder should receive exactly 120 USD worth of NEAR"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_protocol_fee() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, root) = init().await?;
+    let treasury = create_account(&worker, "treasury", to_yocto("1")).await?;
+    call(
+        &root,
+        proxy.id(),
+        "set_protocol_fee",
+        json!({
+            "bps": 100,
+            "treasury_id": treasury.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    // 1% of 12'000.00 USD is capped to 50.00 USD
+    call(
+        &root,
+        proxy.id(),
+        "set_protocol_fee_caps",
+        json!({
+            "currency": USD,
+            "min_amount": U128::from(0),
+            "max_amount": U128::from(5000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    let schedule = view(&proxy, "get_protocol_fee", json!({}))
+        .await?
+        .json::<Value>()?;
+    assert_eq!(schedule["bps"], 100);
+    assert_eq!(schedule["treasury_id"], "treasury");
+
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_treasury_balance = balance(&treasury).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+
+    let spent_amount = initial_alice_balance - balance(&alice).await? - gas_cost(&result);
+    // 12'000.00 + 1.00 + 50.00 USD worth of NEAR / 1.234, each payment being rounded separately
+    let expected_spent = to_yocto("12000") * 1000 / 1234
+        + to_yocto("1") * 1000 / 1234
+        + to_yocto("50") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(spent_amount, expected_spent),
+        "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have spent 12'000 + 1 + 50 USD worth of NEAR.",
+    );
+    assert_eq!(
+        balance(&bob).await? - initial_bob_balance,
+        to_yocto("12000") * 1000 / 1234,
+        "Bob should receive exactly 12'000 USD worth of NEAR."
+    );
+    assert_eq!(
+        balance(&treasury).await? - initial_treasury_balance,
+        to_yocto("50") * 1000 / 1234,
+        "Treasury should receive exactly 50 USD worth of NEAR"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_released() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            // Refundable to the payer after 1 day
+            "escrow_timeout": Some(U64::from(86_400_000_000_000)),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+
+    // Funds are locked, alice got her change back
+    let spent_amount = initial_alice_balance - balance(&alice).await? - gas_cost(&result);
+    let expected_spent = to_yocto("12001") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(spent_amount, expected_spent),
+        "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have locked 12'000 + 1 USD worth of NEAR.",
+    );
+    assert_eq!(balance(&bob).await?, initial_bob_balance);
+    let escrow = view(
+        &proxy,
+        "get_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+    )
+    .await?
+    .json::<Value>()?;
+    assert_eq!(escrow["payer"], "alice");
+    assert_eq!(escrow["to"], "bob");
+
+    // Only the payer can release the escrow
+    let result = call(
+        &bob,
+        proxy.id(),
+        "release_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("Only the payer can release the escrow");
+    let bob_gas = gas_cost(&result);
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "release_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.logs().len(), 2, "Wrong number of logs");
+    assert!(result.logs()[0].contains(r#""event":"escrow_released""#));
+    assert!(result.logs()[1].contains(r#""payment_reference":"0x1122334455667788""#));
+
+    assert_eq!(
+        balance(&bob).await? + bob_gas - initial_bob_balance,
+        to_yocto("12000") * 1000 / 1234,
+        "Bob should receive exactly 12'000 USD worth of NEAR."
+    );
+    let escrow = view(
+        &proxy,
+        "get_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+    )
+    .await?
+    .json::<Value>()?;
+    assert!(escrow.is_null());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_refunded() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            // Refundable to the payer after 1 day
+            "escrow_timeout": Some(U64::from(86_400_000_000_000)),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+    let mut alice_gas = gas_cost(&result);
+
+    // The payer cannot get a refund before the timeout
+    let result = call(
+        &alice,
+        proxy.id(),
+        "refund_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("can refund the escrow");
+    alice_gas += gas_cost(&result);
+
+    // The payee can refund at any time
+    let result = call(
+        &bob,
+        proxy.id(),
+        "refund_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    let bob_gas = gas_cost(&result);
+
+    assert_eq!(
+        balance(&alice).await? + alice_gas,
+        initial_alice_balance,
+        "Alice should be fully refunded"
+    );
+    assert_eq!(balance(&bob).await? + bob_gas, initial_bob_balance);
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "release_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("No escrow for this payment reference");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_refunded_to() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
+    let carol = create_account(&worker, "carol", to_yocto("1")).await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_carol_balance = balance(&carol).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            // Refundable to the payer after 1 day
+            "escrow_timeout": Some(U64::from(86_400_000_000_000)),
+            "refund_to": Some(carol.id()),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+    let payment_gas = gas_cost(&result);
+    let escrow = view(
+        &proxy,
+        "get_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+    )
+    .await?
+    .json::<Value>()?;
+    assert_eq!(escrow["payer"], "alice");
+    assert_eq!(escrow["refund_to"], "carol");
+
+    // Only the payer can get a refund, but Carol receives it
+    let result = call(
+        &carol,
+        proxy.id(),
+        "refund_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("can refund the escrow");
+    let refund_gas = gas_cost(&result);
+    let result = call(
+        &bob,
+        proxy.id(),
+        "refund_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+
+    let spent_amount = initial_alice_balance - balance(&alice).await? - payment_gas;
+    assert!(yocto_almost_eq(spent_amount, transfer_amount));
+    assert!(
+        yocto_almost_eq(
+            balance(&carol).await? + refund_gas - initial_carol_balance,
+            transfer_amount
         ),
-        deposit = transfer_amount
+        "Carol should get the change and the escrow refunded"
     );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scheduled_transfer_claimed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            // Claimable right away
+            "claim_after": Some(U64::from(1)),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(balance(&bob).await?, initial_bob_balance);
+
+    // The payee claims the payment
+    let result = call(
+        &bob,
+        proxy.id(),
+        "release_escrow",
+        json!({ "payment_reference": PAYMENT_REF, "payer": alice.id() }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.logs().len(), 2, "Wrong number of logs");
+    assert!(result.logs()[1].contains(r#""payment_reference":"0x1122334455667788""#));
+    assert_eq!(
+        balance(&bob).await? + gas_cost(&result) - initial_bob_balance,
+        to_yocto("12000") * 1000 / 1234,
+        "Bob should receive exactly 12'000 USD worth of NEAR."
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_invalid_reference_length() -> anyhow::Result<()> {
+    let transfer_amount = to_yocto("500");
+
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    // Token transfer failed
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": "0x11223344556677",
+            "to": payment_address,
+            "amount": U128::from(12),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(1),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
     result.assert_one_promise_error("Incorrect payment reference length");
 
     // Check Alice balance
     assert_eq!(
         to_yocto(DEFAULT_BALANCE),
-        alice.account().unwrap().amount,
+        balance(&alice).await? + gas_cost(&result),
         "Alice should not spend NEAR on invalid payment.",
     );
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_with_wrong_currency() {
-    let (alice, bob, builder, proxy, _) = init();
+#[tokio::test]
+async fn test_transfer_with_wrong_currency() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
     let transfer_amount = to_yocto("100");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
+    let payment_address = bob.id();
+    let fee_address = builder.id();
 
     // Token transfer failed
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
-            U128::from(1200),
-            String::from("WRONG"),
-            fee_address,
-            U128::from(100),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(1200),
+            "currency": String::from("WRONG"),
+            "fee_address": fee_address,
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
     result.assert_one_promise_error("Only payments denominated in USD are implemented for now");
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_with_low_deposit() {
-    let (alice, bob, builder, proxy, _) = init();
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_bob_balance = bob.account().unwrap().amount;
-    let initial_contract_balance = proxy.account().unwrap().amount;
+#[tokio::test]
+async fn test_transfer_with_low_deposit() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_contract_balance = balance(proxy.as_account()).await?;
     let transfer_amount = to_yocto("1000");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
-            U128::from(2000000),
-            USD.into(),
-            fee_address,
-            U128::from(0),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
-    result.assert_success_one_log("Deposit too small for payment");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(2000000),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_payment_failed("ERR_DEPOSIT_TOO_SMALL");
 
     assert_eq!(
-        alice.account().unwrap().amount,
+        balance(&alice).await? + gas_cost(&result),
         initial_alice_balance,
         "Alice should not spend NEAR on a failed payment.",
     );
 
-    assert_eq!(
-        proxy.account().unwrap().amount,
-        initial_contract_balance,
+    assert!(
+        unchanged_but_gas_rewards(
+            balance(proxy.as_account()).await?,
+            initial_contract_balance,
+            &result
+        ),
         "Contract's balance should be unchanged"
     );
     assert_eq!(
-        builder.account().unwrap().amount,
+        balance(&builder).await?,
         initial_bob_balance,
         "Builder's balance should be unchanged"
     );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_refund_to() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
+    let carol = create_account(&worker, "carol", to_yocto("1")).await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_carol_balance = balance(&carol).await?;
+    let transfer_amount = to_yocto("200000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": fee_address,
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+            "refund_to": Some(carol.id()),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_success();
+
+    // Alice spent the whole deposit, the change went to Carol
+    let spent_amount = initial_alice_balance - balance(&alice).await? - gas_cost(&result);
+    assert!(yocto_almost_eq(spent_amount, transfer_amount));
+    let change = balance(&carol).await? - initial_carol_balance;
+    // 12'001.00 USD worth of NEAR / 1.234
+    let expected_change = transfer_amount - to_yocto("12001") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(change, expected_change),
+        "\nChange:   {change} \nExpected: {expected_change} : Carol should receive the change.",
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_low_deposit_refund_to() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
+    let carol = create_account(&worker, "carol", to_yocto("1")).await?;
+    let initial_carol_balance = balance(&carol).await?;
+    let initial_contract_balance = balance(proxy.as_account()).await?;
+    let transfer_amount = to_yocto("1000");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(2000000),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+            "refund_to": Some(carol.id()),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_payment_failed("ERR_DEPOSIT_TOO_SMALL");
+    let refund_log = r#""refund_to":"carol""#;
+    assert!(result.logs().iter().any(|log| log.contains(refund_log)));
+
+    assert_eq!(
+        balance(&carol).await?,
+        initial_carol_balance + transfer_amount,
+        "Carol should get the failed payment refunded"
+    );
+    assert!(
+        unchanged_but_gas_rewards(
+            balance(proxy.as_account()).await?,
+            initial_contract_balance,
+            &result
+        ),
+        "Contract's balance should be unchanged"
+    );
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_high_amounts() {
-    let (alice, bob, builder, proxy, root) = init();
+#[tokio::test]
+async fn test_relayed_transfer() -> anyhow::Result<()> {
+    let (worker, _, bob, builder, _, _) = init().await?;
+    let relayer = Relayer::new(&worker).await?;
+    let carol = relayer
+        .create_sender(&worker, "carol", to_yocto("2000"))
+        .await?;
+    let initial_carol_balance = balance(&carol).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_relayer_balance = balance(&relayer.account).await?;
+    let transfer_amount = to_yocto("1000");
+
+    let result = relayer
+        .relay(
+            &carol,
+            PROXY_ID,
+            "transfer_with_reference",
+            json!({
+                "payment_reference": PAYMENT_REF,
+                "to": bob.id(),
+                // 1000.00 USD (main)
+                "amount": U128::from(100000),
+                "currency": USD,
+                "fee_address": builder.id(),
+                // 1.00 USD (fee)
+                "fee_amount": U128::from(100),
+                "max_rate_timespan": U64::from(0),
+            }),
+            transfer_amount,
+        )
+        .await?;
+    result.assert_success();
+
+    // Carol is the payer and gets the change, not the relayer signing the transaction, which pays for the gas
+    let spent_amount = initial_carol_balance - balance(&carol).await?;
+    // 1'001.00 USD worth of NEAR / 1.234
+    let expected_spent = to_yocto("1001") * 1000 / 1234;
+    assert!(
+        yocto_almost_eq(spent_amount, expected_spent),
+        "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Carol should have spent 1'000 + 1 USD worth of NEAR.",
+    );
+    assert_eq!(
+        balance(&bob).await? - initial_bob_balance,
+        to_yocto("1000") * 1000 / 1234
+    );
+    assert_eq!(
+        balance(&relayer.account).await? + result.gas_cost(),
+        initial_relayer_balance,
+        "The relayer should not receive any change"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_high_amounts() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
     let transfer_amount = to_yocto("20000000");
     // This high amount require a balance greater than the default one
-    root.transfer(alice.account_id(), transfer_amount);
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_bob_balance = bob.account().unwrap().amount;
-    let initial_builder_balance = builder.account().unwrap().amount;
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
+    set_balance(&worker, &alice, to_yocto(DEFAULT_BALANCE) + transfer_amount).await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_builder_balance = balance(&builder).await?;
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
             // 1'200'00.00 USD (main)
-            U128::from(120000000),
-            USD.into(),
-            fee_address,
+            "amount": U128::from(120000000),
+            "currency": USD,
+            "fee_address": fee_address,
             // 1.00 USD (fee)
-            U128::from(100),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
     result.assert_success();
     println!("{:?}", result);
 
-    let alice_balance = alice.account().unwrap().amount;
+    let alice_balance = balance(&alice).await?;
     assert!(alice_balance < initial_alice_balance);
-    let spent_amount = initial_alice_balance - alice_balance;
+    let spent_amount = initial_alice_balance - alice_balance - gas_cost(&result);
     // 1'200'001.00 USD worth of NEAR / 1.234
     let expected_spent = to_yocto("1200001") * 1000 / 1234;
     assert!(
@@ -267,8 +1001,8 @@ fn test_transfer_high_amounts() {
         "\nSpent:    {spent_amount} \nExpected: {expected_spent} : Alice should have spent 1'200'000 + 1 USD worth of NEAR.",
     );
 
-    assert!(bob.account().unwrap().amount > initial_bob_balance);
-    let received_amount = bob.account().unwrap().amount - initial_bob_balance;
+    assert!(balance(&bob).await? > initial_bob_balance);
+    let received_amount = balance(&bob).await? - initial_bob_balance;
     assert_eq!(
         received_amount,
         // 1'200'000 USD / rate mocked
@@ -276,142 +1010,274 @@ fn test_transfer_high_amounts() {
         "Bob should receive exactly 1'200'000 USD worth of NEAR."
     );
 
-    assert!(builder.account().unwrap().amount > initial_builder_balance);
-    let received_amount = builder.account().unwrap().amount - initial_builder_balance;
+    assert!(balance(&builder).await? > initial_builder_balance);
+    let received_amount = balance(&builder).await? - initial_builder_balance;
     assert_eq!(
         received_amount,
         // 1 USD / rate mocked
         to_yocto("1") * 1000 / 1234,
         "Builder should receive exactly 1 USD worth of NEAR"
     );
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_with_wrong_feed_address() {
-    let (alice, bob, builder, proxy, root) = init();
+#[tokio::test]
+async fn test_transfer_with_wrong_feed_address() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, root) = init().await?;
 
-    let result = call!(alice, proxy.get_encoded_feed_address());
-    result.assert_success();
+    view(&proxy, "get_encoded_feed_address", json!({})).await?;
 
-    let result = call!(
-        root,
-        proxy.set_feed_address(&"7igqhpGQ8xPpyjQ4gMHhXRvtZcrKSGJkdKDJYBiPQgcb".to_string())
-    );
+    let result = call(
+        &root,
+        proxy.id(),
+        "set_feed_address",
+        json!({ "feed_address": "7igqhpGQ8xPpyjQ4gMHhXRvtZcrKSGJkdKDJYBiPQgcb" }),
+        0,
+    )
+    .await?;
     result.assert_success();
 
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_bob_balance = bob.account().unwrap().amount;
-    let initial_contract_balance = proxy.account().unwrap().amount;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
+    let initial_contract_balance = balance(proxy.as_account()).await?;
     let transfer_amount = to_yocto("100000");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
-            U128::from(2000000),
-            USD.into(),
-            fee_address,
-            U128::from(0),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
-    result.assert_success_one_log("ERR_FAILED_ORACLE_FETCH");
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(2000000),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_payment_failed("ERR_FAILED_ORACLE_FETCH");
 
     assert_eq!(
-        alice.account().unwrap().amount,
+        balance(&alice).await? + gas_cost(&result),
         initial_alice_balance,
         "Alice should not spend NEAR on a wrong feed address payment.",
     );
 
-    assert_eq!(
-        proxy.account().unwrap().amount,
-        initial_contract_balance,
+    assert!(
+        unchanged_but_gas_rewards(
+            balance(proxy.as_account()).await?,
+            initial_contract_balance,
+            &result
+        ),
         "Contract's balance should be unchanged"
     );
     assert_eq!(
-        builder.account().unwrap().amount,
+        balance(&builder).await?,
         initial_bob_balance,
         "Builder's balance should be unchanged"
     );
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_zero_usd() {
-    let (alice, bob, builder, proxy, _) = init();
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_bob_balance = bob.account().unwrap().amount;
+#[tokio::test]
+async fn test_transfer_zero_usd() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_bob_balance = balance(&bob).await?;
     let transfer_amount = to_yocto("100");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
-            U128::from(0),
-            USD.into(),
-            fee_address,
-            U128::from(0),
-            U64::from(0)
-        ),
-        deposit = transfer_amount
-    );
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(0),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+        }),
+        transfer_amount,
+    )
+    .await?;
     result.assert_success();
 
-    let alice_balance = alice.account().unwrap().amount;
+    let alice_balance = balance(&alice).await? + gas_cost(&result);
     assert_eq!(
         initial_alice_balance, alice_balance,
         "Alice should not spend NEAR on a 0 USD payment.",
     );
 
     assert!(
-        bob.account().unwrap().amount == initial_bob_balance,
+        balance(&bob).await? == initial_bob_balance,
         "Bob's balance should be unchanged"
     );
     assert!(
-        builder.account().unwrap().amount == initial_bob_balance,
+        balance(&builder).await? == initial_bob_balance,
         "Builder's balance should be unchanged"
     );
+
+    Ok(())
 }
 
-#[test]
-fn test_outdated_rate() {
-    let (alice, bob, builder, proxy, _) = init();
-    let initial_alice_balance = alice.account().unwrap().amount;
-    let initial_proxy_balance = proxy.account().unwrap().amount;
+#[tokio::test]
+async fn test_outdated_rate() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, _) = init().await?;
+    let initial_alice_balance = balance(&alice).await?;
+    let initial_proxy_balance = balance(proxy.as_account()).await?;
     let transfer_amount = to_yocto("100");
-    let payment_address = bob.account_id().try_into().unwrap();
-    let fee_address = builder.account_id().try_into().unwrap();
-
-    let result = call!(
-        alice,
-        proxy.transfer_with_reference(
-            PAYMENT_REF.into(),
-            payment_address,
-            U128::from(120000),
-            USD.into(),
-            fee_address,
-            U128::from(0),
+    let payment_address = bob.id();
+    let fee_address = builder.id();
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": payment_address,
+            "amount": U128::from(120000),
+            "currency": USD,
+            "fee_address": fee_address,
+            "fee_amount": U128::from(0),
             // The mocked rate is 10 nanoseconds old
-            U64::from(1)
-        ),
-        deposit = transfer_amount
-    );
-    result.assert_success_one_log("Conversion rate too old");
+            "max_rate_timespan": U64::from(1),
+        }),
+        transfer_amount,
+    )
+    .await?;
+    result.assert_payment_failed("ERR_OUTDATED_RATE");
 
-    assert_eq!(
-        initial_proxy_balance,
-        proxy.account().unwrap().amount,
+    assert!(
+        unchanged_but_gas_rewards(
+            balance(proxy.as_account()).await?,
+            initial_proxy_balance,
+            &result
+        ),
         "Contract's balance should be unchanged"
     );
     assert_eq!(
         initial_alice_balance,
-        alice.account().unwrap().amount,
+        balance(&alice).await? + gas_cost(&result),
         "Alice should not spend NEAR on an outdated rate payment.",
     );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_round() -> anyhow::Result<()> {
+    // The round opened at genesis, more than 1 second ago
+    assert_transfer_fails_with_feed(
+        &[(
+            "set_price",
+            json!({ "feed_key": valid_feed_key(), "mantissa": 1234000000, "scale": 9, "round_open_timestamp": 0 }),
+        )],
+        1_000_000_000,
+        "ERR_OUTDATED_RATE",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_oracle_round_errors() -> anyhow::Result<()> {
+    assert_transfer_fails_with_feed(
+        &[
+            (
+                "set_price",
+                json!({ "feed_key": valid_feed_key(), "mantissa": 1234000000, "scale": 9 }),
+            ),
+            (
+                "set_round_counts",
+                json!({ "feed_key": valid_feed_key(), "num_success": 1, "num_error": 1 }),
+            ),
+        ],
+        0,
+        "ERR_ORACLE_ERRORS",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_negative_rate() -> anyhow::Result<()> {
+    assert_transfer_fails_with_feed(
+        &[(
+            "set_price",
+            json!({ "feed_key": valid_feed_key(), "mantissa": -1234000000, "scale": 9 }),
+        )],
+        0,
+        "ERR_INVALID_RATE",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_missing_rate() -> anyhow::Result<()> {
+    assert_transfer_fails_with_feed(
+        &[("remove_price", json!({ "feed_key": valid_feed_key() }))],
+        0,
+        "ERR_INVALID_ORACLE_RESPONSE",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_unavailable_oracle() -> anyhow::Result<()> {
+    assert_transfer_fails_with_feed(
+        &[("set_error", json!({ "error": "Feed unavailable" }))],
+        0,
+        "ERR_FAILED_ORACLE_FETCH",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_transfer_gas_profile() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _) = init().await?;
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "transfer_with_reference",
+        json!({
+            "payment_reference": PAYMENT_REF,
+            "to": bob.id(),
+            // 12000.00 USD (main)
+            "amount": U128::from(1200000),
+            "currency": USD,
+            "fee_address": builder.id(),
+            // 1.00 USD (fee)
+            "fee_amount": U128::from(100),
+            "max_rate_timespan": U64::from(0),
+        }),
+        to_yocto("200000"),
+    )
+    .await?;
+    result.assert_success();
+
+    // Budgets in Tgas: the gas left to each receipt by the proxy once it has attached the gas of its promises
+    let profile = GasProfile::new(
+        &worker,
+        "conversion_proxy_transfer_with_reference",
+        Gas::from_tgas(50),
+        &result,
+    )
+    .await?;
+    profile.assert_within_budgets(&[
+        ("transfer_with_reference", 10),
+        ("aggregator_read", 10),
+        ("rate_callback", 10),
+        ("on_transfer_with_reference", 20),
+    ])
 }
@@ -1,187 +1,523 @@
 use crate::utils::*;
-use fungible_conversion_proxy::FungibleConversionProxyContract;
-use mocks::fpo_oracle_mock::FPOContractContract;
-use mocks::fungible_token_mock::FungibleTokenContractContract;
-use near_sdk::json_types::U128;
-use near_sdk_sim::init_simulator;
-use near_sdk_sim::runtime::GenesisConfig;
-use near_sdk_sim::ContractAccount;
-use near_sdk_sim::UserAccount;
-use near_sdk_sim::{call, deploy, lazy_static_include, to_yocto};
-use std::convert::TryInto;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde_json::{json, Value};
+use near_workspaces::network::Sandbox;
+use near_workspaces::result::ExecutionFinalResult;
+use near_workspaces::types::Gas;
+use near_workspaces::{Account, Contract, Worker};
 use std::str;
 
-near_sdk::setup_alloc!();
-
 const PROXY_ID: &str = "fungible_conversion_proxy";
-lazy_static_include::lazy_static_include_bytes! {
-   PROXY_BYTES => "target/wasm32-unknown-unknown/release/fungible_conversion_proxy.wasm"
-}
-lazy_static_include::lazy_static_include_bytes! {
-    MOCKED_BYTES => "target/wasm32-unknown-unknown/debug/mocks.wasm"
-}
+const PROXY_WASM: &str = "target/wasm32-unknown-unknown/release/fungible_conversion_proxy.wasm";
 
 const DEFAULT_BALANCE: &str = "400000";
 
-// Initialize test environment with 3 accounts (alice, bob, builder), a fungible conversion mock, and a fungible token mock.
-fn init_fungible() -> (
-    UserAccount,
-    UserAccount,
-    UserAccount,
-    ContractAccount<FungibleConversionProxyContract>,
-    ContractAccount<FungibleTokenContractContract>,
-) {
-    let genesis = GenesisConfig::default();
-    let root = init_simulator(Some(genesis));
-
-    deploy!(
-        contract: FPOContractContract,
-        contract_id: "mockedfpo".to_string(),
-        bytes: &MOCKED_BYTES,
-        signer_account: root,
-        deposit: to_yocto("7")
-    );
+// Initialize test environment with 3 accounts (alice, bob, builder), a fungible conversion mock, and a fungible token mock, and the proxy owner account.
+async fn init_fungible() -> anyhow::Result<(
+    Worker<Sandbox>,
+    Account,
+    Account,
+    Account,
+    Contract,
+    Contract,
+    Account,
+)> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root, _) =
+        init_fungible_with_oracle().await?;
+    Ok((worker, alice, bob, builder, proxy, ft_contract, root))
+}
 
-    let ft_contract = deploy!(
-        contract: FungibleTokenContractContract,
-        contract_id: "mockedft".to_string(),
-        bytes: &MOCKED_BYTES,
-        signer_account: root,
-        deposit: to_yocto("8")
-    );
+// Same as `init_fungible`, also returning the Flux oracle mock, to configure its prices.
+async fn init_fungible_with_oracle() -> anyhow::Result<(
+    Worker<Sandbox>,
+    Account,
+    Account,
+    Account,
+    Contract,
+    Contract,
+    Account,
+    Contract,
+)> {
+    let worker = near_workspaces::sandbox().await?;
+    let root = worker.root_account()?;
 
-    let account = root.create_user("alice".to_string(), to_yocto(DEFAULT_BALANCE));
-    let empty_account_1 = root.create_user("bob".parse().unwrap(), to_yocto(DEFAULT_BALANCE));
-    let empty_account_2 = root.create_user("builder".parse().unwrap(), to_yocto(DEFAULT_BALANCE));
-
-    let proxy = deploy!(
-        contract: FungibleConversionProxyContract,
-        contract_id: PROXY_ID,
-        bytes: &PROXY_BYTES,
-        signer_account: root,
-        deposit: to_yocto("5"),
-        init_method: new("mockedfpo".into(), "any".into())
-    );
+    let oracle = deploy(&worker, "mockedfpo", MOCKED_WASM, to_yocto("7")).await?;
 
-    let get_oracle_result = call!(root, proxy.get_oracle_account());
-    get_oracle_result.assert_success();
+    let ft_contract = deploy(&worker, "mockedft", MOCKED_WASM, to_yocto("8")).await?;
 
-    debug_assert_eq!(
-        &get_oracle_result.unwrap_json_value().to_owned(),
-        &"mockedfpo".to_string()
-    );
+    let account = create_account(&worker, "alice", to_yocto(DEFAULT_BALANCE)).await?;
+    let empty_account_1 = create_account(&worker, "bob", to_yocto(DEFAULT_BALANCE)).await?;
+    let empty_account_2 = create_account(&worker, "builder", to_yocto(DEFAULT_BALANCE)).await?;
+
+    let proxy = deploy(&worker, PROXY_ID, PROXY_WASM, to_yocto("5")).await?;
+    call(
+        &root,
+        proxy.id(),
+        "new",
+        json!({ "oracle_account_id": "mockedfpo", "provider_account_id": "any" }),
+        0,
+    )
+    .await?
+    .assert_success();
 
-    (
+    let oracle_account = view(&proxy, "get_oracle_account", json!({}))
+        .await?
+        .json::<String>()?;
+    assert_eq!(oracle_account, "mockedfpo");
+
+    Ok((
+        worker,
         account,
         empty_account_1,
         empty_account_2,
         proxy,
         ft_contract,
+        root,
+        oracle,
+    ))
+}
+
+// Pays 100.00 USD in USDC.e with the oracle configured by `set_oracle`, expecting the payment to fail with `error`
+async fn assert_transfer_fails_with_oracle(
+    set_oracle: (&str, Value),
+    max_rate_timespan: u64,
+    error: &str,
+) -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _, oracle) =
+        init_fungible_with_oracle().await?;
+    let (method, args) = set_oracle;
+    call(oracle.as_account(), oracle.id(), method, args, 0)
+        .await?
+        .assert_success();
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(max_rate_timespan),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error(error);
+
+    Ok(())
+}
+
+// Registers alice, bob, builder and the proxy with the mocked token, alice holding `alice_balance`, to pay with
+// `ft_transfer_call`
+async fn mocked_ft_setup(
+    alice: &Account,
+    ft_contract: &Contract,
+    alice_balance: u128,
+) -> anyhow::Result<()> {
+    for account in [alice.id().as_str(), "bob", "builder", PROXY_ID] {
+        call(
+            alice,
+            ft_contract.id(),
+            "register_account",
+            json!({ "account": account }),
+            0,
+        )
+        .await?
+        .assert_success();
+    }
+    call(
+        alice,
+        ft_contract.id(),
+        "set_balance",
+        json!({ "account": alice.id(), "balance": U128::from(alice_balance) }),
+        0,
+    )
+    .await?
+    .assert_success();
+    Ok(())
+}
+
+// Alice pays 100 USD to bob, with a `fee_amount` fee in USD cents to builder, sending `send_amt` of the mocked token
+// with `ft_transfer_call`
+async fn mocked_ft_transfer_call(
+    alice: &Account,
+    bob: &Account,
+    proxy: &Contract,
+    ft_contract: &Contract,
+    send_amt: u128,
+    fee_amount: u128,
+) -> anyhow::Result<ExecutionFinalResult> {
+    let msg = view(
+        proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(fee_amount),
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?
+    .json::<String>()?
+    .replace('\\', "");
+
+    call(
+        alice,
+        ft_contract.id(),
+        "ft_transfer_call",
+        json!({
+            "receiver_id": PROXY_ID,
+            "amount": U128::from(send_amt),
+            "msg": msg,
+        }),
+        1,
+    )
+    .await
+}
+
+async fn mocked_ft_balance(ft_contract: &Contract, account: &str) -> anyhow::Result<u128> {
+    Ok(view(
+        ft_contract,
+        "ft_balance_of",
+        json!({ "account_id": account }),
+    )
+    .await?
+    .json::<U128>()?
+    .0)
+}
+
+// Pays 100 USD with a 2 USD fee in USDC.e with `decimals`, through `ft_transfer_call` and the receiver flow
+async fn assert_transfer_call_with_decimals(decimals: u8) -> anyhow::Result<()> {
+    let (_, alice, bob, _, proxy, ft_contract, _) = init_fungible().await?;
+    call(
+        ft_contract.as_account(),
+        ft_contract.id(),
+        "set_metadata",
+        json!({ "symbol": "USDC.e", "decimals": decimals }),
+        0,
     )
+    .await?
+    .assert_success();
+    let one_token = 10u128.pow(decimals.into());
+    mocked_ft_setup(&alice, &ft_contract, 1000 * one_token).await?;
+
+    let result =
+        mocked_ft_transfer_call(&alice, &bob, &proxy, &ft_contract, 500 * one_token, 200).await?;
+    result.assert_success();
+
+    // 1 USD = 1000000/999900 USDC.e
+    let expected_received = 100 * one_token * 1000000 / 999900;
+    let expected_fee = 2 * one_token * 1000000 / 999900;
+    assert_eq!(
+        result.unwrap_json::<U128>().0,
+        expected_received + expected_fee
+    );
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "alice").await?,
+        1000 * one_token - expected_received - expected_fee
+    );
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "bob").await?,
+        expected_received
+    );
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "builder").await?,
+        expected_fee
+    );
+    assert_eq!(mocked_ft_balance(&ft_contract, PROXY_ID).await?, 0);
+
+    Ok(())
+}
+
+// Alice pays with `ft_transfer_call` a token with `behaviors`, expecting the payment to fail and be fully refunded
+async fn assert_transfer_call_refunded_with_behaviors(behaviors: Value) -> anyhow::Result<()> {
+    let (_, alice, bob, _, proxy, ft_contract, _) = init_fungible().await?;
+    mocked_ft_setup(&alice, &ft_contract, 1000000000).await?;
+    call(
+        ft_contract.as_account(),
+        ft_contract.id(),
+        "set_behaviors",
+        json!({ "behaviors": behaviors }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let result =
+        mocked_ft_transfer_call(&alice, &bob, &proxy, &ft_contract, 500000000, 200).await?;
+    result.assert_success();
+
+    // The payment failed: the full amount is refunded, nothing is used
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_eq!(mocked_ft_balance(&ft_contract, "alice").await?, 1000000000);
+    assert_eq!(mocked_ft_balance(&ft_contract, "bob").await?, 0);
+    assert_eq!(mocked_ft_balance(&ft_contract, PROXY_ID).await?, 0);
+
+    Ok(())
 }
 
 // Helper function for setting up fungible token transfer tests
-fn fungible_transfer_setup(
-    alice: &UserAccount,
-    bob: &UserAccount,
-    builder: &UserAccount,
-    ft_contract: &ContractAccount<FungibleTokenContractContract>,
+async fn fungible_transfer_setup(
+    alice: &Account,
+    bob: &Account,
+    builder: &Account,
+    ft_contract: &Contract,
     send_amt: U128,
-) -> (u128, u128, u128) {
+) -> anyhow::Result<(u128, u128, u128)> {
     // Register alice, bob, builder, and the contract with the fungible token
-    call!(alice, ft_contract.register_account(alice.account_id()));
-    call!(bob, ft_contract.register_account(bob.account_id()));
-    call!(builder, ft_contract.register_account(builder.account_id()));
-    call!(builder, ft_contract.register_account(PROXY_ID.into()));
+    call(
+        alice,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": alice.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        bob,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": PROXY_ID,
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
     // Set initial balances
-    call!(
+    call(
         alice,
-        ft_contract.set_balance(alice.account_id(), 1000000000.into()) // 1000 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": alice.id(),
+            "balance": U128::from(1000000000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 1000 USDC.e
+    call(
         bob,
-        ft_contract.set_balance(bob.account_id(), 1000000.into()) // 1 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": bob.id(),
+            "balance": U128::from(1000000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 1 USDC.e
+    call(
         builder,
-        ft_contract.set_balance(builder.account_id(), 0.into()) // 0 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": builder.id(),
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 0 USDC.e
+    call(
         builder,
-        ft_contract.set_balance(PROXY_ID.into(), 0.into()) // 0 USDC.e
-    );
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": PROXY_ID,
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 0 USDC.e
 
-    let alice_balance_before = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let bob_balance_before = call!(bob, ft_contract.ft_balance_of(bob.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let builder_balance_before = call!(builder, ft_contract.ft_balance_of(builder.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    let alice_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let bob_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let builder_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": builder.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
 
     // In real usage, the user calls `ft_transfer_call` on the token contract, which calls `ft_on_transfer` on our contract
     // The token contract will transfer the specificed tokens from the caller to our contract before calling our contract
-    call!(alice, ft_contract.set_balance(PROXY_ID.into(), send_amt));
-    call!(
+    call(
         alice,
-        ft_contract.set_balance(
-            alice.account_id(),
-            U128::from(alice_balance_before - send_amt.0)
-        )
-    );
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": PROXY_ID,
+            "balance": send_amt,
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        alice,
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": alice.id(),
+            "balance": U128::from(alice_balance_before - send_amt.0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
-    (
+    Ok((
         alice_balance_before,
         bob_balance_before,
         builder_balance_before,
-    )
+    ))
 }
 
-#[test]
-fn test_transfer() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
     let (alice_balance_before, bob_balance_before, builder_balance_before) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Constuct the `msg` argument using our contract
     // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            10000.into(), // 100 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            200.into(), // 2 USD
-            0.into(),
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
-    );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
-    );
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
     result.assert_success();
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
-
-    let alice_balance_after = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0
-        + change;
-    let bob_balance_after = call!(bob, ft_contract.ft_balance_of(bob.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let builder_balance_after = call!(builder, ft_contract.ft_balance_of(builder.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    let change = result.unwrap_json::<U128>().0;
+
+    let alice_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0 + change;
+    let bob_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let builder_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": builder.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
 
     // USDC.e has 6 decimals
     let total_usdce_amount = 102 * 1000000; // 102 USD
@@ -207,208 +543,1034 @@ fn test_transfer() {
     let received_amount = builder_balance_after - builder_balance_before;
     let expected_received = fee_usdce_amount * rate_numerator / rate_denominator;
     assert!(received_amount == expected_received);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_reference_token() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _, root) = init_fungible().await?;
+    let token = deploy_reference_ft(&worker, &root, &alice, &[PROXY_ID, "bob", "builder"]).await?;
+
+    // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
+    let msg = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": builder.id(),
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?
+    .json::<String>()?
+    .replace("\\", "");
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let result = call(
+        &alice,
+        token.id(),
+        "ft_transfer_call",
+        json!({
+            "receiver_id": PROXY_ID,
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        1,
+    )
+    .await?;
+    result.assert_success();
+
+    // 1 USD = 1000000/999900 USDC.e
+    let expected_spent = 102 * 1000000 * 1000000 / 999900;
+    // The proxy returns the change as a JSON `U128`, refunded by `ft_resolve_transfer` which returns the used amount
+    assert_eq!(result.unwrap_json::<U128>().0, expected_spent);
+    assert_eq!(
+        reference_ft_balance(&token, "alice").await?,
+        1000000000 - expected_spent
+    );
+    assert_eq!(
+        reference_ft_balance(&token, "bob").await?,
+        100 * 1000000 * 1000000 / 999900
+    );
+    assert_eq!(
+        reference_ft_balance(&token, "builder").await?,
+        2 * 1000000 * 1000000 / 999900
+    );
+    assert_eq!(reference_ft_balance(&token, PROXY_ID).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_register_accounts() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+    add_registration_token(&root, &proxy, &ft_contract).await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, _, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Bob is not registered with the token contract, alice funds his registration
+    call(
+        &bob,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &alice,
+        proxy.id(),
+        "deposit_storage_fund",
+        json!({}),
+        to_yocto("0.01"),
+    )
+    .await?
+    .assert_success();
+
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+            "register_accounts": Some(true),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    let change = result.unwrap_json::<U128>().0;
+
+    // The price of USDC.e returned by the oracle is 999900 with 6 decimals
+    let expected_spent = 102 * 1000000 * 1000000 / 999900;
+    assert_spent(
+        &alice,
+        alice_balance_before - change,
+        expected_spent,
+        &ft_contract,
+    )
+    .await?;
+    assert_received(&bob, 0, 100 * 1000000 * 1000000 / 999900, &ft_contract).await?;
+    assert_received(
+        &builder,
+        builder_balance_before,
+        2 * 1000000 * 1000000 / 999900,
+        &ft_contract,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_multiple_fees() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": "referrer",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder and a 1 USD fee to referrer
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+            "fees": [{ "address": "referrer", "amount": U128::from(100) }], // 1 USD
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    let change = result.unwrap_json::<U128>().0;
+
+    // 1 USD = 1000000/999900 USDC.e, each payment being converted separately
+    let rate_numerator = 1000000;
+    let rate_denominator = 999900;
+    let payment_usdce_amount = 100 * 1000000 * rate_numerator / rate_denominator;
+    let fee_usdce_amount = 2 * 1000000 * rate_numerator / rate_denominator;
+    let referrer_usdce_amount = 1000000 * rate_numerator / rate_denominator;
+
+    assert_eq!(
+        change,
+        send_amt.0 - payment_usdce_amount - fee_usdce_amount - referrer_usdce_amount
+    );
+    // The mocked fungible token does not handle change
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, payment_usdce_amount, &ft_contract).await?;
+    assert_received(
+        &builder,
+        builder_balance_before,
+        fee_usdce_amount,
+        &ft_contract,
+    )
+    .await?;
+    let referrer_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": "referrer",
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(referrer_balance, referrer_usdce_amount);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_protocol_fee() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (_, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": "treasury",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    // 2% protocol fee, at least 3 USD
+    call(
+        &root,
+        proxy.id(),
+        "set_protocol_fee",
+        json!({
+            "bps": 200,
+            "treasury_id": "treasury",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &root,
+        proxy.id(),
+        "set_protocol_fee_caps",
+        json!({
+            "currency": "USD",
+            "min_amount": U128::from(300),
+            "max_amount": U128::from(10000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    let change = result.unwrap_json::<U128>().0;
+
+    // 1 USD = 1000000/999900 USDC.e, each payment being converted separately
+    let rate_numerator = 1000000;
+    let rate_denominator = 999900;
+    let payment_usdce_amount = 100 * 1000000 * rate_numerator / rate_denominator;
+    let fee_usdce_amount = 2 * 1000000 * rate_numerator / rate_denominator;
+    let protocol_fee_usdce_amount = 3 * 1000000 * rate_numerator / rate_denominator;
+
+    assert_eq!(
+        change,
+        send_amt.0 - payment_usdce_amount - fee_usdce_amount - protocol_fee_usdce_amount
+    );
+    assert_received(&bob, bob_balance_before, payment_usdce_amount, &ft_contract).await?;
+    assert_received(
+        &builder,
+        builder_balance_before,
+        fee_usdce_amount,
+        &ft_contract,
+    )
+    .await?;
+    let treasury_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": "treasury",
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(treasury_balance, protocol_fee_usdce_amount);
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_not_enough() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_with_fee_bps() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
-    let (_, _, _) = fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    let (_, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Transferring 100 USD worth of USDC.e from alice to bob, with a 2.5% fee to builder
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(0),
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+            "fee_bps": Some(250),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    // The computed fee is logged in `currency`
+    assert!(result.logs()[0].contains(r#""fee_amount":"250""#));
+
+    // 1 USD = 1000000/999900 USDC.e
+    let rate_numerator = 1000000;
+    let rate_denominator = 999900;
+    assert_received(
+        &bob,
+        bob_balance_before,
+        100 * 1000000 * rate_numerator / rate_denominator,
+        &ft_contract,
+    )
+    .await?;
+    assert_received(
+        &builder,
+        builder_balance_before,
+        2500000 * rate_numerator / rate_denominator,
+        &ft_contract,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_not_enough() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (_, _, _) = fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Constuct the `msg` argument using our contract
     // Transferring 1000 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
     // The request is for 1000 USD, but alice only sends in 500 USDC.e
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            100000.into(), // 1000 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            200.into(), // 2 USD
-            0.into(),
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
-    );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(100000), // 1000 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
-    );
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
     result.assert_one_promise_error("Deposit too small");
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_receiver_send_failed() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_receiver_send_failed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
-    let (alice_balance_before, _, _) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    let (alice_balance_before, _, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Previous line registers all accounts, so we unregister bob here
     // Receiver is not registered with the token contract, so sending to it will fail
-    call!(bob, ft_contract.unregister_account(bob.account_id()));
+    call(
+        &bob,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
     // Constuct the `msg` argument using our contract
     // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            10000.into(), // 100 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            200.into(), // 2 USD
-            0.into(),
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
-    );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
-    );
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
     result.assert_success();
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
+    assert!(result.logs()[0].contains(r#""event":"transfer_failed""#));
+    assert!(!result
+        .logs()
+        .iter()
+        .any(|log| log.contains(r#""crypto_amount""#)));
+    let change = result.unwrap_json::<U128>().0;
 
-    let alice_balance_after = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0
-        + change;
+    let alice_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0 + change;
 
-    // Ensure no balance changes / all funds returned to sender
-    assert!(alice_balance_after == alice_balance_before);
+    // Only the fee was paid, the rest is returned to the sender
+    let fee_usdce_amount = 2 * 1000000 * 1000000 / 999900; // 2 USD
+    assert_eq!(alice_balance_after, alice_balance_before - fee_usdce_amount);
+    assert_received(
+        &builder,
+        builder_balance_before,
+        fee_usdce_amount,
+        &ft_contract,
+    )
+    .await?;
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_fee_receiver_send_failed() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_fee_receiver_send_failed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
-    let (alice_balance_before, _, _) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Previous line registers all accounts, so we unregister builder here
     // Fee_receiver is not registered with the token contract, so sending to it will fail
-    call!(
-        builder,
-        ft_contract.unregister_account(builder.account_id())
-    );
+    call(
+        &builder,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
     // Constuct the `msg` argument using our contract
     // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            10000.into(), // 100 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            200.into(), // 2 USD
-            0.into(),
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
-    );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert!(result.logs()[1].contains(r#""event":"transfer_failed""#));
+    assert!(result
+        .logs()
+        .last()
+        .unwrap()
+        .contains(r#""crypto_fee_amount":"0""#));
+    let change = result.unwrap_json::<U128>().0;
+
+    let alice_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0 + change;
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
+    // The payment was made without its fee, which is returned to the sender
+    let payment_usdce_amount = 100 * 1000000 * 1000000 / 999900; // 100 USD
+    assert_eq!(
+        alice_balance_after,
+        alice_balance_before - payment_usdce_amount
     );
+    assert_received(&bob, bob_balance_before, payment_usdce_amount, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recurring_payment() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Alice authorizes up to 100 USD per day to bob, executed by builder
+    let msg = json!({
+        "authorize": {
+            "amount": "10000",
+            "currency": "USD",
+            "executor": "builder",
+            "expiry": "4000000000000000000", // Year 2096
+            "fee_address": "builder",
+            "fee_amount": "0",
+            "max_rate_timespan": "0",
+            "payment_reference": "abc7c8bb1234fd12",
+            "period": "86400000000000", // 1 day
+            "to": "bob",
+        }
+    })
+    .to_string();
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"recurring_authorized""#);
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+
+    let result = call(
+        &builder,
+        proxy.id(),
+        "execute_recurring",
+        json!({
+            "id": U64::from(0),
+        }),
+        0,
+    )
+    .await?;
     result.assert_success();
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
+    assert!(result.unwrap_json::<bool>());
 
-    let alice_balance_after = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0
-        + change;
+    // 100 USD at the mocked rate of 0.9999 USD per USDC.e
+    let payment_usdce_amount = 100 * 1000000 * 1000000 / 999900;
+    assert_received(&bob, bob_balance_before, payment_usdce_amount, &ft_contract).await?;
+    let recurring = view(
+        &proxy,
+        "get_recurring_payment",
+        json!({
+            "id": U64::from(0),
+        }),
+    )
+    .await?
+    .json::<Value>()?;
+    assert_eq!(
+        recurring["balance"],
+        (send_amt.0 - payment_usdce_amount).to_string()
+    );
 
-    // Ensure no balance changes / all funds returned to sender
-    assert!(alice_balance_after == alice_balance_before);
+    // Only once per period
+    let result = call(
+        &builder,
+        proxy.id(),
+        "execute_recurring",
+        json!({
+            "id": U64::from(0),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("The recurring payment was already executed for this period");
+
+    // Alice gets the remaining balance back
+    let result = call(
+        &alice,
+        proxy.id(),
+        "revoke_recurring",
+        json!({
+            "id": U64::from(0),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"recurring_revoked""#);
+    assert_spent(
+        &alice,
+        alice_balance_before,
+        payment_usdce_amount,
+        &ft_contract,
+    )
+    .await?;
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_zero_usd() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_zero_usd() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(0); // 0 USDC.e
     let (alice_balance_before, bob_balance_before, builder_balance_before) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Constuct the `msg` argument using our contract
     // Transferring 0 USD worth of USDC.e from alice to bob, with a 0 USD fee to builder
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            0.into(), // 0 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            0.into(), // 0 USD
-            0.into(),
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
-    );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(0), // 0 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(0), // 0 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
-    );
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
     result.assert_success();
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
-
-    let alice_balance_after = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0
-        + change;
-    let bob_balance_after = call!(bob, ft_contract.ft_balance_of(bob.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let builder_balance_after = call!(builder, ft_contract.ft_balance_of(builder.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    let change = result.unwrap_json::<U128>().0;
+
+    let alice_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0 + change;
+    let bob_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let builder_balance_after = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": builder.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
 
     assert!(alice_balance_after == alice_balance_before);
     assert!(bob_balance_after == bob_balance_before);
     assert!(builder_balance_after == builder_balance_before);
+
+    Ok(())
 }
 
-#[test]
-fn test_outdated_rate() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_outdated_rate() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
-    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     // Constuct the `msg` argument using our contract
     // Transferring 100 USD worth of USDC.e from alice to bob, with a 2 USD fee to builder
-    let get_args = call!(
-        alice,
-        proxy.get_transfer_with_reference_args(
-            10000.into(), // 100 USD
-            "USD".into(),
-            "builder".to_string().try_into().unwrap(),
-            200.into(), // 2 USD
-            1.into(),   // 1 ns
-            "abc7c8bb1234fd12".into(),
-            bob.account_id().try_into().unwrap()
-        )
+    let get_args = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(1), // 1 ns
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?;
+    let msg = get_args.json::<String>()?.replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("Conversion rate too old");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_price() -> anyhow::Result<()> {
+    // The price was updated at genesis, more than 1 second ago
+    assert_transfer_fails_with_oracle(
+        (
+            "set_price",
+            json!({ "pair": "USDC.e/USD", "price": U128::from(999900), "decimals": 6, "last_update": 0 }),
+        ),
+        1_000_000_000,
+        "Conversion rate too old",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_missing_price() -> anyhow::Result<()> {
+    assert_transfer_fails_with_oracle(
+        ("remove_price", json!({ "pair": "USDC.e/USD" })),
+        0,
+        "Invalid oracle response",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_unavailable_oracle() -> anyhow::Result<()> {
+    assert_transfer_fails_with_oracle(
+        ("set_error", json!({ "error": "Oracle unavailable" })),
+        0,
+        "Failed to fetch the conversion rate",
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_transfer_call_with_0_decimals() -> anyhow::Result<()> {
+    assert_transfer_call_with_decimals(0).await
+}
+
+#[tokio::test]
+async fn test_transfer_call_with_24_decimals() -> anyhow::Result<()> {
+    assert_transfer_call_with_decimals(24).await
+}
+
+#[tokio::test]
+async fn test_transfer_call_metadata_panics() -> anyhow::Result<()> {
+    assert_transfer_call_refunded_with_behaviors(json!({ "panic_in_metadata": true })).await
+}
+
+#[tokio::test]
+async fn test_transfer_call_excessive_gas() -> anyhow::Result<()> {
+    // More than the gas attached by the proxy to `ft_transfer`
+    assert_transfer_call_refunded_with_behaviors(json!({ "burnt_tgas": 25 })).await
+}
+
+#[tokio::test]
+async fn test_transfer_call_zero_fee_rejected() -> anyhow::Result<()> {
+    let (_, alice, bob, _, proxy, ft_contract, _) = init_fungible().await?;
+    mocked_ft_setup(&alice, &ft_contract, 1000000000).await?;
+    call(
+        ft_contract.as_account(),
+        ft_contract.id(),
+        "set_behaviors",
+        json!({ "behaviors": { "reject_zero_transfers": true } }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    // The proxy does not transfer the zero fee
+    let result = mocked_ft_transfer_call(&alice, &bob, &proxy, &ft_contract, 500000000, 0).await?;
+    result.assert_success();
+
+    let expected_received = 100 * 1000000 * 1000000 / 999900;
+    assert_eq!(result.unwrap_json::<U128>().0, expected_received);
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "bob").await?,
+        expected_received
     );
-    get_args.assert_success();
-    let msg = get_args.unwrap_json::<String>().replace("\\", "");
+    assert_eq!(mocked_ft_balance(&ft_contract, "builder").await?, 0);
+    assert_eq!(mocked_ft_balance(&ft_contract, PROXY_ID).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_call_with_transfer_fee() -> anyhow::Result<()> {
+    let (_, alice, bob, _, proxy, ft_contract, _) = init_fungible().await?;
+    mocked_ft_setup(&alice, &ft_contract, 1000000000).await?;
+    // The token withholds 1% of each transfer
+    call(
+        ft_contract.as_account(),
+        ft_contract.id(),
+        "set_behaviors",
+        json!({ "behaviors": { "transfer_fee_bps": 100 } }),
+        0,
+    )
+    .await?
+    .assert_success();
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), msg)
+    let result =
+        mocked_ft_transfer_call(&alice, &bob, &proxy, &ft_contract, 500000000, 200).await?;
+    result.assert_success();
+
+    // The proxy receives 495 USDC.e, pays the converted amounts, and returns the change without fee
+    let expected_amount = 100 * 1000000 * 1000000 / 999900;
+    let expected_fee = 2 * 1000000 * 1000000 / 999900;
+    let change = 495000000 - expected_amount - expected_fee;
+    assert_eq!(
+        result.unwrap_json::<U128>().0,
+        expected_amount + expected_fee
     );
-    result.assert_one_promise_error("Conversion rate too old");
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "alice").await?,
+        500000000 + change
+    );
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "bob").await?,
+        expected_amount - expected_amount / 100
+    );
+    assert_eq!(
+        mocked_ft_balance(&ft_contract, "builder").await?,
+        expected_fee - expected_fee / 100
+    );
+    assert_eq!(mocked_ft_balance(&ft_contract, PROXY_ID).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_gas_profile() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let msg = view(
+        &proxy,
+        "get_transfer_with_reference_args",
+        json!({
+            "amount": U128::from(10000), // 100 USD
+            "currency": "USD",
+            "fee_address": "builder",
+            "fee_amount": U128::from(200), // 2 USD
+            "max_rate_timespan": U64::from(0),
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": bob.id(),
+        }),
+    )
+    .await?
+    .json::<String>()?
+    .replace('\\', "");
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": msg,
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+
+    // Budgets in Tgas: the gas left to each receipt by the proxy once it has attached the gas of its promises
+    let profile = GasProfile::new(
+        &worker,
+        "fungible_conversion_proxy_ft_on_transfer",
+        Gas::from_tgas(150),
+        &result,
+    )
+    .await?;
+    profile.assert_within_budgets(&[
+        ("ft_on_transfer", 20),
+        ("ft_metadata", 10),
+        ("ft_metadata_callback", 30),
+        ("get_entry", 10),
+        ("rate_callback", 30),
+        ("ft_transfer", 20),
+        ("on_transfer_with_reference", 10),
+    ])
 }
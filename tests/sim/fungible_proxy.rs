@@ -1,149 +1,268 @@
 use crate::utils::*;
-use fungible_proxy::FungibleProxyContract;
+use fungible_proxy::FeeRecipient;
 use fungible_proxy::PaymentArgs;
-use mocks::fungible_token_mock::FungibleTokenContractContract;
+use fungible_proxy::StreamArgs;
+use fungible_proxy::SwapArgs;
 use near_sdk::json_types::U128;
-use near_sdk::serde_json::json;
-use near_sdk_sim::init_simulator;
-use near_sdk_sim::runtime::GenesisConfig;
-use near_sdk_sim::ContractAccount;
-use near_sdk_sim::UserAccount;
-use near_sdk_sim::{call, deploy, lazy_static_include, to_yocto};
-use std::convert::TryInto;
+use near_sdk::serde_json::{json, Value};
+use near_workspaces::network::Sandbox;
+use near_workspaces::result::ExecutionFinalResult;
+use near_workspaces::types::Gas;
+use near_workspaces::{Account, Contract, Worker};
+use payment_intents::PaymentIntent;
 use std::ops::Sub;
 use std::str;
 
-near_sdk::setup_alloc!();
-
 const PROXY_ID: &str = "fungible_proxy";
-lazy_static_include::lazy_static_include_bytes! {
-   PROXY_BYTES => "target/wasm32-unknown-unknown/release/fungible_proxy.wasm"
-}
-lazy_static_include::lazy_static_include_bytes! {
-    MOCKED_BYTES => "target/wasm32-unknown-unknown/debug/mocks.wasm"
-}
+const PROXY_WASM: &str = "target/wasm32-unknown-unknown/release/fungible_proxy.wasm";
 
 const DEFAULT_BALANCE: &str = "400000";
 
-// Initialize test environment with 3 accounts (alice, bob, builder), a fungible conversion mock, and a fungible token mock.
-fn init_fungible() -> (
-    UserAccount,
-    UserAccount,
-    UserAccount,
-    ContractAccount<FungibleProxyContract>,
-    ContractAccount<FungibleTokenContractContract>,
-) {
-    let genesis = GenesisConfig::default();
-    let root = init_simulator(Some(genesis));
-
-    let ft_contract = deploy!(
-        contract: FungibleTokenContractContract,
-        contract_id: "mockedft".to_string(),
-        bytes: &MOCKED_BYTES,
-        signer_account: root,
-        deposit: to_yocto("8")
-    );
+// Initialize test environment with 3 accounts (alice, bob, builder), a fungible conversion mock, and a fungible token mock, and the proxy owner account.
+async fn init_fungible() -> anyhow::Result<(
+    Worker<Sandbox>,
+    Account,
+    Account,
+    Account,
+    Contract,
+    Contract,
+    Account,
+)> {
+    let worker = near_workspaces::sandbox().await?;
+    let root = worker.root_account()?;
 
-    let account = root.create_user("alice".to_string(), to_yocto(DEFAULT_BALANCE));
-    let empty_account_1 = root.create_user("bob".parse().unwrap(), to_yocto(DEFAULT_BALANCE));
-    let empty_account_2 = root.create_user("builder".parse().unwrap(), to_yocto(DEFAULT_BALANCE));
+    let ft_contract = deploy(&worker, "mockedft", MOCKED_WASM, to_yocto("8")).await?;
 
-    let proxy = deploy!(
-        contract: FungibleProxyContract,
-        contract_id: PROXY_ID,
-        bytes: &PROXY_BYTES,
-        signer_account: root,
-        deposit: to_yocto("5")
-    );
+    let account = create_account(&worker, "alice", to_yocto(DEFAULT_BALANCE)).await?;
+    let empty_account_1 = create_account(&worker, "bob", to_yocto(DEFAULT_BALANCE)).await?;
+    let empty_account_2 = create_account(&worker, "builder", to_yocto(DEFAULT_BALANCE)).await?;
+
+    let proxy = deploy(&worker, PROXY_ID, PROXY_WASM, to_yocto("5")).await?;
+    call(&root, proxy.id(), "new", json!({}), 0)
+        .await?
+        .assert_success();
 
-    (
+    Ok((
+        worker,
         account,
         empty_account_1,
         empty_account_2,
         proxy,
         ft_contract,
-    )
+        root,
+    ))
 }
 
 // Helper function for setting up fungible token transfer tests
-fn fungible_transfer_setup(
-    alice: &UserAccount,
-    bob: &UserAccount,
-    builder: &UserAccount,
-    ft_contract: &ContractAccount<FungibleTokenContractContract>,
+async fn fungible_transfer_setup(
+    alice: &Account,
+    bob: &Account,
+    builder: &Account,
+    ft_contract: &Contract,
     send_amt: U128,
-) -> (u128, u128, u128) {
+) -> anyhow::Result<(u128, u128, u128)> {
     // Register alice, bob, builder, and the contract with the fungible token
-    call!(alice, ft_contract.register_account(alice.account_id()));
-    call!(bob, ft_contract.register_account(bob.account_id()));
-    call!(builder, ft_contract.register_account(builder.account_id()));
-    call!(builder, ft_contract.register_account(PROXY_ID.into()));
+    call(
+        alice,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": alice.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        bob,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": PROXY_ID,
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
     // Set initial balances
-    call!(
+    call(
         alice,
-        ft_contract.set_balance(alice.account_id(), 1000000000.into()) // 1000 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": alice.id(),
+            "balance": U128::from(1000000000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 1000 USDC.e
+    call(
         bob,
-        ft_contract.set_balance(bob.account_id(), 1000000.into()) // 1 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": bob.id(),
+            "balance": U128::from(1000000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 1 USDC.e
+    call(
         builder,
-        ft_contract.set_balance(builder.account_id(), 0.into()) // 0 USDC.e
-    );
-    call!(
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": builder.id(),
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 0 USDC.e
+    call(
         builder,
-        ft_contract.set_balance(PROXY_ID.into(), 0.into()) // 0 USDC.e
-    );
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": PROXY_ID,
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 0 USDC.e
 
-    let alice_balance_before = call!(alice, ft_contract.ft_balance_of(alice.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let bob_balance_before = call!(bob, ft_contract.ft_balance_of(bob.account_id()))
-        .unwrap_json::<U128>()
-        .0;
-    let builder_balance_before = call!(builder, ft_contract.ft_balance_of(builder.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    let alice_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let bob_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let builder_balance_before = view(
+        ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": builder.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
 
     // In real usage, the user calls `ft_transfer_call` on the token contract, which calls `ft_on_transfer` on our contract
     // The token contract will transfer the specificed tokens from the caller to our contract before calling our contract
-    call!(alice, ft_contract.set_balance(PROXY_ID.into(), send_amt));
-    call!(
+    call(
         alice,
-        ft_contract.set_balance(
-            alice.account_id(),
-            U128::from(alice_balance_before - send_amt.0)
-        )
-    );
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": PROXY_ID,
+            "balance": send_amt,
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        alice,
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": alice.id(),
+            "balance": U128::from(alice_balance_before - send_amt.0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
-    (
+    Ok((
         alice_balance_before,
         bob_balance_before,
         builder_balance_before,
-    )
+    ))
+}
+
+/// Balance of the proxy on the payment token
+async fn proxy_ft_balance(ft_contract: &Contract) -> anyhow::Result<u128> {
+    ft_balance(ft_contract, PROXY_ID).await
 }
 
-#[test]
-fn test_transfer() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
     let (alice_balance_before, bob_balance_before, builder_balance_before) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     let args = PaymentArgs {
-        fee_address: builder.account_id().try_into().unwrap(),
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
         fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
         payment_reference: "abc7c8bb1234fd11".into(),
-        to: bob.account_id().try_into().unwrap(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
     };
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), args.into())
-    );
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
     result.assert_success_one_log(
         &json!({
             "amount": "498000000", // 500 USDC.e - 2 USDC.e fee
@@ -157,141 +276,2328 @@ fn test_transfer() {
     );
 
     // The mocked fungible token does not handle change
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
+    let change = result.unwrap_json::<U128>().0;
     assert!(change == 0);
 
-    assert_spent(alice, alice_balance_before, send_amt.into(), &ft_contract);
-    assert_received(bob, bob_balance_before, 498000000, &ft_contract);
-    assert_received(builder, builder_balance_before, 2000000, &ft_contract);
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 498000000, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+
+    Ok(())
 }
 
-#[test]
-fn transfer_less_than_fee_amount() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_with_reference_token() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, _, _, root) = init_fungible().await?;
+    // The builder is not registered with the token, so that the fee transfer fails
+    let token = deploy_reference_ft(&worker, &root, &alice, &[PROXY_ID, "bob"]).await?;
 
     let send_amt = U128::from(500000000); // 500 USDC.e
-    let (_, _, _) = fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
-
     let args = PaymentArgs {
-        fee_address: "builder".try_into().unwrap(),
-        fee_amount: 500100000.into(), // 500.10 USDC.e
-        payment_reference: "abc7c8bb1234fd12".into(),
-        to: bob.account_id().try_into().unwrap(),
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
     };
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), args.into())
-    );
-    result.assert_one_promise_error("amount smaller than fee_amount");
+    let result = call(
+        &alice,
+        token.id(),
+        "ft_transfer_call",
+        json!({
+            "receiver_id": PROXY_ID,
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        1,
+    )
+    .await?;
+    result.assert_success();
+
+    // The proxy returns the failed fee as a JSON `U128`, refunded by `ft_resolve_transfer` which returns the used amount
+    assert_eq!(result.unwrap_json::<U128>().0, 498000000);
+    assert_eq!(reference_ft_balance(&token, "alice").await?, 502000000);
+    assert_eq!(reference_ft_balance(&token, "bob").await?, 498000000);
+    assert_eq!(reference_ft_balance(&token, PROXY_ID).await?, 0);
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_receiver_send_failed() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_with_reference_token_invalid_msg() -> anyhow::Result<()> {
+    let (worker, alice, _, _, _, _, root) = init_fungible().await?;
+    let token = deploy_reference_ft(&worker, &root, &alice, &[PROXY_ID]).await?;
 
-    let send_amt = U128::from(500000000); // 500 USDC.e
-    let (alice_balance_before, _, _) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    let result = call(
+        &alice,
+        token.id(),
+        "ft_transfer_call",
+        json!({
+            "receiver_id": PROXY_ID,
+            "amount": U128::from(500000000),
+            "msg": "invalid",
+        }),
+        1,
+    )
+    .await?;
+    result.assert_success();
 
-    // Previous line registers all accounts, so we unregister bob here
-    // Receiver is not registered with the token contract, so sending to it will fail
-    call!(bob, ft_contract.unregister_account(bob.account_id()));
+    // `ft_on_transfer` panicked: the full amount is refunded, nothing is used
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_eq!(reference_ft_balance(&token, "alice").await?, 1000000000);
+    assert_eq!(reference_ft_balance(&token, PROXY_ID).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_multiple_fees() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": "referrer",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
 
     let args = PaymentArgs {
-        fee_address: builder.account_id().try_into().unwrap(),
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
         fee_amount: 2000000.into(), // 2 USDC.e
-        payment_reference: "abc7c8bb1234fd12".into(),
-        to: bob.account_id().try_into().unwrap(),
+        fee_bps: None,
+        fees: vec![FeeRecipient {
+            address: "referrer".parse()?,
+            amount: 1000000.into(), // 1 USDC.e
+        }],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
     };
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), args.into())
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(
+        &json!({
+            "amount": "497000000", // 500 USDC.e - 2 USDC.e fee - 1 USDC.e referrer fee
+            "token_address": "mockedft",
+            "fee_address": "builder",
+            "fee_amount": "2000000",
+            "fees": [{ "address": "referrer", "amount": "1000000" }],
+            "payment_reference": "abc7c8bb1234fd11",
+            "to": "bob",
+        })
+        .to_string(),
     );
-    result.assert_success_one_log("Transfer failed to bob or builder. Returning attached amount of 500000000 of token mockedft to alice");
-
-    // The mocked fungible token does not handle change
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
 
-    let result = call!(alice, ft_contract.ft_balance_of(alice.account_id()));
-    let alice_balance_after = result.unwrap_json::<U128>().0 + change;
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 497000000, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+    let referrer_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": "referrer",
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(referrer_balance, 1000000);
 
-    // Ensure no balance changes / all funds returned to sender
-    assert!(alice_balance_after == alice_balance_before);
+    Ok(())
 }
 
-#[test]
-fn test_transfer_fee_receiver_send_failed() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_with_fee_bps() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
-    let send_amt = U128::from(500000000); // 500 USDC.e
-    let (alice_balance_before, _, _) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+    let send_amt = U128::from(505000000); // 505 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
-    // Previous line registers all accounts, so we unregister builder here
-    // Fee_receiver is not registered with the token contract, so sending to it will fail
-    call!(
-        builder,
-        ft_contract.unregister_account(builder.account_id())
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 0.into(),
+        fee_bps: Some(100), // 1%
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(
+        &json!({
+            "amount": "500000000", // 505 USDC.e - 1% of 500 USDC.e
+            "token_address": "mockedft",
+            "fee_address": "builder",
+            "fee_amount": "5000000",
+            "payment_reference": "abc7c8bb1234fd11",
+            "to": "bob",
+        })
+        .to_string(),
     );
 
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 500000000, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 5000000, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_protocol_fee() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(505000000); // 505 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &builder,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": "treasury",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &root,
+        proxy.id(),
+        "set_protocol_fee",
+        json!({
+            "bps": 100,
+            "treasury_id": "treasury",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
     let args = PaymentArgs {
-        fee_address: "builder".try_into().unwrap(),
-        fee_amount: 200.into(),
-        payment_reference: "abc7c8bb1234fd12".into(),
-        to: bob.account_id().try_into().unwrap(),
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
     };
 
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), args.into())
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    // 503 USDC.e are split into 498.019802 USDC.e for bob and 1% of it for the treasury
+    result.assert_success_one_log(
+        &json!({
+            "amount": "498019802",
+            "token_address": "mockedft",
+            "fee_address": "builder",
+            "fee_amount": "2000000",
+            "payment_reference": "abc7c8bb1234fd11",
+            "protocol_fee": { "amount": "4980198", "treasury_id": "treasury" },
+            "to": "bob",
+        })
+        .to_string(),
     );
-    result.assert_success_one_log("Transfer failed to bob or builder. Returning attached amount of 500000000 of token mockedft to alice");
 
-    // The mocked fungible token does not handle change
-    let change = result.unwrap_json::<String>().parse::<u128>().unwrap();
-    assert_eq!(change, 500000000);
-
-    assert_unchanged_balance(
-        alice,
-        alice_balance_before.sub(change),
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 498019802, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+    let treasury_balance = view(
         &ft_contract,
-        "Alice",
-    );
+        "ft_balance_of",
+        json!({
+            "account_id": "treasury",
+        }),
+    )
+    .await?;
+    assert_eq!(treasury_balance.json::<U128>()?.0, 4980198);
+
+    Ok(())
 }
 
-#[test]
-fn test_transfer_zero_usd() {
-    let (alice, bob, builder, proxy, ft_contract) = init_fungible();
+#[tokio::test]
+async fn test_transfer_with_escrow_released() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
 
-    let send_amt = U128::from(0); // 0 USDC.e
+    let send_amt = U128::from(500000000); // 500 USDC.e
     let (alice_balance_before, bob_balance_before, builder_balance_before) =
-        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt);
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
 
     let args = PaymentArgs {
-        fee_address: "builder".try_into().unwrap(),
-        fee_amount: 0.into(),
-        payment_reference: "abc7c8bb1234fd12".into(),
-        to: bob.account_id().try_into().unwrap(),
+        claim_after: None,
+        escrow_timeout: Some(86_400_000_000_000.into()), // 1 day
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
     };
-    let result = call!(
-        ft_contract.user_account,
-        proxy.ft_on_transfer(alice.account_id(), send_amt.0.to_string(), args.into())
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"escrow_locked""#);
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    let bob_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(
+        bob_balance, bob_balance_before,
+        "Bob should not be paid yet"
     );
-    result.assert_success_one_log(
+
+    // Only the payer can release the escrow
+    let result = call(
+        &bob,
+        proxy.id(),
+        "release_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("Only the payer can release the escrow");
+
+    let result = call(
+        &alice,
+        proxy.id(),
+        "release_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.logs().len(), 2, "Wrong number of logs");
+    assert!(result.logs()[0].contains(r#""event":"escrow_released""#));
+    assert!(result.logs()[1].contains(
         &json!({
-            "amount": "0",
+            "amount": "498000000", // 500 USDC.e - 2 USDC.e fee
             "token_address": "mockedft",
             "fee_address": "builder",
-            "fee_amount": "0",
-            "payment_reference": "abc7c8bb1234fd12",
+            "fee_amount": "2000000",
+            "payment_reference": "abc7c8bb1234fd11",
             "to": "bob",
         })
-        .to_string(),
+        .to_string()
+    ));
+
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 498000000, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_partially_released() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: Some(86_400_000_000_000.into()), // 1 day
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+
+    // Fee_receiver is not registered with the token contract, so the fee cannot be released
+    call(
+        &builder,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    let result = call(
+        &alice,
+        proxy.id(),
+        "release_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert!(!result.unwrap_json::<bool>());
+    assert_eq!(result.logs().len(), 5, "Wrong number of logs");
+    assert!(result.logs()[0].contains(r#""amount":"498000000","event":"escrow_released""#));
+    assert!(result.logs()[1].contains(r#""amount":"498000000","fee_address":"builder""#));
+    assert!(result.logs()[2].contains(r#""event":"transfer_succeeded""#));
+    assert!(result.logs()[3].contains(r#""event":"transfer_failed""#));
+    assert_eq!(
+        result.logs()[4],
+        "Transfer failed to builder for escrow abc7c8bb1234fd11. 2000000 of token mockedft are kept in escrow for a later release or refund"
     );
+    assert_received(&bob, bob_balance_before, 498000000, &ft_contract).await?;
+
+    // Only the fee is kept in escrow, and held by the proxy
+    let escrow = view(
+        &proxy,
+        "get_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+    )
+    .await?
+    .json::<Value>()?;
+    assert_eq!(
+        escrow["transfers"],
+        json!([{ "receiver_id": "builder", "amount": "2000000" }])
+    );
+    assert_eq!(escrow["payment_log"], "");
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, 2000000);
+
+    // Refunding the escrow only returns the fee to the payer
+    let result = call(
+        &bob,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""amount":"2000000","event":"escrow_refunded""#);
+    assert_spent(&alice, alice_balance_before, 498000000, &ft_contract).await?;
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_refunded() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: Some(86_400_000_000_000.into()), // 1 day
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+
+    // The payer cannot get a refund before the timeout
+    let result = call(
+        &alice,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("can refund the escrow");
+
+    // The payee can refund at any time
+    let result = call(
+        &bob,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"escrow_refunded""#);
+
+    assert_unchanged_balance(&alice, alice_balance_before, &ft_contract, "alice").await?;
+    assert_received(&bob, bob_balance_before, 0, &ft_contract).await?;
+    let escrow = view(
+        &proxy,
+        "get_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+    )
+    .await?;
+    assert!(escrow.json::<Value>()?.is_null());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_escrow_refunded_to() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+    let carol = create_account(&worker, "carol", to_yocto("1")).await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, _, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &carol,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": carol.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &carol,
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": carol.id(),
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: Some(86_400_000_000_000.into()), // 1 day
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: Some(carol.id().parse()?),
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""refund_to":"carol""#);
+
+    // Only the payer can get a refund, carol receives it
+    let result = call(
+        &carol,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("can refund the escrow");
+    let result = call(
+        &bob,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"escrow_refunded","payer":"alice""#);
+    assert!(result.logs()[0].contains(r#""refund_to":"carol""#));
+
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&carol, 0, send_amt.0, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scheduled_transfer_cancelled() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: Some(4_000_000_000_000_000_000.into()), // Year 2096
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""claim_after":"4000000000000000000""#);
+
+    // The payee cannot claim the payment yet
+    let result = call(
+        &bob,
+        proxy.id(),
+        "release_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("or the payee after claim_after");
+
+    // The payer can cancel it
+    let result = call(
+        &alice,
+        proxy.id(),
+        "refund_escrow",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"escrow_refunded""#);
+
+    assert_unchanged_balance(&alice, alice_balance_before, &ft_contract, "alice").await?;
+    assert_received(&bob, bob_balance_before, 0, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_withdrawn_and_cancelled() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(102000000); // 102 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // 1 USDC.e per second, for 100 seconds
+    let start = worker.view_block().await?.timestamp();
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: Some(StreamArgs {
+            rate: 1000000.into(),
+            start: start.into(),
+            end: (start + 100_000_000_000).into(),
+        }),
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""event":"stream_created""#);
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
 
-    assert_unchanged_balance(alice, alice_balance_before, &ft_contract, "Alice");
-    assert_unchanged_balance(bob, bob_balance_before, &ft_contract, "Bob");
-    assert_unchanged_balance(builder, builder_balance_before, &ft_contract, "Builder");
+    // The payee withdraws the amount vested so far
+    worker.fast_forward(10).await?;
+    let result = call(
+        &bob,
+        proxy.id(),
+        "withdraw_stream",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(r#""payment_reference":"abc7c8bb1234fd11""#);
+    let stream = view(
+        &proxy,
+        "get_stream",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+    )
+    .await?
+    .json::<Value>()?;
+    let withdrawn: u128 = stream["withdrawn"].as_str().unwrap().parse().unwrap();
+    assert!(withdrawn > 0, "Bob should have withdrawn a vested amount");
+    let bob_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(bob_balance, bob_balance_before + withdrawn);
+
+    // Only the payer can cancel the stream
+    let result = call(
+        &bob,
+        proxy.id(),
+        "cancel_stream",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("Only the payer can cancel the stream");
+
+    worker.fast_forward(10).await?;
+    let result = call(
+        &alice,
+        proxy.id(),
+        "cancel_stream",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert!(result.logs()[0].contains(r#""event":"stream_cancelled""#));
+
+    // Bob got the vested amount, alice the rest of the 100 USDC.e streamed
+    let bob_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": bob.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let alice_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    let bob_received = bob_balance - bob_balance_before;
+    assert!(bob_received > withdrawn && bob_received < 100000000);
+    assert_eq!(alice_balance_before - alice_balance, 2000000 + bob_received);
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+    let stream = view(
+        &proxy,
+        "get_stream",
+        json!({
+            "payment_reference": "abc7c8bb1234fd11",
+            "payer": alice.id(),
+        }),
+    )
+    .await?;
+    assert!(stream.json::<Value>()?.is_null());
+
+    Ok(())
+}
+
+// Helper function for setting up swap tests: a second token, "mockedftout", and an exchange swapping 1 "mockedft"
+// for 2 "mockedftout", with 1000 "mockedftout" of liquidity, allowed by the proxy
+async fn swap_setup(
+    worker: &Worker<Sandbox>,
+    root: &Account,
+    proxy: &Contract,
+    bob: &Account,
+    builder: &Account,
+    ft_contract: &Contract,
+) -> anyhow::Result<Contract> {
+    let ft_out_contract = deploy(worker, "mockedftout", MOCKED_WASM, to_yocto("8")).await?;
+    let exchange = deploy(worker, "mockedexchange", MOCKED_WASM, to_yocto("8")).await?;
+    call(
+        root,
+        exchange.id(),
+        "set_pool",
+        json!({
+            "pool_id": 0,
+            "token_in": "mockedft",
+            "token_out": "mockedftout",
+            "rate_numerator": U128::from(2),
+            "rate_denominator": U128::from(1),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        root,
+        proxy.id(),
+        "add_exchange",
+        json!({
+            "exchange_id": "mockedexchange",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    call(
+        root,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": "mockedexchange",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    for account in [
+        "mockedexchange",
+        PROXY_ID,
+        bob.id().as_str(),
+        builder.id().as_str(),
+    ] {
+        call(
+            root,
+            ft_out_contract.id(),
+            "register_account",
+            json!({
+                "account": account,
+            }),
+            0,
+        )
+        .await?
+        .assert_success();
+    }
+    call(
+        root,
+        ft_out_contract.id(),
+        "set_balance",
+        json!({
+            "account": "mockedexchange",
+            "balance": U128::from(1000000000),
+        }),
+        0,
+    )
+    .await?
+    .assert_success(); // 1000 USDC.e
+    Ok(ft_out_contract)
+}
+
+#[tokio::test]
+async fn test_transfer_with_swap() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(100000000); // 100 USDC.e
+    let (alice_balance_before, _, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    let ft_out_contract = swap_setup(&worker, &root, &proxy, &bob, &builder, &ft_contract).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 out tokens
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: Some(SwapArgs {
+            exchange_id: "mockedexchange".parse()?,
+            pool_id: 0,
+            token_out: "mockedftout".parse()?,
+            min_amount_out: 190000000.into(),
+        }),
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(
+        &json!({
+            "amount": "198000000", // 200 out tokens - 2 out tokens fee
+            "token_address": "mockedftout",
+            "fee_address": "builder",
+            "fee_amount": "2000000",
+            "payment_reference": "abc7c8bb1234fd12",
+            "swap": {
+                "amount_in": "100000000",
+                "amount_out": "200000000",
+                "token_in": "mockedft",
+            },
+            "to": "bob",
+        })
+        .to_string(),
+    );
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, 0, 198000000, &ft_out_contract).await?;
+    assert_received(&builder, 0, 2000000, &ft_out_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_swap_slippage() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(100000000); // 100 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    let ft_out_contract = swap_setup(&worker, &root, &proxy, &bob, &builder, &ft_contract).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 out tokens
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: Some(SwapArgs {
+            exchange_id: "mockedexchange".parse()?,
+            pool_id: 0,
+            token_out: "mockedftout".parse()?,
+            min_amount_out: 210000000.into(),
+        }),
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    // The full amount is returned to `ft_resolve_transfer`, for the payer to be refunded
+    assert_eq!(result.unwrap_json::<U128>(), send_amt);
+    let proxy_balance = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": PROXY_ID,
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(proxy_balance, send_amt.0);
+    assert_received(&bob, 0, 0, &ft_out_contract).await?;
+
+    Ok(())
+}
+
+// Helper function: alice pays bob `send_amt` of mockedft swapped to mockedftout (see `swap_setup`), for at least
+// `min_amount_out`, with a fee of 2 out tokens to builder
+async fn swap_payment(
+    alice: &Account,
+    bob: &Account,
+    builder: &Account,
+    proxy: &Contract,
+    ft_contract: &Contract,
+    send_amt: U128,
+    min_amount_out: U128,
+) -> anyhow::Result<ExecutionFinalResult> {
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: Some(SwapArgs {
+            exchange_id: "mockedexchange".parse()?,
+            pool_id: 0,
+            token_out: "mockedftout".parse()?,
+            min_amount_out,
+        }),
+        to: bob.id().parse()?,
+    };
+    call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await
+}
+
+// Helper function: alice's balance of `token_id` kept on mockedexchange by the proxy
+async fn exchange_balance(
+    proxy: &Contract,
+    alice: &Account,
+    token_id: &str,
+) -> anyhow::Result<u128> {
+    Ok(view(
+        proxy,
+        "get_exchange_balance",
+        json!({
+            "account_id": alice.id(),
+            "exchange_id": "mockedexchange",
+            "token_id": token_id,
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0)
+}
+
+// Helper function: sets whether withdrawals from mockedexchange fail
+async fn pause_exchange_withdrawals(root: &Account, paused: bool) -> anyhow::Result<()> {
+    call(
+        root,
+        &"mockedexchange".parse()?,
+        "set_withdrawals_paused",
+        json!({ "paused": paused }),
+        0,
+    )
+    .await?
+    .assert_success();
+    Ok(())
+}
+
+// Helper function: registers `account` with `token`
+async fn register_with_token(
+    root: &Account,
+    token: &Contract,
+    account: &str,
+) -> anyhow::Result<()> {
+    call(
+        root,
+        token.id(),
+        "register_account",
+        json!({ "account": account }),
+        0,
+    )
+    .await?
+    .assert_success();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_swap_balance_check_failed() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(100000000); // 100 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    let ft_out_contract = swap_setup(&worker, &root, &proxy, &bob, &builder, &ft_contract).await?;
+    register_with_token(&root, &ft_out_contract, alice.id().as_str()).await?;
+    // The proxy balance of the out token cannot be checked
+    call(
+        &root,
+        ft_out_contract.id(),
+        "unregister_account",
+        json!({ "account": PROXY_ID }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let result = swap_payment(
+        &alice,
+        &bob,
+        &builder,
+        &proxy,
+        &ft_contract,
+        send_amt,
+        190000000.into(),
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_received(&bob, 0, 0, &ft_out_contract).await?;
+    assert_eq!(
+        exchange_balance(&proxy, &alice, "mockedftout").await?,
+        200000000
+    );
+
+    // Alice claims the swapped tokens
+    register_with_token(&root, &ft_out_contract, PROXY_ID).await?;
+    let result = call(
+        &alice,
+        proxy.id(),
+        "withdraw_from_exchange",
+        json!({
+            "exchange_id": "mockedexchange",
+            "token_id": "mockedftout",
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_received(&alice, 0, 200000000, &ft_out_contract).await?;
+    assert_eq!(exchange_balance(&proxy, &alice, "mockedftout").await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_swap_refund_withdrawal_failed() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(100000000); // 100 USDC.e
+    let (alice_balance_before, _, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    swap_setup(&worker, &root, &proxy, &bob, &builder, &ft_contract).await?;
+    pause_exchange_withdrawals(&root, true).await?;
+
+    // Slippage, the attached tokens cannot be withdrawn from the exchange
+    let result = swap_payment(
+        &alice,
+        &bob,
+        &builder,
+        &proxy,
+        &ft_contract,
+        send_amt,
+        210000000.into(),
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_eq!(
+        exchange_balance(&proxy, &alice, "mockedft").await?,
+        send_amt.0
+    );
+
+    // Claiming fails until withdrawals resume, the balance being kept
+    let claim = || {
+        call(
+            &alice,
+            proxy.id(),
+            "withdraw_from_exchange",
+            json!({
+                "exchange_id": "mockedexchange",
+                "token_id": "mockedft",
+            }),
+            0,
+        )
+    };
+    claim().await?.assert_success();
+    assert_eq!(
+        exchange_balance(&proxy, &alice, "mockedft").await?,
+        send_amt.0
+    );
+    pause_exchange_withdrawals(&root, false).await?;
+    claim().await?.assert_success();
+    assert_eq!(exchange_balance(&proxy, &alice, "mockedft").await?, 0);
+    // The attached tokens held by the proxy are transferred to alice
+    assert_received(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_swap_withdrawal_failed() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+
+    let send_amt = U128::from(100000000); // 100 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    let ft_out_contract = swap_setup(&worker, &root, &proxy, &bob, &builder, &ft_contract).await?;
+    register_with_token(&root, &ft_out_contract, alice.id().as_str()).await?;
+    pause_exchange_withdrawals(&root, true).await?;
+
+    // The swap succeeds, the swapped tokens cannot be withdrawn from the exchange
+    let result = swap_payment(
+        &alice,
+        &bob,
+        &builder,
+        &proxy,
+        &ft_contract,
+        send_amt,
+        190000000.into(),
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_received(&bob, 0, 0, &ft_out_contract).await?;
+    assert_eq!(
+        exchange_balance(&proxy, &alice, "mockedftout").await?,
+        200000000
+    );
+
+    pause_exchange_withdrawals(&root, false).await?;
+    call(
+        &alice,
+        proxy.id(),
+        "withdraw_from_exchange",
+        json!({
+            "exchange_id": "mockedexchange",
+            "token_id": "mockedftout",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    assert_received(&alice, 0, 200000000, &ft_out_contract).await?;
+    assert_eq!(exchange_balance(&proxy, &alice, "mockedftout").await?, 0);
+
+    Ok(())
+}
+
+// Helper function for setting up wrapped NEAR tests: a wrapped NEAR mock, "mockedwnear", on which
+// `registered_accounts` are registered, used by the proxy
+async fn wrap_setup(
+    worker: &Worker<Sandbox>,
+    root: &Account,
+    proxy: &Contract,
+    registered_accounts: &[&str],
+) -> anyhow::Result<Contract> {
+    let wnear_contract = deploy(worker, "mockedwnear", MOCKED_WASM, to_yocto("8")).await?;
+    for account in registered_accounts {
+        call(
+            root,
+            wnear_contract.id(),
+            "register_account",
+            json!({
+                "account": account,
+            }),
+            0,
+        )
+        .await?
+        .assert_success();
+    }
+    call(
+        root,
+        proxy.id(),
+        "set_wrap_account",
+        json!({
+            "wrap": "mockedwnear",
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    Ok(wnear_contract)
+}
+
+fn wrapped_near_payment_args(bob: &Account, builder: &Account) -> anyhow::Result<PaymentArgs> {
+    Ok(PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: to_yocto("1").into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    })
+}
+
+#[tokio::test]
+async fn test_transfer_with_wrapped_near() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _, root) = init_fungible().await?;
+    let wnear_contract = wrap_setup(&worker, &root, &proxy, &[PROXY_ID, "bob", "builder"]).await?;
+
+    let alice_balance_before = balance(&alice).await?;
+    let result = call(
+        &alice,
+        proxy.id(),
+        "wrap_and_transfer_with_reference",
+        json!({
+            "args": wrapped_near_payment_args(&bob, &builder)?,
+        }),
+        to_yocto("10"),
+    )
+    .await?;
+    result.assert_success();
+
+    let spent_amount = alice_balance_before - balance(&alice).await?;
+    assert!(spent_amount >= to_yocto("10") && spent_amount < to_yocto("11"));
+    assert_received(&bob, 0, to_yocto("9"), &wnear_contract).await?;
+    assert_received(&builder, 0, to_yocto("1"), &wnear_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_wrapped_near_wrap_failed() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _, root) = init_fungible().await?;
+    // The proxy is not registered on the wrap contract
+    let wnear_contract = wrap_setup(&worker, &root, &proxy, &["bob", "builder"]).await?;
+
+    let alice_balance_before = balance(&alice).await?;
+    let result = call(
+        &alice,
+        proxy.id(),
+        "wrap_and_transfer_with_reference",
+        json!({
+            "args": wrapped_near_payment_args(&bob, &builder)?,
+        }),
+        to_yocto("10"),
+    )
+    .await?;
+    result.assert_success();
+
+    // Alice only paid for gas
+    let spent_amount = alice_balance_before - balance(&alice).await?;
+    assert!(spent_amount < to_yocto("1"));
+    assert_received(&bob, 0, 0, &wnear_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_wrapped_near_receiver_send_failed() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, _, root) = init_fungible().await?;
+    // Bob is not registered on the wrap contract
+    let wnear_contract = wrap_setup(&worker, &root, &proxy, &[PROXY_ID, "builder"]).await?;
+
+    let alice_balance_before = balance(&alice).await?;
+    let result = call(
+        &alice,
+        proxy.id(),
+        "wrap_and_transfer_with_reference",
+        json!({
+            "args": wrapped_near_payment_args(&bob, &builder)?,
+        }),
+        to_yocto("10"),
+    )
+    .await?;
+    result.assert_success();
+    // The fee was paid, the amount not paid to bob was unwrapped and refunded
+    assert_eq!(result.unwrap_json::<U128>().0, to_yocto("9"));
+
+    let spent_amount = alice_balance_before - balance(&alice).await?;
+    assert!(spent_amount >= to_yocto("1") && spent_amount < to_yocto("2"));
+    assert_received(&builder, 0, to_yocto("1"), &wnear_contract).await?;
+    let proxy_balance = view(
+        &wnear_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": PROXY_ID,
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(proxy_balance, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn transfer_less_than_fee_amount() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (_, _, _) = fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: "builder".parse()?,
+        fee_amount: 500100000.into(), // 500.10 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("amount smaller than fee_amount");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_receiver_send_failed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, _, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Previous line registers all accounts, so we unregister bob here
+    // Receiver is not registered with the token contract, so sending to it will fail
+    call(
+        &bob,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    // Each transfer is logged, then the failure, and no payment
+    assert_eq!(result.logs().len(), 3);
+    assert!(result.logs()[0].contains(r#""event":"transfer_failed""#));
+    assert!(result.logs()[1].contains(r#""event":"transfer_succeeded""#));
+    assert_eq!(
+        result.logs()[2],
+        "Transfer failed to bob. Returning 498000000 of token mockedft to alice"
+    );
+
+    // The mocked fungible token does not handle change
+    let change = result.unwrap_json::<U128>().0;
+    assert_eq!(change, 498000000);
+    // The proxy holds exactly what it returns, the fee having left
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, change);
+
+    // Only the fee was paid
+    let result = view(
+        &ft_contract,
+        "ft_balance_of",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?;
+    let alice_balance_after = result.json::<U128>()?.0 + change;
+    assert_eq!(alice_balance_after, alice_balance_before - 2000000);
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+
+    Ok(())
+}
+
+fn register_accounts_payment_args(bob: &Account, builder: &Account) -> anyhow::Result<PaymentArgs> {
+    Ok(PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: Some(true),
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    })
+}
+
+#[tokio::test]
+async fn test_transfer_with_register_accounts() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+    add_registration_token(&root, &proxy, &ft_contract).await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, _, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Bob is not registered with the token contract, alice funds his registration
+    call(
+        &bob,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &alice,
+        proxy.id(),
+        "deposit_storage_fund",
+        json!({}),
+        to_yocto("0.01"),
+    )
+    .await?
+    .assert_success();
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(register_accounts_payment_args(&bob, &builder)?),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert!(result
+        .logs()
+        .iter()
+        .any(|log| log.contains(r#""event":"accounts_registered""#)));
+
+    // The storage cost of the mocked fungible token is 0.00125 NEAR
+    let storage_fund = view(
+        &proxy,
+        "get_storage_fund",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(storage_fund, to_yocto("0.01") - to_yocto("0.00125"));
+
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, 0, 498000000, &ft_contract).await?;
+    assert_received(&builder, builder_balance_before, 2000000, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_register_accounts_without_storage_fund() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, root) = init_fungible().await?;
+    add_registration_token(&root, &proxy, &ft_contract).await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &bob,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": bob.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    // The token contract refunds the payer when `ft_on_transfer` fails
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(register_accounts_payment_args(&bob, &builder)?),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("Not enough storage fund to register bob");
+
+    let is_registered = view(
+        &ft_contract,
+        "is_registered",
+        json!({
+            "account": bob.id(),
+        }),
+    )
+    .await?
+    .json::<bool>()?;
+    assert!(!is_registered);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_register_accounts_token_not_allowed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &alice,
+        proxy.id(),
+        "deposit_storage_fund",
+        json!({}),
+        to_yocto("0.01"),
+    )
+    .await?
+    .assert_success();
+
+    // Any contract can call `ft_on_transfer` with alice as `sender_id`, her storage fund is only spent for allowed tokens
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(register_accounts_payment_args(&bob, &builder)?),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("ERR_REGISTRATION_NOT_ALLOWED");
+    let storage_fund = view(
+        &proxy,
+        "get_storage_fund",
+        json!({
+            "account_id": alice.id(),
+        }),
+    )
+    .await?
+    .json::<U128>()?
+    .0;
+    assert_eq!(storage_fund, to_yocto("0.01"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_fee_receiver_send_failed() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Previous line registers all accounts, so we unregister builder here
+    // Fee_receiver is not registered with the token contract, so sending to it will fail
+    call(
+        &builder,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: "builder".parse()?,
+        fee_amount: 200.into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    // Each transfer is logged, then the failure and the payment without its fee
+    assert_eq!(result.logs().len(), 4);
+    assert!(result.logs()[0].contains(r#""event":"transfer_succeeded""#));
+    assert!(result.logs()[1].contains(r#""event":"transfer_failed""#));
+    assert_eq!(
+        result.logs()[2],
+        "Transfer failed to builder. Returning 200 of token mockedft to alice"
+    );
+    assert!(result.logs()[3]
+        .contains(r#""amount":"499999800","fee_address":"builder","fee_amount":"0""#));
+
+    // The mocked fungible token does not handle change
+    let change = result.unwrap_json::<U128>().0;
+    assert_eq!(change, 200);
+    // The proxy holds exactly what it returns, the main amount having left
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, change);
+    assert_received(&bob, bob_balance_before, 499999800, &ft_contract).await?;
+
+    // Alice only spent what was paid to bob, once the change is returned
+    assert_spent(
+        &alice,
+        alice_balance_before.sub(change),
+        send_amt.0 - change,
+        &ft_contract,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_fee_receiver_send_failed_refund_to() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+    let carol = create_account(&worker, "carol", to_yocto("1")).await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+    call(
+        &carol,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": carol.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &carol,
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": carol.id(),
+            "balance": U128::from(0),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    // Fee_receiver is not registered with the token contract, so sending to it will fail
+    call(
+        &builder,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: "builder".parse()?,
+        fee_amount: 200.into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: Some(carol.id().parse()?),
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    let refund_log = r#""event":"unused_returned","refund_to":"carol""#;
+    assert!(result.logs().iter().any(|log| log.contains(refund_log)));
+
+    // The failed fee went to carol, nothing is returned to `ft_resolve_transfer`
+    assert_eq!(result.unwrap_json::<U128>().0, 0);
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, 0);
+    assert_received(&carol, 0, 200, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 499999800, &ft_contract).await?;
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_with_intent() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (alice_balance_before, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    // Bob registers the key signing his payment requests
+    let bob_secret_key = [7; 32];
+    call(
+        &bob,
+        proxy.id(),
+        "set_payee_key",
+        json!({
+            "public_key": Some(payment_intents::public_key(&bob_secret_key)),
+        }),
+        to_yocto("0.01"),
+    )
+    .await?
+    .assert_success();
+    let intent = PaymentIntent {
+        payment_reference: hex::decode("abc7c8bb1234fd12").unwrap(),
+        to: bob.id().parse()?,
+        amount: 498000000, // 500 USDC.e - 2 USDC.e fee
+        currency: ft_contract.id().parse()?,
+        token: ft_contract.id().parse()?,
+        expiry: worker.view_block().await?.timestamp() + 3600 * 1_000_000_000,
+    };
+
+    let mut args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: Some(intent.sign(&bob_secret_key)),
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    // Payments tampered with by a compromised frontend are rejected: paying another payee...
+    let mut tampered_args = args.clone();
+    tampered_args.to = builder.id().parse()?;
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(tampered_args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("ERR_PAYEE_KEY_NOT_FOUND");
+    // ... or paying bob another amount
+    args.fee_amount = 1000000.into();
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args.clone()),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("ERR_INVALID_INTENT_SIGNATURE");
+    // ... or dropping the intent
+    let mut unsigned_args = args.clone();
+    unsigned_args.intent = None;
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(unsigned_args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_one_promise_error("ERR_INTENT_REQUIRED");
+
+    // The payment signed by bob goes through
+    args.fee_amount = 2000000.into();
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+    assert_spent(&alice, alice_balance_before, send_amt.0, &ft_contract).await?;
+    assert_received(&bob, bob_balance_before, 498000000, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relayed_transfer() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, _, ft_contract, _) = init_fungible().await?;
+    let relayer = Relayer::new(&worker).await?;
+    // Carol holds no NEAR for gas, only for her storage and the yoctoNEAR deposit of `ft_transfer_call`
+    let carol = relayer
+        .create_sender(&worker, "carol", to_yocto("10"))
+        .await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    let (_, bob_balance_before, _) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, 0.into()).await?;
+    call(
+        &carol,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": carol.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &carol,
+        ft_contract.id(),
+        "set_balance",
+        json!({
+            "account": carol.id(),
+            "balance": send_amt,
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    call(
+        &relayer.account,
+        ft_contract.id(),
+        "register_account",
+        json!({
+            "account": relayer.account.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    // The fee transfer fails, so that the payment has an amount to return
+    call(
+        &builder,
+        ft_contract.id(),
+        "unregister_account",
+        json!({
+            "account": builder.id(),
+        }),
+        0,
+    )
+    .await?
+    .assert_success();
+    let relayer_near_balance = balance(&relayer.account).await?;
+    let carol_near_balance = balance(&carol).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: "builder".parse()?,
+        fee_amount: 200.into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+    let msg: String = args.into();
+    let result = relayer
+        .relay(
+            &carol,
+            ft_contract.id().as_str(),
+            "ft_transfer_call",
+            json!({ "receiver_id": PROXY_ID, "amount": send_amt, "memo": None::<String>, "msg": msg }),
+            1,
+        )
+        .await?;
+    result.assert_success();
+
+    // The relayer paid for the gas, carol only for the deposit
+    assert!(balance(&relayer.account).await? < relayer_near_balance);
+    assert!(balance(&carol).await? >= carol_near_balance - 1);
+
+    // Carol paid bob, and got the failed fee back: nothing goes to the relayer
+    assert_eq!(proxy_ft_balance(&ft_contract).await?, 0);
+    assert_received(&bob, bob_balance_before, 499999800, &ft_contract).await?;
+    assert_received(&relayer.account, 0, 0, &ft_contract).await?;
+    assert_received(&carol, 0, 200, &ft_contract).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_zero_usd() -> anyhow::Result<()> {
+    let (_, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(0); // 0 USDC.e
+    let (alice_balance_before, bob_balance_before, builder_balance_before) =
+        fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: "builder".parse()?,
+        fee_amount: 0.into(),
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd12".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success_one_log(
+        &json!({
+            "amount": "0",
+            "token_address": "mockedft",
+            "fee_address": "builder",
+            "fee_amount": "0",
+            "payment_reference": "abc7c8bb1234fd12",
+            "to": "bob",
+        })
+        .to_string(),
+    );
+
+    assert_unchanged_balance(&alice, alice_balance_before, &ft_contract, "Alice").await?;
+    assert_unchanged_balance(&bob, bob_balance_before, &ft_contract, "Bob").await?;
+    assert_unchanged_balance(&builder, builder_balance_before, &ft_contract, "Builder").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transfer_gas_profile() -> anyhow::Result<()> {
+    let (worker, alice, bob, builder, proxy, ft_contract, _) = init_fungible().await?;
+
+    let send_amt = U128::from(500000000); // 500 USDC.e
+    fungible_transfer_setup(&alice, &bob, &builder, &ft_contract, send_amt).await?;
+
+    let args = PaymentArgs {
+        claim_after: None,
+        escrow_timeout: None,
+        fee_address: builder.id().parse()?,
+        fee_amount: 2000000.into(), // 2 USDC.e
+        fee_bps: None,
+        fees: vec![],
+        intent: None,
+        payment_reference: "abc7c8bb1234fd11".into(),
+        register_accounts: None,
+        refund_to: None,
+        stream: None,
+        swap: None,
+        to: bob.id().parse()?,
+    };
+
+    let result = call(
+        ft_contract.as_account(),
+        proxy.id(),
+        "ft_on_transfer",
+        json!({
+            "sender_id": alice.id(),
+            "amount": send_amt,
+            "msg": String::from(args),
+        }),
+        0,
+    )
+    .await?;
+    result.assert_success();
+
+    // Budgets in Tgas: the gas left to each receipt by the proxy once it has attached the gas of its promises.
+    // The proxy requires 150 Tgas, plus 20 Tgas per transfer leg.
+    let profile = GasProfile::new(
+        &worker,
+        "fungible_proxy_ft_on_transfer",
+        Gas::from_tgas(190),
+        &result,
+    )
+    .await?;
+    profile.assert_within_budgets(&[
+        ("ft_on_transfer", 20),
+        ("ft_transfer", 20),
+        ("on_transfer_with_reference", 10),
+    ])
 }
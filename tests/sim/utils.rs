@@ -1,23 +1,314 @@
-use mocks::fungible_token_mock::FungibleTokenContractContract;
+use near_crypto::InMemorySigner;
+use near_jsonrpc_client::methods::broadcast_tx_commit::RpcBroadcastTxCommitRequest;
+use near_jsonrpc_client::JsonRpcClient;
+use near_jsonrpc_primitives::types::transactions::TransactionInfo;
+use near_primitives::delegate_action::{DelegateAction, NonDelegateAction, SignedDelegateAction};
+use near_primitives::hash::CryptoHash;
+use near_primitives::transaction::{Action, FunctionCallAction, Transaction};
+use near_primitives::views::{
+    ActionView, FinalExecutionOutcomeView, FinalExecutionStatus, ReceiptEnumView,
+};
 use near_sdk::json_types::U128;
-use near_sdk_sim::transaction::ExecutionStatus;
-use near_sdk_sim::{call, ContractAccount, ExecutionResult, UserAccount};
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json::{json, Value};
+use near_sdk::Balance;
+use near_workspaces::network::Sandbox;
+use near_workspaces::result::{ExecutionFinalResult, ViewResultDetails};
+use near_workspaces::types::{Gas, KeyType, NearToken, SecretKey};
+use near_workspaces::{AccessKey, Account, AccountDetailsPatch, Contract, Worker};
+
+/// Gas attached to each call, the maximum of a transaction
+pub const DEFAULT_GAS: Gas = Gas::from_tgas(300);
+
+pub const MOCKED_WASM: &str = "target/wasm32-unknown-unknown/debug/mocks.wasm";
+const REFERENCE_FT_WASM: &str = "target/wasm32-unknown-unknown/debug/reference_ft_mock.wasm";
+
+/// Converts an amount of NEAR, e.g. "0.01", to yoctoNEAR
+pub fn to_yocto(value: &str) -> u128 {
+    let parts: Vec<&str> = value.split('.').collect();
+    let units = parts[0].parse::<u128>().unwrap() * 10u128.pow(24);
+    match parts.get(1) {
+        Some(decimals) => {
+            units + decimals.parse::<u128>().unwrap() * 10u128.pow(24 - decimals.len() as u32)
+        }
+        None => units,
+    }
+}
 
 /// Util to compare 2 numbers in yocto, +/- 1 yocto to ignore math precision issues
 pub fn yocto_almost_eq(left: u128, right: u128) -> bool {
-    return std::cmp::max(left, right) - std::cmp::min(left, right) <= 1;
+    std::cmp::max(left, right) - std::cmp::min(left, right) <= 1
+}
+
+/// Creates the top-level account `account_id` holding `balance`, optionally with the contract `wasm`.
+/// Accounts are patched into the sandbox state, only the registrar being able to create short top-level accounts.
+async fn patch_account(
+    worker: &Worker<Sandbox>,
+    account_id: &str,
+    balance: Balance,
+    wasm: Option<&[u8]>,
+) -> anyhow::Result<Contract> {
+    let account_id: near_workspaces::AccountId = account_id.parse()?;
+    let secret_key = SecretKey::from_random(KeyType::ED25519);
+    let mut patch = worker
+        .patch(&account_id)
+        .account(AccountDetailsPatch::default().balance(NearToken::from_yoctonear(balance)))
+        .access_key(secret_key.public_key(), AccessKey::full_access());
+    if let Some(wasm) = wasm {
+        patch = patch.code(wasm);
+    }
+    patch.transact().await?;
+    Ok(Contract::from_secret_key(account_id, secret_key, worker))
+}
+
+/// Creates the top-level account `account_id` holding `balance`
+pub async fn create_account(
+    worker: &Worker<Sandbox>,
+    account_id: &str,
+    balance: Balance,
+) -> anyhow::Result<Account> {
+    Ok(patch_account(worker, account_id, balance, None)
+        .await?
+        .as_account()
+        .clone())
+}
+
+/// Deploys the contract built at `wasm_path` on the top-level account `account_id`, holding `balance`
+pub async fn deploy(
+    worker: &Worker<Sandbox>,
+    account_id: &str,
+    wasm_path: &str,
+    balance: Balance,
+) -> anyhow::Result<Contract> {
+    let wasm = std::fs::read(wasm_path)?;
+    patch_account(worker, account_id, balance, Some(&wasm)).await
+}
+
+/// Sets the NEAR balance of `account`
+pub async fn set_balance(
+    worker: &Worker<Sandbox>,
+    account: &Account,
+    balance: Balance,
+) -> anyhow::Result<()> {
+    worker
+        .patch(account.id())
+        .account_from_current(move |current| {
+            AccountDetailsPatch::from(current).balance(NearToken::from_yoctonear(balance))
+        })
+        .transact()
+        .await?;
+    Ok(())
+}
+
+/// NEAR balance of `account`
+pub async fn balance(account: &Account) -> anyhow::Result<Balance> {
+    Ok(account.view_account().await?.balance.as_yoctonear())
+}
+
+/// Calls `method` on `contract_id` with the JSON `args` and `deposit`, signed by `signer`.
+/// The gas burnt by the call is reported on the standard output, shown with `cargo test -- --nocapture`.
+pub async fn call(
+    signer: &Account,
+    contract_id: &near_workspaces::AccountId,
+    method: &str,
+    args: Value,
+    deposit: Balance,
+) -> anyhow::Result<ExecutionFinalResult> {
+    let result = signer
+        .call(contract_id, method)
+        .args_json(args)
+        .deposit(NearToken::from_yoctonear(deposit))
+        .gas(DEFAULT_GAS)
+        .transact()
+        .await?;
+    report_gas(contract_id, method, &result);
+    Ok(result)
+}
+
+/// Prints the gas burnt by a call, over its transaction and all its receipts
+pub fn report_gas(contract_id: &str, method: &str, result: &ExecutionFinalResult) {
+    println!(
+        "{}.{}: {:.2} Tgas burnt{}",
+        contract_id,
+        method,
+        result.total_gas_burnt.as_gas() as f64 / 1e12,
+        if result.is_failure() { " (failed)" } else { "" }
+    );
+}
+
+/// Calls the view method `method` of `contract` with the JSON `args`
+pub async fn view(
+    contract: &Contract,
+    method: &str,
+    args: Value,
+) -> anyhow::Result<ViewResultDetails> {
+    Ok(contract.view(method).args_json(args).await?)
+}
+
+/// NEAR burnt for the gas of a transaction and its receipts, paid by its signer
+pub fn gas_cost(result: &ExecutionFinalResult) -> Balance {
+    result
+        .outcomes()
+        .iter()
+        .map(|outcome| outcome.tokens_burnt.as_yoctonear())
+        .sum()
+}
+
+/// Gas attached to and burnt by a function call receipt
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReceiptGas {
+    pub receiver_id: String,
+    pub method_name: String,
+    pub gas_attached: u64,
+    pub gas_burnt: u64,
+}
+
+/// Gas profile of a payment path: the function call receipts of its transaction, in execution order
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct GasProfile {
+    pub path: String,
+    /// Minimum gas required by the proxy for the path
+    pub min_gas: u64,
+    /// Gas burnt by the transaction and all its receipts, function calls or not
+    pub total_gas_burnt: u64,
+    pub receipts: Vec<ReceiptGas>,
+}
+
+impl GasProfile {
+    /// Profiles the gas of the payment path `path`, executed by `result`
+    pub async fn new(
+        worker: &Worker<Sandbox>,
+        path: &str,
+        min_gas: Gas,
+        result: &ExecutionFinalResult,
+    ) -> anyhow::Result<Self> {
+        let transaction_info = TransactionInfo::TransactionId {
+            hash: CryptoHash(result.outcome().transaction_hash.0),
+            account_id: result.outcome().executor_id.clone(),
+        };
+        let execution = worker.tx_status(transaction_info).await?;
+        let mut receipts = vec![];
+        for outcome in execution.final_outcome.receipts_outcome {
+            let Some(receipt) = execution
+                .receipts
+                .iter()
+                .find(|receipt| receipt.receipt_id == outcome.id)
+            else {
+                continue;
+            };
+            let ReceiptEnumView::Action { actions, .. } = &receipt.receipt else {
+                continue;
+            };
+            for action in actions {
+                if let ActionView::FunctionCall {
+                    method_name, gas, ..
+                } = action
+                {
+                    receipts.push(ReceiptGas {
+                        receiver_id: receipt.receiver_id.to_string(),
+                        method_name: method_name.clone(),
+                        gas_attached: *gas,
+                        gas_burnt: outcome.outcome.gas_burnt,
+                    });
+                }
+            }
+        }
+        Ok(Self {
+            path: path.to_string(),
+            min_gas: min_gas.as_gas(),
+            total_gas_burnt: result.total_gas_burnt.as_gas(),
+            receipts,
+        })
+    }
+
+    /// Writes the profile as JSON to `target/gas-report/<path>.json`, then asserts that each receipt burns at most
+    /// the budget of its method in `budgets`, in Tgas, and that the whole path burns at most `min_gas`.
+    /// Receipts of methods without a budget fail the assertion, so that new payment legs get a budget.
+    pub fn assert_within_budgets(&self, budgets: &[(&str, u64)]) -> anyhow::Result<()> {
+        std::fs::create_dir_all("target/gas-report")?;
+        std::fs::write(
+            format!("target/gas-report/{}.json", self.path),
+            near_sdk::serde_json::to_string_pretty(self)?,
+        )?;
+        for receipt in &self.receipts {
+            let budget = budgets
+                .iter()
+                .find(|(method_name, _)| *method_name == receipt.method_name)
+                .map(|(_, budget)| Gas::from_tgas(*budget).as_gas())
+                .unwrap_or_else(|| {
+                    panic!("{}: no gas budget for {}", self.path, receipt.method_name)
+                });
+            assert!(
+                receipt.gas_burnt <= budget,
+                "{}: {} on {} burnt {} gas, over its budget of {}",
+                self.path,
+                receipt.method_name,
+                receipt.receiver_id,
+                receipt.gas_burnt,
+                budget
+            );
+        }
+        assert!(
+            self.total_gas_burnt <= self.min_gas,
+            "{}: burnt {} gas, over the minimum gas of {}",
+            self.path,
+            self.total_gas_burnt,
+            self.min_gas
+        );
+        Ok(())
+    }
+}
+
+/// Util to check a contract balance is the same as in a previous state, but for its share of the gas burnt by `result`
+pub fn unchanged_but_gas_rewards(
+    current_balance: Balance,
+    previous_balance: Balance,
+    result: &ExecutionFinalResult,
+) -> bool {
+    current_balance >= previous_balance && current_balance - previous_balance <= gas_cost(result)
+}
+
+/// Allows `proxy`, owned by `owner`, to register payment recipients with `token` from storage funds
+pub async fn add_registration_token(
+    owner: &Account,
+    proxy: &Contract,
+    token: &Contract,
+) -> anyhow::Result<()> {
+    call(
+        owner,
+        proxy.id(),
+        "add_registration_token",
+        json!({ "token_address": token.id() }),
+        0,
+    )
+    .await?
+    .assert_success();
+    Ok(())
+}
+
+/// Balance of `account_id` on the mocked fungible token `ft_contract`
+pub async fn ft_balance(ft_contract: &Contract, account_id: &str) -> anyhow::Result<u128> {
+    Ok(view(
+        ft_contract,
+        "ft_balance_of",
+        json!({ "account_id": account_id }),
+    )
+    .await?
+    .json::<U128>()?
+    .0)
 }
 
 /// Util to check a balance is the same as in a previous state
-pub fn assert_unchanged_balance(
-    account: UserAccount,
+pub async fn assert_unchanged_balance(
+    account: &Account,
     previous_balance: u128,
-    ft_contract: &ContractAccount<FungibleTokenContractContract>,
+    ft_contract: &Contract,
     account_name: &str,
-) {
-    let current_balance = call!(account, ft_contract.ft_balance_of(account.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+) -> anyhow::Result<()> {
+    let current_balance = ft_balance(ft_contract, account.id()).await?;
     assert!(
         current_balance == previous_balance,
         "{}'s balance changed by {} (from {} to {})",
@@ -26,18 +317,17 @@ pub fn assert_unchanged_balance(
         previous_balance,
         current_balance
     );
+    Ok(())
 }
 
 /// Util to assert that an account has spent a given amount of token.
-pub fn assert_spent(
-    account: UserAccount,
+pub async fn assert_spent(
+    account: &Account,
     previous_balance: u128,
     expected_spent_amount: u128,
-    ft_contract: &ContractAccount<FungibleTokenContractContract>,
-) {
-    let current_balance = call!(account, ft_contract.ft_balance_of(account.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    ft_contract: &Contract,
+) -> anyhow::Result<()> {
+    let current_balance = ft_balance(ft_contract, account.id()).await?;
     assert!(current_balance <= previous_balance, "Did not spend.");
     assert!(
         current_balance == previous_balance - expected_spent_amount,
@@ -45,18 +335,17 @@ pub fn assert_spent(
         previous_balance - current_balance,
         expected_spent_amount
     );
+    Ok(())
 }
 
 /// Util to assert that an account has received a given amount of token.
-pub fn assert_received(
-    account: UserAccount,
+pub async fn assert_received(
+    account: &Account,
     previous_balance: u128,
     expected_received_amount: u128,
-    ft_contract: &ContractAccount<FungibleTokenContractContract>,
-) {
-    let current_balance = call!(account, ft_contract.ft_balance_of(account.account_id()))
-        .unwrap_json::<U128>()
-        .0;
+    ft_contract: &Contract,
+) -> anyhow::Result<()> {
+    let current_balance = ft_balance(ft_contract, account.id()).await?;
     assert!(current_balance >= previous_balance, "Did not receive.");
     assert!(
         current_balance == previous_balance + expected_received_amount,
@@ -64,40 +353,213 @@ pub fn assert_received(
         current_balance - previous_balance,
         expected_received_amount
     );
+    Ok(())
 }
 
 pub trait ExecutionResultAssertion {
+    fn assert_success(&self);
     fn assert_one_promise_error(&self, expected_error: &str);
     fn assert_success_one_log(&self, expected_log: &str);
+    fn assert_payment_failed(&self, expected_code: &str);
+    fn unwrap_json<T: DeserializeOwned>(&self) -> T;
 }
 
-impl ExecutionResultAssertion for ExecutionResult {
+impl ExecutionResultAssertion for ExecutionFinalResult {
+    fn assert_success(&self) {
+        assert!(
+            self.is_success(),
+            "Transaction failed: {:?}",
+            self.failures()
+        );
+    }
+
     fn assert_one_promise_error(&self, expected_error: &str) {
-        assert!(!self.is_ok(), "Promise succeeded, expected to fail.");
+        assert!(self.is_failure(), "Promise succeeded, expected to fail.");
+        let failures = self.receipt_failures();
         assert_eq!(
-            self.promise_errors().len(),
+            failures.len(),
             1,
             "Expected 1 error, got {}",
-            self.promise_errors().len()
+            failures.len()
         );
 
-        if let ExecutionStatus::Failure(execution_error) =
-            &self.promise_errors().remove(0).unwrap().outcome().status
-        {
-            assert!(
-                execution_error.to_string().contains(expected_error),
-                "Expected error containing: '{}'. Got: '{}'",
-                expected_error,
-                execution_error.to_string()
-            );
-        } else {
-            unreachable!();
-        }
+        let execution_error = failures[0].clone().into_result().unwrap_err().to_string();
+        assert!(
+            execution_error.contains(expected_error),
+            "Expected error containing: '{}'. Got: '{}'",
+            expected_error,
+            execution_error
+        );
     }
 
     fn assert_success_one_log(&self, expected_log: &str) {
         self.assert_success();
         assert_eq!(self.logs().len(), 1, "Wrong number of logs");
-        assert!(self.logs()[0].contains(&expected_log));
+        assert!(self.logs()[0].contains(expected_log));
+    }
+
+    /// Checks that the payment failed with the error `expected_code`, after logging a `failure` event
+    fn assert_payment_failed(&self, expected_code: &str) {
+        self.assert_one_promise_error(expected_code);
+        let expected_log = format!(r#""code":"{}","event":"failure""#, expected_code);
+        assert!(
+            self.logs().iter().any(|log| log.contains(&expected_log)),
+            "Expected a log containing: '{}'",
+            expected_log
+        );
+    }
+
+    fn unwrap_json<T: DeserializeOwned>(&self) -> T {
+        self.clone().json().unwrap()
+    }
+}
+
+/// Local relayer, submitting the calls of sender accounts as NEP-366 delegate actions: the sender signs the delegate
+/// action, and the relayer the transaction carrying it, paying for the gas. The sender is the predecessor of its calls.
+pub struct Relayer {
+    pub account: Account,
+    worker: Worker<Sandbox>,
+}
+
+impl Relayer {
+    pub async fn new(worker: &Worker<Sandbox>) -> anyhow::Result<Self> {
+        Ok(Self {
+            account: create_account(worker, "relayer", to_yocto("100")).await?,
+            worker: worker.clone(),
+        })
     }
+
+    /// Creates a sender account `sender_id`, holding `balance` for its storage and the deposits of its calls
+    pub async fn create_sender(
+        &self,
+        worker: &Worker<Sandbox>,
+        sender_id: &str,
+        balance: Balance,
+    ) -> anyhow::Result<Account> {
+        create_account(worker, sender_id, balance).await
+    }
+
+    /// Relays the call of `method_name` on `receiver_id` with `args` and `deposit` by `sender`.
+    /// near-workspaces cannot send delegate actions, the transaction is sent to the sandbox RPC instead.
+    pub async fn relay(
+        &self,
+        sender: &Account,
+        receiver_id: &str,
+        method_name: &str,
+        args: Value,
+        deposit: Balance,
+    ) -> anyhow::Result<RelayedResult> {
+        let block = self.worker.view_block().await?;
+        let sender_key: near_crypto::SecretKey = sender.secret_key().to_string().parse()?;
+        let function_call = Action::FunctionCall(FunctionCallAction {
+            method_name: method_name.into(),
+            args: args.to_string().into_bytes(),
+            gas: DEFAULT_GAS.as_gas() - Gas::from_tgas(20).as_gas(),
+            deposit,
+        });
+        let delegate_action = DelegateAction {
+            sender_id: sender.id().as_str().parse()?,
+            receiver_id: receiver_id.parse()?,
+            actions: vec![NonDelegateAction::try_from(function_call)
+                .map_err(|_| anyhow::anyhow!("Delegate actions cannot be nested"))?],
+            nonce: self.nonce(sender).await? + 1,
+            max_block_height: block.height() + 100,
+            public_key: sender_key.public_key(),
+        };
+        let signature = sender_key.sign(delegate_action.get_nep461_hash().as_ref());
+
+        let relayer = InMemorySigner::from_secret_key(
+            self.account.id().as_str().parse()?,
+            self.account.secret_key().to_string().parse()?,
+        );
+        let transaction = Transaction {
+            signer_id: relayer.account_id.clone(),
+            public_key: relayer.public_key.clone(),
+            nonce: self.nonce(&self.account).await? + 1,
+            receiver_id: delegate_action.sender_id.clone(),
+            block_hash: CryptoHash(block.hash().0),
+            actions: vec![Action::Delegate(SignedDelegateAction {
+                delegate_action,
+                signature,
+            })],
+        };
+        let outcome = JsonRpcClient::connect(self.worker.rpc_addr())
+            .call(RpcBroadcastTxCommitRequest {
+                signed_transaction: transaction.sign(&relayer),
+            })
+            .await?;
+        Ok(RelayedResult(outcome))
+    }
+
+    /// Nonce of the access key of `account`
+    async fn nonce(&self, account: &Account) -> anyhow::Result<u64> {
+        let access_key = self
+            .worker
+            .view_access_key(account.id(), &account.secret_key().public_key())
+            .await?;
+        Ok(access_key.nonce)
+    }
+}
+
+/// Outcome of a relayed transaction, the RPC outcome not converting to an `ExecutionFinalResult`
+pub struct RelayedResult(pub FinalExecutionOutcomeView);
+
+impl RelayedResult {
+    pub fn assert_success(&self) {
+        assert!(
+            matches!(self.0.status, FinalExecutionStatus::SuccessValue(_)),
+            "Relayed transaction failed: {:?}",
+            self.0.status
+        );
+    }
+
+    /// Gas paid by the relayer, in yoctoNEAR
+    pub fn gas_cost(&self) -> Balance {
+        std::iter::once(&self.0.transaction_outcome)
+            .chain(&self.0.receipts_outcome)
+            .map(|outcome| outcome.outcome.tokens_burnt)
+            .sum()
+    }
+}
+
+/// Deploys the reference fungible token of `near-contract-standards`, with 1000 USDC.e held by `owner`, and registers
+/// `accounts` with it. Unlike the mocked token, payments to the proxies go through `ft_transfer_call`.
+pub async fn deploy_reference_ft(
+    worker: &Worker<Sandbox>,
+    root: &Account,
+    owner: &Account,
+    accounts: &[&str],
+) -> anyhow::Result<Contract> {
+    let token = deploy(worker, "referenceft", REFERENCE_FT_WASM, to_yocto("8")).await?;
+    call(
+        root,
+        token.id(),
+        "new",
+        json!({ "owner_id": owner.id(), "total_supply": U128::from(1000000000) }),
+        0,
+    )
+    .await?
+    .assert_success();
+    for account_id in accounts {
+        call(
+            root,
+            token.id(),
+            "storage_deposit",
+            json!({ "account_id": account_id, "registration_only": null }),
+            to_yocto("0.01"),
+        )
+        .await?
+        .assert_success();
+    }
+    Ok(token)
+}
+
+/// Balance of `account_id` on the reference fungible token
+pub async fn reference_ft_balance(token: &Contract, account_id: &str) -> anyhow::Result<u128> {
+    Ok(
+        view(token, "ft_balance_of", json!({ "account_id": account_id }))
+            .await?
+            .json::<U128>()?
+            .0,
+    )
 }